@@ -11,9 +11,9 @@ use std::sync::mpsc;
 use rand::Rng;
 use smr::maps::{StringBTMap, EncBTMap, UnencBTMap, BTMap};
 use smr::runtime::Runtime;
-use smr::indexed_queue::{IndexedQueue, ContendedQueue, HttpClient, DynamoQueue, SharedQueue, ObjId, Entry, LogData, LogIndex};
+use smr::indexed_queue::{IndexedQueue, SharedLog, ContendedQueue, HttpClient, DynamoQueue, SharedQueue, ObjId, Entry, LogData, LogIndex};
 use std::sync::{Arc, Mutex};
-use smr::vm::{VM, MapSkiplist, Snapshotter, AsyncSnapshotter};
+use smr::vm::{VM, Skiplist, MapSkiplist, SortedSkiplist, Snapshotter, AsyncSnapshotter, SnapshotPolicy};
 use smr::encryptors::{MetaEncryptor, Ordable, Encrypted};
 use std::collections::{BTreeMap, HashSet};
 use std::thread;
@@ -30,7 +30,7 @@ use self::rustc_serialize::{Encodable, Decodable};
 use std::fmt::Debug;
 
 // Need: to be able to create an indexed queue that is also clonable
-trait IndexedClonable: 'static+IndexedQueue+Clone+Send+Sync {}
+trait IndexedClonable: 'static+SharedLog+Send+Sync {}
 
 #[derive(Clone)]
 struct MockHttpQueue<Q> {
@@ -62,12 +62,13 @@ impl<Q: IndexedClonable> IndexedQueue for MockHttpQueue<Q> {
 
 
 
+impl<Q: IndexedClonable> SharedLog for MockHttpQueue<Q> {}
 impl<Q: IndexedClonable> IndexedClonable for MockHttpQueue<Q> {}
 impl IndexedClonable for DynamoQueue {}
 impl IndexedClonable for SharedQueue {}
 impl IndexedClonable for HttpClient {}
 impl IndexedClonable for ContendedQueue {}
-impl<Q: IndexedClonable> IndexedClonable for VM<Q, MapSkiplist, AsyncSnapshotter> {}
+impl<Q: IndexedClonable> IndexedClonable for VM<Q, SortedSkiplist, AsyncSnapshotter> {}
 
 trait QueueFactory<Q> {
     fn new_queue(&mut self) -> Q;
@@ -121,8 +122,8 @@ impl<Q: IndexedClonable, F: QueueFactory<Q>> MockVMClientFactory<Q, F> {
 }
 
 
-impl<Q: IndexedClonable, F: QueueFactory<Q>> QueueFactory<MockHttpQueue<VM<Q, MapSkiplist, AsyncSnapshotter>>> for MockVMClientFactory<Q, F> {
-    fn new_queue(&mut self) -> MockHttpQueue<VM<Q, MapSkiplist, AsyncSnapshotter>> {
+impl<Q: IndexedClonable, F: QueueFactory<Q>> QueueFactory<MockHttpQueue<VM<Q, SortedSkiplist, AsyncSnapshotter>>> for MockVMClientFactory<Q, F> {
+    fn new_queue(&mut self) -> MockHttpQueue<VM<Q, SortedSkiplist, AsyncSnapshotter>> {
         // Start up the vm in a separate thread
         let q = start_vm(self.factory.new_queue());
         MockHttpQueue::from(q)
@@ -169,9 +170,9 @@ impl<Q: IndexedClonable, F: QueueFactory<Q>> QueueFactory<HttpClient> for VMClie
         let q = start_vm(self.factory.new_queue());
 
         let handle = thread::spawn(move || {
-            let mut s = HttpServer::new(q, &server_addr);
+            let mut s = HttpServer::new(q).serve(&server_addr).expect("error starting server");
             stop_recv.recv().expect("stop_recv does not exist");
-            s.close();
+            s.shutdown(Duration::from_secs(5));
             //done_send.send(());
         });
         self.handle = Some(handle);
@@ -214,8 +215,11 @@ enum Op<K, V> {
     Read(K),
 }
 
-fn start_vm<Q: 'static+IndexedClonable>(q: Q) -> VM<Q, MapSkiplist, AsyncSnapshotter> {
-    let mut vm = VM::new(q, MapSkiplist::new(), AsyncSnapshotter::new());
+fn start_vm<Q: 'static+IndexedClonable>(q: Q) -> VM<Q, SortedSkiplist, AsyncSnapshotter> {
+    let mut vm = VM::new(q,
+                         SortedSkiplist::new(),
+                         AsyncSnapshotter::new(),
+                         SnapshotPolicy::new());
     let id = 1 as ObjId;
     let map = EncBTMap::new(&vm.runtime, id, BTreeMap::new());
     let mut map_copy = map.clone();
@@ -543,6 +547,135 @@ fn bench_read_latency<Q: IndexedClonable,
     opts.output_csv(t as u64)
 }
 
+#[derive(Clone)]
+struct SkiplistOpts {
+    out: String,
+    mode: i64, // 0 for MapSkiplist, 1 for SortedSkiplist
+    nentries: i64, // number of log entries
+    nobjs: i64, // number of objects entries are spread over
+}
+
+impl SkiplistOpts {
+    fn header(mut out: &mut File) {
+        writeln!(&mut out, "mode, nentries, nobjs, t_append, t_stream_one, t_stream_all, t_gc").unwrap();
+    }
+    fn output_csv(&self, t_append: u64, t_stream_one: u64, t_stream_all: u64, t_gc: u64) {
+        let mut out = OpenOptions::new()
+                .write(true)
+                .append(true)
+                .open(&self.out).unwrap();
+        writeln!(&mut out, "{}, {}, {}, {}, {}, {}, {}", self.mode, self.nentries, self.nobjs, t_append, t_stream_one, t_stream_all, t_gc).unwrap();
+    }
+}
+
+// bench_skiplist: times the VM skiplist on a log of nentries entries, each touching a random object
+// t_append: time per append
+// t_stream_one: time to stream the last 100 entries of a single object
+// t_stream_all: time to stream the second half of the log for all objects
+// t_gc: time to gc the first half of the log
+fn bench_skiplist<S: Skiplist>(mut skiplist: S, opts: SkiplistOpts) {
+    let nsamples = 20;
+    for obj in 0..opts.nobjs {
+        skiplist.insert(obj as ObjId);
+    }
+    let objs : Vec<ObjId> = (0..opts.nentries).map(|_| (rand::random::<u32>() as i64 % opts.nobjs) as ObjId).collect();
+
+    let start = time::precise_time_ns();
+    for (idx, &obj) in objs.iter().enumerate() {
+        skiplist.append(obj, idx as LogIndex);
+    }
+    let end = time::precise_time_ns();
+    let t_append = (end - start) / (opts.nentries as u64);
+
+    let one : HashSet<ObjId> = vec![0].into_iter().collect();
+    let from = opts.nentries - 100 * opts.nobjs;
+    let start = time::precise_time_ns();
+    for _ in 0..nsamples {
+        skiplist.stream(&one, from, None);
+    }
+    let end = time::precise_time_ns();
+    let t_stream_one = (end - start) / nsamples;
+
+    let all : HashSet<ObjId> = (0..opts.nobjs).map(|obj| obj as ObjId).collect();
+    let start = time::precise_time_ns();
+    for _ in 0..nsamples {
+        let idxs = skiplist.stream(&all, opts.nentries / 2, None);
+        assert_eq!(idxs.len() as i64, opts.nentries - opts.nentries / 2);
+    }
+    let end = time::precise_time_ns();
+    let t_stream_all = (end - start) / nsamples;
+
+    let start = time::precise_time_ns();
+    skiplist.gc(opts.nentries / 2);
+    let end = time::precise_time_ns();
+    let t_gc = end - start;
+
+    opts.output_csv(t_append, t_stream_one, t_stream_all, t_gc);
+}
+
+#[derive(Clone)]
+struct ThroughputOpts {
+    out: String,
+    mode: i64, // 0 for appends only, 1 for reads and writes
+    nclients: i64, // number of clients sending requests at once
+    nops: i64, // number of operations per client
+}
+
+impl ThroughputOpts {
+    fn header(mut out: &mut File) {
+        writeln!(&mut out, "mode, nclients, nops, t, ops_per_sec").unwrap();
+    }
+    fn output_csv(&self, t: u64) {
+        let mut out = OpenOptions::new()
+                .write(true)
+                .append(true)
+                .open(&self.out).unwrap();
+        let ops = (self.nclients * self.nops) as u64;
+        writeln!(&mut out, "{}, {}, {}, {}, {}", self.mode, self.nclients, self.nops, t, ops * 1000000000 / t).unwrap();
+    }
+}
+
+// bench_throughput: nclients clients concurrently insert into (and read from) one encrypted map,
+// through an HttpServer in front of a VM
+// t: time for all clients to complete their operations
+fn bench_throughput(opts: ThroughputOpts) {
+    let port = PORT_NUM.fetch_add(1, Ordering::SeqCst);
+    let server_addr = String::from("127.0.0.1:") + &port.to_string();
+    let to_server_addr = String::from("http://127.0.0.1:")+ &port.to_string();
+    let mut server = HttpServer::new(start_vm(SharedQueue::new()))
+                         .serve(&server_addr)
+                         .expect("error starting server");
+    thread::sleep(Duration::from_millis(1000));
+
+    let encryptor = Some(MetaEncryptor::new());
+    let (keys, values) = gen_kvs(1000);
+    let w = if opts.mode == 0 { 1000 } else { 500 };
+    let clients : Vec<_> = (0..opts.nclients).map(|_| {
+        let runtime = Runtime::new(HttpClient::new(&to_server_addr), encryptor.clone());
+        let mut map = StringBTMap::new(&Arc::new(Mutex::new(runtime)), 1, BTreeMap::new());
+        map.start();
+        (map, gen_ops(&keys, &values, opts.nops, w))
+    }).collect();
+
+    let start = time::precise_time_ns();
+    let handles : Vec<_> = clients.into_iter().map(|(mut map, ops)| {
+        thread::spawn(move || {
+            for op in ops {
+                match op {
+                    Op::Write(k, v) => { map.insert(k, v); },
+                    Op::Read(k) => { map.get(&k); },
+                }
+            }
+        })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let end = time::precise_time_ns();
+    server.shutdown(Duration::from_secs(5));
+    opts.output_csv(end - start);
+}
+
 fn main() {
     println!("creating options");
     let mut opts = Options::new();
@@ -551,6 +684,8 @@ fn main() {
     opts.optopt("l", "latency", "set read latency output file name", "NAME");
     opts.optopt("r", "recovery", "set recovery latency output file name", "NAME");
     opts.optopt("i", "integration", "set the integration benchmark output file name", "NAME");
+    opts.optopt("s", "skiplist", "set the skiplist benchmark output file name", "NAME");
+    opts.optopt("t", "throughput", "set the http throughput benchmark output file name", "NAME");
     println!("parsing args");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
@@ -635,6 +770,44 @@ fn main() {
             }
         }
     }
+    if matches.opt_present("s") {
+        let output = matches.opt_str("s").unwrap();
+        {
+            let mut out = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(output.clone()).unwrap();
+            SkiplistOpts::header(&mut out);
+        }
+        // test skiplist stream and gc up to 1M entries
+        for nentries in vec![10000, 100000, 1000000] {
+            for nobjs in vec![1, 10, 100] {
+                println!("Benching Skiplist: nentries={} nobjs={}", nentries, nobjs);
+                bench_skiplist(MapSkiplist::new(), SkiplistOpts{out: output.clone(), mode: 0, nentries: nentries, nobjs: nobjs});
+                bench_skiplist(SortedSkiplist::new(), SkiplistOpts{out: output.clone(), mode: 1, nentries: nentries, nobjs: nobjs});
+            }
+        }
+    }
+    if matches.opt_present("t") {
+        let output = matches.opt_str("t").unwrap();
+        {
+            let mut out = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(output.clone()).unwrap();
+            ThroughputOpts::header(&mut out);
+        }
+        // test http server throughput as clients are added
+        for nclients in vec![1, 2, 4, 8, 16, 32] {
+            println!("Benching Throughput: nclients={}", nclients);
+            bench_throughput(ThroughputOpts{out: output.clone(), mode: 0, nclients: nclients, nops: 200});
+            bench_throughput(ThroughputOpts{out: output.clone(), mode: 1, nclients: nclients, nops: 200});
+        }
+    }
     return;
     if matches.opt_present("i") {
         let output = matches.opt_str("i").unwrap();
@@ -26,14 +26,15 @@ extern crate smr;
 use std::time::Duration;
 use std::thread;
 use std::sync::{Arc, Mutex};
-use std::fs::OpenOptions;
-use std::io::Write;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
 
 use std::collections::{BTreeMap};
 
 use smr::runtime::Runtime;
-use smr::encryptors::{MetaEncryptor};
+use smr::encryptors::{MetaEncryptor, Authenticator};
 use smr::maps::{StringBTMap, UnencBTMap};
+use smr::directory;
 use smr::indexed_queue::{IndexedQueue, HttpClient, DynamoQueue};
 
 use clap::App;
@@ -64,19 +65,33 @@ impl QueueFactory<DynamoQueue> for DynamoQueueFactory {
 
 struct HttpClientFactory {
     server_addr: String,
+    failover: Vec<String>,
+    ca: Option<String>,
+    token: Option<String>,
 }
 
 impl HttpClientFactory {
-    fn new(h: &str, p: &str) -> HttpClientFactory {
+    fn new(h: &str, p: &str, failover: Option<&str>, ca: Option<&str>, token: Option<&str>)
+           -> HttpClientFactory {
         HttpClientFactory{
             server_addr: String::from(h)+ p,
+            failover: failover.map_or(vec![], |f| f.split(',').map(String::from).collect()),
+            ca: ca.map(String::from),
+            token: token.map(String::from),
         }
     }
 }
 
 impl QueueFactory<HttpClient> for HttpClientFactory {
     fn new_queue(&mut self) -> HttpClient {
-        let client = HttpClient::new(&self.server_addr);
+        let failover: Vec<&str> = self.failover.iter().map(|s| &s[..]).collect();
+        let mut client = HttpClient::new(&self.server_addr).with_failover(&failover);
+        if let Some(ref ca) = self.ca {
+            client = client.with_tls(ca).expect("ca: error reading file");
+        }
+        if let Some(ref token) = self.token {
+            client = client.with_token(token);
+        }
         return client;
     }
 }
@@ -130,9 +145,12 @@ fn print_csv(f: &str, enc: bool, vm: bool, times: &[(i32, u64)]) {
 fn run_client<Q: IndexedClonable, F: QueueFactory<Q>>(mut f: F, mut ops: Vec<Op<String, String>>, opts: Opts) {
     let q = f.new_queue();
     // create an underlying encryptor for this runtime
-    let encryptor = Some(MetaEncryptor::new());
+    // entries are macked with the key shared by all clients, or they reject each other's
+    let encryptor = Some(MetaEncryptor::new().with_auth(Authenticator::from_key(opts.mac_key.clone())));
     // create a new runtime with the encryptor
-    let runtime = Runtime::new(q, encryptor.clone());
+    let mut runtime = Runtime::new(q, encryptor.clone());
+    // tag the map so the server replicates it with the matching type
+    runtime.set_type_tag(1, if opts.enc { directory::BTMAP } else { directory::UNENC_BTMAP });
 
 
     // create a map
@@ -193,6 +211,7 @@ fn run_client<Q: IndexedClonable, F: QueueFactory<Q>>(mut f: F, mut ops: Vec<Op<
 
 struct Opts {
     enc: bool,
+    mac_key: Vec<u8>,
     out: String,
     vm: bool,
     delay: u64,
@@ -211,12 +230,18 @@ fn main() {
     let host = matches.value_of("host");
     let port = matches.value_of("port");
     let enc = matches.is_present("enc");
+    let mut mac_key = Vec::new();
+    File::open(matches.value_of("mac-key").unwrap())
+        .and_then(|mut f| f.read_to_end(&mut mac_key))
+        .expect("mac-key: error reading file");
 
     let (k, v) = gen_kvs(keys);
     let ops = gen_ops(&k, &v, nops, writes);
-    let opts = Opts{enc: enc, out: out.to_string(), vm: vm, delay: delay};
+    let opts = Opts{enc: enc, mac_key: mac_key, out: out.to_string(), vm: vm, delay: delay};
     if vm {
-        let factory = HttpClientFactory::new(host.unwrap(), port.unwrap());
+        let factory = HttpClientFactory::new(host.unwrap(), port.unwrap(),
+                                             matches.value_of("failover"),
+                                             matches.value_of("ca"), matches.value_of("token"));
         run_client(factory, ops, opts);
     } else {
         let factory = DynamoQueueFactory::new();
@@ -147,6 +147,28 @@ retry:
 	return index, err
 }
 
+// store data at index, outside of the log: the log length is not updated
+// if conditional, fails with ConditionalCheckFailedException when index exists
+func record(index int64, data string, conditional bool) error {
+	log.Println("RECORD:", index, data, conditional)
+	i := Index
+	params := &dynamodb.PutItemInput{
+		TableName: aws.String(table),
+		Item: map[string]*dynamodb.AttributeValue{
+			Index: {N: aws.String(strconv.FormatInt(index, 10))},
+			Data:  {S: aws.String(data)},
+		},
+	}
+	if conditional {
+		params.ExpressionAttributeNames = map[string]*string{
+			"#" + Index: &i,
+		}
+		params.ConditionExpression = aws.String("attribute_not_exists(#index)")
+	}
+	_, err := svc.PutItem(params)
+	return err
+}
+
 func updateLength() error {
 	params := &dynamodb.UpdateItemInput{
 		TableName: aws.String(table),
@@ -248,6 +270,7 @@ const (
 	Delete             = 2
 	Length             = 3
 	Reset              = 4
+	Record             = 5
 )
 
 // Request for log
@@ -314,6 +337,11 @@ func handleConnection(conn net.Conn) {
 			resp.Length = l
 		case Reset:
 			reset()
+		case Record:
+			err = record(req.Index, req.Data, req.Conditional)
+			if ae, ok := err.(awserr.RequestFailure); ok {
+				resp.ValidationErr = ae.Code() == "ConditionalCheckFailedException"
+			}
 		}
 		if err != nil {
 			resp.Err = err.Error()
@@ -1,15 +1,22 @@
 #[macro_use]
 extern crate clap;
+extern crate chan_signal;
 extern crate smr;
-use smr::maps::{EncBTMap, UnencBTMap};
+use std::time::Duration;
+use std::io::{self, Write};
+use std::process;
+use chan_signal::Signal;
 use smr::indexed_queue::{DynamoQueue, ObjId};
-use smr::vm::{VM, MapSkiplist, AsyncSnapshotter};
-use std::collections::{BTreeMap};
+use smr::vm::{VM, SortedSkiplist, AsyncSnapshotter, SnapshotPolicy};
+use smr::directory;
 use smr::http_server::HttpServer;
 use clap::App;
 
 
 fn main() {
+    // must be set up before any thread is spawned, so they all block the signals
+    let signal = chan_signal::notify(&[Signal::INT, Signal::TERM]);
+
     let yml = load_yaml!("app.yml");
     let app = App::from_yaml(yml);
     let matches = app.get_matches();
@@ -20,24 +27,39 @@ fn main() {
     println!("Hello, world!");
 
     // start up a vm given an underlying dynamodb queue
+    // objects are registered as clients name them or first operate on them,
+    // their type tag picks the encrypted replica
     let q = DynamoQueue::new();
-    let mut vm = VM::new(q, MapSkiplist::new(), AsyncSnapshotter::new());
-    let id = 1 as ObjId;
-    if enc {
-        let map = EncBTMap::new(&vm.runtime, id, BTreeMap::new());
-        let mut map_copy = map.clone();
-        vm.register_object(id as ObjId,
-                           Box::new(move |_, e| map_copy.callback(e)),
-                           map.clone());
-        vm.start();
-    } else {
-        let map = UnencBTMap::new(&vm.runtime, id, BTreeMap::new());
-        let mut map_copy = map.clone();
-        vm.register_object(id as ObjId,
-                           Box::new(move |_, e| map_copy.callback(e)),
-                           map.clone());
-        vm.start();
-    }
+    let mut vm = VM::new(q,
+                         SortedSkiplist::new(),
+                         AsyncSnapshotter::new(),
+                         SnapshotPolicy::new());
+    // map of clients that do not tag their operations
+    let default_type = if enc { directory::BTMAP } else { directory::UNENC_BTMAP };
+    vm.register_as(1 as ObjId, default_type);
+    vm.start();
     // start up the server at the given address
-    HttpServer::new(vm, &server_addr);
+    let mut server = HttpServer::new(vm.clone());
+    if let (Some(cert), Some(key)) = (matches.value_of("cert"), matches.value_of("key")) {
+        server = server.with_tls(cert, key);
+    }
+    if let Some(token) = matches.value_of("token") {
+        server = server.with_token(token);
+    }
+    let mut server = match server.serve(&server_addr) {
+        Ok(server) => server,
+        Err(err) => {
+            let _ = writeln!(io::stderr(), "Cannot serve {}: {}", server_addr, err);
+            vm.shutdown();
+            process::exit(1);
+        }
+    };
+
+    // serve until told to stop, then let requests in flight complete and snapshot the VM
+    let sig = signal.recv();
+    println!("Received {:?}, shutting down", sig);
+    if !server.shutdown(Duration::from_secs(10)) {
+        println!("Requests still in flight after 10s, shutting down anyway");
+    }
+    vm.shutdown();
 }
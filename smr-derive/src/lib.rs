@@ -0,0 +1,264 @@
+// Crate: smr_derive
+// Procedural macros defining replicated types on top of smr's Runtime and ConvertersLib
+// * #[derive(Replicated)] on a struct whose fields are all marked #[encrypt(eq|ord|add|aes)]
+//   generates the op enum NameOp, the encrypted twin EncName, ReplicatedObject for both,
+//   and Diff turning changes of the struct into ops
+// * #[replicated_ops] on an impl block of such a struct generates the trait NameOps,
+//   implemented by Replica<Name, Q>, with one method per #[op] method of the block
+// See smr::replicated for the types the generated code builds on
+#![crate_type = "proc-macro"]
+
+extern crate proc_macro;
+extern crate syn;
+#[macro_use]
+extern crate quote;
+
+use proc_macro::TokenStream;
+
+// encryption schemes of smr::replicated
+const SCHEMES: &'static [&'static str] = &["eq", "ord", "add", "aes"];
+// inherent methods of Replica, an op of the same name could never be called
+const RESERVED: &'static [&'static str] = &["new", "secure", "start", "read", "try_read",
+                                            "append", "try_append", "try_update", "callback"];
+
+#[proc_macro_derive(Replicated, attributes(encrypt))]
+pub fn derive_replicated(input: TokenStream) -> TokenStream {
+    let ast = syn::parse_derive_input(&input.to_string()).unwrap();
+    match expand_replicated(&ast) {
+        Ok(tokens) => tokens.to_string().parse().unwrap(),
+        Err(msg) => panic!("#[derive(Replicated)] on {}: {}", ast.ident, msg),
+    }
+}
+
+#[proc_macro_attribute]
+pub fn replicated_ops(_: TokenStream, input: TokenStream) -> TokenStream {
+    let mut item = syn::parse_item(&input.to_string()).unwrap();
+    match expand_ops(&mut item) {
+        Ok(tokens) => tokens.to_string().parse().unwrap(),
+        Err(msg) => panic!("#[replicated_ops]: {}", msg),
+    }
+}
+
+fn is_generic(generics: &syn::Generics) -> bool {
+    !generics.lifetimes.is_empty() || !generics.ty_params.is_empty()
+}
+
+// Scheme named by the #[encrypt(..)] attribute of field
+fn scheme(field: &syn::Field) -> Result<syn::Ident, String> {
+    let ident = field.ident.as_ref().unwrap();
+    for attr in &field.attrs {
+        if let syn::MetaItem::List(ref name, ref items) = attr.value {
+            if name.as_ref() != "encrypt" {
+                continue;
+            }
+            if items.len() == 1 {
+                if let syn::NestedMetaItem::MetaItem(syn::MetaItem::Word(ref scheme)) = items[0] {
+                    if SCHEMES.contains(&scheme.as_ref()) {
+                        return Ok(scheme.clone());
+                    }
+                }
+            }
+            return Err(format!("field {} has an unknown scheme, expected one of {:?}",
+                               ident,
+                               SCHEMES));
+        }
+    }
+    Err(format!("field {} is missing #[encrypt(eq|ord|add|aes)]", ident))
+}
+
+fn expand_replicated(ast: &syn::DeriveInput) -> Result<quote::Tokens, String> {
+    let fields = match ast.body {
+        syn::Body::Struct(syn::VariantData::Struct(ref fields)) => fields,
+        _ => return Err(String::from("only structs with named fields can be replicated")),
+    };
+    if is_generic(&ast.generics) {
+        return Err(String::from("generic structs cannot be replicated"));
+    }
+
+    let name = &ast.ident;
+    let vis = &ast.vis;
+    let twin = syn::Ident::new(format!("Enc{}", name));
+    let op = syn::Ident::new(format!("{}Op", name));
+
+    let mut twin_fields = Vec::new();
+    let mut variants = Vec::new();
+    let mut applies = Vec::new();
+    let mut applies_enc = Vec::new();
+    let mut restores = Vec::new();
+    let mut diffs = Vec::new();
+    for field in fields {
+        let scheme = try!(scheme(field));
+        let ty = &field.ty;
+        let field = field.ident.as_ref().unwrap();
+        let update = quote! {
+            ::smr::replicated::Update<::smr::replicated::#scheme, #ty>
+        };
+        twin_fields.push(quote! {
+            pub #field: Option<<::smr::replicated::#scheme
+                                as ::smr::replicated::Scheme<#ty>>::Enc>
+        });
+        variants.push(quote! { #field(#update) });
+        applies.push(quote! { #op::#field(update) => update.apply(secure, &mut self.#field) });
+        applies_enc.push(quote! { #op::#field(update) => update.apply_enc(&mut self.#field) });
+        restores.push(quote! {
+            self.#field = ::smr::replicated::restore::<::smr::replicated::#scheme, #ty>(
+                secure, twin.#field);
+        });
+        diffs.push(quote! {
+            if let Some(update) = <#update>::diff(secure, &before.#field, &self.#field) {
+                ops.push(#op::#field(update));
+            }
+        });
+    }
+
+    Ok(quote! {
+        #[derive(RustcEncodable, RustcDecodable, Clone, Default)]
+        #vis struct #twin {
+            #(#twin_fields),*
+        }
+
+        #[allow(non_camel_case_types)]
+        #[derive(RustcEncodable, RustcDecodable)]
+        #vis enum #op {
+            #(#variants),*
+        }
+
+        impl ::smr::replica::ReplicatedObject for #name {
+            type Op = #op;
+            type Twin = #twin;
+
+            fn apply(&mut self, secure: &Option<::smr::encryptors::MetaEncryptor>, op: #op) {
+                match op {
+                    #(#applies),*
+                }
+            }
+
+            fn restore(&mut self, secure: &Option<::smr::encryptors::MetaEncryptor>, twin: #twin) {
+                #(#restores)*
+            }
+        }
+
+        impl ::smr::replica::ReplicatedObject for #twin {
+            type Op = #op;
+            type Twin = #twin;
+
+            fn apply(&mut self, _: &Option<::smr::encryptors::MetaEncryptor>, op: #op) {
+                match op {
+                    #(#applies_enc),*
+                }
+            }
+
+            fn restore(&mut self, _: &Option<::smr::encryptors::MetaEncryptor>, twin: #twin) {
+                *self = twin;
+            }
+        }
+
+        impl ::smr::replicated::Diff for #name {
+            fn diff(&self,
+                    secure: &Option<::smr::encryptors::MetaEncryptor>,
+                    before: &#name)
+                    -> Vec<#op> {
+                let mut ops = Vec::new();
+                #(#diffs)*
+                ops
+            }
+        }
+    })
+}
+
+// Signature and body of the Replica method running #[op] method item
+fn expand_op(item: &syn::ImplItem) -> Result<(quote::Tokens, quote::Tokens), String> {
+    let method = &item.ident;
+    let sig = match item.node {
+        syn::ImplItemKind::Method(ref sig, _) => sig,
+        _ => return Err(format!("#[op] {} is not a method", method)),
+    };
+    if is_generic(&sig.generics) {
+        return Err(format!("op {} cannot be generic", method));
+    }
+    if RESERVED.contains(&method.as_ref()) {
+        return Err(format!("op {} is shadowed by Replica::{}", method, method));
+    }
+
+    let mut inputs = sig.decl.inputs.iter();
+    match inputs.next() {
+        Some(&syn::FnArg::SelfRef(_, syn::Mutability::Mutable)) => {}
+        _ => return Err(format!("op {} must take &mut self", method)),
+    }
+    let mut args = Vec::new();
+    let mut names = Vec::new();
+    for input in inputs {
+        match *input {
+            syn::FnArg::Captured(syn::Pat::Ident(_, ref arg, None), ref ty) => {
+                args.push(quote! { #arg: #ty });
+                names.push(arg.clone());
+            }
+            _ => return Err(format!("arguments of op {} must be plain names", method)),
+        }
+    }
+    let ret = match sig.decl.output {
+        syn::FunctionRetTy::Default => quote! { () },
+        syn::FunctionRetTy::Ty(ref ty) => quote! { #ty },
+    };
+
+    let sig = quote! {
+        fn #method(&mut self, #(#args),*) -> Result<#ret, ::smr::runtime::LogError>
+    };
+    // arguments are cloned as the op is run again if its transaction aborts
+    let body = quote! {
+        #sig {
+            self.try_update(|obj| obj.#method(#(#names.clone()),*))
+        }
+    };
+    Ok((sig, body))
+}
+
+fn expand_ops(item: &mut syn::Item) -> Result<quote::Tokens, String> {
+    let (name, vis, sigs, bodies) = match item.node {
+        syn::ItemKind::Impl(_, _, ref generics, None, ref self_ty, ref mut items) => {
+            if is_generic(generics) {
+                return Err(String::from("generic impl blocks cannot define ops"));
+            }
+            let name = match **self_ty {
+                syn::Ty::Path(None, ref path) if path.segments.len() == 1 => {
+                    path.segments[0].ident.clone()
+                }
+                _ => return Err(String::from("ops must be defined on a replicated struct")),
+            };
+            let mut vis = None;
+            let mut sigs = Vec::new();
+            let mut bodies = Vec::new();
+            for item in items.iter_mut() {
+                let attrs = item.attrs.len();
+                item.attrs.retain(|attr| attr.name() != "op");
+                if item.attrs.len() == attrs {
+                    continue;
+                }
+                let (sig, body) = try!(expand_op(item));
+                vis = vis.or(Some(item.vis.clone()));
+                sigs.push(sig);
+                bodies.push(body);
+            }
+            match vis {
+                Some(vis) => (name, vis, sigs, bodies),
+                None => return Err(String::from("no #[op] methods")),
+            }
+        }
+        _ => return Err(String::from("expected an inherent impl block")),
+    };
+    let ops = syn::Ident::new(format!("{}Ops", name));
+
+    Ok(quote! {
+        #item
+
+        #vis trait #ops {
+            #(#sigs;)*
+        }
+
+        impl<Q> #ops for ::smr::replica::Replica<#name, Q>
+            where Q: 'static + ::smr::indexed_queue::IndexedQueue + Send + Clone
+        {
+            #(#bodies)*
+        }
+    })
+}
@@ -2,6 +2,7 @@ extern crate rustc_serialize;
 use self::rustc_serialize::json;
 use self::rustc_serialize::{Encodable, Decodable};
 use encryptors::{MetaEncryptor, Addable, Eqable, Ordable, Encrypted, Int};
+use byteorder::{BigEndian, ByteOrder};
 
 use std::sync::Arc;
 
@@ -56,6 +57,23 @@ impl ConvertersLib {
 
     }
 
+    // u64 is encrypted as 8 big endian bytes, so Ordable order is numeric order
+    // (json encoding would order 10 before 9)
+    fn m_ordable_from_u64(secure: &Option<MetaEncryptor>, val: u64) -> Ordable {
+        let mut data = [0u8; 8];
+        BigEndian::write_u64(&mut data, val);
+        secure.as_ref()
+              .map(|secure| secure.encrypt_ordable(&data))
+              .unwrap()
+    }
+
+    fn m_u64_from_ordable(secure: &Option<MetaEncryptor>, e: Ordable) -> u64 {
+        match secure {
+            &Some(ref secure) => BigEndian::read_u64(&secure.decrypt_ordable(e)),
+            &None => panic!("no secure given"),
+        }
+    }
+
     fn m_eqable_from_eqable(_: &Option<MetaEncryptor>, e: Eqable) -> Eqable {
         e
     }
@@ -147,6 +165,14 @@ impl ConvertersLib {
         Box::new(ConvertersLib::m_encodable_from_ordable)
     }
 
+    pub fn ordable_from_u64() -> Box<Fn(&Option<MetaEncryptor>, u64) -> Ordable + Send + Sync> {
+        Box::new(ConvertersLib::m_ordable_from_u64)
+    }
+
+    pub fn u64_from_ordable() -> Box<Fn(&Option<MetaEncryptor>, Ordable) -> u64 + Send + Sync> {
+        Box::new(ConvertersLib::m_u64_from_ordable)
+    }
+
     pub fn eqable_from_eqable
         ()
         -> Box<Fn(&Option<MetaEncryptor>, Eqable) -> Eqable + Send + Sync>
@@ -288,7 +314,7 @@ mod test {
     extern crate rustc_serialize;
     use super::{ConvertersLib, AddableConverter, EqableConverter, OrdableConverter, Converter,
                 SimpleConverter};
-    use encryptors::{Encrypted};
+    use encryptors::{Encrypted, MetaEncryptor};
 
     #[test]
     fn create_addable_converter() {
@@ -313,6 +339,17 @@ mod test {
 
     }
 
+    #[test]
+    fn ordable_u64_order() {
+        let secure = Some(MetaEncryptor::new());
+        let to = ConvertersLib::ordable_from_u64();
+        let from = ConvertersLib::u64_from_ordable();
+        let e9 = to(&secure, 9);
+        let e10 = to(&secure, 10);
+        assert!(e9 < e10);
+        assert_eq!(from(&secure, e10), 10);
+    }
+
     #[test]
     fn create_converter() {
         let _: Converter<String> = Converter::new(ConvertersLib::encodable_from_encrypted(),
@@ -0,0 +1,257 @@
+extern crate rustc_serialize;
+use self::rustc_serialize::json;
+use self::rustc_serialize::{Encodable, Encoder};
+
+use runtime::Runtime;
+use indexed_queue::{Operation, IndexedQueue, State, LogOp, ObjId};
+use encryptors::Eqable;
+use maps::{StringHMap, StringBTMap, UnencBTMap};
+use sets::{StringHSet, StringBTSet};
+use ds::{StringList, StringQueue, IntRegister, DeadlineQueue, StringLock, IntGCounter,
+         IntPNCounter};
+
+use std::i32;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet, BTreeMap, BTreeSet, VecDeque};
+
+// Object id of the directory itself
+pub const DIRECTORY: ObjId = i32::MAX;
+// Ids handed out by the directory start here, lower ids are left to hand picked objects
+pub const FIRST_ID: ObjId = 1 << 20;
+
+// Type tags of objects that can be opened by name
+pub const HMAP: &'static str = "hmap";
+pub const BTMAP: &'static str = "btmap";
+pub const UNENC_BTMAP: &'static str = "btmap/unenc";
+pub const HSET: &'static str = "hset";
+pub const BTSET: &'static str = "btset";
+pub const LIST: &'static str = "list";
+pub const QUEUE: &'static str = "queue";
+pub const REGISTER: &'static str = "register";
+pub const PRIORITY_QUEUE: &'static str = "pqueue";
+pub const LOCK: &'static str = "lock";
+pub const GCOUNTER: &'static str = "gcounter";
+pub const PNCOUNTER: &'static str = "pncounter";
+
+#[derive(RustcEncodable, RustcDecodable, Debug)]
+pub enum DirectoryOp {
+    Create {
+        name: Eqable,
+        type_tag: String,
+        scheme: String,
+    },
+}
+
+// Class: DirectoryEntry
+// Object registered in the directory
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug)]
+pub struct DirectoryEntry {
+    pub name: Eqable, // encrypted name, VM can only compare names
+    pub obj_id: ObjId, // id assigned to the object
+    pub type_tag: String, // data structure of the object, picks the VM replica type
+    pub scheme: String, // encryption scheme of the object's elements
+}
+
+// Encoded state of the directory, as snapshotted by VM
+#[derive(RustcEncodable, RustcDecodable)]
+struct DirectorySnapshot {
+    entries: Vec<DirectoryEntry>,
+    next_id: ObjId,
+}
+
+// Class: Directory
+// Maps object names to ids, types and encryption schemes, replicated through the SharedLog
+// Ids are assigned in log order, the first Create of a name wins
+// Same structure is used by clients and VM, names stay encrypted on both
+#[derive(Clone)]
+pub struct Directory {
+    entries: Arc<Mutex<HashMap<Eqable, DirectoryEntry>>>, // local data structure
+    next_id: Arc<Mutex<ObjId>>, // id of next created object
+    created: Option<Arc<Mutex<VecDeque<DirectoryEntry>>>>, // entries created since last drained
+}
+
+impl Encodable for Directory {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let snap = DirectorySnapshot {
+            entries: self.entries.lock().unwrap().values().cloned().collect(),
+            next_id: *self.next_id.lock().unwrap(),
+        };
+        snap.encode(s)
+    }
+}
+
+impl Directory {
+    pub fn new() -> Directory {
+        Directory {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            next_id: Arc::new(Mutex::new(FIRST_ID)),
+            created: None,
+        }
+    }
+
+    // Keep track of created entries, to be drained by the caller (used by VM)
+    // Must be called before the directory is registered with a runtime
+    pub fn watch(&mut self) -> Arc<Mutex<VecDeque<DirectoryEntry>>> {
+        let created = Arc::new(Mutex::new(VecDeque::new()));
+        self.created = Some(created.clone());
+        created
+    }
+
+    pub fn get(&self, name: &Eqable) -> Option<DirectoryEntry> {
+        self.entries.lock().unwrap().get(name).cloned()
+    }
+
+    pub fn entries(&self) -> Vec<DirectoryEntry> {
+        self.entries.lock().unwrap().values().cloned().collect()
+    }
+
+    fn insert(&self, entry: DirectoryEntry) {
+        self.created.as_ref().map(|created| created.lock().unwrap().push_back(entry.clone()));
+        self.entries.lock().unwrap().insert(entry.name.clone(), entry);
+    }
+
+    pub fn callback(&mut self, op: Operation) {
+        match op.operator {
+            LogOp::Op(State::Encrypted(ref bytes)) => {
+                let decoded = String::from_utf8(bytes.clone())
+                                  .map_err(|err| err.to_string())
+                                  .and_then(|s| json::decode(&s).map_err(|err| err.to_string()));
+                let encrypted_op = match decoded {
+                    Ok(encrypted_op) => encrypted_op,
+                    Err(err) => {
+                        let _ = writeln!(io::stderr(), "directory: skipping op: {}", err);
+                        return;
+                    }
+                };
+                match encrypted_op {
+                    DirectoryOp::Create{name, type_tag, scheme} => {
+                        if self.entries.lock().unwrap().contains_key(&name) {
+                            // name already taken, first create wins
+                            return;
+                        }
+                        let obj_id = {
+                            let mut next_id = self.next_id.lock().unwrap();
+                            *next_id += 1;
+                            *next_id - 1
+                        };
+                        self.insert(DirectoryEntry {
+                            name: name,
+                            obj_id: obj_id,
+                            type_tag: type_tag,
+                            scheme: scheme,
+                        });
+                    }
+                }
+            }
+            LogOp::Snapshot(State::Encoded(ref s)) => {
+                let mut snap: DirectorySnapshot = match json::decode(&s) {
+                    Ok(snap) => snap,
+                    Err(err) => {
+                        let _ = writeln!(io::stderr(), "directory: skipping snapshot: {}", err);
+                        return;
+                    }
+                };
+                *self.next_id.lock().unwrap() = snap.next_id;
+                for entry in snap.entries.drain(..) {
+                    if !self.entries.lock().unwrap().contains_key(&entry.name) {
+                        self.insert(entry);
+                    }
+                }
+            }
+            // the directory only writes encrypted ops and encoded snapshots
+            _ => {
+                let _ = writeln!(io::stderr(), "directory: skipping unexpected op");
+            }
+        }
+    }
+}
+
+// Trait: DirectoryObject
+// Implemented by client data structures that can be opened by name, see Runtime::open
+pub trait DirectoryObject<Q>: Sized {
+    // data structure, VM uses it to pick its replica type
+    fn type_tag() -> &'static str;
+    // encryption scheme of elements, recorded for readers of the directory
+    fn scheme() -> &'static str;
+    // client replica of object obj_id, started
+    fn open(aruntime: &Arc<Mutex<Runtime<Q>>>, obj_id: ObjId) -> Self;
+}
+
+// Implements DirectoryObject for $obj, a replica created by $obj::new(aruntime, obj_id, $args..)
+macro_rules! directory_object {
+    ($obj:ident, $type_tag:expr, $scheme:expr $(, $arg:expr)*) => {
+        impl<Q> DirectoryObject<Q> for $obj<Q> where Q: 'static + IndexedQueue + Send + Clone
+        {
+            fn type_tag() -> &'static str {
+                $type_tag
+            }
+            fn scheme() -> &'static str {
+                $scheme
+            }
+            fn open(aruntime: &Arc<Mutex<Runtime<Q>>>, obj_id: ObjId) -> $obj<Q> {
+                let mut obj = $obj::new(aruntime, obj_id $(, $arg)*);
+                obj.start();
+                obj
+            }
+        }
+    }
+}
+
+directory_object!(StringHMap, HMAP, "eq/aes", HashMap::new());
+directory_object!(StringBTMap, BTMAP, "ord/aes", BTreeMap::new());
+directory_object!(UnencBTMap, UNENC_BTMAP, "none", BTreeMap::new());
+directory_object!(StringHSet, HSET, "eq", HashSet::new());
+directory_object!(StringBTSet, BTSET, "ord", BTreeSet::new());
+directory_object!(StringList, LIST, "aes");
+directory_object!(StringQueue, QUEUE, "aes");
+directory_object!(IntRegister, REGISTER, "add", 0);
+directory_object!(DeadlineQueue, PRIORITY_QUEUE, "ord/aes");
+directory_object!(StringLock, LOCK, "eq");
+directory_object!(IntGCounter, GCOUNTER, "add");
+directory_object!(IntPNCounter, PNCOUNTER, "add");
+
+#[cfg(test)]
+mod test {
+    use super::FIRST_ID;
+    use std::sync::{Arc, Mutex};
+    use runtime::{Runtime, LogError};
+    use indexed_queue::SharedQueue;
+    use encryptors::MetaEncryptor;
+    use maps::StringHMap;
+    use sets::{ReplicatedSet, StringHSet};
+
+    #[test]
+    fn open_by_name() {
+        let q = SharedQueue::new();
+        let me = Some(MetaEncryptor::new());
+        let runtime1 = Arc::new(Mutex::new(Runtime::new(q.clone(), me.clone())));
+        let runtime2 = Arc::new(Mutex::new(Runtime::new(q, me)));
+
+        let mut users1: StringHMap<SharedQueue> = Runtime::open(&runtime1, "users/by_email")
+                                                      .unwrap();
+        let users2: StringHMap<SharedQueue> = Runtime::open(&runtime2, "users/by_email").unwrap();
+        let tags: StringHSet<SharedQueue> = Runtime::open(&runtime2, "tags").unwrap();
+
+        users1.insert(String::from("a@x.org"), String::from("alice"));
+        assert_eq!(users2.get(&String::from("a@x.org")).unwrap(), "alice");
+        assert_eq!(tags.len(), 0);
+
+        let entries = runtime1.lock().unwrap().directory_entries();
+        assert_eq!(entries.len(), 2);
+        let mut ids: Vec<_> = entries.iter().map(|e| e.obj_id).collect();
+        ids.sort();
+        assert_eq!(ids, vec![FIRST_ID, FIRST_ID + 1]);
+    }
+
+    #[test]
+    fn open_wrong_type() {
+        let q = SharedQueue::new();
+        let runtime = Arc::new(Mutex::new(Runtime::new(q, Some(MetaEncryptor::new()))));
+        let _: StringHMap<SharedQueue> = Runtime::open(&runtime, "users").unwrap();
+        match Runtime::open::<StringHSet<SharedQueue>>(&runtime, "users") {
+            Err(LogError::TypeMismatch(_)) => {}
+            _ => panic!("users opened as a set"),
+        }
+    }
+}
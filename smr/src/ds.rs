@@ -19,13 +19,20 @@ extern crate rustc_serialize;
 use self::rustc_serialize::json;
 use self::rustc_serialize::{Encodable, Decodable, Encoder, Decoder};
 
-use runtime::Runtime;
+use runtime::{Runtime, LogError};
 use indexed_queue::{Operation, IndexedQueue, State, LogOp};
-use encryptors::{MetaEncryptor, Addable};
-use converters::{ConvertersLib, AddableConverter};
+use replica::{self, ReplicatedObject, with_runtime};
+use encryptors::{MetaEncryptor, Addable, Encrypted, Ordable, Eqable};
+use converters::{ConvertersLib, AddableConverter, Converter, OrdableConverter, EqableConverter};
+use rand;
 
-use std::sync::{Arc, Mutex, MutexGuard};
-use std::ops::Add;
+use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, VecDeque, BTreeMap};
+use std::ops::{Add, Sub};
+use std::cmp;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::thread;
+use std::io::{self, Write};
 
 // Unencrypted Register/ Counter, to be used by client
 // Supports Additive Homomorphic Encryption
@@ -118,7 +125,7 @@ impl<Q, I> Register<Q, I> {
             runtime: Some(aruntime.clone()),
             data: Arc::new(Mutex::new(data)),
             convert: Some(convert),
-            secure: aruntime.lock().unwrap().secure.clone(),
+            secure: aruntime.lock().unwrap().secure_for(obj_id),
         };
         return reg;
     }
@@ -138,65 +145,1861 @@ impl<Q, I> Register<Q, I>
     where Q: 'static + IndexedQueue + Send + Clone,
           I: 'static + Encodable + Decodable + Send + Clone + Add<Output = I>
 {
-    // lock runtime, call f with runtime, release lock
-    fn with_runtime<R, T, F>(&self, f: F) -> T
-        where F: FnOnce(MutexGuard<Runtime<Q>>) -> T
+    pub fn start(&mut self) {
+        replica::register(&self.runtime, self.obj_id, None, self.clone());
+    }
+
+    pub fn read(&mut self) -> I {
+        match self.try_read() {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as read, returns the integrity violation detected while syncing
+    pub fn try_read(&mut self) -> Result<I, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok(self.data.lock().unwrap().clone())
+        })
+    }
+
+    pub fn write(&mut self, val: I) {
+        if let Err(err) = self.try_write(val) {
+            panic!("{}", err);
+        }
+    }
+
+    // Same as write, returns the error of the log if it refused the op
+    pub fn try_write(&mut self, val: I) -> Result<(), LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            let data: Addable = self.convert
+                                    .as_ref()
+                                    .map(|convert| {
+                                        let to = &convert.to;
+                                        to(&self.secure, val)
+                                    })
+                                    .unwrap();
+
+            let encrypted_op = RegisterOp::Write { data: data };
+            let op = json::encode(&encrypted_op).unwrap();
+            runtime.try_append(self.obj_id, State::Encrypted(op.into_bytes()))
+        })
+    }
+
+    pub fn inc(&mut self, val: I) {
+        if let Err(err) = self.try_inc(val) {
+            panic!("{}", err);
+        }
+    }
+
+    // Same as inc, returns the error of the log if it refused the op
+    pub fn try_inc(&mut self, val: I) -> Result<(), LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            let data: Addable = self.convert
+                                    .as_ref()
+                                    .map(|convert| {
+                                        let to = &convert.to;
+                                        to(&self.secure, val)
+                                    })
+                                    .unwrap();
+
+            let encrypted_op = RegisterOp::Inc { add: data };
+            let op = json::encode(&encrypted_op).unwrap();
+            runtime.try_append(self.obj_id, State::Encrypted(op.into_bytes()))
+        })
+    }
+
+    pub fn get_data(&self, data: Addable) -> I {
+        self.convert
+            .as_ref()
+            .map(|convert| {
+                let from = &convert.from;
+                from(&self.secure, data)
+            })
+            .unwrap()
+    }
+
+    pub fn callback(&mut self, op: Operation) {
+        // plain ops, appended without encryption
+        if let LogOp::Op(State::Encoded(ref s)) = op.operator {
+            let op = json::decode(&s).unwrap();
+            match op {
+                RegisterOp::Write{data} => {
+                    let mut m_data = self.data.lock().unwrap();
+                    *m_data = data;
+                }
+                RegisterOp::Inc{add} => {
+                    let mut m_data = self.data.lock().unwrap();
+                    *m_data = m_data.clone() + add;
+                }
+            }
+            return;
+        }
+        replica::callback(self, &None, op);
+    }
+}
+
+impl<Q, I> ReplicatedObject for Register<Q, I>
+    where Q: 'static + IndexedQueue + Send + Clone,
+          I: 'static + Encodable + Decodable + Send + Clone + Add<Output = I>
+{
+    type Op = RegisterOp<Addable>;
+    type Twin = Addable;
+
+    fn apply(&mut self, _: &Option<MetaEncryptor>, op: RegisterOp<Addable>) {
+        match op {
+            RegisterOp::Write{data} => {
+                let data = self.get_data(data);
+                let mut m_data = self.data.lock().unwrap();
+                *m_data = data;
+            }
+            RegisterOp::Inc{add} => {
+                let add = self.get_data(add);
+                let mut m_data = self.data.lock().unwrap();
+                *m_data = m_data.clone() + add;
+            }
+        }
+    }
+
+    fn restore(&mut self, _: &Option<MetaEncryptor>, twin: Addable) {
+        *self.data.lock().unwrap() = self.get_data(twin);
+    }
+}
+
+// Unencrypted StringList, to be used by client
+// Supports AES encryption for elements
+pub type StringList<Q> = ReplicatedList<Q, String>;
+
+impl<Q> StringList<Q> where Q: 'static + IndexedQueue + Send + Clone
+{
+    pub fn new(aruntime: &Arc<Mutex<Runtime<Q>>>, obj_id: i32) -> StringList<Q> {
+        ReplicatedList::with_callbacks(aruntime,
+                                       obj_id,
+                                       Converter::new(ConvertersLib::encodable_from_encrypted(),
+                                                      ConvertersLib::encrypted_from_encodable()))
+    }
+}
+
+// Encrypted List, to be used by VM
+// Stores AES encrypted elements opaquely
+pub type EncList<Q> = ReplicatedList<Q, Encrypted>;
+
+impl<Q> EncList<Q> where Q: 'static + IndexedQueue + Send + Clone
+{
+    pub fn new(aruntime: &Arc<Mutex<Runtime<Q>>>, obj_id: i32) -> EncList<Q> {
+        ReplicatedList::with_callbacks(aruntime,
+                                       obj_id,
+                                       Converter::new(ConvertersLib::encrypted_from_encrypted(),
+                                                      ConvertersLib::encrypted_from_encrypted()))
+    }
+}
+
+#[derive(RustcEncodable, RustcDecodable, Debug)]
+pub enum ListOp<T> {
+    PushBack {
+        data: T,
+    },
+}
+
+// Class: ReplicatedList
+// Append-only list, elements are ordered by the log index of their PushBack
+// Parametrized by:
+// * Q : structure allowing seamless communicating with Shared Log
+// * T : Data Type stored in List
+#[derive(Clone)]
+pub struct ReplicatedList<Q, T> {
+    runtime: Option<Arc<Mutex<Runtime<Q>>>>, // runtime object is registered with
+    obj_id: i32, // unique id
+    pub data: Arc<Mutex<Vec<T>>>, // local data structure
+
+    convert: Option<Converter<T>>, // converters between data states
+    secure: Option<MetaEncryptor>, // structure to allow use of existing Encryptors/ Decryptors
+}
+
+impl<Q, T: Decodable> Decodable for ReplicatedList<Q, T> {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Self, D::Error> {
+        let data = try!(Decodable::decode(d));
+        let list: ReplicatedList<Q, T> = ReplicatedList::default(data);
+        let res: Result<Self, D::Error> = Ok(list);
+        return res;
+    }
+}
+
+impl<Q, T: Encodable> Encodable for ReplicatedList<Q, T> {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let data = self.data.lock().unwrap();
+        data.encode(s)
+    }
+}
+
+impl<Q, T> ReplicatedList<Q, T> {
+    pub fn with_callbacks(aruntime: &Arc<Mutex<Runtime<Q>>>,
+                          obj_id: i32,
+                          convert: Converter<T>)
+                          -> ReplicatedList<Q, T> {
+        ReplicatedList {
+            obj_id: obj_id,
+            runtime: Some(aruntime.clone()),
+            data: Arc::new(Mutex::new(Vec::new())),
+            convert: Some(convert),
+            secure: aruntime.lock().unwrap().secure_for(obj_id),
+        }
+    }
+
+    fn default(data: Vec<T>) -> ReplicatedList<Q, T> {
+        ReplicatedList {
+            obj_id: 0,
+            data: Arc::new(Mutex::new(data)),
+            convert: None,
+            runtime: None,
+            secure: None,
+        }
+    }
+}
+
+impl<Q, T> ReplicatedList<Q, T>
+    where Q: 'static + IndexedQueue + Send + Clone,
+          T: 'static + Encodable + Decodable + Send + Clone
+{
+    pub fn start(&mut self) {
+        replica::register(&self.runtime, self.obj_id, None, self.clone());
+    }
+
+    pub fn push_back(&mut self, val: T) {
+        if let Err(err) = self.try_push_back(val) {
+            panic!("{}", err);
+        }
+    }
+
+    // Same as push_back, returns the error of the log if it refused the op
+    pub fn try_push_back(&mut self, val: T) -> Result<(), LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            let data = self.to_data(val);
+            let encrypted_op = ListOp::PushBack { data: data };
+            let op = json::encode(&encrypted_op).unwrap();
+            runtime.try_append(self.obj_id, State::Encrypted(op.into_bytes()))
+        })
+    }
+
+    pub fn get(&self, i: usize) -> Option<T> {
+        match self.try_get(i) {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as get, returns the integrity violation detected while syncing
+    pub fn try_get(&self, i: usize) -> Result<Option<T>, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok(self.data.lock().unwrap().get(i).cloned())
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        match self.try_len() {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as len, returns the integrity violation detected while syncing
+    pub fn try_len(&self) -> Result<usize, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok(self.data.lock().unwrap().len())
+        })
+    }
+
+    // Method: range
+    // Returns elements with positions in [from, to), truncated to the length of the list
+    pub fn range(&self, from: usize, to: usize) -> Vec<T> {
+        match self.try_range(from, to) {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as range, returns the integrity violation detected while syncing
+    pub fn try_range(&self, from: usize, to: usize) -> Result<Vec<T>, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            let data = self.data.lock().unwrap();
+            let to = if to > data.len() { data.len() } else { to };
+            if from >= to {
+                return Ok(Vec::new());
+            }
+            Ok(data[from..to].to_vec())
+        })
+    }
+
+    fn to_data(&self, val: T) -> Encrypted {
+        self.convert
+            .as_ref()
+            .map(|convert| {
+                let to = &convert.to;
+                to(&self.secure, val)
+            })
+            .unwrap()
+    }
+
+    pub fn get_data(&self, data: Encrypted) -> T {
+        self.convert
+            .as_ref()
+            .map(|convert| {
+                let from = &convert.from;
+                from(&self.secure, data)
+            })
+            .unwrap()
+    }
+
+    pub fn callback(&mut self, op: Operation) {
+        replica::callback(self, &None, op);
+    }
+}
+
+impl<Q, T> ReplicatedObject for ReplicatedList<Q, T>
+    where Q: 'static + IndexedQueue + Send + Clone,
+          T: 'static + Encodable + Decodable + Send + Clone
+{
+    type Op = ListOp<Encrypted>;
+    type Twin = Vec<Encrypted>;
+
+    fn apply(&mut self, _: &Option<MetaEncryptor>, op: ListOp<Encrypted>) {
+        match op {
+            ListOp::PushBack{data} => {
+                let data = self.get_data(data);
+                self.data.lock().unwrap().push(data);
+            }
+        }
+    }
+
+    fn restore(&mut self, _: &Option<MetaEncryptor>, mut twin: Vec<Encrypted>) {
+        let converted: Vec<T> = twin.drain(..).map(|e| self.get_data(e)).collect();
+        *self.data.lock().unwrap() = converted;
+    }
+}
+
+// Unencrypted StringQueue, to be used by client
+// Supports AES encryption for elements
+pub type StringQueue<Q> = ReplicatedQueue<Q, String>;
+
+impl<Q> StringQueue<Q> where Q: 'static + IndexedQueue + Send + Clone
+{
+    pub fn new(aruntime: &Arc<Mutex<Runtime<Q>>>, obj_id: i32) -> StringQueue<Q> {
+        ReplicatedQueue::with_callbacks(aruntime,
+                                        obj_id,
+                                        Converter::new(ConvertersLib::encodable_from_encrypted(),
+                                                       ConvertersLib::encrypted_from_encodable()))
+    }
+}
+
+// Encrypted Queue, to be used by VM
+// Stores AES encrypted elements opaquely
+pub type EncQueue<Q> = ReplicatedQueue<Q, Encrypted>;
+
+impl<Q> EncQueue<Q> where Q: 'static + IndexedQueue + Send + Clone
+{
+    pub fn new(aruntime: &Arc<Mutex<Runtime<Q>>>, obj_id: i32) -> EncQueue<Q> {
+        ReplicatedQueue::with_callbacks(aruntime,
+                                        obj_id,
+                                        Converter::new(ConvertersLib::encrypted_from_encrypted(),
+                                                       ConvertersLib::encrypted_from_encrypted()))
+    }
+}
+
+// Claim token: (client id, request id), chosen by the client appending the claim
+pub type Claim = (u64, u64);
+
+// Class: Claims
+// Results of claims (Dequeue, PopMin, lock requests) as resolved by log order
+// A client waits for each claim before appending the next one, so only the latest
+// result of every client is remembered: it is never dropped before its client reads it
+// The client's own replica drops it once read, other replicas keep one result per client
+#[derive(Clone)]
+struct Claims<T> {
+    results: HashMap<u64, (u64, Option<T>)>, // client -> (latest request resolved, result)
+}
+
+impl<T: Clone> Claims<T> {
+    fn new() -> Claims<T> {
+        Claims { results: HashMap::new() }
+    }
+
+    fn from_vec(mut claims: Vec<(Claim, Option<T>)>) -> Claims<T> {
+        let mut c = Claims::new();
+        for (claim, data) in claims.drain(..) {
+            c.resolve(claim, data);
+        }
+        c
+    }
+
+    fn resolve(&mut self, claim: Claim, data: Option<T>) {
+        let (client, request) = claim;
+        self.results.insert(client, (request, data));
+    }
+
+    // result of claim, if it is the latest resolved for its client
+    // the element is handed out once, the client's result is dropped with it
+    fn take(&mut self, claim: Claim) -> Option<T> {
+        let (client, request) = claim;
+        let latest = self.results.get(&client).map_or(false, |&(resolved, _)| resolved == request);
+        if !latest {
+            return None;
+        }
+        self.results.remove(&client).and_then(|(_, data)| data)
+    }
+
+    fn to_vec(&self) -> Vec<(Claim, Option<T>)> {
+        let mut claims: Vec<(Claim, Option<T>)> = self.results
+                                                      .iter()
+                                                      .map(|(client, &(request, ref data))| {
+                                                          ((*client, request), data.clone())
+                                                      })
+                                                      .collect();
+        claims.sort_by_key(|&(claim, _)| claim);
+        claims
+    }
+}
+
+#[derive(RustcEncodable, RustcDecodable, Debug)]
+pub enum QueueOp<T> {
+    Enqueue {
+        data: T,
+    },
+    // claim is a client chosen token, the replica that applies the Dequeue
+    // records which element (if any) the claim received
+    Dequeue {
+        claim: Claim,
+    },
+}
+
+// Class: ReplicatedQueue
+// FIFO queue, concurrent dequeues are resolved by log order:
+// every element is handed to exactly one Dequeue, the first in the log to reach it
+// Parametrized by:
+// * Q : structure allowing seamless communicating with Shared Log
+// * T : Data Type stored in Queue
+#[derive(Clone)]
+pub struct ReplicatedQueue<Q, T> {
+    runtime: Option<Arc<Mutex<Runtime<Q>>>>, // runtime object is registered with
+    obj_id: i32, // unique id
+    pub data: Arc<Mutex<VecDeque<T>>>, // local data structure
+    client: u64, // id of this client in claims
+    claims: Arc<Mutex<Claims<T>>>, // resolved claims
+
+    convert: Option<Converter<T>>, // converters between data states
+    secure: Option<MetaEncryptor>, // structure to allow use of existing Encryptors/ Decryptors
+}
+
+impl<Q, T: Decodable + Clone> Decodable for ReplicatedQueue<Q, T> {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Self, D::Error> {
+        let (data, claims): (Vec<T>, Vec<(Claim, Option<T>)>) = try!(Decodable::decode(d));
+        let queue: ReplicatedQueue<Q, T> = ReplicatedQueue::default(data.into_iter().collect());
+        *queue.claims.lock().unwrap() = Claims::from_vec(claims);
+        let res: Result<Self, D::Error> = Ok(queue);
+        return res;
+    }
+}
+
+// Claims are part of the snapshot, so a client whose Dequeue was folded
+// into a snapshot still learns what it received
+impl<Q, T: Encodable + Clone> Encodable for ReplicatedQueue<Q, T> {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let data: Vec<T> = self.data.lock().unwrap().iter().cloned().collect();
+        let claims = self.claims.lock().unwrap().to_vec();
+        (data, claims).encode(s)
+    }
+}
+
+impl<Q, T: Clone> ReplicatedQueue<Q, T> {
+    pub fn with_callbacks(aruntime: &Arc<Mutex<Runtime<Q>>>,
+                          obj_id: i32,
+                          convert: Converter<T>)
+                          -> ReplicatedQueue<Q, T> {
+        ReplicatedQueue {
+            obj_id: obj_id,
+            runtime: Some(aruntime.clone()),
+            data: Arc::new(Mutex::new(VecDeque::new())),
+            client: rand::random::<u64>(),
+            claims: Arc::new(Mutex::new(Claims::new())),
+            convert: Some(convert),
+            secure: aruntime.lock().unwrap().secure_for(obj_id),
+        }
+    }
+
+    fn default(data: VecDeque<T>) -> ReplicatedQueue<Q, T> {
+        ReplicatedQueue {
+            obj_id: 0,
+            data: Arc::new(Mutex::new(data)),
+            client: 0,
+            claims: Arc::new(Mutex::new(Claims::new())),
+            convert: None,
+            runtime: None,
+            secure: None,
+        }
+    }
+}
+
+impl<Q, T> ReplicatedQueue<Q, T>
+    where Q: 'static + IndexedQueue + Send + Clone,
+          T: 'static + Encodable + Decodable + Send + Clone
+{
+    pub fn start(&mut self) {
+        replica::register(&self.runtime, self.obj_id, None, self.clone());
+    }
+
+    pub fn enqueue(&mut self, val: T) {
+        if let Err(err) = self.try_enqueue(val) {
+            panic!("{}", err);
+        }
+    }
+
+    // Same as enqueue, returns the error of the log if it refused the op
+    pub fn try_enqueue(&mut self, val: T) -> Result<(), LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            let data = self.to_data(val);
+            let encrypted_op = QueueOp::Enqueue { data: data };
+            let op = json::encode(&encrypted_op).unwrap();
+            runtime.try_append(self.obj_id, State::Encrypted(op.into_bytes()))
+        })
+    }
+
+    // Method: dequeue, Blocking
+    // Appends a claim on the head of the queue and syncs up to it
+    // Returns:
+    // * the element the claim received, or None if the queue was empty when the claim was applied
+    // Note: the claim is only resolved once a transaction ends, so dequeue cannot be
+    //       part of a transaction
+    pub fn dequeue(&mut self) -> Option<T> {
+        match self.try_dequeue() {
+            Ok(val) => val,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as dequeue, returns the error of the log if it refused the claim
+    pub fn try_dequeue(&mut self) -> Result<Option<T>, LogError> {
+        let claim = (self.client, rand::random::<u64>());
+        with_runtime(&self.runtime, |mut runtime| {
+            assert!(!runtime.tx_mode, "dequeue cannot be part of a transaction");
+            let encrypted_op: QueueOp<Encrypted> = QueueOp::Dequeue { claim: claim };
+            let op = json::encode(&encrypted_op).unwrap();
+            try!(runtime.try_append(self.obj_id, State::Encrypted(op.into_bytes())));
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok(self.claims.lock().unwrap().take(claim))
+        })
+    }
+
+    pub fn peek(&self) -> Option<T> {
+        match self.try_peek() {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as peek, returns the integrity violation detected while syncing
+    pub fn try_peek(&self) -> Result<Option<T>, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok(self.data.lock().unwrap().front().cloned())
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        match self.try_len() {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as len, returns the integrity violation detected while syncing
+    pub fn try_len(&self) -> Result<usize, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok(self.data.lock().unwrap().len())
+        })
+    }
+
+    fn to_data(&self, val: T) -> Encrypted {
+        self.convert
+            .as_ref()
+            .map(|convert| {
+                let to = &convert.to;
+                to(&self.secure, val)
+            })
+            .unwrap()
+    }
+
+    pub fn get_data(&self, data: Encrypted) -> T {
+        self.convert
+            .as_ref()
+            .map(|convert| {
+                let from = &convert.from;
+                from(&self.secure, data)
+            })
+            .unwrap()
+    }
+
+    pub fn callback(&mut self, op: Operation) {
+        replica::callback(self, &None, op);
+    }
+}
+
+impl<Q, T> ReplicatedObject for ReplicatedQueue<Q, T>
+    where Q: 'static + IndexedQueue + Send + Clone,
+          T: 'static + Encodable + Decodable + Send + Clone
+{
+    type Op = QueueOp<Encrypted>;
+    type Twin = (Vec<Encrypted>, Vec<(Claim, Option<Encrypted>)>);
+
+    fn apply(&mut self, _: &Option<MetaEncryptor>, op: QueueOp<Encrypted>) {
+        match op {
+            QueueOp::Enqueue{data} => {
+                let data = self.get_data(data);
+                self.data.lock().unwrap().push_back(data);
+            }
+            QueueOp::Dequeue{claim} => {
+                let head = self.data.lock().unwrap().pop_front();
+                self.claims.lock().unwrap().resolve(claim, head);
+            }
+        }
+    }
+
+    fn restore(&mut self,
+               _: &Option<MetaEncryptor>,
+               twin: (Vec<Encrypted>, Vec<(Claim, Option<Encrypted>)>)) {
+        let (mut data, mut claims) = twin;
+        let converted: VecDeque<T> = data.drain(..).map(|e| self.get_data(e)).collect();
+        *self.data.lock().unwrap() = converted;
+        // snapshot replaces claims, as it replaces data
+        let claims = claims.drain(..)
+                           .map(|(claim, data)| (claim, data.map(|e| self.get_data(e))))
+                           .collect();
+        *self.claims.lock().unwrap() = Claims::from_vec(claims);
+    }
+}
+
+// Unencrypted DeadlineQueue, to be used by client
+// Supports Ordable encryption for u64 priorities (eg. deadlines), AES encryption for payloads
+pub type DeadlineQueue<Q> = ReplicatedPriorityQueue<Q, u64, String>;
+
+impl<Q> DeadlineQueue<Q> where Q: 'static + IndexedQueue + Send + Clone
+{
+    pub fn new(aruntime: &Arc<Mutex<Runtime<Q>>>, obj_id: i32) -> DeadlineQueue<Q> {
+        ReplicatedPriorityQueue::with_callbacks(aruntime,
+                                                obj_id,
+                                                OrdableConverter::new(ConvertersLib::u64_from_ordable(),
+                                                                      ConvertersLib::ordable_from_u64()),
+                                                Converter::new(ConvertersLib::encodable_from_encrypted(),
+                                                               ConvertersLib::encrypted_from_encodable()))
+    }
+}
+
+// Encrypted PriorityQueue, to be used by VM
+// Orders Ordable priorities without decrypting them, stores AES encrypted payloads opaquely
+pub type EncPriorityQueue<Q> = ReplicatedPriorityQueue<Q, Ordable, Encrypted>;
+
+impl<Q> EncPriorityQueue<Q> where Q: 'static + IndexedQueue + Send + Clone
+{
+    pub fn new(aruntime: &Arc<Mutex<Runtime<Q>>>, obj_id: i32) -> EncPriorityQueue<Q> {
+        ReplicatedPriorityQueue::with_callbacks(aruntime,
+                                                obj_id,
+                                                OrdableConverter::new(ConvertersLib::ordable_from_ordable(),
+                                                                      ConvertersLib::ordable_from_ordable()),
+                                                Converter::new(ConvertersLib::encrypted_from_encrypted(),
+                                                               ConvertersLib::encrypted_from_encrypted()))
+    }
+}
+
+#[derive(RustcEncodable, RustcDecodable, Debug)]
+pub enum PriorityQueueOp {
+    Push {
+        prio: Ordable,
+        data: Encrypted,
+    },
+    // claim is a client chosen token, the replica that applies the PopMin
+    // records which element (if any) the claim received
+    PopMin {
+        claim: Claim,
+    },
+}
+
+// Encoded state of a priority queue, as snapshotted by VM
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct PriorityQueueSnapshot {
+    elems: Vec<(Ordable, u64, Encrypted)>, // (priority, sequence number, payload)
+    seq: u64, // sequence number of next push
+    claims: Vec<(Claim, Option<(Ordable, Encrypted)>)>, // latest resolved claim of every client
+}
+
+// Class: ReplicatedPriorityQueue
+// Min priority queue, ordered by Ordable priority on every replica
+// (so client and VM agree on the minimum), ties are broken by push order
+// Concurrent pops are resolved by log order, as for ReplicatedQueue
+// Parametrized by:
+// * Q : structure allowing seamless communicating with Shared Log
+// * P : priority type
+// * T : Data Type stored in PriorityQueue
+#[derive(Clone)]
+pub struct ReplicatedPriorityQueue<Q, P, T> {
+    runtime: Option<Arc<Mutex<Runtime<Q>>>>, // runtime object is registered with
+    obj_id: i32, // unique id
+    pub data: Arc<Mutex<BTreeMap<(Ordable, u64), (P, T)>>>, // local data structure, keyed by log state
+    seq: Arc<Mutex<u64>>, // sequence number of next push, agreed on through log order
+    client: u64, // id of this client in claims
+    claims: Arc<Mutex<Claims<(P, T)>>>, // resolved claims
+
+    convert_ord: Option<OrdableConverter<P>>, // converters between priority states
+    convert: Option<Converter<T>>, // converters between payload states
+    secure: Option<MetaEncryptor>, // structure to allow use of existing Encryptors/ Decryptors
+}
+
+impl<Q> Decodable for EncPriorityQueue<Q> {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Self, D::Error> {
+        let mut snap: PriorityQueueSnapshot = try!(Decodable::decode(d));
+        let data = snap.elems
+                       .drain(..)
+                       .map(|(prio, seq, payload)| ((prio.clone(), seq), (prio, payload)))
+                       .collect();
+        let pq = ReplicatedPriorityQueue {
+            obj_id: 0,
+            runtime: None,
+            data: Arc::new(Mutex::new(data)),
+            seq: Arc::new(Mutex::new(snap.seq)),
+            client: 0,
+            claims: Arc::new(Mutex::new(Claims::from_vec(snap.claims))),
+            convert_ord: None,
+            convert: None,
+            secure: None,
+        };
+        let res: Result<Self, D::Error> = Ok(pq);
+        return res;
+    }
+}
+
+// Only the encrypted twin is snapshotted
+impl<Q> Encodable for EncPriorityQueue<Q> {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let snap = {
+            let data = self.data.lock().unwrap();
+            PriorityQueueSnapshot {
+                elems: data.iter()
+                           .map(|(&(ref prio, seq), &(_, ref payload))| {
+                               (prio.clone(), seq, payload.clone())
+                           })
+                           .collect(),
+                seq: *self.seq.lock().unwrap(),
+                claims: self.claims.lock().unwrap().to_vec(),
+            }
+        };
+        snap.encode(s)
+    }
+}
+
+impl<Q, P: Clone, T: Clone> ReplicatedPriorityQueue<Q, P, T> {
+    pub fn with_callbacks(aruntime: &Arc<Mutex<Runtime<Q>>>,
+                          obj_id: i32,
+                          convert_ord: OrdableConverter<P>,
+                          convert: Converter<T>)
+                          -> ReplicatedPriorityQueue<Q, P, T> {
+        ReplicatedPriorityQueue {
+            obj_id: obj_id,
+            runtime: Some(aruntime.clone()),
+            data: Arc::new(Mutex::new(BTreeMap::new())),
+            seq: Arc::new(Mutex::new(0)),
+            client: rand::random::<u64>(),
+            claims: Arc::new(Mutex::new(Claims::new())),
+            convert_ord: Some(convert_ord),
+            convert: Some(convert),
+            secure: aruntime.lock().unwrap().secure_for(obj_id),
+        }
+    }
+}
+
+impl<Q, P, T> ReplicatedPriorityQueue<Q, P, T>
+    where Q: 'static + IndexedQueue + Send + Clone,
+          P: 'static + Send + Clone,
+          T: 'static + Send + Clone
+{
+    pub fn start(&mut self) {
+        replica::register(&self.runtime, self.obj_id, None, self.clone());
+    }
+
+    pub fn push(&mut self, prio: P, val: T) {
+        if let Err(err) = self.try_push(prio, val) {
+            panic!("{}", err);
+        }
+    }
+
+    // Same as push, returns the error of the log if it refused the op
+    pub fn try_push(&mut self, prio: P, val: T) -> Result<(), LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            let encrypted_op = PriorityQueueOp::Push {
+                prio: self.to_prio(prio),
+                data: self.to_data(val),
+            };
+            let op = json::encode(&encrypted_op).unwrap();
+            runtime.try_append(self.obj_id, State::Encrypted(op.into_bytes()))
+        })
+    }
+
+    // Method: peek_min
+    // Syncs, returns element with smallest priority without removing it
+    // On the VM replica this is answered over Ordable priorities, without decryption
+    pub fn peek_min(&self) -> Option<(P, T)> {
+        match self.try_peek_min() {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as peek_min, returns the integrity violation detected while syncing
+    pub fn try_peek_min(&self) -> Result<Option<(P, T)>, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            let data = self.data.lock().unwrap();
+            Ok(data.values().next().cloned())
+        })
+    }
+
+    // Method: pop_min, Blocking
+    // Appends a claim on the element with smallest priority and syncs up to it
+    // Returns:
+    // * the element the claim received, or None if the queue was empty when the claim was applied
+    // Note: the claim is only resolved once a transaction ends, so pop_min cannot be
+    //       part of a transaction
+    pub fn pop_min(&mut self) -> Option<(P, T)> {
+        match self.try_pop_min() {
+            Ok(elem) => elem,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as pop_min, returns the error of the log if it refused the claim
+    pub fn try_pop_min(&mut self) -> Result<Option<(P, T)>, LogError> {
+        let claim = (self.client, rand::random::<u64>());
+        with_runtime(&self.runtime, |mut runtime| {
+            assert!(!runtime.tx_mode, "pop_min cannot be part of a transaction");
+            let encrypted_op = PriorityQueueOp::PopMin { claim: claim };
+            let op = json::encode(&encrypted_op).unwrap();
+            try!(runtime.try_append(self.obj_id, State::Encrypted(op.into_bytes())));
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok(self.claims.lock().unwrap().take(claim))
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        match self.try_len() {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as len, returns the integrity violation detected while syncing
+    pub fn try_len(&self) -> Result<usize, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok(self.data.lock().unwrap().len())
+        })
+    }
+
+    fn to_prio(&self, prio: P) -> Ordable {
+        self.convert_ord
+            .as_ref()
+            .map(|convert_ord| {
+                let to = &convert_ord.to;
+                to(&self.secure, prio)
+            })
+            .unwrap()
+    }
+
+    fn to_data(&self, val: T) -> Encrypted {
+        self.convert
+            .as_ref()
+            .map(|convert| {
+                let to = &convert.to;
+                to(&self.secure, val)
+            })
+            .unwrap()
+    }
+
+    pub fn get_prio(&self, prio: Ordable) -> P {
+        self.convert_ord
+            .as_ref()
+            .map(|convert_ord| {
+                let from = &convert_ord.from;
+                from(&self.secure, prio)
+            })
+            .unwrap()
+    }
+
+    pub fn get_data(&self, data: Encrypted) -> T {
+        self.convert
+            .as_ref()
+            .map(|convert| {
+                let from = &convert.from;
+                from(&self.secure, data)
+            })
+            .unwrap()
+    }
+
+    // insert element under the next sequence number
+    fn insert(&self, prio: Ordable, data: Encrypted) {
+        let mut seq = self.seq.lock().unwrap();
+        let elem = (self.get_prio(prio.clone()), self.get_data(data));
+        self.data.lock().unwrap().insert((prio, *seq), elem);
+        *seq += 1;
+    }
+
+    pub fn callback(&mut self, op: Operation) {
+        replica::callback(self, &None, op);
+    }
+}
+
+impl<Q, P, T> ReplicatedObject for ReplicatedPriorityQueue<Q, P, T>
+    where Q: 'static + IndexedQueue + Send + Clone,
+          P: 'static + Send + Clone,
+          T: 'static + Send + Clone
+{
+    type Op = PriorityQueueOp;
+    type Twin = PriorityQueueSnapshot;
+
+    fn apply(&mut self, _: &Option<MetaEncryptor>, op: PriorityQueueOp) {
+        match op {
+            PriorityQueueOp::Push{prio, data} => {
+                self.insert(prio, data);
+            }
+            PriorityQueueOp::PopMin{claim} => {
+                let popped = {
+                    let mut data = self.data.lock().unwrap();
+                    let min = data.keys().next().cloned();
+                    min.and_then(|key| data.remove(&key))
+                };
+                self.claims.lock().unwrap().resolve(claim, popped);
+            }
+        }
+    }
+
+    fn restore(&mut self, _: &Option<MetaEncryptor>, mut snap: PriorityQueueSnapshot) {
+        let mut converted = BTreeMap::new();
+        for (prio, seq, data) in snap.elems.drain(..) {
+            let elem = (self.get_prio(prio.clone()), self.get_data(data));
+            converted.insert((prio, seq), elem);
+        }
+        *self.data.lock().unwrap() = converted;
+        *self.seq.lock().unwrap() = snap.seq;
+        // snapshot replaces claims, as it replaces data
+        let claims = snap.claims
+                         .drain(..)
+                         .map(|(claim, popped)| {
+                             (claim,
+                              popped.map(|(prio, data)| (self.get_prio(prio), self.get_data(data))))
+                         })
+                         .collect();
+        *self.claims.lock().unwrap() = Claims::from_vec(claims);
+    }
+}
+
+// Unencrypted StringLock, to be used by client
+// Supports Eqable encryption for owners
+pub type StringLock<Q> = Lock<Q, String>;
+
+impl<Q> StringLock<Q> where Q: 'static + IndexedQueue + Send + Clone
+{
+    pub fn new(aruntime: &Arc<Mutex<Runtime<Q>>>, obj_id: i32) -> StringLock<Q> {
+        Lock::with_callbacks(aruntime,
+                             obj_id,
+                             EqableConverter::new(ConvertersLib::encodable_from_eqable(),
+                                                  ConvertersLib::eqable_from_encodable()))
+    }
+}
+
+// Encrypted Lock, to be used by VM
+// Compares Eqable owners without decrypting them
+pub type EncLock<Q> = Lock<Q, Eqable>;
+
+impl<Q> EncLock<Q> where Q: 'static + IndexedQueue + Send + Clone
+{
+    pub fn new(aruntime: &Arc<Mutex<Runtime<Q>>>, obj_id: i32) -> EncLock<Q> {
+        Lock::with_callbacks(aruntime,
+                             obj_id,
+                             EqableConverter::new(ConvertersLib::eqable_from_eqable(),
+                                                  ConvertersLib::eqable_from_eqable()))
+    }
+}
+
+// milliseconds since the epoch, as recorded in lock operations by the appending client
+fn now_ms() -> u64 {
+    let d = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    d.as_secs() * 1000 + (d.subsec_nanos() / 1000000) as u64
+}
+
+// Lock operations may run at most this many ms ahead of log time, later ts are clamped to it
+// Bounds how far a client with a fast clock moves log time, and so expires leases, per op
+pub const MAX_SKEW_MS: u64 = 5000;
+
+// Lock operations carry the time they were issued at (ts, in ms)
+// Replicas only ever use these log-recorded times to decide expiry
+#[derive(RustcEncodable, RustcDecodable, Debug)]
+pub enum LockOp<O> {
+    Acquire {
+        owner: O,
+        ttl: u64,
+        ts: u64,
+        request: Claim,
+    },
+    Renew {
+        owner: O,
+        token: u64,
+        ttl: u64,
+        ts: u64,
+        request: Claim,
+    },
+    Release {
+        owner: O,
+        token: u64,
+        ts: u64,
+        request: Claim,
+    },
+}
+
+// Class: Lease
+// Current holder of a Lock
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug, PartialEq)]
+pub struct Lease<O> {
+    pub owner: O, // owner holding the lock
+    pub token: u64, // fencing token, strictly increasing with every successful acquire
+    pub expires: u64, // log time (ms) at which the lease expires
+}
+
+// Encoded state of a lock, as snapshotted by VM
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct LockSnapshot {
+    holder: Option<Lease<Eqable>>,
+    next_token: u64,
+    now: u64,
+    results: Vec<(Claim, Option<u64>)>,
+}
+
+// Class: Lock
+// Lease based lock, replicated through the SharedLog
+// Every replica applies Acquire/ Renew/ Release in log order, so all agree on the holder
+// Log time is the largest ts seen so far: a lease is expired once log time reaches its expiry
+// Each op moves log time at most MAX_SKEW_MS ahead, so a lease outlives ttl if ops are sparse
+// Parametrized by:
+// * Q : structure allowing seamless communicating with Shared Log
+// * O : owner type
+#[derive(Clone)]
+pub struct Lock<Q, O> {
+    runtime: Option<Arc<Mutex<Runtime<Q>>>>, // runtime object is registered with
+    obj_id: i32, // unique id
+    pub holder: Arc<Mutex<Option<Lease<O>>>>, // local data structure
+    next_token: Arc<Mutex<u64>>, // fencing token of next successful acquire
+    now: Arc<Mutex<u64>>, // log time, largest ts applied
+    client: u64, // id of this client in results
+    results: Arc<Mutex<Claims<u64>>>, // request -> token granted, if any
+
+    convert_eq: Option<EqableConverter<O>>, // converters between owner states
+    secure: Option<MetaEncryptor>, // structure to allow use of existing Encryptors/ Decryptors
+}
+
+// Only the encrypted twin is snapshotted
+impl<Q> Encodable for EncLock<Q> {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let snap = LockSnapshot {
+            holder: self.holder.lock().unwrap().clone(),
+            next_token: *self.next_token.lock().unwrap(),
+            now: *self.now.lock().unwrap(),
+            results: self.results.lock().unwrap().to_vec(),
+        };
+        snap.encode(s)
+    }
+}
+
+impl<Q, O> Lock<Q, O> {
+    pub fn with_callbacks(aruntime: &Arc<Mutex<Runtime<Q>>>,
+                          obj_id: i32,
+                          convert_eq: EqableConverter<O>)
+                          -> Lock<Q, O> {
+        Lock {
+            obj_id: obj_id,
+            runtime: Some(aruntime.clone()),
+            holder: Arc::new(Mutex::new(None)),
+            next_token: Arc::new(Mutex::new(1)),
+            now: Arc::new(Mutex::new(0)),
+            client: rand::random::<u64>(),
+            results: Arc::new(Mutex::new(Claims::new())),
+            convert_eq: Some(convert_eq),
+            secure: aruntime.lock().unwrap().secure_for(obj_id),
+        }
+    }
+}
+
+impl<Q, O> Lock<Q, O>
+    where Q: 'static + IndexedQueue + Send + Clone,
+          O: 'static + Send + Clone + PartialEq
+{
+    pub fn start(&mut self) {
+        replica::register(&self.runtime, self.obj_id, None, self.clone());
+    }
+
+    // append op, sync up to it and return result recorded for request
+    fn request(&self, op: LockOp<Eqable>, request: Claim) -> Result<Option<u64>, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            assert!(!runtime.tx_mode, "lock requests cannot be part of a transaction");
+            let op = json::encode(&op).unwrap();
+            try!(runtime.try_append(self.obj_id, State::Encrypted(op.into_bytes())));
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok(self.results.lock().unwrap().take(request))
+        })
+    }
+
+    // Method: acquire, Blocking
+    // Acquires lock for owner for ttl ms, succeeds if lock is free, expired or held by owner
+    // Returns:
+    // * fencing token of the lease, or None if the lock is held by another owner
+    pub fn acquire(&mut self, owner: O, ttl: u64) -> Option<u64> {
+        match self.try_acquire(owner, ttl) {
+            Ok(token) => token,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as acquire, returns the error of the log if it refused the request
+    pub fn try_acquire(&mut self, owner: O, ttl: u64) -> Result<Option<u64>, LogError> {
+        self.try_acquire_at(owner, ttl, now_ms())
+    }
+
+    // acquire as issued at ts by the client's clock
+    fn try_acquire_at(&mut self, owner: O, ttl: u64, ts: u64) -> Result<Option<u64>, LogError> {
+        let request = (self.client, rand::random::<u64>());
+        let op = LockOp::Acquire {
+            owner: self.to_owner(owner),
+            ttl: ttl,
+            ts: ts,
+            request: request,
+        };
+        self.request(op, request)
+    }
+
+    // Method: renew, Blocking
+    // Extends lease identified by token for another ttl ms
+    // Returns:
+    // * token if lease was renewed, None if it expired or is held by someone else
+    pub fn renew(&mut self, owner: O, token: u64, ttl: u64) -> Option<u64> {
+        match self.try_renew(owner, token, ttl) {
+            Ok(token) => token,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as renew, returns the error of the log if it refused the request
+    pub fn try_renew(&mut self,
+                     owner: O,
+                     token: u64,
+                     ttl: u64)
+                     -> Result<Option<u64>, LogError> {
+        let request = (self.client, rand::random::<u64>());
+        let op = LockOp::Renew {
+            owner: self.to_owner(owner),
+            token: token,
+            ttl: ttl,
+            ts: now_ms(),
+            request: request,
+        };
+        self.request(op, request)
+    }
+
+    // Method: release, Blocking
+    // Returns:
+    // * true if owner held the lease identified by token and released it
+    pub fn release(&mut self, owner: O, token: u64) -> bool {
+        match self.try_release(owner, token) {
+            Ok(released) => released,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as release, returns the error of the log if it refused the request
+    pub fn try_release(&mut self, owner: O, token: u64) -> Result<bool, LogError> {
+        let request = (self.client, rand::random::<u64>());
+        let op = LockOp::Release {
+            owner: self.to_owner(owner),
+            token: token,
+            ts: now_ms(),
+            request: request,
+        };
+        self.request(op, request).map(|token| token.is_some())
+    }
+
+    // Syncs, returns current lease if it has not expired as of log time
+    pub fn holder(&self) -> Option<Lease<O>> {
+        match self.try_holder() {
+            Ok(holder) => holder,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as holder, returns the integrity violation detected while syncing
+    pub fn try_holder(&self) -> Result<Option<Lease<O>>, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            let now = *self.now.lock().unwrap();
+            Ok(self.holder.lock().unwrap().clone().and_then(|lease| {
+                if lease.expires > now {
+                    Some(lease)
+                } else {
+                    None
+                }
+            }))
+        })
+    }
+
+    fn to_owner(&self, owner: O) -> Eqable {
+        self.convert_eq
+            .as_ref()
+            .map(|convert_eq| {
+                let to = &convert_eq.to;
+                to(&self.secure, owner)
+            })
+            .unwrap()
+    }
+
+    pub fn get_owner(&self, owner: Eqable) -> O {
+        self.convert_eq
+            .as_ref()
+            .map(|convert_eq| {
+                let from = &convert_eq.from;
+                from(&self.secure, owner)
+            })
+            .unwrap()
+    }
+
+    // apply op at log time, returns token for successful acquire/ renew/ release
+    fn apply_at(&self, owner: O, ts: u64, op: LockOp<Eqable>) -> Option<u64> {
+        let now = {
+            let mut now = self.now.lock().unwrap();
+            // the first op sets log time, clocks running ahead of it are clamped from then on
+            let ts = if *now == 0 {
+                ts
+            } else {
+                cmp::min(ts, *now + MAX_SKEW_MS)
+            };
+            if ts > *now {
+                *now = ts;
+            }
+            *now
+        };
+        let mut holder = self.holder.lock().unwrap();
+        let live = holder.as_ref().map_or(false, |lease| lease.expires > now);
+        match op {
+            LockOp::Acquire{ttl, ..} => {
+                let held_by_owner = holder.as_ref().map_or(false, |lease| lease.owner == owner);
+                if live && !held_by_owner {
+                    return None;
+                }
+                let mut next_token = self.next_token.lock().unwrap();
+                let token = *next_token;
+                *next_token += 1;
+                *holder = Some(Lease {
+                    owner: owner,
+                    token: token,
+                    expires: now + ttl,
+                });
+                Some(token)
+            }
+            LockOp::Renew{token, ttl, ..} => {
+                match *holder {
+                    Some(ref mut lease) if live && lease.owner == owner && lease.token == token => {
+                        lease.expires = now + ttl;
+                        Some(token)
+                    }
+                    _ => None,
+                }
+            }
+            LockOp::Release{token, ..} => {
+                let holds = holder.as_ref()
+                                  .map_or(false, |lease| lease.owner == owner && lease.token == token);
+                if live && holds {
+                    *holder = None;
+                    Some(token)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    pub fn callback(&mut self, op: Operation) {
+        replica::callback(self, &None, op);
+    }
+}
+
+impl<Q, O> ReplicatedObject for Lock<Q, O>
+    where Q: 'static + IndexedQueue + Send + Clone,
+          O: 'static + Send + Clone + PartialEq
+{
+    type Op = LockOp<Eqable>;
+    type Twin = LockSnapshot;
+
+    fn apply(&mut self, _: &Option<MetaEncryptor>, op: LockOp<Eqable>) {
+        let (owner, ts, request) = match op {
+            LockOp::Acquire{ref owner, ts, request, ..} => (owner.clone(), ts, request),
+            LockOp::Renew{ref owner, ts, request, ..} => (owner.clone(), ts, request),
+            LockOp::Release{ref owner, ts, request, ..} => (owner.clone(), ts, request),
+        };
+        let owner = self.get_owner(owner);
+        let result = self.apply_at(owner, ts, op);
+        self.results.lock().unwrap().resolve(request, result);
+    }
+
+    fn restore(&mut self, _: &Option<MetaEncryptor>, snap: LockSnapshot) {
+        *self.holder.lock().unwrap() = snap.holder.map(|lease| {
+            Lease {
+                owner: self.get_owner(lease.owner),
+                token: lease.token,
+                expires: lease.expires,
+            }
+        });
+        *self.next_token.lock().unwrap() = snap.next_token;
+        *self.now.lock().unwrap() = snap.now;
+        *self.results.lock().unwrap() = Claims::from_vec(snap.results);
+    }
+}
+
+// Unencrypted GCounter, to be used by client
+// Supports Additive Homomorphic Encryption
+pub type IntGCounter<Q> = GCounter<Q, i32>;
+
+impl<Q> IntGCounter<Q> where Q: 'static + IndexedQueue + Send + Clone
+{
+    pub fn new(aruntime: &Arc<Mutex<Runtime<Q>>>, obj_id: i32) -> IntGCounter<Q> {
+        GCounter::with_callbacks(aruntime,
+                                 obj_id,
+                                 AddableConverter::new(ConvertersLib::i32_from_addable(),
+                                                       ConvertersLib::addable_from_i32()))
+    }
+}
+
+// Encrypted GCounter, to be used by VM
+// Supports Additive Homomorphic Encryption
+pub type AddableGCounter<Q> = GCounter<Q, Addable>;
+
+impl<Q> AddableGCounter<Q> where Q: 'static + IndexedQueue + Send + Clone
+{
+    pub fn new(aruntime: &Arc<Mutex<Runtime<Q>>>, obj_id: i32) -> AddableGCounter<Q> {
+        GCounter::with_callbacks(aruntime,
+                                 obj_id,
+                                 AddableConverter::new(ConvertersLib::addable_from_addable(),
+                                                       ConvertersLib::addable_from_addable()))
+    }
+}
+
+// Per client totals of a counter, merged by addition
+struct Slots<I> {
+    slots: HashMap<u64, I>, // client id -> total added by client
+}
+
+impl<I> Slots<I> {
+    fn new() -> Slots<I> {
+        Slots { slots: HashMap::new() }
+    }
+}
+
+impl<I> Slots<I> where I: Clone + Add<Output = I>
+{
+    fn add(&mut self, client: u64, inc: I) {
+        let total = match self.slots.remove(&client) {
+            Some(total) => total + inc,
+            None => inc,
+        };
+        self.slots.insert(client, total);
+    }
+
+    // sum of all slots, None if nothing was ever added
+    fn total(&self) -> Option<I> {
+        self.slots.values().cloned().fold(None, |acc, v| {
+            match acc {
+                Some(acc) => Some(acc + v),
+                None => Some(v),
+            }
+        })
+    }
+
+    fn to_vec(&self) -> Vec<(u64, I)> {
+        self.slots.iter().map(|(client, v)| (*client, v.clone())).collect()
+    }
+}
+
+// Increments not yet flushed to the log
+struct Pending<I> {
+    sum: Option<I>, // sum of pending increments
+    n: usize, // number of pending increments
+}
+
+impl<I> Pending<I> {
+    fn new() -> Pending<I> {
+        Pending { sum: None, n: 0 }
+    }
+}
+
+impl<I> Pending<I> where I: Add<Output = I>
+{
+    fn add(&mut self, inc: I) {
+        self.sum = Some(match self.sum.take() {
+            Some(sum) => sum + inc,
+            None => inc,
+        });
+        self.n += 1;
+    }
+}
+
+// Runs f when dropped, handles of a client share it to act once the last of them is gone
+struct OnDrop {
+    f: Mutex<Option<Box<FnMut() + Send>>>,
+}
+
+impl OnDrop {
+    fn new<F: 'static + FnMut() + Send>(f: F) -> OnDrop {
+        OnDrop { f: Mutex::new(Some(Box::new(f))) }
+    }
+}
+
+impl Drop for OnDrop {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            return;
+        }
+        if let Some(mut f) = self.f.lock().unwrap().take() {
+            f();
+        }
+    }
+}
+
+#[derive(RustcEncodable, RustcDecodable, Debug)]
+pub enum GCounterOp<T> {
+    Add {
+        client: u64,
+        inc: T,
+    },
+}
+
+// Class: GCounter
+// Grow-only counter, one slot per client
+// Each client only ever adds to its own slot, slots are summed homomorphically
+// Increments are batched locally, and appended to the log as a single entry
+// Parametrized by:
+// * Q : structure allowing seamless communicating with Shared Log
+// * I : Data Type of increments
+#[derive(Clone)]
+pub struct GCounter<Q, I> {
+    runtime: Option<Arc<Mutex<Runtime<Q>>>>, // runtime object is registered with
+    obj_id: i32, // unique id
+    client: u64, // id of this client's slot
+    slots: Arc<Mutex<Slots<I>>>, // local data structure
+    pending: Arc<Mutex<Pending<I>>>, // increments not yet appended
+    batch: usize, // number of increments appended as one log entry
+    flush_on_drop: Option<Arc<OnDrop>>, // flushes once the last started handle is dropped
+
+    convert: Option<AddableConverter<I>>, // converters between data states
+    secure: Option<MetaEncryptor>, // structure to allow use of existing Encryptors/ Decryptors
+}
+
+impl<Q, I: Encodable + Clone + Add<Output = I>> Encodable for GCounter<Q, I> {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let slots = self.slots.lock().unwrap();
+        slots.to_vec().encode(s)
+    }
+}
+
+impl<Q, I> GCounter<Q, I> {
+    pub fn with_callbacks(aruntime: &Arc<Mutex<Runtime<Q>>>,
+                          obj_id: i32,
+                          convert: AddableConverter<I>)
+                          -> GCounter<Q, I> {
+        GCounter {
+            obj_id: obj_id,
+            runtime: Some(aruntime.clone()),
+            client: rand::random::<u64>(),
+            slots: Arc::new(Mutex::new(Slots::new())),
+            pending: Arc::new(Mutex::new(Pending::new())),
+            batch: 1,
+            flush_on_drop: None,
+            convert: Some(convert),
+            secure: aruntime.lock().unwrap().secure_for(obj_id),
+        }
+    }
+
+    // Append increments to the log once n of them are pending
+    pub fn with_batch(mut self, n: usize) -> GCounter<Q, I> {
+        assert!(n > 0, "batch must hold at least one increment");
+        self.batch = n;
+        self
+    }
+}
+
+impl<Q, I> GCounter<Q, I>
+    where Q: 'static + IndexedQueue + Send + Clone,
+          I: 'static + Send + Clone + Add<Output = I>
+{
+    // Registers counter with runtime
+    // Increments still pending when this handle and its clones are all dropped get appended
+    pub fn start(&mut self) {
+        replica::register(&self.runtime, self.obj_id, None, self.clone());
+        let mut counter = self.clone();
+        self.flush_on_drop = Some(Arc::new(OnDrop::new(move || {
+            if let Err(err) = counter.try_flush() {
+                let _ = writeln!(io::stderr(), "pending counter changes dropped: {}", err);
+            }
+        })));
+    }
+
+    // Method: inc, Non-Blocking
+    // Adds val (non negative) to this client's slot, appended once batch is full
+    pub fn inc(&mut self, val: I) {
+        let full = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.add(val);
+            pending.n >= self.batch
+        };
+        if full {
+            self.flush();
+        }
+    }
+
+    // Method: flush, Non-Blocking
+    // Appends all pending increments as one log entry
+    pub fn flush(&mut self) {
+        if let Err(err) = self.try_flush() {
+            panic!("{}", err);
+        }
+    }
+
+    // Same as flush, returns the error of the log if it refused the increments
+    pub fn try_flush(&mut self) -> Result<(), LogError> {
+        let sum = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.n = 0;
+            pending.sum.take()
+        };
+        if let Some(sum) = sum {
+            let inc = self.to_addable(sum);
+            with_runtime(&self.runtime, |mut runtime| {
+                let encrypted_op = GCounterOp::Add {
+                    client: self.client,
+                    inc: inc,
+                };
+                let op = json::encode(&encrypted_op).unwrap();
+                runtime.try_append(self.obj_id, State::Encrypted(op.into_bytes()))
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    // Syncs, returns sum of all slots, None if counter was never incremented
+    pub fn total(&mut self) -> Option<I> {
+        match self.try_total() {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as total, returns the integrity violation detected while syncing
+    pub fn try_total(&mut self) -> Result<Option<I>, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok(self.slots.lock().unwrap().total())
+        })
+    }
+
+    // Flushes this client's increments, syncs and returns counter value
+    pub fn read(&mut self) -> I
+        where I: Default
+    {
+        match self.try_read() {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as read, returns the error of the log if it refused the increments or was tampered with
+    pub fn try_read(&mut self) -> Result<I, LogError>
+        where I: Default
     {
-        assert!(self.runtime.is_some(), "invalid runtime");
-        self.runtime
+        try!(self.try_flush());
+        Ok(try!(self.try_total()).unwrap_or_default())
+    }
+
+    fn to_addable(&self, val: I) -> Addable {
+        self.convert
             .as_ref()
-            .map(|runtime| {
-                let runtime = runtime.lock().unwrap();
-                f(runtime)
+            .map(|convert| {
+                let to = &convert.to;
+                to(&self.secure, val)
             })
             .unwrap()
     }
 
-    pub fn start(&mut self) {
-        self.with_runtime::<(), _, _>(|mut runtime| {
-            let mut reg = self.clone();
-            runtime.register_object(self.obj_id,
-                                    Box::new(move |_, op: Operation| reg.callback(op)));
-        });
+    pub fn get_data(&self, data: Addable) -> I {
+        self.convert
+            .as_ref()
+            .map(|convert| {
+                let from = &convert.from;
+                from(&self.secure, data)
+            })
+            .unwrap()
     }
 
-    pub fn read(&mut self) -> I {
-        self.with_runtime::<I, _, _>(|mut runtime| {
-            runtime.sync(Some(self.obj_id));
-            self.data.lock().unwrap().clone()
-        })
+    pub fn callback(&mut self, op: Operation) {
+        replica::callback(self, &None, op);
     }
+}
 
-    pub fn write(&mut self, val: I) {
-        self.with_runtime::<(), _, _>(|mut runtime| {
-            let data: Addable = self.convert
-                                    .as_ref()
-                                    .map(|convert| {
-                                        let to = &convert.to;
-                                        to(&self.secure, val)
-                                    })
-                                    .unwrap();
+impl<Q, I> ReplicatedObject for GCounter<Q, I>
+    where Q: 'static + IndexedQueue + Send + Clone,
+          I: 'static + Send + Clone + Add<Output = I>
+{
+    type Op = GCounterOp<Addable>;
+    type Twin = Vec<(u64, Addable)>;
 
-            let encrypted_op = RegisterOp::Write { data: data };
-            let op = json::encode(&encrypted_op).unwrap();
-            runtime.append(self.obj_id, State::Encrypted(op.into_bytes()));
-        });
+    fn apply(&mut self, _: &Option<MetaEncryptor>, op: GCounterOp<Addable>) {
+        match op {
+            GCounterOp::Add{client, inc} => {
+                let inc = self.get_data(inc);
+                self.slots.lock().unwrap().add(client, inc);
+            }
+        }
+    }
+
+    fn restore(&mut self, _: &Option<MetaEncryptor>, mut enc_slots: Vec<(u64, Addable)>) {
+        let mut slots = Slots::new();
+        for (client, total) in enc_slots.drain(..) {
+            slots.add(client, self.get_data(total));
+        }
+        *self.slots.lock().unwrap() = slots;
+    }
+}
+
+// Unencrypted PNCounter, to be used by client
+// Supports Additive Homomorphic Encryption
+pub type IntPNCounter<Q> = PNCounter<Q, i32>;
+
+impl<Q> IntPNCounter<Q> where Q: 'static + IndexedQueue + Send + Clone
+{
+    pub fn new(aruntime: &Arc<Mutex<Runtime<Q>>>, obj_id: i32) -> IntPNCounter<Q> {
+        PNCounter::with_callbacks(aruntime,
+                                  obj_id,
+                                  AddableConverter::new(ConvertersLib::i32_from_addable(),
+                                                        ConvertersLib::addable_from_i32()))
+    }
+}
+
+// Encrypted PNCounter, to be used by VM
+// Supports Additive Homomorphic Encryption
+pub type AddablePNCounter<Q> = PNCounter<Q, Addable>;
+
+impl<Q> AddablePNCounter<Q> where Q: 'static + IndexedQueue + Send + Clone
+{
+    pub fn new(aruntime: &Arc<Mutex<Runtime<Q>>>, obj_id: i32) -> AddablePNCounter<Q> {
+        PNCounter::with_callbacks(aruntime,
+                                  obj_id,
+                                  AddableConverter::new(ConvertersLib::addable_from_addable(),
+                                                        ConvertersLib::addable_from_addable()))
+    }
+}
+
+#[derive(RustcEncodable, RustcDecodable, Debug)]
+pub enum PNCounterOp<T> {
+    Add {
+        client: u64,
+        inc: Option<T>,
+        dec: Option<T>,
+    },
+}
+
+// Class: PNCounter
+// Counter supporting decrements, made of two grow-only halves
+// Value is the sum of increments minus the sum of decrements, only computed by clients
+// Parametrized by:
+// * Q : structure allowing seamless communicating with Shared Log
+// * I : Data Type of increments
+#[derive(Clone)]
+pub struct PNCounter<Q, I> {
+    runtime: Option<Arc<Mutex<Runtime<Q>>>>, // runtime object is registered with
+    obj_id: i32, // unique id
+    client: u64, // id of this client's slots
+    incs: Arc<Mutex<Slots<I>>>, // local data structure, increments
+    decs: Arc<Mutex<Slots<I>>>, // local data structure, decrements
+    pending_incs: Arc<Mutex<Pending<I>>>, // increments not yet appended
+    pending_decs: Arc<Mutex<Pending<I>>>, // decrements not yet appended
+    batch: usize, // number of increments/ decrements appended as one log entry
+    flush_on_drop: Option<Arc<OnDrop>>, // flushes once the last started handle is dropped
+
+    convert: Option<AddableConverter<I>>, // converters between data states
+    secure: Option<MetaEncryptor>, // structure to allow use of existing Encryptors/ Decryptors
+}
+
+impl<Q, I: Encodable + Clone + Add<Output = I>> Encodable for PNCounter<Q, I> {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let incs = self.incs.lock().unwrap().to_vec();
+        let decs = self.decs.lock().unwrap().to_vec();
+        (incs, decs).encode(s)
+    }
+}
+
+impl<Q, I> PNCounter<Q, I> {
+    pub fn with_callbacks(aruntime: &Arc<Mutex<Runtime<Q>>>,
+                          obj_id: i32,
+                          convert: AddableConverter<I>)
+                          -> PNCounter<Q, I> {
+        PNCounter {
+            obj_id: obj_id,
+            runtime: Some(aruntime.clone()),
+            client: rand::random::<u64>(),
+            incs: Arc::new(Mutex::new(Slots::new())),
+            decs: Arc::new(Mutex::new(Slots::new())),
+            pending_incs: Arc::new(Mutex::new(Pending::new())),
+            pending_decs: Arc::new(Mutex::new(Pending::new())),
+            batch: 1,
+            flush_on_drop: None,
+            convert: Some(convert),
+            secure: aruntime.lock().unwrap().secure_for(obj_id),
+        }
+    }
+
+    // Append increments/ decrements to the log once n of them are pending
+    pub fn with_batch(mut self, n: usize) -> PNCounter<Q, I> {
+        assert!(n > 0, "batch must hold at least one increment");
+        self.batch = n;
+        self
+    }
+}
+
+impl<Q, I> PNCounter<Q, I>
+    where Q: 'static + IndexedQueue + Send + Clone,
+          I: 'static + Send + Clone + Add<Output = I>
+{
+    // Registers counter with runtime
+    // Increments and decrements still pending when this handle and its clones are all dropped
+    // get appended
+    pub fn start(&mut self) {
+        replica::register(&self.runtime, self.obj_id, None, self.clone());
+        let mut counter = self.clone();
+        self.flush_on_drop = Some(Arc::new(OnDrop::new(move || {
+            if let Err(err) = counter.try_flush() {
+                let _ = writeln!(io::stderr(), "pending counter changes dropped: {}", err);
+            }
+        })));
     }
 
+    // Method: inc, Non-Blocking
+    // Adds val (non negative) to counter, appended once batch is full
     pub fn inc(&mut self, val: I) {
-        self.with_runtime::<(), _, _>(|mut runtime| {
-            let data: Addable = self.convert
-                                    .as_ref()
-                                    .map(|convert| {
-                                        let to = &convert.to;
-                                        to(&self.secure, val)
-                                    })
-                                    .unwrap();
+        let full = {
+            let mut pending = self.pending_incs.lock().unwrap();
+            pending.add(val);
+            pending.n + self.pending_decs.lock().unwrap().n >= self.batch
+        };
+        if full {
+            self.flush();
+        }
+    }
 
-            let encrypted_op = RegisterOp::Inc { add: data };
+    // Method: dec, Non-Blocking
+    // Subtracts val (non negative) from counter, appended once batch is full
+    pub fn dec(&mut self, val: I) {
+        let full = {
+            let mut pending = self.pending_decs.lock().unwrap();
+            pending.add(val);
+            pending.n + self.pending_incs.lock().unwrap().n >= self.batch
+        };
+        if full {
+            self.flush();
+        }
+    }
+
+    // Method: flush, Non-Blocking
+    // Appends all pending increments and decrements as one log entry
+    pub fn flush(&mut self) {
+        if let Err(err) = self.try_flush() {
+            panic!("{}", err);
+        }
+    }
+
+    // Same as flush, returns the error of the log if it refused the changes
+    pub fn try_flush(&mut self) -> Result<(), LogError> {
+        let inc = {
+            let mut pending = self.pending_incs.lock().unwrap();
+            pending.n = 0;
+            pending.sum.take()
+        };
+        let dec = {
+            let mut pending = self.pending_decs.lock().unwrap();
+            pending.n = 0;
+            pending.sum.take()
+        };
+        if inc.is_none() && dec.is_none() {
+            return Ok(());
+        }
+        let inc = inc.map(|inc| self.to_addable(inc));
+        let dec = dec.map(|dec| self.to_addable(dec));
+        with_runtime(&self.runtime, |mut runtime| {
+            let encrypted_op = PNCounterOp::Add {
+                client: self.client,
+                inc: inc,
+                dec: dec,
+            };
             let op = json::encode(&encrypted_op).unwrap();
-            runtime.append(self.obj_id, State::Encrypted(op.into_bytes()));
-        });
+            runtime.try_append(self.obj_id, State::Encrypted(op.into_bytes()))
+        })
+    }
+
+    // Syncs, returns sums of increments and of decrements
+    pub fn totals(&mut self) -> (Option<I>, Option<I>) {
+        match self.try_totals() {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as totals, returns the integrity violation detected while syncing
+    pub fn try_totals(&mut self) -> Result<(Option<I>, Option<I>), LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok((self.incs.lock().unwrap().total(), self.decs.lock().unwrap().total()))
+        })
+    }
+
+    // Flushes this client's updates, syncs and returns counter value
+    pub fn read(&mut self) -> I
+        where I: Default + Sub<Output = I>
+    {
+        match self.try_read() {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as read, returns the error of the log if it refused the updates or was tampered with
+    pub fn try_read(&mut self) -> Result<I, LogError>
+        where I: Default + Sub<Output = I>
+    {
+        try!(self.try_flush());
+        let (incs, decs) = try!(self.try_totals());
+        Ok(incs.unwrap_or_default() - decs.unwrap_or_default())
+    }
+
+    fn to_addable(&self, val: I) -> Addable {
+        self.convert
+            .as_ref()
+            .map(|convert| {
+                let to = &convert.to;
+                to(&self.secure, val)
+            })
+            .unwrap()
     }
 
     pub fn get_data(&self, data: Addable) -> I {
@@ -210,52 +2013,58 @@ impl<Q, I> Register<Q, I>
     }
 
     pub fn callback(&mut self, op: Operation) {
-        match op.operator {
-            LogOp::Op(State::Encrypted(ref bytes)) => {
-                let s = String::from_utf8(bytes.clone()).unwrap();
-                let encrypted_op = json::decode(&s).unwrap();
-                match encrypted_op {
-                    RegisterOp::Write{data} => {
-                        let data = self.get_data(data);
-                        let mut m_data = self.data.lock().unwrap();
-                        *m_data = data;
-                    }
-                    RegisterOp::Inc{add} => {
-                        let add = self.get_data(add);
-                        let mut m_data = self.data.lock().unwrap();
-                        *m_data = m_data.clone() + add;
-                    }
+        replica::callback(self, &None, op);
+    }
+}
+
+impl<Q, I> ReplicatedObject for PNCounter<Q, I>
+    where Q: 'static + IndexedQueue + Send + Clone,
+          I: 'static + Send + Clone + Add<Output = I>
+{
+    type Op = PNCounterOp<Addable>;
+    type Twin = (Vec<(u64, Addable)>, Vec<(u64, Addable)>);
+
+    fn apply(&mut self, _: &Option<MetaEncryptor>, op: PNCounterOp<Addable>) {
+        match op {
+            PNCounterOp::Add{client, inc, dec} => {
+                if let Some(inc) = inc {
+                    let inc = self.get_data(inc);
+                    self.incs.lock().unwrap().add(client, inc);
                 }
-            }
-            LogOp::Op(State::Encoded(ref s)) => {
-                let op = json::decode(&s).unwrap();
-                match op {
-                    RegisterOp::Write{data} => {
-                        let mut m_data = self.data.lock().unwrap();
-                        *m_data = data;
-                    }
-                    RegisterOp::Inc{add} => {
-                        let mut m_data = self.data.lock().unwrap();
-                        *m_data = m_data.clone() + add;
-                    }
+                if let Some(dec) = dec {
+                    let dec = self.get_data(dec);
+                    self.decs.lock().unwrap().add(client, dec);
                 }
             }
-            LogOp::Snapshot(State::Encoded(ref s)) => {
-                let enc_reg: Register<Q, Addable> = json::decode(&s).unwrap();
-                let mut data = self.data.lock().unwrap();
-                *data = self.get_data(enc_reg.data.lock().unwrap().clone());
-            }
-            _ => {
-                unimplemented!();
-            }
         }
     }
+
+    fn restore(&mut self,
+               _: &Option<MetaEncryptor>,
+               twin: (Vec<(u64, Addable)>, Vec<(u64, Addable)>)) {
+        let (mut enc_incs, mut enc_decs) = twin;
+        let mut incs = Slots::new();
+        for (client, total) in enc_incs.drain(..) {
+            incs.add(client, self.get_data(total));
+        }
+        let mut decs = Slots::new();
+        for (client, total) in enc_decs.drain(..) {
+            decs.add(client, self.get_data(total));
+        }
+        *self.incs.lock().unwrap() = incs;
+        *self.decs.lock().unwrap() = decs;
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::IntRegister;
+    use super::{IntRegister, StringList, StringQueue, DeadlineQueue, EncPriorityQueue, StringLock,
+                IntGCounter, IntPNCounter, Claims, MAX_SKEW_MS, now_ms};
+    use super::rustc_serialize::json;
     use std::sync::{Arc, Mutex};
+    use std::collections::HashSet;
+    use std::thread;
+    use std::time::Duration;
     use runtime::Runtime;
     use indexed_queue::{InMemoryQueue, SharedQueue, ObjId, TxState};
     use encryptors::MetaEncryptor;
@@ -401,4 +2210,289 @@ mod test {
         assert_eq!(user2_reg2.read(), 20);
     }
 
+    #[test]
+    fn list_push_get_range() {
+        let q = InMemoryQueue::new();
+        let runtime: Runtime<InMemoryQueue> = Runtime::new(q, Some(MetaEncryptor::new()));
+        let aruntime = Arc::new(Mutex::new(runtime));
+        let mut list = StringList::new(&aruntime, 1 as ObjId);
+        list.start();
+
+        let n = 10;
+        for i in 0..n {
+            list.push_back(i.to_string());
+            assert_eq!(list.len(), i + 1);
+        }
+        assert_eq!(list.get(3).unwrap(), "3");
+        assert!(list.get(n).is_none());
+        assert_eq!(list.range(2, 5), vec!["2", "3", "4"]);
+        assert_eq!(list.range(8, 20), vec!["8", "9"]);
+        assert!(list.range(5, 2).is_empty());
+    }
+
+    #[test]
+    fn queue_fifo() {
+        let q = InMemoryQueue::new();
+        let runtime: Runtime<InMemoryQueue> = Runtime::new(q, Some(MetaEncryptor::new()));
+        let aruntime = Arc::new(Mutex::new(runtime));
+        let mut queue = StringQueue::new(&aruntime, 1 as ObjId);
+        queue.start();
+
+        assert!(queue.dequeue().is_none());
+        queue.enqueue(String::from("job0"));
+        queue.enqueue(String::from("job1"));
+        assert_eq!(queue.peek().unwrap(), "job0");
+        assert_eq!(queue.dequeue().unwrap(), "job0");
+        assert_eq!(queue.dequeue().unwrap(), "job1");
+        assert!(queue.dequeue().is_none());
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn claims_kept_per_client() {
+        let mut claims = Claims::new();
+        claims.resolve((1, 7), Some(String::from("job0")));
+        // other clients' claims do not evict client 1's result
+        for i in 0..2048 {
+            claims.resolve((i + 2, 0), None);
+        }
+        assert!(claims.take((1, 6)).is_none());
+        assert_eq!(claims.take((1, 7)).unwrap(), "job0");
+        // element is handed out once, the result is dropped with it
+        assert!(claims.take((1, 7)).is_none());
+        assert_eq!(claims.to_vec().len(), 2048);
+        claims.resolve((1, 8), None);
+        assert!(claims.take((1, 8)).is_none());
+        assert_eq!(claims.to_vec().len(), 2048);
+    }
+
+    #[test]
+    #[should_panic(expected = "dequeue cannot be part of a transaction")]
+    fn queue_dequeue_in_tx() {
+        let q = InMemoryQueue::new();
+        let runtime: Runtime<InMemoryQueue> = Runtime::new(q, Some(MetaEncryptor::new()));
+        let aruntime = Arc::new(Mutex::new(runtime));
+        let mut queue = StringQueue::new(&aruntime, 1 as ObjId);
+        queue.start();
+        queue.enqueue(String::from("job0"));
+
+        aruntime.lock().unwrap().begin_tx();
+        queue.dequeue();
+    }
+
+    #[test]
+    fn queue_exactly_once() {
+        let q = SharedQueue::new();
+        let me = Some(MetaEncryptor::new());
+        let n = 20;
+        let nworkers = 4;
+
+        let producer = Arc::new(Mutex::new(Runtime::new(q.clone(), me.clone())));
+        let mut jobs = StringQueue::new(&producer, 1 as ObjId);
+        jobs.start();
+        for i in 0..n {
+            jobs.enqueue(i.to_string());
+        }
+
+        // workers with their own runtimes race to dequeue
+        let handles: Vec<_> = (0..nworkers)
+                                  .map(|_| {
+                                      let q = q.clone();
+                                      let me = me.clone();
+                                      thread::spawn(move || {
+                                          let runtime = Arc::new(Mutex::new(Runtime::new(q, me)));
+                                          let mut jobs = StringQueue::new(&runtime, 1 as ObjId);
+                                          jobs.start();
+                                          let mut claimed = Vec::new();
+                                          while let Some(job) = jobs.dequeue() {
+                                              claimed.push(job);
+                                          }
+                                          claimed
+                                      })
+                                  })
+                                  .collect();
+
+        let mut seen = HashSet::new();
+        for h in handles {
+            for job in h.join().unwrap() {
+                // every job is handed out once
+                assert!(seen.insert(job));
+            }
+        }
+        assert_eq!(seen.len(), n);
+    }
+
+    #[test]
+    fn priority_queue_order() {
+        let q = InMemoryQueue::new();
+        let runtime: Runtime<InMemoryQueue> = Runtime::new(q, Some(MetaEncryptor::new()));
+        let aruntime = Arc::new(Mutex::new(runtime));
+        let mut pq = DeadlineQueue::new(&aruntime, 1 as ObjId);
+        pq.start();
+
+        assert!(pq.pop_min().is_none());
+        pq.push(30, String::from("job30"));
+        pq.push(5, String::from("job5"));
+        pq.push(100, String::from("job100"));
+        pq.push(10, String::from("job10a"));
+        pq.push(10, String::from("job10b"));
+        assert_eq!(pq.len(), 5);
+        assert_eq!(pq.peek_min().unwrap(), (5, String::from("job5")));
+
+        // ties are popped in push order
+        let expected = vec![(5, "job5"), (10, "job10a"), (10, "job10b"), (30, "job30"),
+                            (100, "job100")];
+        for (prio, job) in expected {
+            assert_eq!(pq.pop_min().unwrap(), (prio, String::from(job)));
+        }
+        assert!(pq.peek_min().is_none());
+    }
+
+    #[test]
+    fn priority_queue_snapshot_round_trip() {
+        let q = InMemoryQueue::new();
+        let runtime: Runtime<InMemoryQueue> = Runtime::new(q, Some(MetaEncryptor::new()));
+        let aruntime = Arc::new(Mutex::new(runtime));
+        let mut pq = DeadlineQueue::new(&aruntime, 1 as ObjId);
+        pq.start();
+        let mut enc = EncPriorityQueue::new(&aruntime, 1 as ObjId);
+        enc.start();
+
+        pq.push(30, String::from("job30"));
+        pq.push(5, String::from("job5"));
+        pq.push(10, String::from("job10"));
+        assert_eq!(pq.pop_min().unwrap(), (5, String::from("job5")));
+        assert_eq!(enc.len(), 2);
+
+        let encoded = json::encode(&enc).unwrap();
+        let restored: EncPriorityQueue<InMemoryQueue> = json::decode(&encoded).unwrap();
+        assert_eq!(restored.data.lock().unwrap().len(), 2);
+        assert_eq!(*restored.seq.lock().unwrap(), 3);
+        assert_eq!(restored.claims.lock().unwrap().to_vec().len(), 1);
+        // elements, sequence number and claims all survive the round trip
+        assert_eq!(json::encode(&restored).unwrap(), encoded);
+    }
+
+    #[test]
+    fn lock_acquire_release() {
+        let q = SharedQueue::new();
+        let me = Some(MetaEncryptor::new());
+        let runtime1 = Arc::new(Mutex::new(Runtime::new(q.clone(), me.clone())));
+        let runtime2 = Arc::new(Mutex::new(Runtime::new(q, me)));
+        let mut lock1 = StringLock::new(&runtime1, 1 as ObjId);
+        let mut lock2 = StringLock::new(&runtime2, 1 as ObjId);
+        lock1.start();
+        lock2.start();
+
+        let ttl = 60000;
+        let token = lock1.acquire(String::from("alice"), ttl).expect("lock is free");
+        // both replicas agree on the holder
+        assert_eq!(lock2.holder().unwrap().owner, "alice");
+        assert!(lock2.acquire(String::from("bob"), ttl).is_none());
+
+        // renewing keeps the fencing token, stale tokens are refused
+        assert_eq!(lock1.renew(String::from("alice"), token, ttl), Some(token));
+        assert!(lock1.renew(String::from("alice"), token + 1, ttl).is_none());
+        assert!(!lock2.release(String::from("bob"), token));
+
+        assert!(lock1.release(String::from("alice"), token));
+        assert!(lock1.holder().is_none());
+        let token2 = lock2.acquire(String::from("bob"), ttl).expect("lock was released");
+        assert!(token2 > token);
+    }
+
+    #[test]
+    fn lock_lease_expires() {
+        let q = SharedQueue::new();
+        let me = Some(MetaEncryptor::new());
+        let runtime1 = Arc::new(Mutex::new(Runtime::new(q.clone(), me.clone())));
+        let runtime2 = Arc::new(Mutex::new(Runtime::new(q, me)));
+        let mut lock1 = StringLock::new(&runtime1, 1 as ObjId);
+        let mut lock2 = StringLock::new(&runtime2, 1 as ObjId);
+        lock1.start();
+        lock2.start();
+
+        let token = lock1.acquire(String::from("alice"), 50).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        // bob's acquire is logged past alice's expiry
+        let token2 = lock2.acquire(String::from("bob"), 60000).expect("lease expired");
+        assert!(token2 > token);
+        assert!(lock1.renew(String::from("alice"), token, 50).is_none());
+        assert_eq!(lock1.holder().unwrap().token, token2);
+    }
+
+    #[test]
+    fn lock_skewed_client() {
+        let q = SharedQueue::new();
+        let me = Some(MetaEncryptor::new());
+        let runtime1 = Arc::new(Mutex::new(Runtime::new(q.clone(), me.clone())));
+        let runtime2 = Arc::new(Mutex::new(Runtime::new(q, me)));
+        let mut lock1 = StringLock::new(&runtime1, 1 as ObjId);
+        let mut lock2 = StringLock::new(&runtime2, 1 as ObjId);
+        lock1.start();
+        lock2.start();
+
+        let token = lock1.acquire(String::from("alice"), 60000).unwrap();
+        // bob's clock is an hour ahead, his acquire only moves log time MAX_SKEW_MS
+        let start = now_ms();
+        let ahead = lock2.try_acquire_at(String::from("bob"), 60000, start + 3600000).unwrap();
+        assert!(ahead.is_none());
+        assert!(*lock2.now.lock().unwrap() <= now_ms() + MAX_SKEW_MS);
+        assert_eq!(lock1.holder().unwrap().token, token);
+        assert_eq!(lock1.renew(String::from("alice"), token, 60000), Some(token));
+    }
+
+    #[test]
+    fn gcounter_batched() {
+        let q = SharedQueue::new();
+        let me = Some(MetaEncryptor::new());
+        let runtime1 = Arc::new(Mutex::new(Runtime::new(q.clone(), me.clone())));
+        let runtime2 = Arc::new(Mutex::new(Runtime::new(q, me)));
+        let mut counter1 = IntGCounter::new(&runtime1, 1 as ObjId).with_batch(10);
+        let mut counter2 = IntGCounter::new(&runtime2, 1 as ObjId).with_batch(10);
+        counter1.start();
+        counter2.start();
+
+        for _ in 0..25 {
+            counter1.inc(1);
+            counter2.inc(2);
+        }
+        // unflushed increments are not counted, not even by their own client
+        assert_eq!(counter1.total(), Some(60));
+        assert_eq!(counter1.read(), 65);
+        assert_eq!(counter2.read(), 75);
+        // 25 increments per client took 3 log entries each
+        assert_eq!(runtime2.lock().unwrap().global_idx, 5);
+
+        // increments pending when the last handle of a client is dropped are appended
+        let clone1 = counter1.clone();
+        counter1.inc(4);
+        drop(counter1);
+        assert_eq!(counter2.read(), 75);
+        drop(clone1);
+        assert_eq!(counter2.read(), 79);
+    }
+
+    #[test]
+    fn pncounter_inc_dec() {
+        let q = SharedQueue::new();
+        let me = Some(MetaEncryptor::new());
+        let runtime1 = Arc::new(Mutex::new(Runtime::new(q.clone(), me.clone())));
+        let runtime2 = Arc::new(Mutex::new(Runtime::new(q, me)));
+        let mut counter1 = IntPNCounter::new(&runtime1, 1 as ObjId).with_batch(4);
+        let mut counter2 = IntPNCounter::new(&runtime2, 1 as ObjId);
+        counter1.start();
+        counter2.start();
+
+        counter1.inc(10);
+        counter1.dec(3);
+        counter2.dec(5);
+        counter1.inc(1);
+        counter1.dec(1);
+        // counter1's batch is full, everything has been appended
+        assert_eq!(counter2.read(), 2);
+        counter2.inc(7);
+        assert_eq!(counter1.read(), 9);
+        assert_eq!(counter1.totals(), (Some(18), Some(9)));
+    }
 }
@@ -14,7 +14,9 @@ use rand::{OsRng, Rng};
 use rustc_serialize::{json, Encodable, Decodable, Encoder, Decoder};
 use openssl::crypto::symm::{self, encrypt, decrypt};
 use openssl::crypto::hash;
+use openssl::crypto::hmac;
 use rustc_serialize::base64::{STANDARD, ToBase64, FromBase64};
+use crypto::ed25519;
 
 use ore::{RandomFn, Vecu8Traversable, RandomIntPRNG, OrdData};
 
@@ -58,6 +60,14 @@ impl Addable {
             m: pk.n_squared.clone(),
         }
     }
+    // Encryption of 0 under any key (1 is g^0 r^n for r = 1), for replicas without keys
+    // Takes the modulus of the first Addable it is added to
+    pub fn zero() -> Addable {
+        Addable {
+            i: Int::from(1),
+            m: Int::from(0),
+        }
+    }
     pub fn from(i: Int, pk: PublicKey) -> Addable {
         Addable {
             i: pk.encrypt(&Int::from(i)),
@@ -70,6 +80,13 @@ impl Add for Addable {
     type Output = Addable;
 
     fn add(self, _rhs: Addable) -> Addable {
+        // zero carries no modulus, the sum is the other term
+        if self.m == Int::from(0) {
+            return _rhs;
+        }
+        if _rhs.m == Int::from(0) {
+            return self;
+        }
         assert_eq!(self.m, _rhs.m);
         return Addable::new((self.i * _rhs.i) % &self.m, self.m);
     }
@@ -183,6 +200,10 @@ impl OrdEncryptor {
     pub fn new(enc: Encryptor) -> OrdEncryptor {
         let mut gen = OsRng::new().expect("Failed to get OS random generator");
         let key = gen.gen_int(128);
+        OrdEncryptor::from_key(key, enc)
+    }
+
+    pub fn from_key(key: Int, enc: Encryptor) -> OrdEncryptor {
         let m = Int::from(2).pow(40);
 
         OrdEncryptor {
@@ -325,6 +346,165 @@ impl Encryptor {
     }
 }
 
+// Token of a keyword, as stored in searchable indexes
+// Equal keywords under the same key give equal tokens
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SearchToken {
+    tag: Vec<u8>, // keyed PRF of keyword
+}
+
+// Class: SearchEncryptor
+// Implements searchable encryption tokens
+// A keyed PRF (HMAC-SHA256) produces deterministic tags, not reversible
+#[derive(Clone)]
+pub struct SearchEncryptor {
+    key: Vec<u8>,
+}
+
+impl SearchEncryptor {
+    pub fn new() -> SearchEncryptor {
+        let mut gen = OsRng::new().expect("Failed to get OS random generator");
+        let mut key: Vec<u8> = repeat(0u8).take(32).collect();
+        gen.fill_bytes(&mut key[..]);
+        SearchEncryptor { key: key }
+    }
+    pub fn from_key(key: Vec<u8>) -> SearchEncryptor {
+        SearchEncryptor { key: key }
+    }
+    pub fn token(&self, word: &[u8]) -> SearchToken {
+        SearchToken { tag: hmac::hmac(hash::Type::SHA256, &self.key, word) }
+    }
+}
+
+// Class: Authenticator
+// Computes keyed macs (HMAC-SHA256) of log entries
+// Clients sharing a log must share the key, the log server never sees it
+#[derive(Clone)]
+pub struct Authenticator {
+    key: Vec<u8>,
+}
+
+impl Authenticator {
+    pub fn new() -> Authenticator {
+        let mut gen = OsRng::new().expect("Failed to get OS random generator");
+        let mut key: Vec<u8> = repeat(0u8).take(32).collect();
+        gen.fill_bytes(&mut key[..]);
+        Authenticator { key: key }
+    }
+    pub fn from_key(key: Vec<u8>) -> Authenticator {
+        assert!(key.len() >= 16, "mac key must be at least 16 bytes");
+        Authenticator { key: key }
+    }
+    pub fn mac(&self, data: &[u8]) -> Vec<u8> {
+        hmac::hmac(hash::Type::SHA256, &self.key, data)
+    }
+}
+
+// Id of a writer: its Ed25519 public key
+pub type WriterId = Vec<u8>;
+
+// Class: Signer
+// Ed25519 signing key of a writer
+// Signatures are checked with the writer's public key only, so the VM can check them too
+#[derive(Clone)]
+pub struct Signer {
+    secret: Vec<u8>,
+    public: WriterId,
+}
+
+impl Signer {
+    pub fn new() -> Signer {
+        let mut gen = OsRng::new().expect("Failed to get OS random generator");
+        let mut seed: Vec<u8> = repeat(0u8).take(32).collect();
+        gen.fill_bytes(&mut seed[..]);
+        Signer::from_seed(&seed)
+    }
+    pub fn from_seed(seed: &[u8]) -> Signer {
+        let (secret, public) = ed25519::keypair(seed);
+        Signer {
+            secret: secret.to_vec(),
+            public: public.to_vec(),
+        }
+    }
+    pub fn writer_id(&self) -> WriterId {
+        self.public.clone()
+    }
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        ed25519::signature(data, &self.secret).to_vec()
+    }
+}
+
+// True if signature of data was made by writer
+pub fn verify_signature(writer: &WriterId, data: &[u8], signature: &[u8]) -> bool {
+    writer.len() == 32 && signature.len() == 64 && ed25519::verify(data, writer, signature)
+}
+
+// Class: KeySet
+// Symmetric keys of a MetaEncryptor, as wrapped for other users
+// The additive (Paillier) and mac keys are not part of it, they stay shared by the runtime
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug)]
+pub struct KeySet {
+    enc: (Vec<u8>, Vec<u8>), // key and nonce of the reversible encryptor
+    eq: (Vec<u8>, Vec<u8>), // key and nonce of the equable encryptor
+    ord: (String, Vec<u8>, Vec<u8>), // order preserving key, key and nonce of its encryptor
+    search: Vec<u8>, // search token key
+}
+
+// Class: WrappedKeys
+// Key sets of some objects, encrypted for one user
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug)]
+pub struct WrappedKeys {
+    iv: Vec<u8>,
+    data: Vec<u8>, // AES-256-CBC encryption of the key sets
+    mac: Vec<u8>, // HMAC-SHA256 of iv and data
+}
+
+// Class: KeyWrapper
+// Key of a user, wraps the key sets of objects the user is granted access to
+// Encrypt-then-mac, with a fresh iv per grant
+#[derive(Clone)]
+pub struct KeyWrapper {
+    key: Vec<u8>,
+    mac_key: Vec<u8>,
+}
+
+impl KeyWrapper {
+    pub fn new() -> KeyWrapper {
+        let mut gen = OsRng::new().expect("Failed to get OS random generator");
+        let mut key: Vec<u8> = repeat(0u8).take(32).collect();
+        gen.fill_bytes(&mut key[..]);
+        let mut mac_key: Vec<u8> = repeat(0u8).take(32).collect();
+        gen.fill_bytes(&mut mac_key[..]);
+        KeyWrapper::from_keys(key, mac_key)
+    }
+    pub fn from_keys(key: Vec<u8>, mac_key: Vec<u8>) -> KeyWrapper {
+        KeyWrapper {
+            key: key,
+            mac_key: mac_key,
+        }
+    }
+    pub fn wrap(&self, data: &[u8]) -> WrappedKeys {
+        let mut gen = OsRng::new().expect("Failed to get OS random generator");
+        let mut iv: Vec<u8> = repeat(0u8).take(16).collect();
+        gen.fill_bytes(&mut iv[..]);
+        let data = encrypt(symm::Type::AES_256_CBC, &self.key, &iv, data);
+        let mac = hmac::hmac(hash::Type::SHA256, &self.mac_key, &[&iv[..], &data[..]].concat());
+        WrappedKeys {
+            iv: iv,
+            data: data,
+            mac: mac,
+        }
+    }
+    // None if keys were wrapped for another user, or modified
+    pub fn unwrap(&self, wrapped: &WrappedKeys) -> Option<Vec<u8>> {
+        let signed = [&wrapped.iv[..], &wrapped.data[..]].concat();
+        if hmac::hmac(hash::Type::SHA256, &self.mac_key, &signed) != wrapped.mac {
+            return None;
+        }
+        Some(decrypt(symm::Type::AES_256_CBC, &self.key, &wrapped.iv, &wrapped.data))
+    }
+}
+
 // Class: MetaEncryptor
 // Collection of implemented encryptors to allow structured access
 // from data structures
@@ -334,31 +514,103 @@ pub struct MetaEncryptor {
     pub add: AddEncryptor,
     pub enc: Encryptor,
     pub ord: OrdEncryptor,
+    pub search: SearchEncryptor,
+    pub auth: Authenticator,
 }
 
 impl MetaEncryptor {
+    // Fresh keys for every encryptor, for runtimes of a single process
+    // Runtimes in other processes reject its entries, see from
     pub fn new() -> MetaEncryptor {
         return MetaEncryptor {
             eq: EqEncryptor::new(Encryptor::new()),
             add: AddEncryptor::new(),
             enc: Encryptor::new(),
             ord: OrdEncryptor::new(Encryptor::new()),
+            search: SearchEncryptor::new(),
+            auth: Authenticator::new(),
         };
     }
 
+    // Encryptor from keys shared by all clients of a log
+    // search is required: tokens only match the index of clients sharing its key
+    // auth is required: entries are only accepted if macked with the same key
     pub fn from(eq: EqEncryptor,
                 add: AddEncryptor,
                 enc: Encryptor,
-                ord: OrdEncryptor)
+                ord: OrdEncryptor,
+                search: SearchEncryptor,
+                auth: Authenticator)
                 -> MetaEncryptor {
         return MetaEncryptor {
             eq: eq,
             add: add,
             enc: enc,
             ord: ord,
+            search: search,
+            auth: auth,
         };
     }
 
+    // Replace search key, clients searching the same objects must share it
+    pub fn with_search(mut self, search: SearchEncryptor) -> MetaEncryptor {
+        self.search = search;
+        self
+    }
+
+    // Replace mac key, clients sharing a log must share it
+    pub fn with_auth(mut self, auth: Authenticator) -> MetaEncryptor {
+        self.auth = auth;
+        self
+    }
+
+    // Fresh symmetric keys, for an object encrypted under its own keys
+    // Additive and mac keys are kept: Addable values of the object (registers, counters)
+    // stay readable by every holder of the runtime's Paillier key, granted or not
+    pub fn fresh_keys(&self) -> MetaEncryptor {
+        MetaEncryptor {
+            eq: EqEncryptor::new(Encryptor::new()),
+            add: self.add.clone(),
+            enc: Encryptor::new(),
+            ord: OrdEncryptor::new(Encryptor::new()),
+            search: SearchEncryptor::new(),
+            auth: self.auth.clone(),
+        }
+    }
+
+    pub fn key_set(&self) -> KeySet {
+        KeySet {
+            enc: (self.enc.key.clone(), self.enc.nonce.clone()),
+            eq: (self.eq.encryptor.key.clone(), self.eq.encryptor.nonce.clone()),
+            ord: (self.ord.key.to_str_radix(10, false),
+                  self.ord.encryptor.key.clone(),
+                  self.ord.encryptor.nonce.clone()),
+            search: self.search.key.clone(),
+        }
+    }
+
+    // Encryptor using the keys of key_set, and the additive and mac keys of self
+    pub fn with_key_set(&self, key_set: KeySet) -> MetaEncryptor {
+        let (ord_key, key, nonce) = key_set.ord;
+        let ord_key = Int::from_str_radix(&ord_key, 10).expect("invalid order preserving key");
+        MetaEncryptor {
+            eq: EqEncryptor::new(Encryptor::from_key_nonce(key_set.eq.0, key_set.eq.1)),
+            add: self.add.clone(),
+            enc: Encryptor::from_key_nonce(key_set.enc.0, key_set.enc.1),
+            ord: OrdEncryptor::from_key(ord_key, Encryptor::from_key_nonce(key, nonce)),
+            search: SearchEncryptor::from_key(key_set.search),
+            auth: self.auth.clone(),
+        }
+    }
+
+    pub fn search_token(&self, word: &[u8]) -> SearchToken {
+        self.search.token(word)
+    }
+
+    pub fn mac(&self, data: &[u8]) -> Vec<u8> {
+        self.auth.mac(data)
+    }
+
     pub fn encrypt(&self, s: &[u8]) -> Encrypted {
         self.enc.encrypt(s)
     }
@@ -398,7 +650,8 @@ impl MetaEncryptor {
 
 #[cfg(test)]
 mod test {
-    use super::{AddEncryptor, OrdEncryptor, Encryptor, EqEncryptor, Int, Addable};
+    use super::{AddEncryptor, OrdEncryptor, Encryptor, EqEncryptor, Int, Addable,
+                SearchEncryptor, Signer, verify_signature};
     extern crate rustc_serialize;
     use self::rustc_serialize::json;
 
@@ -439,4 +692,23 @@ mod test {
         assert!(x1 < x3);
         assert_eq!("abcd".as_bytes(), e.decrypt(x1).as_slice());
     }
+
+    #[test]
+    fn search_token() {
+        let s = SearchEncryptor::new();
+        assert_eq!(s.token(b"apple"), s.token(b"apple"));
+        assert!(s.token(b"apple") != s.token(b"apples"));
+        // tokens depend on the key
+        assert!(s.token(b"apple") != SearchEncryptor::new().token(b"apple"));
+    }
+
+    #[test]
+    fn signatures() {
+        let s = Signer::new();
+        let sig = s.sign(b"append");
+        assert!(verify_signature(&s.writer_id(), b"append", &sig));
+        assert!(!verify_signature(&s.writer_id(), b"appent", &sig));
+        assert!(!verify_signature(&Signer::new().writer_id(), b"append", &sig));
+        assert!(!verify_signature(&s.writer_id(), b"append", &sig[..32]));
+    }
 }
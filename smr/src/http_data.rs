@@ -2,17 +2,98 @@ extern crate rustc_serialize;
 use self::rustc_serialize::Encodable;
 
 use std::collections::HashSet;
+use std::fmt;
 
 use indexed_queue::{LogData, Entry, LogIndex, ObjId};
+use encryptors::SearchToken;
 
 #[derive(RustcEncodable, RustcDecodable)]
 pub enum HttpResponse {
-    Stream(Vec<LogData>),
+    Stream(Vec<LogData>, Option<LogIndex>), // page of stream, and where the next page starts
     Append(LogIndex),
+    Search(Vec<String>),
+    Rejected(String), // append refused, e.g. writer not allowed
+    Error(HttpError), // request could not be served, sent with a non 200 status
+}
+
+// Enum: HttpError
+// Why HttpServer could not serve a request
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone, PartialEq, Eq)]
+pub enum HttpError {
+    BadRequest(String), // 400, body is not a json encoded HttpRequest
+    Unauthorized(String), // 401, request does not carry the server's token
+    MethodNotAllowed(String), // 405, only POST is served
+    PayloadTooLarge(u64), // 413, body is over the server's limit, in bytes
+    Queue(String), // 500, queue failed to serve the request
+    Unavailable(String), // 503, server is shutting down
+}
+
+impl HttpError {
+    pub fn status(&self) -> u16 {
+        match *self {
+            HttpError::BadRequest(_) => 400,
+            HttpError::Unauthorized(_) => 401,
+            HttpError::MethodNotAllowed(_) => 405,
+            HttpError::PayloadTooLarge(_) => 413,
+            HttpError::Queue(_) => 500,
+            HttpError::Unavailable(_) => 503,
+        }
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HttpError::BadRequest(ref reason) => write!(f, "bad request: {}", reason),
+            HttpError::Unauthorized(ref reason) => write!(f, "unauthorized: {}", reason),
+            HttpError::MethodNotAllowed(ref method) => write!(f, "method {} not allowed", method),
+            HttpError::PayloadTooLarge(limit) => write!(f, "request over {} bytes", limit),
+            HttpError::Queue(ref reason) => write!(f, "queue failure: {}", reason),
+            HttpError::Unavailable(ref reason) => write!(f, "unavailable: {}", reason),
+        }
+    }
+}
+
+// Enum: ClientError
+// Failure of a request sent by HttpClient
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientError {
+    Connection(String), // server could not be reached, or the connection dropped
+    Decode(u16, String), // status and error of a response that could not be decoded
+    Server(HttpError), // server refused or failed to serve the request
+    Rejected(String), // append refused by the queue
+    Unexpected, // response of another request type
+}
+
+impl ClientError {
+    // Whether sending the request again, possibly to another server, may succeed
+    pub fn retryable(&self) -> bool {
+        match *self {
+            ClientError::Connection(_) => true,
+            ClientError::Server(HttpError::Unavailable(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClientError::Connection(ref reason) => write!(f, "connection failed: {}", reason),
+            ClientError::Decode(status, ref reason) => {
+                write!(f, "undecodable response (status {}): {}", status, reason)
+            }
+            ClientError::Server(ref err) => write!(f, "server error: {}", err),
+            ClientError::Rejected(ref reason) => write!(f, "rejected: {}", reason),
+            ClientError::Unexpected => write!(f, "unexpected response type"),
+        }
+    }
 }
 
 #[derive(RustcEncodable, RustcDecodable, Debug)]
 pub enum HttpRequest {
-    Stream(HashSet<ObjId>, LogIndex, Option<LogIndex>),
+    Stream(HashSet<ObjId>, LogIndex, Option<LogIndex>, usize), // ..., page size
+    StreamAll(LogIndex, Option<LogIndex>, usize), // entries of all objects, page size
     Append(Entry),
+    Search(ObjId, SearchToken),
 }
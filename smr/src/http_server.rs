@@ -1,96 +1,635 @@
 extern crate rustc_serialize;
 extern crate hyper;
 
-use std::io::Read;
-use std::sync::Mutex;
+use std::io::{self, Read};
+use std::fmt;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::cmp;
 
 use self::rustc_serialize::json;
 use self::hyper::Server;
 use self::hyper::net::Fresh;
+use self::hyper::header::{ContentLength, Connection, Authorization};
+use self::hyper::net::Openssl;
+use self::hyper::status::StatusCode;
 use self::hyper::server::{Handler, Request, Response, Listening};
 
-use indexed_queue::{IndexedQueue, LogData};
-use http_data::{HttpRequest, HttpResponse};
+use indexed_queue::{IndexedQueue, SharedLog, LogData, LogIndex};
+use http_data::{HttpRequest, HttpResponse, HttpError};
+use runtime::LogError;
+use crypto::util::fixed_time_eq;
+
+// Largest request body served by default, in bytes
+pub const MAX_BODY: u64 = 16 * 1024 * 1024;
+// Most log entries sent in one stream response, larger pages are cut to it
+pub const MAX_PAGE: usize = 10000;
 
 // Class: HttpServer
 // Handles connections to the SharedLog
+// Requests are served concurrently, each by a clone of the queue, so clones must share the log
 // Parametrized by:
 // * Q : structure allowing seamless communicating with Shared Log
 pub struct HttpServer<Q: 'static> {
     iq: Q,
-    listener: Listening,
+    max_body: u64, // larger requests are refused with 413
+    tls: Option<(PathBuf, PathBuf)>, // certificate and private key files, None for plain http
+    token: Option<String>, // bearer token requests must carry, None to serve anyone
+}
+
+// Class: ServerHandle
+// Server listening for requests, see HttpServer::serve
+pub struct ServerHandle {
+    listener: Option<Listening>, // None once shut down
+    in_flight: Arc<InFlight>,
+}
+
+// Requests being served, shutdown refuses new ones and waits for the others to complete
+struct InFlight {
+    state: Mutex<(usize, bool)>, // number of requests being served, and whether server is closing
+    done: Condvar, // notified as the last request being served completes
+}
+
+// Counts a request as being served until dropped
+struct Serving<'a> {
+    in_flight: &'a InFlight,
+}
+
+impl InFlight {
+    fn new() -> InFlight {
+        InFlight {
+            state: Mutex::new((0, false)),
+            done: Condvar::new(),
+        }
+    }
+
+    // None if server is closing, request must be refused
+    fn start(&self) -> Option<Serving> {
+        let mut state = self.state.lock().unwrap();
+        if state.1 {
+            return None;
+        }
+        state.0 += 1;
+        Some(Serving { in_flight: self })
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().1 = true;
+    }
+
+    // waits up to timeout for requests being served, returns true if all completed
+    fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        while state.0 > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.done.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+impl<'a> Drop for Serving<'a> {
+    fn drop(&mut self) {
+        let mut state = self.in_flight.state.lock().unwrap();
+        state.0 -= 1;
+        if state.0 == 0 {
+            self.in_flight.done.notify_all();
+        }
+    }
 }
 
 struct HttpHandler<Q> {
-    iq: Mutex<Q>,
+    iq: Q, // cloned when no idle queue is left
+    idle: Mutex<Vec<Q>>, // queues not serving a request, only locked to take or return one
+    max_body: u64, // larger requests are refused with 413
+    token: Option<String>, // requests without it are refused with 401
+    in_flight: Arc<InFlight>, // requests being served
+}
+
+impl<Q: SharedLog> HttpHandler<Q> {
+    pub fn new(iq: Q,
+               max_body: u64,
+               token: Option<String>,
+               in_flight: Arc<InFlight>)
+               -> HttpHandler<Q> {
+        return HttpHandler {
+            iq: iq,
+            idle: Mutex::new(Vec::new()),
+            max_body: max_body,
+            token: token,
+            in_flight: in_flight,
+        };
+    }
+
+    // Checks the request's bearer token, in constant time
+    fn authorized(&self, req: &Request) -> bool {
+        match self.token {
+            None => true,
+            Some(ref token) => {
+                let expected = format!("Bearer {}", token);
+                req.headers
+                   .get::<Authorization<String>>()
+                   .map_or(false, |auth| fixed_time_eq(auth.0.as_bytes(), expected.as_bytes()))
+            }
+        }
+    }
+
+    // Method: respond
+    // Reads and serves request
+    // Returns: response to send with status 200, or error to send with its status
+    fn respond(&self, req: &mut Request) -> Result<HttpResponse, HttpError> {
+        if !self.authorized(req) {
+            return Err(HttpError::Unauthorized(String::from("missing or wrong bearer token")));
+        }
+        if req.method != hyper::Post {
+            return Err(HttpError::MethodNotAllowed(format!("{}", req.method)));
+        }
+        if let Some(&ContentLength(len)) = req.headers.get::<ContentLength>() {
+            if len > self.max_body {
+                return Err(HttpError::PayloadTooLarge(self.max_body));
+            }
+        }
+        // content length may be missing, never read more than the limit
+        let mut body = String::new();
+        try!(req.by_ref()
+                .take(self.max_body + 1)
+                .read_to_string(&mut body)
+                .map_err(|err| HttpError::BadRequest(format!("{}", err))));
+        if body.len() as u64 > self.max_body {
+            return Err(HttpError::PayloadTooLarge(self.max_body));
+        }
+        let body: HttpRequest = try!(json::decode(&body)
+                                         .map_err(|err| HttpError::BadRequest(format!("{}", err))));
+
+        self.with_queue(|iq| {
+            match body {
+                HttpRequest::Append(entry) => {
+                    // retries, possibly served by another server, are deduplicated by the queue
+                    match iq.try_append(entry) {
+                        Ok(idx) => Ok(HttpResponse::Append(idx)),
+                        Err(LogError::Unauthorized(_, reason)) => {
+                            Ok(HttpResponse::Rejected(reason))
+                        }
+                        Err(err) => Err(HttpError::Queue(format!("{}", err))),
+                    }
+                }
+                HttpRequest::Stream(ref obj_ids, from, to, page) => {
+                    // the queue stops after one page, rather than streaming all that is left
+                    let page = cmp::max(cmp::min(page, MAX_PAGE), 1);
+                    let rx = iq.stream_page(obj_ids, from, to, page);
+                    Ok(page_of(rx, page))
+                }
+                HttpRequest::StreamAll(from, to, page) => {
+                    let page = cmp::max(cmp::min(page, MAX_PAGE), 1);
+                    iq.stream_all_page(from, to, page)
+                      .map(|rx| page_of(rx, page))
+                      .map_err(|err| HttpError::Queue(format!("{}", err)))
+                }
+                HttpRequest::Search(obj_id, ref token) => {
+                    iq.search(obj_id, token)
+                      .map(HttpResponse::Search)
+                      .map_err(|err| HttpError::Queue(format!("{}", err)))
+                }
+            }
+        })
+    }
+
+    // Calls f with a queue of its own, requests only contend in the queue itself
+    fn with_queue<F>(&self, f: F) -> Result<HttpResponse, HttpError>
+        where F: FnOnce(&mut Q) -> Result<HttpResponse, HttpError>
+    {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut iq = idle.unwrap_or_else(|| self.iq.clone());
+        let r = f(&mut iq);
+        self.idle.lock().unwrap().push(iq);
+        r
+    }
 }
 
-impl<Q: IndexedQueue> HttpHandler<Q> {
-    pub fn new(iq: Mutex<Q>) -> HttpHandler<Q> {
-        return HttpHandler { iq: iq };
+// Method: page_of
+// Reads up to page log entries of stream rx
+// Snapshots come first and share an index, they are all sent whatever the page size
+// Returns: stream response, telling where to continue if entries are left out
+fn page_of(rx: mpsc::Receiver<LogData>, page: usize) -> HttpResponse {
+    let page = cmp::max(page, 1);
+    let mut data = Vec::new();
+    let mut n_entries = 0;
+    for d in rx.iter() {
+        let idx = match d {
+            LogData::LogEntry(ref e) => Some(e.idx.expect("streamed entry has no index")),
+            LogData::LogSnapshot(_) => None,
+        };
+        data.push(d);
+        if let Some(idx) = idx {
+            n_entries += 1;
+            if n_entries == page {
+                // stream may be over, in which case the next page is empty
+                return HttpResponse::Stream(data, Some(idx + 1));
+            }
+        }
     }
+    HttpResponse::Stream(data, None)
 }
 
-impl<Q: IndexedQueue + Send> Handler for HttpHandler<Q> {
-    fn handle(&self, mut req: Request, resp: Response<Fresh>) {
-        match req.method {
-            hyper::Post => {
-                let mut body = String::new();
-                req.read_to_string(&mut body).unwrap();
-                let body = json::decode(&body).unwrap();
-                match body {
-                    HttpRequest::Append(entry) => {
-                        let idx = self.iq.lock().unwrap().append(entry);
-                        let r = HttpResponse::Append(idx);
-                        let r = json::encode(&r).unwrap();
-                        resp.send(r.as_bytes()).unwrap();
+impl<Q: SharedLog + Sync + Send> Handler for HttpHandler<Q> {
+    fn handle(&self, mut req: Request, mut resp: Response<Fresh>) {
+        // request is served until its response is sent
+        let serving = self.in_flight.start();
+        let r = match serving {
+            Some(_) => self.respond(&mut req),
+            None => Err(HttpError::Unavailable(String::from("server is shutting down"))),
+        };
+        let r = match r {
+            Ok(r) => r,
+            Err(err) => {
+                match err {
+                    HttpError::Unavailable(_) => {
+                        // connections kept alive would otherwise keep hitting a closed server
+                        resp.headers_mut().set(Connection::close());
                     }
-                    HttpRequest::Stream(ref obj_ids, from, to) => {
-                        let mut entries: Vec<LogData> = Vec::new();
-                        let rx = self.iq.lock().unwrap().stream(obj_ids, from, to);
-                        for e in &rx {
-                            entries.push(e);
-                        }
-                        let r = HttpResponse::Stream(entries);
-                        let r = json::encode(&r).unwrap();
-                        resp.send(r.as_bytes()).unwrap();
+                    HttpError::Unauthorized(_) => {
+                        resp.headers_mut().set_raw("WWW-Authenticate", vec![b"Bearer".to_vec()]);
                     }
-                };
+                    _ => {}
+                }
+                *resp.status_mut() = StatusCode::from_u16(err.status());
+                HttpResponse::Error(err)
             }
-            _ => unimplemented!(),
         };
+        let r = json::encode(&r).unwrap();
+        // client may be gone, nothing left to tell it
+        let _ = resp.send(r.as_bytes());
     }
 }
 
-impl<Q> HttpServer<Q> where Q: IndexedQueue + Clone + Sync + Send
+impl<Q> HttpServer<Q> where Q: SharedLog + Sync + Send
 {
-    pub fn new(iq: Q, server_addr: &str) -> HttpServer<Q> {
-        let handler = HttpHandler::new(Mutex::new(iq.clone()));
+    pub fn new(iq: Q) -> HttpServer<Q> {
         return HttpServer {
             iq: iq,
-            listener: Server::http(&server_addr).unwrap().handle(handler).unwrap(),
+            max_body: MAX_BODY,
+            tls: None,
+            token: None,
+        };
+    }
+
+    // Serve requests of up to max_body bytes
+    pub fn with_max_body(mut self, max_body: u64) -> HttpServer<Q> {
+        self.max_body = max_body;
+        self
+    }
+
+    // Serve https, with the certificate and private key in PEM files cert and key
+    pub fn with_tls<P: AsRef<Path>>(mut self, cert: P, key: P) -> HttpServer<Q> {
+        self.tls = Some((cert.as_ref().to_path_buf(), key.as_ref().to_path_buf()));
+        self
+    }
+
+    // Only serve requests carrying token as bearer token, see HttpClient::with_token
+    // Tokens are sent in the clear over plain http, use with TLS
+    pub fn with_token(mut self, token: &str) -> HttpServer<Q> {
+        self.token = Some(String::from(token));
+        self
+    }
+
+    // Listens for requests at server_addr until the returned handle is shut down
+    // Returns: error if the certificate or key cannot be read, or server_addr cannot be bound
+    pub fn serve(self, server_addr: &str) -> io::Result<ServerHandle> {
+        let in_flight = Arc::new(InFlight::new());
+        let handler = HttpHandler::new(self.iq, self.max_body, self.token, in_flight.clone());
+        let listening = match self.tls {
+            Some((cert, key)) => {
+                let ssl = try!(Openssl::with_cert_and_key(cert, key)
+                                   .map_err(|err| serve_error("error reading certificate", err)));
+                try!(Server::https(&server_addr, ssl)
+                         .map_err(|err| serve_error("error binding server address", err)))
+                    .handle(handler)
+            }
+            None => {
+                try!(Server::http(&server_addr)
+                         .map_err(|err| serve_error("error binding server address", err)))
+                    .handle(handler)
+            }
         };
+        let listener = try!(listening.map_err(|err| serve_error("error starting server", err)));
+        Ok(ServerHandle {
+            listener: Some(listener),
+            in_flight: in_flight,
+        })
     }
+}
 
-    pub fn close(&mut self) {
-        self.listener.close().unwrap();
+fn serve_error<E: fmt::Display>(what: &str, err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("{}: {}", what, err))
+}
+
+impl ServerHandle {
+    // Method: shutdown, Blocking
+    // Refuses new requests with 503, stops listening,
+    // and waits up to timeout for requests being served to complete
+    // Returns: true if they all completed
+    // Note: hyper does not join its threads when closing,
+    // requests on connections already accepted keep being refused
+    pub fn shutdown(&mut self, timeout: Duration) -> bool {
+        self.in_flight.close();
+        if let Some(mut listener) = self.listener.take() {
+            let _ = listener.close();
+        }
+        self.in_flight.drain(timeout)
     }
 }
 
 
 #[cfg(test)]
 mod test {
-    use indexed_queue::InMemoryQueue;
+    use indexed_queue::{SharedQueue, SharedLog, HttpClient, IndexedQueue, Entry, Operation,
+                        State, TxType, TxState, ObjId, LogIndex};
+    use indexed_queue::LogData;
+    use indexed_queue::LogData::LogEntry;
+    use http_data::{HttpError, ClientError};
+    use encryptors::SearchEncryptor;
     use super::HttpServer;
+    use super::hyper::Client;
+    use super::hyper::status::StatusCode;
+    use std::collections::{HashMap, HashSet};
+    use std::iter::repeat;
     use std::thread;
+    use std::time::Duration;
+    use std::sync::{mpsc, Arc};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::env;
+    use std::fs::{self, File};
+    use openssl::x509::X509Generator;
+    use openssl::crypto::hash::Type;
+    use rand;
+
+    // entry writing i to obj_id
+    fn entry(obj_id: ObjId, i: i32) -> Entry {
+        Entry::new(HashMap::new(),
+                   vec![obj_id].into_iter().collect(),
+                   vec![Operation::new(obj_id, State::Encoded(i.to_string()))],
+                   TxType::None,
+                   TxState::None)
+    }
 
     #[test]
     fn http_server() {
-        let iq = InMemoryQueue::new();
+        let iq = SharedQueue::new();
         let child = thread::spawn(move || {
-            let mut s = HttpServer::new(iq, "127.0.0.1:6768");
-            s.close();
+            let mut s = HttpServer::new(iq).serve("127.0.0.1:6768").unwrap();
+            assert!(s.shutdown(Duration::from_secs(1)));
         });
         child.join().unwrap()
     }
+
+    #[test]
+    fn http_server_errors() {
+        let mut s = HttpServer::new(SharedQueue::new())
+                        .with_max_body(1024)
+                        .serve("127.0.0.1:6769")
+                        .unwrap();
+        let addr = "http://127.0.0.1:6769";
+        let c = Client::new();
+        assert_eq!(c.get(addr).send().unwrap().status, StatusCode::MethodNotAllowed);
+        assert_eq!(c.post(addr).body("not json").send().unwrap().status,
+                   StatusCode::BadRequest);
+        let big: String = repeat('a').take(2048).collect();
+        assert_eq!(c.post(addr).body(&big[..]).send().unwrap().status,
+                   StatusCode::PayloadTooLarge);
+
+        // shared queues do not serve searches
+        let mut client = HttpClient::new(addr);
+        match client.try_search(0, &SearchEncryptor::new().token(b"word")) {
+            Err(ClientError::Server(HttpError::Queue(_))) => {}
+            r => panic!("expected queue failure, got {:?}", r),
+        }
+        // server still serves requests
+        assert!(client.try_stream(&HashSet::new(), 0, None).is_ok());
+        assert!(s.shutdown(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn http_stream_pages() {
+        let mut s = HttpServer::new(SharedQueue::new()).serve("127.0.0.1:6770").unwrap();
+        let mut client = HttpClient::new("http://127.0.0.1:6770").with_page(10);
+        let n = 25;
+        for i in 0..n {
+            client.append(entry(i % 2, i));
+        }
+
+        let idxs = |data: &Vec<LogData>| {
+            data.iter()
+                .map(|d| {
+                    match *d {
+                        LogEntry(ref e) => e.idx.unwrap(),
+                        _ => panic!("unexpected snapshot"),
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+        let obj_ids = vec![0, 1].into_iter().collect();
+        let all: Vec<LogData> = client.try_stream(&obj_ids, 0, None).unwrap().iter().collect();
+        assert_eq!(idxs(&all), (0..n as i64).collect::<Vec<_>>());
+        // pages cut on entries relevant to the stream, and stop at to
+        let obj_ids = vec![1].into_iter().collect();
+        let odd: Vec<LogData> = client.try_stream(&obj_ids, 4, Some(24)).unwrap().iter().collect();
+        assert_eq!(idxs(&odd), (4..24).filter(|i| i % 2 == 1).collect::<Vec<_>>());
+        // entries of all objects, as a VM discovering objects streams them
+        let rest: Vec<LogData> = client.stream_all(20, None).unwrap().iter().collect();
+        assert_eq!(idxs(&rest), (20..n as i64).collect::<Vec<_>>());
+        assert!(s.shutdown(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn http_concurrent_appends() {
+        let mut s = HttpServer::new(SharedQueue::new()).serve("127.0.0.1:6771").unwrap();
+        let (n_clients, n) = (8, 20);
+        let clients: Vec<_> = (0..n_clients)
+                                  .map(|obj_id| {
+                                      thread::spawn(move || {
+                                          let mut c = HttpClient::new("http://127.0.0.1:6771");
+                                          (0..n)
+                                              .map(|i| c.append(entry(obj_id, i)))
+                                              .collect::<Vec<_>>()
+                                      })
+                                  })
+                                  .collect();
+        let mut idxs: Vec<i64> = clients.into_iter()
+                                        .flat_map(|c| c.join().unwrap())
+                                        .collect();
+        idxs.sort();
+        assert_eq!(idxs, (0..(n_clients * n) as i64).collect::<Vec<_>>());
+        assert!(s.shutdown(Duration::from_secs(1)));
+    }
+
+    // queue answering streams slowly
+    #[derive(Clone)]
+    struct SlowQueue {
+        q: SharedQueue,
+    }
+
+    impl IndexedQueue for SlowQueue {
+        fn append(&mut self, e: Entry) -> LogIndex {
+            self.q.append(e)
+        }
+        fn stream(&mut self,
+                  obj_ids: &HashSet<ObjId>,
+                  from: LogIndex,
+                  to: Option<LogIndex>)
+                  -> mpsc::Receiver<LogData> {
+            thread::sleep(Duration::from_millis(300));
+            self.q.stream(obj_ids, from, to)
+        }
+    }
+
+    impl SharedLog for SlowQueue {}
+
+    #[test]
+    fn http_shutdown_drains() {
+        let mut s = HttpServer::new(SlowQueue { q: SharedQueue::new() })
+                        .serve("127.0.0.1:6772")
+                        .unwrap();
+        let mut client = HttpClient::new("http://127.0.0.1:6772");
+        client.append(entry(0, 0));
+
+        let mut slow = client.clone();
+        let streaming = thread::spawn(move || {
+            slow.try_stream(&vec![0].into_iter().collect(), 0, None).map(|rx| rx.iter().count())
+        });
+        thread::sleep(Duration::from_millis(100));
+        // stream in flight completes, requests after shutdown are refused
+        assert!(s.shutdown(Duration::from_secs(2)));
+        assert_eq!(streaming.join().unwrap(), Ok(1));
+        assert!(client.try_append(entry(0, 1)).is_err());
+    }
+
+    // queue answering its first append slowly
+    #[derive(Clone)]
+    struct SlowAppends {
+        q: SharedQueue,
+        slowed: Arc<AtomicBool>,
+    }
+
+    impl IndexedQueue for SlowAppends {
+        fn append(&mut self, e: Entry) -> LogIndex {
+            if !self.slowed.swap(true, Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(300));
+            }
+            self.q.append(e)
+        }
+        fn stream(&mut self,
+                  obj_ids: &HashSet<ObjId>,
+                  from: LogIndex,
+                  to: Option<LogIndex>)
+                  -> mpsc::Receiver<LogData> {
+            self.q.stream(obj_ids, from, to)
+        }
+    }
+
+    impl SharedLog for SlowAppends {}
+
+    #[test]
+    fn http_retried_append_once() {
+        let q = SharedQueue::new();
+        let slow = SlowAppends {
+            q: q.clone(),
+            slowed: Arc::new(AtomicBool::new(false)),
+        };
+        let mut s1 = HttpServer::new(slow).serve("127.0.0.1:6774").unwrap();
+        let mut s2 = HttpServer::new(q).serve("127.0.0.1:6777").unwrap();
+        // first attempt times out, its retry is served by the other server before it completes
+        let mut client = HttpClient::new("http://127.0.0.1:6774")
+                             .with_failover(&["http://127.0.0.1:6777"])
+                             .with_timeouts(Duration::from_secs(1), Duration::from_millis(100))
+                             .with_retries(3, Duration::from_millis(10));
+        assert_eq!(client.try_append(entry(0, 0)), Ok(0));
+        // once the first attempt completes, the queue has kept a single copy of the entry
+        assert!(s1.shutdown(Duration::from_secs(1)));
+        assert_eq!(client.try_append(entry(0, 1)), Ok(1));
+        let rx = client.try_stream(&vec![0].into_iter().collect(), 0, None).unwrap();
+        assert_eq!(rx.iter().count(), 2);
+        assert!(s2.shutdown(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn http_failover() {
+        let mut s = HttpServer::new(SharedQueue::new()).serve("127.0.0.1:6776").unwrap();
+        // nothing listens on the first server
+        let mut client = HttpClient::new("http://127.0.0.1:6775")
+                             .with_failover(&["http://127.0.0.1:6776"])
+                             .with_retries(1, Duration::from_millis(10));
+        assert_eq!(client.try_append(entry(0, 0)), Ok(0));
+        assert_eq!(client.try_append(entry(0, 1)), Ok(1));
+        assert!(s.shutdown(Duration::from_secs(1)));
+
+        // no server left
+        let mut lost = HttpClient::new("http://127.0.0.1:6775")
+                           .with_retries(2, Duration::from_millis(10));
+        match lost.try_stream(&vec![0].into_iter().collect(), 0, None) {
+            Err(ClientError::Connection(_)) => {}
+            other => panic!("expected connection error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn https_with_token() {
+        let dir = env::temp_dir().join(format!("smr-tls-{}", rand::random::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        let (x509, pkey) = X509Generator::new()
+                               .set_bitlength(2048)
+                               .set_valid_period(1)
+                               .add_name(String::from("CN"), String::from("127.0.0.1"))
+                               .set_sign_hash(Type::SHA256)
+                               .generate()
+                               .unwrap();
+        x509.write_pem(&mut File::create(&cert).unwrap()).unwrap();
+        pkey.write_pem(&mut File::create(&key).unwrap()).unwrap();
+
+        let mut s = HttpServer::new(SharedQueue::new())
+                        .with_tls(&cert, &key)
+                        .with_token("secret")
+                        .serve("127.0.0.1:6773")
+                        .unwrap();
+        let mut client = HttpClient::new("https://127.0.0.1:6773")
+                             .with_tls(&cert)
+                             .unwrap()
+                             .with_token("secret");
+        assert_eq!(client.try_append(entry(0, 0)), Ok(0));
+
+        let mut wrong = HttpClient::new("https://127.0.0.1:6773")
+                            .with_tls(&cert)
+                            .unwrap()
+                            .with_token("guess");
+        match wrong.try_stream(&vec![0].into_iter().collect(), 0, None) {
+            Err(ClientError::Server(HttpError::Unauthorized(_))) => {}
+            other => panic!("expected 401, got {:?}", other.map(|_| ())),
+        }
+        let mut plain = HttpClient::new("http://127.0.0.1:6773").with_token("secret");
+        assert!(plain.try_append(entry(0, 1)).is_err());
+        // same server, under a name its certificate does not carry
+        let mut misnamed = HttpClient::new("https://localhost:6773")
+                               .with_tls(&cert)
+                               .unwrap()
+                               .with_token("secret")
+                               .with_retries(0, Duration::from_millis(10));
+        assert!(misnamed.try_append(entry(0, 1)).is_err());
+        let mut failover = HttpClient::new("https://localhost:6773")
+                               .with_failover(&["https://127.0.0.1:6773"])
+                               .with_tls(&cert)
+                               .unwrap()
+                               .with_token("secret")
+                               .with_retries(1, Duration::from_millis(10));
+        assert_eq!(failover.try_append(entry(0, 1)), Ok(1));
+        assert!(HttpClient::new("https://127.0.0.1:6773").with_tls(dir.join("none.pem")).is_err());
+
+        assert!(s.shutdown(Duration::from_secs(1)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
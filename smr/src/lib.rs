@@ -1,6 +1,7 @@
 #![allow(dead_code)]
-#![feature(custom_derive, plugin)]
+#![feature(custom_derive, plugin, btree_range, collections_bound)]
 #![plugin(serde_macros)]
+#![cfg_attr(test, feature(proc_macro))]
 
 extern crate hyper;
 extern crate serde;
@@ -10,7 +11,11 @@ extern crate rpaillier;
 extern crate ramp;
 extern crate rand;
 extern crate openssl;
+extern crate crypto;
 extern crate byteorder;
+#[cfg(test)]
+#[macro_use]
+extern crate smr_derive;
 
 pub mod runtime;
 pub mod indexed_queue;
@@ -21,4 +26,14 @@ pub mod http_server;
 pub mod encryptors;
 pub mod converters;
 pub mod maps;
+pub mod sets;
+pub mod replica;
+pub mod replicated;
 pub mod ore;
+pub mod directory;
+
+// code generated by smr_derive refers to ::smr, which is this crate root in its own tests
+#[cfg(test)]
+mod smr {
+    pub use super::{encryptors, indexed_queue, replica, replicated, runtime};
+}
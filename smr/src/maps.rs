@@ -2,25 +2,279 @@ extern crate rustc_serialize;
 use self::rustc_serialize::json;
 use self::rustc_serialize::{Encodable, Decodable, Encoder, Decoder};
 
-use runtime::Runtime;
-use indexed_queue::{Operation, IndexedQueue, State, LogOp};
-use encryptors::{MetaEncryptor, Encrypted, Eqable, Ordable};
+use runtime::{Runtime, LogError};
+use indexed_queue::{Operation, IndexedQueue, State};
+use replica::{self, ReplicatedObject, with_runtime};
+use encryptors::{MetaEncryptor, Encrypted, Eqable, Ordable, SearchToken};
 use converters::{SimpleConverter, Converter, EqableConverter, ConvertersLib};
 
 use std::fmt::Debug;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::{Arc, Mutex};
 use std::collections::{HashMap, BTreeMap};
+use std::collections::Bound::{Included, Excluded};
 use std::hash::Hash;
 use std::cmp::Eq;
 
-#[derive(RustcEncodable, RustcDecodable, Debug)]
+#[derive(RustcEncodable, Debug)]
 pub enum MapOp<K, V> {
     Insert {
         key: K,
         val: V,
+        index: Vec<IndexTag>, // encrypted attributes of val, one per secondary index
+        tokens: Vec<SearchToken>, // keyword tokens of val, if map is searchable
     },
 }
 
+// Inserts appended before maps had indexes only have key and val, index and tokens default to
+// empty when missing (json decoders report them as EOF, ops are decoded on their own)
+impl<K: Decodable, V: Decodable> Decodable for MapOp<K, V> {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Self, D::Error> {
+        d.read_enum("MapOp", |d| {
+            d.read_enum_struct_variant(&["Insert"], |d, _| {
+                let key = try!(d.read_enum_struct_variant_field("key", 0, Decodable::decode));
+                let val = try!(d.read_enum_struct_variant_field("val", 1, Decodable::decode));
+                let index = d.read_enum_struct_variant_field("index", 2, Decodable::decode)
+                             .unwrap_or_else(|_| Vec::new());
+                let tokens = d.read_enum_struct_variant_field("tokens", 3, Decodable::decode)
+                              .unwrap_or_else(|_| Vec::new());
+                Ok(MapOp::Insert {
+                    key: key,
+                    val: val,
+                    index: index,
+                    tokens: tokens,
+                })
+            })
+        })
+    }
+}
+
+// Encrypted attribute of a value, as kept by secondary indexes
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug)]
+pub enum IndexKey {
+    Eq(Eqable), // supports lookups by equality
+    Ord(Ordable), // supports range lookups
+}
+
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug)]
+pub struct IndexTag {
+    pub name: String, // name of secondary index
+    pub key: IndexKey, // encrypted attribute
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum IndexKind {
+    Eq,
+    Ord,
+}
+
+// Class: Indexer
+// Client side definition of a secondary index
+// Extracts the indexed attribute from a value, attribute is encrypted according to kind
+// Ord attributes sort as strings: numbers must be zero padded to a fixed width, e.g. "{:010}"
+pub struct Indexer<V> {
+    name: String,
+    kind: IndexKind,
+    extract: Arc<Fn(&V) -> String + Send + Sync>,
+}
+
+impl<V> Clone for Indexer<V> {
+    fn clone(&self) -> Indexer<V> {
+        Indexer {
+            name: self.name.clone(),
+            kind: self.kind,
+            extract: self.extract.clone(),
+        }
+    }
+}
+
+// encrypt the attributes of v indexed by indexers
+fn index_tags<V>(secure: &Option<MetaEncryptor>, indexers: &[Indexer<V>], v: &V) -> Vec<IndexTag> {
+    let mut tags = Vec::new();
+    for indexer in indexers.iter() {
+        let attr = (indexer.extract)(v);
+        let key = match indexer.kind {
+            IndexKind::Eq => IndexKey::Eq((ConvertersLib::eqable_from_encodable())(secure, attr)),
+            IndexKind::Ord => {
+                IndexKey::Ord((ConvertersLib::ordable_from_encodable())(secure, attr))
+            }
+        };
+        tags.push(IndexTag {
+            name: indexer.name.clone(),
+            key: key,
+        });
+    }
+    tags
+}
+
+// Extracts keywords of a value, for searchable maps
+pub type Keywords<V> = Arc<Fn(&V) -> Vec<String> + Send + Sync>;
+
+// tokens of the distinct keywords of v
+fn search_tokens<V>(secure: &Option<MetaEncryptor>,
+                    keywords: &Option<Keywords<V>>,
+                    v: &V)
+                    -> Vec<SearchToken> {
+    match *keywords {
+        Some(ref keywords) => {
+            let mut words = keywords(v);
+            words.sort();
+            words.dedup();
+            let secure = secure.as_ref().expect("no secure given");
+            words.iter().map(|word| secure.search_token(word.as_bytes())).collect()
+        }
+        None => Vec::new(),
+    }
+}
+
+// Trait: Searchable
+// Implemented by maps whose encrypted replica can answer keyword searches (used by VM)
+pub trait Searchable: 'static + Send {
+    // json encoded keys of values containing the keyword behind token
+    fn search(&self, token: &SearchToken) -> Vec<String>;
+}
+
+// Class: Index
+// Secondary indexes of a map, from encrypted attributes and keyword tokens to primary keys
+// Maintained from the IndexTags and SearchTokens of Insert ops, so client and VM replicas agree
+// Keys are identified by their json encoding, K needs neither Hash nor Ord
+pub struct Index<K> {
+    eq: HashMap<String, HashMap<Eqable, HashMap<String, K>>>, // index -> attribute -> keys
+    ord: HashMap<String, BTreeMap<Ordable, HashMap<String, K>>>, // index -> attribute -> keys
+    search: HashMap<SearchToken, HashMap<String, K>>, // keyword token -> keys
+    entries: HashMap<String, (K, Vec<IndexTag>, Vec<SearchToken>)>, // key -> what it is indexed by
+}
+
+impl<K> Index<K> {
+    pub fn new() -> Index<K> {
+        Index {
+            eq: HashMap::new(),
+            ord: HashMap::new(),
+            search: HashMap::new(),
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Clone + Encodable> Index<K> {
+    // index key by tags and tokens, dropping what it was previously indexed by
+    pub fn insert(&mut self, key: K, tags: Vec<IndexTag>, tokens: Vec<SearchToken>) {
+        let id = json::encode(&key).unwrap();
+        self.remove(&id);
+        if tags.is_empty() && tokens.is_empty() {
+            return;
+        }
+        for token in tokens.iter() {
+            self.search
+                .entry(token.clone())
+                .or_insert_with(HashMap::new)
+                .insert(id.clone(), key.clone());
+        }
+        for tag in tags.iter() {
+            match tag.key {
+                IndexKey::Eq(ref attr) => {
+                    self.eq
+                        .entry(tag.name.clone())
+                        .or_insert_with(HashMap::new)
+                        .entry(attr.clone())
+                        .or_insert_with(HashMap::new)
+                        .insert(id.clone(), key.clone());
+                }
+                IndexKey::Ord(ref attr) => {
+                    self.ord
+                        .entry(tag.name.clone())
+                        .or_insert_with(BTreeMap::new)
+                        .entry(attr.clone())
+                        .or_insert_with(HashMap::new)
+                        .insert(id.clone(), key.clone());
+                }
+            }
+        }
+        self.entries.insert(id, (key, tags, tokens));
+    }
+
+    fn remove(&mut self, id: &String) {
+        let (_, tags, tokens) = match self.entries.remove(id) {
+            Some(entry) => entry,
+            None => return,
+        };
+        for token in tokens {
+            let empty = self.search.get_mut(&token).map_or(false, |keys| {
+                keys.remove(id);
+                keys.is_empty()
+            });
+            if empty {
+                self.search.remove(&token);
+            }
+        }
+        for tag in tags {
+            match tag.key {
+                IndexKey::Eq(attr) => {
+                    if let Some(attrs) = self.eq.get_mut(&tag.name) {
+                        let empty = attrs.get_mut(&attr).map_or(false, |keys| {
+                            keys.remove(id);
+                            keys.is_empty()
+                        });
+                        if empty {
+                            attrs.remove(&attr);
+                        }
+                    }
+                }
+                IndexKey::Ord(attr) => {
+                    if let Some(attrs) = self.ord.get_mut(&tag.name) {
+                        let empty = attrs.get_mut(&attr).map_or(false, |keys| {
+                            keys.remove(id);
+                            keys.is_empty()
+                        });
+                        if empty {
+                            attrs.remove(&attr);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.eq.clear();
+        self.ord.clear();
+        self.search.clear();
+        self.entries.clear();
+    }
+
+    // keys with the attributes and keyword tokens they are indexed by, snapshot along with the
+    // data since replicas unable to decrypt values (i.e. VMs) cannot rebuild them
+    pub fn entries(&self) -> Vec<(K, Vec<IndexTag>, Vec<SearchToken>)> {
+        self.entries.values().cloned().collect()
+    }
+
+    // keys whose values contain the keyword behind token
+    pub fn get_search(&self, token: &SearchToken) -> Vec<K> {
+        self.search.get(token).map_or(Vec::new(), |keys| keys.values().cloned().collect())
+    }
+
+    // keys whose attribute in index name equals attr
+    pub fn get_eq(&self, name: &str, attr: &Eqable) -> Vec<K> {
+        self.eq
+            .get(name)
+            .and_then(|attrs| attrs.get(attr))
+            .map_or(Vec::new(), |keys| keys.values().cloned().collect())
+    }
+
+    // keys whose attribute in index name is in [from, to), in attribute order
+    pub fn get_range(&self, name: &str, from: &Ordable, to: &Ordable) -> Vec<K> {
+        let mut res = Vec::new();
+        if from >= to {
+            return res;
+        }
+        if let Some(attrs) = self.ord.get(name) {
+            for (_, keys) in attrs.range(Included(from), Excluded(to)) {
+                res.extend(keys.values().cloned());
+            }
+        }
+        res
+    }
+}
+
 // Unencrypted StringHMap, to be used by client
 // Supports Eqable encryption for keys, AES encryption for values
 pub type StringHMap<Q> = HMap<String, String, Q>;
@@ -67,6 +321,9 @@ pub struct HMap<K, V, Q> {
     runtime: Option<Arc<Mutex<Runtime<Q>>>>, // runtime object is registered with
     obj_id: i32, // unique id
     pub data: Arc<Mutex<HashMap<K, V>>>, // local data structure
+    pub index: Arc<Mutex<Index<K>>>, // secondary indexes
+    indexers: Vec<Indexer<V>>, // secondary indexes maintained by this client
+    keywords: Option<Keywords<V>>, // keyword extractor, if values are searchable
 
     convert_eq: Option<EqableConverter<K>>, // converter between data states
     convert: Option<Converter<V>>, // convert between data states
@@ -74,16 +331,20 @@ pub struct HMap<K, V, Q> {
 }
 
 impl<K, V, Q> Decodable for HMap<K, V, Q>
-    where K: Encodable + Decodable + Hash + Eq,
+    where K: Encodable + Decodable + Hash + Eq + Clone,
           V: Encodable + Decodable
 {
     fn decode<D: Decoder>(d: &mut D) -> Result<Self, D::Error> {
-        let mut vec: Vec<(K, V)> = try!(Decodable::decode(d));
+        let (mut vec, indexed): (Vec<(K, V)>, Vec<(K, Vec<IndexTag>, Vec<SearchToken>)>) =
+            try!(Decodable::decode(d));
         let mut data: HashMap<K, V> = HashMap::new();
         for (k, v) in vec.drain(..) {
             data.insert(k, v);
         }
         let hmap: HMap<K, V, Q> = HMap::default(data);
+        for (k, tags, tokens) in indexed {
+            hmap.index.lock().unwrap().insert(k, tags, tokens);
+        }
         let res: Result<Self, D::Error> = Ok(hmap);
         return res;
     }
@@ -99,7 +360,7 @@ impl<K, V, Q> Encodable for HMap<K, V, Q>
         for (k, v) in data.iter() {
             vec.push((k.clone(), v.clone()));
         }
-        vec.encode(s)
+        (vec, self.index.lock().unwrap().entries()).encode(s)
     }
 }
 
@@ -114,18 +375,47 @@ impl<K, V, Q> HMap<K, V, Q> {
             obj_id: obj_id,
             runtime: Some(aruntime.clone()),
             data: Arc::new(Mutex::new(data)),
+            index: Arc::new(Mutex::new(Index::new())),
+            indexers: Vec::new(),
+            keywords: None,
             convert: Some(convert),
             convert_eq: Some(convert_eq),
-            secure: aruntime.lock().unwrap().secure.clone(),
+            secure: aruntime.lock().unwrap().secure_for(obj_id),
         };
         return hmap;
     }
 
+    // Define secondary index name over attribute extracted from values
+    // Must be called before start, by every client inserting into the map
+    // Ord attributes sort as strings, numbers must be zero padded to sort numerically
+    pub fn with_index<F>(mut self, name: &str, kind: IndexKind, extract: F) -> HMap<K, V, Q>
+        where F: 'static + Fn(&V) -> String + Send + Sync
+    {
+        self.indexers.push(Indexer {
+            name: String::from(name),
+            kind: kind,
+            extract: Arc::new(extract),
+        });
+        self
+    }
+
+    // Make values searchable by the keywords extracted from them
+    // Must be called before start, by every client inserting into the map
+    pub fn with_search<F>(mut self, keywords: F) -> Self
+        where F: 'static + Fn(&V) -> Vec<String> + Send + Sync
+    {
+        self.keywords = Some(Arc::new(keywords));
+        self
+    }
+
     fn default(data: HashMap<K, V>) -> HMap<K, V, Q> {
         HMap {
             runtime: None,
             obj_id: 0,
             data: Arc::new(Mutex::new(data)),
+            index: Arc::new(Mutex::new(Index::new())),
+            indexers: Vec::new(),
+            keywords: None,
             convert: None,
             convert_eq: None,
             secure: None,
@@ -138,39 +428,37 @@ impl<K, V, Q> HMap<K, V, Q>
           V: 'static + Send + Clone + Encodable + Decodable,
           Q: 'static + IndexedQueue + Send + Clone
 {
-    // lock runtime, call f with runtime, release lock
-    fn with_runtime<R, T, F>(&self, f: F) -> T
-        where F: FnOnce(MutexGuard<Runtime<Q>>) -> T
-    {
-        assert!(self.runtime.is_some(), "invalid runtime");
-        self.runtime
-            .as_ref()
-            .map(|runtime| {
-                let runtime = runtime.lock().unwrap();
-                f(runtime)
-            })
-            .unwrap()
-    }
-
     pub fn start(&mut self) {
-        self.with_runtime::<(), _, _>(|mut runtime| {
-            let mut obj = self.clone();
-            runtime.register_object(self.obj_id,
-                                    Box::new(move |_, op: Operation| obj.callback(op)));
-
-        });
+        replica::register(&self.runtime, self.obj_id, None, self.clone());
     }
 
     pub fn get(&self, k: &K) -> Option<V> {
-        self.with_runtime::<V, _, _>(|mut runtime| {
-            runtime.sync(Some(self.obj_id));
+        match self.try_get(k) {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as get, returns the integrity violation detected while syncing
+    pub fn try_get(&self, k: &K) -> Result<Option<V>, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
             let data = self.data.lock().unwrap();
-            data.get(k).cloned()
+            Ok(data.get(k).cloned())
         })
     }
 
     pub fn insert(&mut self, k: K, v: V) {
-        self.with_runtime::<(), _, _>(|mut runtime| {
+        if let Err(err) = self.try_insert(k, v) {
+            panic!("{}", err);
+        }
+    }
+
+    // Same as insert, returns the error of the log if it refused the op
+    pub fn try_insert(&mut self, k: K, v: V) -> Result<(), LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            let index = index_tags(&self.secure, &self.indexers, &v);
+            let tokens = search_tokens(&self.secure, &self.keywords, &v);
             // convert key and value to shared log state
             let key = self.convert_eq
                           .as_ref()
@@ -189,10 +477,108 @@ impl<K, V, Q> HMap<K, V, Q>
             let encrypted_op = MapOp::Insert {
                 key: key,
                 val: val,
+                index: index,
+                tokens: tokens,
             };
             let op = json::encode(&encrypted_op).unwrap();
-            runtime.append(self.obj_id, State::Encrypted(op.into_bytes()));
-        });
+            runtime.try_append(self.obj_id, State::Encrypted(op.into_bytes()))
+        })
+    }
+
+    // Syncs, returns keys whose attribute in index name equals attr
+    pub fn find(&self, name: &str, attr: String) -> Vec<K> {
+        match self.try_find(name, attr) {
+            Ok(keys) => keys,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as find, returns the integrity violation detected while syncing
+    pub fn try_find(&self, name: &str, attr: String) -> Result<Vec<K>, LogError> {
+        let attr = (ConvertersLib::eqable_from_encodable())(&self.secure, attr);
+        self.try_lookup(name, &attr)
+    }
+
+    // Syncs, returns keys whose attribute in index name is in [from, to)
+    pub fn find_range(&self, name: &str, from: String, to: String) -> Vec<K> {
+        match self.try_find_range(name, from, to) {
+            Ok(keys) => keys,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as find_range, returns the integrity violation detected while syncing
+    pub fn try_find_range(&self, name: &str, from: String, to: String) -> Result<Vec<K>, LogError> {
+        let from = (ConvertersLib::ordable_from_encodable())(&self.secure, from);
+        let to = (ConvertersLib::ordable_from_encodable())(&self.secure, to);
+        self.try_lookup_range(name, &from, &to)
+    }
+
+    // Same as find, for already encrypted attributes (used by VM replicas)
+    pub fn lookup(&self, name: &str, attr: &Eqable) -> Vec<K> {
+        match self.try_lookup(name, attr) {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as lookup, returns the integrity violation detected while syncing
+    pub fn try_lookup(&self, name: &str, attr: &Eqable) -> Result<Vec<K>, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok(self.index.lock().unwrap().get_eq(name, attr))
+        })
+    }
+
+    // Same as find_range, for already encrypted attributes (used by VM replicas)
+    pub fn lookup_range(&self, name: &str, from: &Ordable, to: &Ordable) -> Vec<K> {
+        match self.try_lookup_range(name, from, to) {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as lookup_range, returns the integrity violation detected while syncing
+    pub fn try_lookup_range(&self,
+                            name: &str,
+                            from: &Ordable,
+                            to: &Ordable)
+                            -> Result<Vec<K>, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok(self.index.lock().unwrap().get_range(name, from, to))
+        })
+    }
+
+    // Syncs, returns keys whose values contain word
+    pub fn search(&self, word: &str) -> Vec<K> {
+        match self.try_search(word) {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as search, returns the integrity violation detected while syncing
+    pub fn try_search(&self, word: &str) -> Result<Vec<K>, LogError> {
+        let token = self.search_token(word);
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok(self.index.lock().unwrap().get_search(&token))
+        })
+    }
+
+    // Returns keys whose values contain word, as answered by the log's VM
+    // Does not require syncing the map locally, fails if the log does not serve searches
+    pub fn remote_search(&self, word: &str) -> Result<Vec<K>, LogError> {
+        let token = self.search_token(word);
+        let keys = try!(with_runtime(&self.runtime, |mut runtime| {
+            runtime.search(self.obj_id, &token)
+        }));
+        Ok(keys.iter().map(|key| self.get_key(json::decode(key).unwrap())).collect())
+    }
+
+    fn search_token(&self, word: &str) -> SearchToken {
+        self.secure.as_ref().expect("no secure given").search_token(word.as_bytes())
     }
 
     pub fn get_val(&self, val: Encrypted) -> V {
@@ -217,33 +603,65 @@ impl<K, V, Q> HMap<K, V, Q>
             .unwrap()
     }
 
+    // index comes with the snapshot, as built from the tags and tokens of the ops it covers
+    fn rebuild_index(&self, indexed: Vec<(K, Vec<IndexTag>, Vec<SearchToken>)>) {
+        let mut index = self.index.lock().unwrap();
+        index.clear();
+        for (k, tags, tokens) in indexed {
+            index.insert(k, tags, tokens);
+        }
+    }
+
     pub fn callback(&mut self, op: Operation) {
-        match op.operator {
-            LogOp::Op(State::Encrypted(ref s)) => {
-                let encrypted_op = json::decode(&String::from_utf8(s.clone()).unwrap()).unwrap();
-                match encrypted_op {
-                    MapOp::Insert{key: k, val: v} => {
-                        let k = self.get_key(k);
-                        let v = self.get_val(v);
-                        let mut m_data = self.data.lock().unwrap();
-                        m_data.insert(k, v);
-                    }
-                }
-            }
-            LogOp::Snapshot(State::Encoded(ref s)) => {
-                let obj: HMap<Eqable, Encrypted, Q> = json::decode(&s).unwrap();
-                let mut converted: HashMap<K, V> = HashMap::new();
-                let data = obj.data.lock().unwrap();
-                for (k, v) in data.iter() {
-                    converted.insert(self.get_key(k.clone()), self.get_val(v.clone()));
-                }
-                *self.data.lock().unwrap() = converted;
-            }
-            _ => {
-                unimplemented!();
+        replica::callback(self, &None, op);
+    }
+}
+
+impl<K, V, Q> ReplicatedObject for HMap<K, V, Q>
+    where K: 'static + Send + Clone + Encodable + Decodable + Hash + Eq,
+          V: 'static + Send + Clone + Encodable + Decodable,
+          Q: 'static + IndexedQueue + Send + Clone
+{
+    type Op = MapOp<Eqable, Encrypted>;
+    type Twin = (Vec<(Eqable, Encrypted)>, Vec<(Eqable, Vec<IndexTag>, Vec<SearchToken>)>);
+
+    fn apply(&mut self, _: &Option<MetaEncryptor>, op: MapOp<Eqable, Encrypted>) {
+        match op {
+            MapOp::Insert{key: k, val: v, index, tokens} => {
+                let k = self.get_key(k);
+                let v = self.get_val(v);
+                self.index.lock().unwrap().insert(k.clone(), index, tokens);
+                let mut m_data = self.data.lock().unwrap();
+                m_data.insert(k, v);
             }
         }
     }
+
+    fn restore(&mut self,
+               _: &Option<MetaEncryptor>,
+               twin: (Vec<(Eqable, Encrypted)>, Vec<(Eqable, Vec<IndexTag>, Vec<SearchToken>)>)) {
+        let (mut data, indexed) = twin;
+        let mut converted: HashMap<K, V> = HashMap::new();
+        for (k, v) in data.drain(..) {
+            converted.insert(self.get_key(k), self.get_val(v));
+        }
+        let indexed = indexed.into_iter()
+                             .map(|(k, tags, tokens)| (self.get_key(k), tags, tokens))
+                             .collect();
+        self.rebuild_index(indexed);
+        *self.data.lock().unwrap() = converted;
+    }
+}
+
+impl<K, V, Q> Searchable for HMap<K, V, Q>
+    where K: 'static + Send + Clone + Encodable,
+          V: 'static + Send,
+          Q: 'static + Send
+{
+    fn search(&self, token: &SearchToken) -> Vec<String> {
+        let keys = self.index.lock().unwrap().get_search(token);
+        keys.iter().map(|key| json::encode(key).unwrap()).collect()
+    }
 }
 
 // Unencrypted StringBTMap, to be used by client
@@ -318,31 +736,38 @@ pub struct BTMap<K, V, Q, KE, VE> {
 
     secure: Option<MetaEncryptor>,
     pub data: Arc<Mutex<BTreeMap<K, V>>>,
+    pub index: Arc<Mutex<Index<K>>>,
+    indexers: Vec<Indexer<V>>,
+    keywords: Option<Keywords<V>>,
 }
 
 impl<K, V, Q, KE, VE> Decodable for BTMap<K, V, Q, KE, VE>
-    where K: Encodable + Decodable + Ord,
+    where K: Encodable + Decodable + Ord + Clone,
           V: Encodable + Decodable,
           KE: Encodable + Decodable + Ord,
           VE: Encodable + Decodable
 {
     fn decode<D: Decoder>(d: &mut D) -> Result<Self, D::Error> {
-        let data = try!(Decodable::decode(d));
+        let (data, indexed): (BTreeMap<K, V>, Vec<(K, Vec<IndexTag>, Vec<SearchToken>)>) =
+            try!(Decodable::decode(d));
         let btmap: BTMap<K, V, Q, KE, VE> = BTMap::default(data);
+        for (k, tags, tokens) in indexed {
+            btmap.index.lock().unwrap().insert(k, tags, tokens);
+        }
         let res: Result<Self, D::Error> = Ok(btmap);
         return res;
     }
 }
 
 impl<K, V, Q, KE, VE> Encodable for BTMap<K, V, Q, KE, VE>
-    where K: Encodable + Decodable + Ord,
+    where K: Encodable + Decodable + Ord + Clone,
           V: Encodable + Decodable,
           KE: Encodable + Decodable + Ord,
           VE: Encodable + Decodable
 {
     fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
         let data = self.data.lock().unwrap();
-        data.encode(s)
+        (&*data, self.index.lock().unwrap().entries()).encode(s)
     }
 }
 
@@ -356,20 +781,53 @@ impl<K, V, Q, KE, VE> BTMap<K, V, Q, KE, VE> {
         let btmap = BTMap {
             obj_id: obj_id,
             runtime: Some(aruntime.clone()),
-            secure: aruntime.lock().unwrap().secure.clone(),
+            secure: aruntime.lock().unwrap().secure_for(obj_id),
             data: Arc::new(Mutex::new(data)),
+            index: Arc::new(Mutex::new(Index::new())),
+            indexers: Vec::new(),
+            keywords: None,
             convert: Some(convert),
             convert_ord: Some(convert_ord),
         };
         return btmap;
     }
 
+    // Define secondary index name over attribute extracted from values
+    // Must be called before start, by every client inserting into the map
+    // Ord attributes sort as strings, numbers must be zero padded to sort numerically
+    pub fn with_index<F>(mut self,
+                         name: &str,
+                         kind: IndexKind,
+                         extract: F)
+                         -> BTMap<K, V, Q, KE, VE>
+        where F: 'static + Fn(&V) -> String + Send + Sync
+    {
+        self.indexers.push(Indexer {
+            name: String::from(name),
+            kind: kind,
+            extract: Arc::new(extract),
+        });
+        self
+    }
+
+    // Make values searchable by the keywords extracted from them
+    // Must be called before start, by every client inserting into the map
+    pub fn with_search<F>(mut self, keywords: F) -> Self
+        where F: 'static + Fn(&V) -> Vec<String> + Send + Sync
+    {
+        self.keywords = Some(Arc::new(keywords));
+        self
+    }
+
     fn default(data: BTreeMap<K, V>) -> BTMap<K, V, Q, KE, VE> {
         BTMap {
             obj_id: 0,
             runtime: None,
             secure: None,
             data: Arc::new(Mutex::new(data)),
+            index: Arc::new(Mutex::new(Index::new())),
+            indexers: Vec::new(),
+            keywords: None,
             convert: None,
             convert_ord: None,
         }
@@ -383,41 +841,30 @@ impl<K, V, Q, KE, VE> BTMap<K, V, Q, KE, VE>
           KE: 'static + Ord + Send + Clone + Encodable + Decodable + Debug,
           VE: 'static + Send + Clone + Encodable + Decodable + Debug
 {
-    // lock runtime, call f with runtime, release lock
-    fn with_runtime<R, T, F>(&self, f: F) -> T
-        where F: FnOnce(MutexGuard<Runtime<Q>>) -> T
-    {
-        assert!(self.runtime.is_some(), "invalid runtime");
-        self.runtime
-            .as_ref()
-            .map(|runtime| {
-                let runtime = runtime.lock().unwrap();
-                f(runtime)
-            })
-            .unwrap()
-    }
-
     pub fn start(&mut self) {
-        self.with_runtime::<(), _, _>(|mut runtime| {
-            let mut obj = self.clone();
-            runtime.register_object(self.obj_id,
-                                    Box::new(move |_, op: Operation| obj.callback(op)));
-
-        });
+        replica::register(&self.runtime, self.obj_id, None, self.clone());
     }
 
     pub fn get(&self, k: &K) -> Option<V> {
-        self.with_runtime::<V, _, _>(|mut runtime| {
-            runtime.sync(Some(self.obj_id));
+        match self.try_get(k) {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as get, returns the integrity violation detected while syncing
+    pub fn try_get(&self, k: &K) -> Result<Option<V>, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
             let data = self.data.lock().unwrap();
-            data.get(k).cloned()
+            Ok(data.get(k).cloned())
         })
     }
 
     // use only for testing map is in order
     // modfies local map without syncing to log
     pub fn pop_first(&mut self) -> Option<(K, V)> {
-        self.with_runtime::<K, _, _>(|mut runtime| {
+        with_runtime(&self.runtime, |mut runtime| {
             runtime.sync(Some(self.obj_id));
             // println!("synced!");
             {
@@ -438,6 +885,102 @@ impl<K, V, Q, KE, VE> BTMap<K, V, Q, KE, VE>
         })
     }
 
+    // Syncs, returns keys whose attribute in index name equals attr
+    pub fn find(&self, name: &str, attr: String) -> Vec<K> {
+        match self.try_find(name, attr) {
+            Ok(keys) => keys,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as find, returns the integrity violation detected while syncing
+    pub fn try_find(&self, name: &str, attr: String) -> Result<Vec<K>, LogError> {
+        let attr = (ConvertersLib::eqable_from_encodable())(&self.secure, attr);
+        self.try_lookup(name, &attr)
+    }
+
+    // Syncs, returns keys whose attribute in index name is in [from, to)
+    pub fn find_range(&self, name: &str, from: String, to: String) -> Vec<K> {
+        match self.try_find_range(name, from, to) {
+            Ok(keys) => keys,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as find_range, returns the integrity violation detected while syncing
+    pub fn try_find_range(&self, name: &str, from: String, to: String) -> Result<Vec<K>, LogError> {
+        let from = (ConvertersLib::ordable_from_encodable())(&self.secure, from);
+        let to = (ConvertersLib::ordable_from_encodable())(&self.secure, to);
+        self.try_lookup_range(name, &from, &to)
+    }
+
+    // Same as find, for already encrypted attributes (used by VM replicas)
+    pub fn lookup(&self, name: &str, attr: &Eqable) -> Vec<K> {
+        match self.try_lookup(name, attr) {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as lookup, returns the integrity violation detected while syncing
+    pub fn try_lookup(&self, name: &str, attr: &Eqable) -> Result<Vec<K>, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok(self.index.lock().unwrap().get_eq(name, attr))
+        })
+    }
+
+    // Same as find_range, for already encrypted attributes (used by VM replicas)
+    pub fn lookup_range(&self, name: &str, from: &Ordable, to: &Ordable) -> Vec<K> {
+        match self.try_lookup_range(name, from, to) {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as lookup_range, returns the integrity violation detected while syncing
+    pub fn try_lookup_range(&self,
+                            name: &str,
+                            from: &Ordable,
+                            to: &Ordable)
+                            -> Result<Vec<K>, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok(self.index.lock().unwrap().get_range(name, from, to))
+        })
+    }
+
+    // Syncs, returns keys whose values contain word
+    pub fn search(&self, word: &str) -> Vec<K> {
+        match self.try_search(word) {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as search, returns the integrity violation detected while syncing
+    pub fn try_search(&self, word: &str) -> Result<Vec<K>, LogError> {
+        let token = self.search_token(word);
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok(self.index.lock().unwrap().get_search(&token))
+        })
+    }
+
+    // Returns keys whose values contain word, as answered by the log's VM
+    // Does not require syncing the map locally, fails if the log does not serve searches
+    pub fn remote_search(&self, word: &str) -> Result<Vec<K>, LogError> {
+        let token = self.search_token(word);
+        let keys = try!(with_runtime(&self.runtime, |mut runtime| {
+            runtime.search(self.obj_id, &token)
+        }));
+        Ok(keys.iter().map(|key| self.get_key(json::decode(key).unwrap())).collect())
+    }
+
+    fn search_token(&self, word: &str) -> SearchToken {
+        self.secure.as_ref().expect("no secure given").search_token(word.as_bytes())
+    }
+
     pub fn get_val(&self, val: VE) -> V {
         // convert value from shared log state to local state
         self.convert
@@ -461,7 +1004,16 @@ impl<K, V, Q, KE, VE> BTMap<K, V, Q, KE, VE>
     }
 
     pub fn insert(&mut self, k: K, v: V) {
-        self.with_runtime::<(), _, _>(|mut runtime| {
+        if let Err(err) = self.try_insert(k, v) {
+            panic!("{}", err);
+        }
+    }
+
+    // Same as insert, returns the error of the log if it refused the op
+    pub fn try_insert(&mut self, k: K, v: V) -> Result<(), LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            let index = index_tags(&self.secure, &self.indexers, &v);
+            let tokens = search_tokens(&self.secure, &self.keywords, &v);
             // convert key and value to shared log state
             let key = self.convert_ord
                           .as_ref()
@@ -480,49 +1032,90 @@ impl<K, V, Q, KE, VE> BTMap<K, V, Q, KE, VE>
             let encrypted_op = MapOp::Insert {
                 key: key,
                 val: val,
+                index: index,
+                tokens: tokens,
             };
             let op = json::encode(&encrypted_op).unwrap();
-            runtime.append(self.obj_id, State::Encrypted(op.into_bytes()));
-        });
+            runtime.try_append(self.obj_id, State::Encrypted(op.into_bytes()))
+        })
+    }
+
+    // index comes with the snapshot, as built from the tags and tokens of the ops it covers
+    fn rebuild_index(&self, indexed: Vec<(K, Vec<IndexTag>, Vec<SearchToken>)>) {
+        let mut index = self.index.lock().unwrap();
+        index.clear();
+        for (k, tags, tokens) in indexed {
+            index.insert(k, tags, tokens);
+        }
     }
 
     pub fn callback(&mut self, op: Operation) {
-        match op.operator {
-            LogOp::Op(State::Encrypted(ref s)) => {
-                let encrypted_op = json::decode(&String::from_utf8(s.clone()).unwrap()).unwrap();
-                match encrypted_op {
-                    MapOp::Insert{key: k, val: v} => {
-                        let k = self.get_key(k);
-                        let v = self.get_val(v);
-                        let mut m_data = self.data.lock().unwrap();
-                        m_data.insert(k, v);
-                    }
-                }
-            }
-            LogOp::Snapshot(State::Encoded(ref s)) => {
-                let mut obj: BTreeMap<KE, VE> = json::decode(&s).unwrap();
-                let mut converted = BTreeMap::new();
-                for (k, v) in obj.iter_mut() {
-                    converted.insert(self.get_key(k.clone()), self.get_val(v.clone()));
-                }
-                *self.data.lock().unwrap() = converted;
-            }
-            _ => {
-                unimplemented!();
+        replica::callback(self, &None, op);
+    }
+}
+
+impl<K, V, Q, KE, VE> ReplicatedObject for BTMap<K, V, Q, KE, VE>
+    where K: 'static + Ord + Send + Clone + Encodable + Decodable + Debug,
+          V: 'static + Send + Clone + Encodable + Decodable + Debug,
+          Q: 'static + IndexedQueue + Send + Clone,
+          KE: 'static + Ord + Send + Clone + Encodable + Decodable + Debug,
+          VE: 'static + Send + Clone + Encodable + Decodable + Debug
+{
+    type Op = MapOp<KE, VE>;
+    type Twin = (BTreeMap<KE, VE>, Vec<(KE, Vec<IndexTag>, Vec<SearchToken>)>);
+
+    fn apply(&mut self, _: &Option<MetaEncryptor>, op: MapOp<KE, VE>) {
+        match op {
+            MapOp::Insert{key: k, val: v, index, tokens} => {
+                let k = self.get_key(k);
+                let v = self.get_val(v);
+                self.index.lock().unwrap().insert(k.clone(), index, tokens);
+                let mut m_data = self.data.lock().unwrap();
+                m_data.insert(k, v);
             }
         }
     }
+
+    fn restore(&mut self,
+               _: &Option<MetaEncryptor>,
+               twin: (BTreeMap<KE, VE>, Vec<(KE, Vec<IndexTag>, Vec<SearchToken>)>)) {
+        let (data, indexed) = twin;
+        let mut converted = BTreeMap::new();
+        for (k, v) in data.into_iter() {
+            converted.insert(self.get_key(k), self.get_val(v));
+        }
+        let indexed = indexed.into_iter()
+                             .map(|(k, tags, tokens)| (self.get_key(k), tags, tokens))
+                             .collect();
+        self.rebuild_index(indexed);
+        *self.data.lock().unwrap() = converted;
+    }
+}
+
+impl<K, V, Q, KE, VE> Searchable for BTMap<K, V, Q, KE, VE>
+    where K: 'static + Send + Clone + Encodable,
+          V: 'static + Send,
+          Q: 'static + Send,
+          KE: 'static + Send,
+          VE: 'static + Send
+{
+    fn search(&self, token: &SearchToken) -> Vec<String> {
+        let keys = self.index.lock().unwrap().get_search(token);
+        keys.iter().map(|key| json::encode(key).unwrap()).collect()
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{StringHMap, StringBTMap, UnencBTMap};
+    use super::{MapOp, HMap, StringHMap, EncHMap, StringBTMap, UnencBTMap, IndexKind, Searchable};
+    use super::rustc_serialize::json;
     use std::collections::{HashMap, BTreeMap};
     use std::char;
     use std::sync::{Arc, Mutex};
     use runtime::Runtime;
-    use indexed_queue::InMemoryQueue;
+    use indexed_queue::{InMemoryQueue, SharedQueue};
     use encryptors::MetaEncryptor;
+    use replica::ReplicatedObject;
     use converters::{Converter, ConvertersLib, EqableConverter};
 
     #[test]
@@ -619,4 +1212,173 @@ mod test {
         }
 
     }
+
+    // records are "email|created_at"
+    fn email(record: &String) -> String {
+        String::from(record.split('|').next().unwrap())
+    }
+
+    fn created_at(record: &String) -> String {
+        String::from(record.split('|').nth(1).unwrap())
+    }
+
+    #[test]
+    fn map_op_without_index() {
+        let op: MapOp<String, String> =
+            json::decode(r#"{"variant":"Insert","fields":["k","v"]}"#).unwrap();
+        match op {
+            MapOp::Insert{key, val, index, tokens} => {
+                assert_eq!((key, val), (String::from("k"), String::from("v")));
+                assert!(index.is_empty() && tokens.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn hmap_secondary_index() {
+        let q = InMemoryQueue::new();
+        let runtime: Runtime<InMemoryQueue> = Runtime::new(q, Some(MetaEncryptor::new()));
+        let aruntime = Arc::new(Mutex::new(runtime));
+        let mut hmap = StringHMap::new(&aruntime, 1, HashMap::new())
+                           .with_index("email", IndexKind::Eq, email)
+                           .with_index("created_at", IndexKind::Ord, created_at);
+        hmap.start();
+
+        hmap.insert(String::from("u1"), String::from("a@x.org|2016-01-03"));
+        hmap.insert(String::from("u2"), String::from("b@x.org|2016-01-01"));
+        hmap.insert(String::from("u3"), String::from("a@x.org|2016-02-10"));
+
+        let mut found = hmap.find("email", String::from("a@x.org"));
+        found.sort();
+        assert_eq!(found, vec!["u1", "u3"]);
+        let found = hmap.find_range("created_at",
+                                    String::from("2016-01-01"),
+                                    String::from("2016-02-01"));
+        assert_eq!(found, vec!["u2", "u1"]);
+
+        // overwriting a record moves it in the indexes
+        hmap.insert(String::from("u1"), String::from("c@x.org|2016-03-01"));
+        assert_eq!(hmap.find("email", String::from("a@x.org")), vec!["u3"]);
+        assert_eq!(hmap.find("email", String::from("c@x.org")), vec!["u1"]);
+        assert!(hmap.find("email", String::from("d@x.org")).is_empty());
+    }
+
+    #[test]
+    fn btmap_secondary_index() {
+        let q = InMemoryQueue::new();
+        let runtime: Runtime<InMemoryQueue> = Runtime::new(q, Some(MetaEncryptor::new()));
+        let aruntime = Arc::new(Mutex::new(runtime));
+        let mut btmap = StringBTMap::new(&aruntime, 1, BTreeMap::new())
+                            .with_index("created_at", IndexKind::Ord, created_at);
+        btmap.start();
+
+        btmap.insert(String::from("u1"), String::from("a@x.org|2016-01-03"));
+        btmap.insert(String::from("u2"), String::from("b@x.org|2016-01-01"));
+        btmap.insert(String::from("u3"), String::from("a@x.org|2016-02-10"));
+        let found = btmap.find_range("created_at",
+                                     String::from("2016-01-02"),
+                                     String::from("2016-12-31"));
+        assert_eq!(found, vec!["u1", "u3"]);
+    }
+
+    // records are "name|score", scores are zero padded so they sort as numbers
+    fn score(record: &String) -> String {
+        let score: u64 = record.split('|').nth(1).unwrap().parse().unwrap();
+        format!("{:010}", score)
+    }
+
+    #[test]
+    fn btmap_numeric_index() {
+        let q = InMemoryQueue::new();
+        let runtime: Runtime<InMemoryQueue> = Runtime::new(q, Some(MetaEncryptor::new()));
+        let aruntime = Arc::new(Mutex::new(runtime));
+        let mut btmap = StringBTMap::new(&aruntime, 1, BTreeMap::new())
+                            .with_index("score", IndexKind::Ord, score);
+        btmap.start();
+
+        btmap.insert(String::from("u1"), String::from("alice|100"));
+        btmap.insert(String::from("u2"), String::from("bob|9"));
+        btmap.insert(String::from("u3"), String::from("carol|25"));
+        btmap.insert(String::from("u4"), String::from("dave|1000"));
+        let found = btmap.find_range("score", format!("{:010}", 9), format!("{:010}", 101));
+        assert_eq!(found, vec!["u2", "u3", "u1"]);
+        let found = btmap.find_range("score", format!("{:010}", 26), format!("{:010}", 2000));
+        assert_eq!(found, vec!["u1", "u4"]);
+        assert!(btmap.find_range("score", format!("{:010}", 101), format!("{:010}", 9)).is_empty());
+    }
+
+    fn words(val: &String) -> Vec<String> {
+        val.split_whitespace().map(|w| w.to_lowercase()).collect()
+    }
+
+    #[test]
+    fn hmap_keyword_search() {
+        let q = InMemoryQueue::new();
+        let runtime: Runtime<InMemoryQueue> = Runtime::new(q, Some(MetaEncryptor::new()));
+        let aruntime = Arc::new(Mutex::new(runtime));
+        let mut hmap = StringHMap::new(&aruntime, 1, HashMap::new()).with_search(words);
+        hmap.start();
+
+        hmap.insert(String::from("d1"), String::from("the quick brown fox"));
+        hmap.insert(String::from("d2"), String::from("The lazy dog"));
+        hmap.insert(String::from("d3"), String::from("quick quick dog"));
+
+        let mut found = hmap.search("quick");
+        found.sort();
+        assert_eq!(found, vec!["d1", "d3"]);
+        let mut found = hmap.search("the");
+        found.sort();
+        assert_eq!(found, vec!["d1", "d2"]);
+        assert!(hmap.search("cat").is_empty());
+
+        // overwritten values are no longer found by their old keywords
+        hmap.insert(String::from("d1"), String::from("a cat"));
+        assert_eq!(hmap.search("quick"), vec!["d3"]);
+        assert_eq!(hmap.search("cat"), vec!["d1"]);
+    }
+
+    #[test]
+    fn hmap_snapshot_keeps_search_index() {
+        let q = InMemoryQueue::new();
+        let secure = MetaEncryptor::new();
+        let runtime: Runtime<InMemoryQueue> = Runtime::new(q, Some(secure.clone()));
+        let aruntime = Arc::new(Mutex::new(runtime));
+        let mut hmap = StringHMap::new(&aruntime, 1, HashMap::new()).with_search(words);
+        hmap.start();
+        hmap.insert(String::from("d1"), String::from("the quick brown fox"));
+        hmap.insert(String::from("d2"), String::from("The lazy dog"));
+        assert_eq!(hmap.search("lazy"), vec!["d2"]);
+
+        // restored without keywords of its own, like a VM replica
+        let snapshot = json::encode(&hmap).unwrap();
+        let restored: HMap<String, String, InMemoryQueue> = json::decode(&snapshot).unwrap();
+        let token = secure.search_token(b"quick");
+        assert_eq!(Searchable::search(&restored, &token), vec![json::encode(&"d1").unwrap()]);
+    }
+
+    #[test]
+    fn hmap_snapshot_keeps_secondary_index() {
+        let q = SharedQueue::new();
+        let secure = MetaEncryptor::new();
+        let runtime = Arc::new(Mutex::new(Runtime::new(q.clone(), Some(secure.clone()))));
+        let mut hmap = StringHMap::new(&runtime, 1, HashMap::new())
+                           .with_index("email", IndexKind::Eq, email);
+        hmap.start();
+        hmap.insert(String::from("u1"), String::from("a@x.org|2016-01-03"));
+        hmap.insert(String::from("u2"), String::from("b@x.org|2016-01-01"));
+
+        // snapshot of a replica without keys, like the VM's
+        let vm_runtime = Arc::new(Mutex::new(Runtime::new(q, None)));
+        let mut enc = EncHMap::new(&vm_runtime, 1, HashMap::new());
+        enc.start();
+        vm_runtime.lock().unwrap().sync(None);
+        let snapshot = json::encode(&enc).unwrap();
+
+        // restored by a client without indexers of its own, on a log holding no entries
+        let runtime = Arc::new(Mutex::new(Runtime::new(SharedQueue::new(), Some(secure))));
+        let mut restored = StringHMap::new(&runtime, 1, HashMap::new());
+        restored.start();
+        ReplicatedObject::restore(&mut restored, &None, json::decode(&snapshot).unwrap());
+        assert_eq!(restored.find("email", String::from("b@x.org")), vec!["u2"]);
+    }
 }
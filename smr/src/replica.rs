@@ -0,0 +1,400 @@
+extern crate rustc_serialize;
+use self::rustc_serialize::json;
+use self::rustc_serialize::{Encodable, Decodable, Encoder};
+
+use runtime::{Runtime, LogError};
+use indexed_queue::{Operation, IndexedQueue, State, LogOp, ObjId, TxState};
+use encryptors::MetaEncryptor;
+use replicated::Diff;
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// Trait: ReplicatedObject
+// A data type whose state is rebuilt from the SharedLog
+// Every type comes in two flavours sharing the same Op type:
+// * client version, holding plain data, decrypting ops with the MetaEncryptor
+// * encrypted twin, held by VM, applying ops without ever decrypting them
+// VM snapshots are encodings of the twin, clients decode them as Twin and restore from it
+// secure holds the keys of the Replica handle, types carrying their own converters
+// (ds, sets, maps) are handed None and ignore it
+pub trait ReplicatedObject: 'static + Send {
+    // Operation as appended to the log (encrypted fields, json encoded)
+    type Op: Encodable + Decodable;
+    // Encrypted twin, as snapshotted by VM
+    type Twin: Decodable;
+
+    // Apply op, called in log order
+    fn apply(&mut self, secure: &Option<MetaEncryptor>, op: Self::Op);
+    // Replace state with that of a snapshotted twin
+    fn restore(&mut self, secure: &Option<MetaEncryptor>, twin: Self::Twin);
+}
+
+// A replica shared between handles applies ops under its lock
+impl<T: ReplicatedObject> ReplicatedObject for Arc<Mutex<T>> {
+    type Op = T::Op;
+    type Twin = T::Twin;
+
+    fn apply(&mut self, secure: &Option<MetaEncryptor>, op: T::Op) {
+        self.lock().unwrap().apply(secure, op);
+    }
+
+    fn restore(&mut self, secure: &Option<MetaEncryptor>, twin: T::Twin) {
+        self.lock().unwrap().restore(secure, twin);
+    }
+}
+
+// lock runtime, call f with runtime, release lock
+pub fn with_runtime<Q, U, F>(runtime: &Option<Arc<Mutex<Runtime<Q>>>>, f: F) -> U
+    where F: FnOnce(MutexGuard<Runtime<Q>>) -> U
+{
+    assert!(runtime.is_some(), "invalid runtime");
+    runtime.as_ref()
+           .map(|runtime| {
+               let runtime = runtime.lock().unwrap();
+               f(runtime)
+           })
+           .unwrap()
+}
+
+// Registers obj with runtime, ops and snapshots of obj_id are applied to obj from then on
+pub fn register<T, Q>(runtime: &Option<Arc<Mutex<Runtime<Q>>>>,
+                      obj_id: ObjId,
+                      secure: Option<MetaEncryptor>,
+                      mut obj: T)
+    where T: ReplicatedObject,
+          Q: 'static + IndexedQueue + Send + Clone
+{
+    with_runtime(runtime, |mut runtime| {
+        runtime.register_object(obj_id,
+                                Box::new(move |_, op: Operation| callback(&mut obj, &secure, op)));
+    });
+}
+
+// Decodes op (or snapshot of twin) and applies it to obj
+pub fn callback<T: ReplicatedObject>(obj: &mut T, secure: &Option<MetaEncryptor>, op: Operation) {
+    match op.operator {
+        LogOp::Op(State::Encrypted(ref bytes)) => {
+            match String::from_utf8(bytes.clone()) {
+                Ok(s) => apply(obj, secure, &s),
+                Err(err) => {
+                    let _ = writeln!(io::stderr(), "skipping undecodable op: {}", err);
+                }
+            }
+        }
+        LogOp::Op(State::Encoded(ref s)) => apply(obj, secure, s),
+        LogOp::Snapshot(State::Encoded(ref s)) => {
+            match json::decode::<T::Twin>(&s) {
+                Ok(twin) => obj.restore(secure, twin),
+                Err(err) => {
+                    let _ = writeln!(io::stderr(), "skipping undecodable snapshot: {}", err);
+                }
+            }
+        }
+        _ => {
+            let _ = writeln!(io::stderr(), "skipping encrypted snapshot");
+        }
+    }
+}
+
+// Applies json encoded op s to obj, ops that cannot be decoded are skipped
+fn apply<T: ReplicatedObject>(obj: &mut T, secure: &Option<MetaEncryptor>, s: &str) {
+    match json::decode::<T::Op>(s) {
+        Ok(op) => obj.apply(secure, op),
+        Err(err) => {
+            let _ = writeln!(io::stderr(), "skipping undecodable op: {}", err);
+        }
+    }
+}
+
+// Class: Replica
+// Generic handle registering a ReplicatedObject with a Runtime
+// Takes care of appending ops, syncing and snapshot decoding
+// Parametrized by:
+// * T : replicated type
+// * Q : structure allowing seamless communicating with Shared Log
+pub struct Replica<T, Q> {
+    runtime: Option<Arc<Mutex<Runtime<Q>>>>, // runtime object is registered with
+    obj_id: ObjId, // unique id
+    pub obj: Arc<Mutex<T>>, // local replica of the object
+    secure: Option<MetaEncryptor>, // structure to allow use of existing Encryptors/ Decryptors
+}
+
+impl<T, Q> Clone for Replica<T, Q> {
+    fn clone(&self) -> Replica<T, Q> {
+        Replica {
+            runtime: self.runtime.clone(),
+            obj_id: self.obj_id,
+            obj: self.obj.clone(),
+            secure: self.secure.clone(),
+        }
+    }
+}
+
+// Snapshots of a replica are snapshots of the object it holds
+impl<T: Encodable, Q> Encodable for Replica<T, Q> {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let obj = self.obj.lock().unwrap();
+        obj.encode(s)
+    }
+}
+
+impl<T, Q> Replica<T, Q> {
+    pub fn new(aruntime: &Arc<Mutex<Runtime<Q>>>, obj_id: ObjId, obj: T) -> Replica<T, Q> {
+        Replica {
+            runtime: Some(aruntime.clone()),
+            obj_id: obj_id,
+            obj: Arc::new(Mutex::new(obj)),
+            secure: aruntime.lock().unwrap().secure_for(obj_id),
+        }
+    }
+
+    // Encryptors to be used when building ops
+    pub fn secure(&self) -> &Option<MetaEncryptor> {
+        &self.secure
+    }
+}
+
+impl<T, Q> Replica<T, Q>
+    where T: ReplicatedObject,
+          Q: 'static + IndexedQueue + Send + Clone
+{
+    pub fn start(&mut self) {
+        register(&self.runtime, self.obj_id, self.secure.clone(), self.obj.clone());
+    }
+
+    // Method: read, Blocking
+    // Syncs object, then calls f on the up to date replica
+    pub fn read<U, F>(&self, f: F) -> U
+        where F: FnOnce(&T) -> U
+    {
+        match self.try_read(f) {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as read, returns the integrity violation detected while syncing
+    pub fn try_read<U, F>(&self, f: F) -> Result<U, LogError>
+        where F: FnOnce(&T) -> U
+    {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            let obj = self.obj.lock().unwrap();
+            Ok(f(&obj))
+        })
+    }
+
+    // Method: append, Non-Blocking
+    // Appends op to the log, op is applied once synced
+    pub fn append(&mut self, op: T::Op) {
+        if let Err(err) = self.try_append(op) {
+            panic!("{}", err);
+        }
+    }
+
+    // Same as append, returns the error of the log if it refused op
+    pub fn try_append(&mut self, op: T::Op) -> Result<(), LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            let op = json::encode(&op).unwrap();
+            runtime.try_append(self.obj_id, State::Encrypted(op.into_bytes()))
+        })
+    }
+
+    // Method: try_update, Blocking
+    // Runs f on a copy of the synced object and appends the changes f made as one transaction
+    // f is run again on the new state if a concurrent write on the object aborts the transaction
+    // Returns: result of f's accepted run, or the error of the log
+    pub fn try_update<U, F>(&mut self, mut f: F) -> Result<U, LogError>
+        where T: Diff,
+              F: FnMut(&mut T) -> U
+    {
+        with_runtime(&self.runtime, |mut runtime| {
+            loop {
+                try!(runtime.try_begin_tx());
+                // records the version read, a later write on obj_id aborts the transaction
+                try!(runtime.try_sync(Some(self.obj_id)));
+                let before = self.obj.lock().unwrap().clone();
+                let mut obj = before.clone();
+                let ret = f(&mut obj);
+                for op in obj.diff(&self.secure, &before) {
+                    let op = json::encode(&op).unwrap();
+                    try!(runtime.try_append(self.obj_id, State::Encrypted(op.into_bytes())));
+                }
+                if try!(runtime.try_end_tx()) == TxState::Accepted {
+                    return Ok(ret);
+                }
+            }
+        })
+    }
+
+    pub fn callback(&mut self, op: Operation) {
+        callback(&mut self.obj, &self.secure, op);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ReplicatedObject, Replica};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use runtime::Runtime;
+    use indexed_queue::{SharedQueue, ObjId};
+    use vm::{VM, MapSkiplist, AsyncSnapshotter, SnapshotPolicy};
+    use encryptors::{MetaEncryptor, Encrypted};
+
+    #[derive(RustcEncodable, RustcDecodable, Debug)]
+    enum AccountOp {
+        Deposit {
+            amount: Encrypted,
+        },
+        SetOwner {
+            owner: Encrypted,
+        },
+    }
+
+    // Encrypted twin only keeps the op log's ciphertexts
+    #[derive(RustcEncodable, RustcDecodable)]
+    struct EncAccount {
+        deposits: Vec<Encrypted>,
+        owner: Option<Encrypted>,
+    }
+
+    struct Account {
+        balance: i64,
+        owner: String,
+    }
+
+    fn decrypt(secure: &Option<MetaEncryptor>, e: Encrypted) -> String {
+        String::from_utf8(secure.as_ref().unwrap().decrypt(e)).unwrap()
+    }
+
+    impl ReplicatedObject for Account {
+        type Op = AccountOp;
+        type Twin = EncAccount;
+
+        fn apply(&mut self, secure: &Option<MetaEncryptor>, op: AccountOp) {
+            match op {
+                AccountOp::Deposit{amount} => {
+                    self.balance += decrypt(secure, amount).parse::<i64>().unwrap();
+                }
+                AccountOp::SetOwner{owner} => {
+                    self.owner = decrypt(secure, owner);
+                }
+            }
+        }
+
+        fn restore(&mut self, secure: &Option<MetaEncryptor>, twin: EncAccount) {
+            self.balance = twin.deposits
+                               .into_iter()
+                               .map(|d| decrypt(secure, d).parse::<i64>().unwrap())
+                               .fold(0, |a, b| a + b);
+            self.owner = twin.owner.map_or(String::new(), |o| decrypt(secure, o));
+        }
+    }
+
+    impl ReplicatedObject for EncAccount {
+        type Op = AccountOp;
+        type Twin = EncAccount;
+
+        fn apply(&mut self, _: &Option<MetaEncryptor>, op: AccountOp) {
+            match op {
+                AccountOp::Deposit{amount} => self.deposits.push(amount),
+                AccountOp::SetOwner{owner} => self.owner = Some(owner),
+            }
+        }
+
+        fn restore(&mut self, _: &Option<MetaEncryptor>, twin: EncAccount) {
+            *self = twin;
+        }
+    }
+
+    fn deposit(account: &mut Replica<Account, SharedQueue>, amount: i64) {
+        let amount = account.secure().as_ref().unwrap().encrypt(amount.to_string().as_bytes());
+        account.append(AccountOp::Deposit { amount: amount });
+    }
+
+    #[test]
+    fn replica_account() {
+        let q = SharedQueue::new();
+        let me = Some(MetaEncryptor::new());
+        let runtime1 = Arc::new(Mutex::new(Runtime::new(q.clone(), me.clone())));
+        let runtime2 = Arc::new(Mutex::new(Runtime::new(q, me)));
+        let empty = || {
+            Account {
+                balance: 0,
+                owner: String::new(),
+            }
+        };
+        let mut account1 = Replica::new(&runtime1, 1 as ObjId, empty());
+        let mut account2 = Replica::new(&runtime2, 1 as ObjId, empty());
+        account1.start();
+        account2.start();
+
+        deposit(&mut account1, 10);
+        deposit(&mut account2, 32);
+        let owner = account1.secure().as_ref().unwrap().encrypt(b"alice");
+        account1.append(AccountOp::SetOwner { owner: owner });
+
+        assert_eq!(account1.read(|a| a.balance), 42);
+        assert_eq!(account2.read(|a| (a.balance, a.owner.clone())),
+                   (42, String::from("alice")));
+    }
+
+    #[test]
+    fn replica_restore() {
+        let me = Some(MetaEncryptor::new());
+        let runtime = Arc::new(Mutex::new(Runtime::new(SharedQueue::new(), me.clone())));
+        let account = Replica::new(&runtime,
+                                   1 as ObjId,
+                                   Account {
+                                       balance: 0,
+                                       owner: String::new(),
+                                   });
+        let secure = me.as_ref().unwrap();
+        let twin = EncAccount {
+            deposits: vec![secure.encrypt(b"5"), secure.encrypt(b"7")],
+            owner: Some(secure.encrypt(b"bob")),
+        };
+        account.obj.lock().unwrap().restore(&me, twin);
+        let account = account.obj.lock().unwrap();
+        assert_eq!(account.balance, 12);
+        assert_eq!(account.owner, "bob");
+    }
+
+    #[test]
+    fn replica_vm_twin() {
+        let q = SharedQueue::new();
+        let mut vm: VM<SharedQueue, MapSkiplist, AsyncSnapshotter> =
+            VM::new(q.clone(),
+                    MapSkiplist::new(),
+                    AsyncSnapshotter::new(),
+                    SnapshotPolicy::new());
+        let twin = vm.register_replica(1 as ObjId,
+                                       EncAccount {
+                                           deposits: Vec::new(),
+                                           owner: None,
+                                       });
+        vm.start();
+
+        let me = Some(MetaEncryptor::new());
+        let runtime = Arc::new(Mutex::new(Runtime::new(q, me.clone())));
+        let mut account = Replica::new(&runtime,
+                                       1 as ObjId,
+                                       Account {
+                                           balance: 0,
+                                           owner: String::new(),
+                                       });
+        account.start();
+        deposit(&mut account, 3);
+        deposit(&mut account, 4);
+        assert_eq!(account.read(|a| a.balance), 7);
+
+        // VM applies ops to its twin asynchronously
+        thread::sleep(Duration::from_millis(200));
+        let twin = twin.obj.lock().unwrap();
+        assert_eq!(twin.deposits.len(), 2);
+        assert!(twin.owner.is_none());
+    }
+}
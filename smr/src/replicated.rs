@@ -0,0 +1,401 @@
+// Module: replicated
+// Encryption schemes and field updates behind #[derive(Replicated)] of crate smr_derive
+// Deriving Replicated on a struct whose fields are marked #[encrypt(scheme)] generates:
+// * encrypted twin EncName, to be registered with VM (snapshots are encodings of it)
+// * op enum NameOp, one variant per field, carrying an Update of that field
+// * ReplicatedObject for both, and Diff, turning changes of the struct into ops
+// #[replicated_ops] on an impl block of the struct adds its #[op] methods to Replica<Name, Q>,
+// through the generated trait NameOps: see Replica::try_update
+//
+// #[derive(Clone, Default, Replicated)]
+// pub struct Account {
+//     #[encrypt(add)] pub balance: i32,
+//     #[encrypt(eq)] pub owner: String,
+// }
+//
+// #[replicated_ops]
+// impl Account {
+//     #[op]
+//     pub fn deposit(&mut self, amount: i32) {
+//         self.balance += amount;
+//     }
+// }
+//
+// let mut account = Replica::new(&aruntime, obj_id, Account::default());
+// account.start();
+// try!(account.deposit(5));
+//
+// Schemes: eq (Eqable), ord (Ordable), add (Addable, i32 only, allows inc), aes (Encrypted)
+// Increments decode only for add fields, other schemes reject them
+// Field types must implement Clone, PartialEq and Default, op arguments Clone
+// The crate deriving needs rustc_serialize for the derived encodings
+extern crate rustc_serialize;
+use self::rustc_serialize::{Encodable, Decodable, Encoder, Decoder};
+
+use encryptors::{MetaEncryptor, Addable, Eqable, Ordable, Encrypted};
+use converters::ConvertersLib;
+use replica::ReplicatedObject;
+
+use std::marker::PhantomData;
+
+// Trait: Scheme
+// Encryption scheme of a field of a replicated struct
+pub trait Scheme<T> {
+    type Enc: 'static + Encodable + Decodable + Clone + Send;
+
+    fn encrypt(secure: &Option<MetaEncryptor>, data: T) -> Self::Enc;
+    fn decrypt(secure: &Option<MetaEncryptor>, data: Self::Enc) -> T;
+
+    // True if data of the scheme can be incremented, Update refuses to decode increments otherwise
+    fn supports_inc() -> bool {
+        false
+    }
+    // Increment plain data, schemes without increments overwrite
+    fn inc(_: T, data: T) -> T {
+        data
+    }
+    // Increment encrypted data, schemes without increments overwrite
+    fn inc_enc(_: Self::Enc, data: Self::Enc) -> Self::Enc {
+        data
+    }
+    // Increment turning before into after, None if the scheme has no increments
+    fn delta(_: T, _: T) -> Option<T> {
+        None
+    }
+}
+
+// Trait: Diff
+// Replicated struct whose changes can be expressed as ops, derived with Replicated
+pub trait Diff: ReplicatedObject + Clone {
+    // Ops turning before into self, increments where the scheme of a field allows it
+    fn diff(&self, secure: &Option<MetaEncryptor>, before: &Self) -> Vec<Self::Op>;
+}
+
+#[allow(non_camel_case_types)]
+pub struct eq;
+#[allow(non_camel_case_types)]
+pub struct ord;
+#[allow(non_camel_case_types)]
+pub struct add;
+#[allow(non_camel_case_types)]
+pub struct aes;
+
+impl<T: 'static + Encodable + Decodable> Scheme<T> for eq {
+    type Enc = Eqable;
+
+    fn encrypt(secure: &Option<MetaEncryptor>, data: T) -> Eqable {
+        (ConvertersLib::eqable_from_encodable())(secure, data)
+    }
+    fn decrypt(secure: &Option<MetaEncryptor>, data: Eqable) -> T {
+        (ConvertersLib::encodable_from_eqable())(secure, data)
+    }
+}
+
+impl<T: 'static + Encodable + Decodable> Scheme<T> for ord {
+    type Enc = Ordable;
+
+    fn encrypt(secure: &Option<MetaEncryptor>, data: T) -> Ordable {
+        (ConvertersLib::ordable_from_encodable())(secure, data)
+    }
+    fn decrypt(secure: &Option<MetaEncryptor>, data: Ordable) -> T {
+        (ConvertersLib::encodable_from_ordable())(secure, data)
+    }
+}
+
+impl Scheme<i32> for add {
+    type Enc = Addable;
+
+    fn encrypt(secure: &Option<MetaEncryptor>, data: i32) -> Addable {
+        (ConvertersLib::addable_from_i32())(secure, data)
+    }
+    fn decrypt(secure: &Option<MetaEncryptor>, data: Addable) -> i32 {
+        (ConvertersLib::i32_from_addable())(secure, data)
+    }
+    fn supports_inc() -> bool {
+        true
+    }
+    fn inc(data: i32, add: i32) -> i32 {
+        data + add
+    }
+    fn inc_enc(data: Addable, add: Addable) -> Addable {
+        data + add
+    }
+    fn delta(before: i32, after: i32) -> Option<i32> {
+        Some(after - before)
+    }
+}
+
+impl<T: 'static + Encodable + Decodable> Scheme<T> for aes {
+    type Enc = Encrypted;
+
+    fn encrypt(secure: &Option<MetaEncryptor>, data: T) -> Encrypted {
+        (ConvertersLib::encrypted_from_encodable())(secure, data)
+    }
+    fn decrypt(secure: &Option<MetaEncryptor>, data: Encrypted) -> T {
+        (ConvertersLib::encodable_from_encrypted())(secure, data)
+    }
+}
+
+// Class: Update
+// Write or increment of a single field, data is encrypted with scheme S
+// Parametrized by:
+// * S : encryption scheme
+// * T : plain type of the field
+pub struct Update<S: Scheme<T>, T> {
+    inc: bool, // true if data is to be added to current value
+    data: S::Enc, // encrypted data
+    scheme: PhantomData<(S, T)>,
+}
+
+impl<S: Scheme<T>, T> Encodable for Update<S, T> {
+    fn encode<E: Encoder>(&self, s: &mut E) -> Result<(), E::Error> {
+        (self.inc, &self.data).encode(s)
+    }
+}
+
+impl<S: Scheme<T>, T> Decodable for Update<S, T> {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Self, D::Error> {
+        let (inc, data): (bool, S::Enc) = try!(Decodable::decode(d));
+        if inc && !S::supports_inc() {
+            return Err(d.error("increment of a field whose scheme does not support increments"));
+        }
+        Ok(Update {
+            inc: inc,
+            data: data,
+            scheme: PhantomData,
+        })
+    }
+}
+
+impl<S: Scheme<T>, T> Update<S, T> {
+    pub fn write(secure: &Option<MetaEncryptor>, data: T) -> Update<S, T> {
+        Update {
+            inc: false,
+            data: S::encrypt(secure, data),
+            scheme: PhantomData,
+        }
+    }
+
+    // update turning before into after, None if they are equal
+    // increments commute with concurrent increments, writes overwrite them
+    pub fn diff(secure: &Option<MetaEncryptor>, before: &T, after: &T) -> Option<Update<S, T>>
+        where T: Clone + PartialEq
+    {
+        if before == after {
+            return None;
+        }
+        Some(match S::delta(before.clone(), after.clone()) {
+            Some(delta) => {
+                Update {
+                    inc: true,
+                    data: S::encrypt(secure, delta),
+                    scheme: PhantomData,
+                }
+            }
+            None => Update::write(secure, after.clone()),
+        })
+    }
+
+    // apply update to plain field
+    pub fn apply(self, secure: &Option<MetaEncryptor>, field: &mut T)
+        where T: Default
+    {
+        let data = S::decrypt(secure, self.data);
+        if self.inc {
+            let current = ::std::mem::replace(field, T::default());
+            *field = S::inc(current, data);
+        } else {
+            *field = data;
+        }
+    }
+
+    // apply update to encrypted field, never decrypts
+    pub fn apply_enc(self, field: &mut Option<S::Enc>) {
+        let current = field.take();
+        *field = match current {
+            Some(current) if self.inc => Some(S::inc_enc(current, self.data)),
+            _ => Some(self.data),
+        };
+    }
+}
+
+impl Update<add, i32> {
+    pub fn inc(secure: &Option<MetaEncryptor>, data: i32) -> Update<add, i32> {
+        Update {
+            inc: true,
+            data: <add as Scheme<i32>>::encrypt(secure, data),
+            scheme: PhantomData,
+        }
+    }
+}
+
+// decrypt field of a snapshotted twin, unset fields restore to their default
+pub fn restore<S, T>(secure: &Option<MetaEncryptor>, data: Option<S::Enc>) -> T
+    where S: Scheme<T>,
+          T: Default
+{
+    data.map_or_else(T::default, |data| S::decrypt(secure, data))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Update, Diff, eq, add};
+    use super::rustc_serialize::json;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use smr_derive::replicated_ops;
+    use runtime::Runtime;
+    use replica::{Replica, ReplicatedObject};
+    use indexed_queue::{SharedQueue, ObjId};
+    use vm::{VM, MapSkiplist, AsyncSnapshotter, SnapshotPolicy};
+    use encryptors::MetaEncryptor;
+
+    #[derive(Clone, Debug, Default, Replicated)]
+    pub struct Account {
+        #[encrypt(add)]
+        pub balance: i32,
+        #[encrypt(eq)]
+        pub owner: String,
+        #[encrypt(aes)]
+        pub note: String,
+    }
+
+    #[replicated_ops]
+    impl Account {
+        #[op]
+        pub fn deposit(&mut self, amount: i32) {
+            self.balance += amount;
+        }
+
+        #[op]
+        pub fn withdraw(&mut self, amount: i32, note: String) -> bool {
+            if self.balance < amount {
+                return false;
+            }
+            self.balance -= amount;
+            self.note = note;
+            true
+        }
+    }
+
+    #[test]
+    fn replicated_account() {
+        let q = SharedQueue::new();
+        let me = Some(MetaEncryptor::new());
+        let runtime1 = Arc::new(Mutex::new(Runtime::new(q.clone(), me.clone())));
+        let runtime2 = Arc::new(Mutex::new(Runtime::new(q, me)));
+        let mut account1 = Replica::new(&runtime1, 1 as ObjId, Account::default());
+        let mut account2 = Replica::new(&runtime2, 1 as ObjId, Account::default());
+        account1.start();
+        account2.start();
+
+        let op = AccountOp::owner(Update::write(account1.secure(), String::from("alice")));
+        account1.append(op);
+        let op = AccountOp::balance(Update::inc(account1.secure(), 10));
+        account1.append(op);
+        let op = AccountOp::balance(Update::inc(account2.secure(), 32));
+        account2.append(op);
+
+        let account = account2.read(|a| a.clone());
+        assert_eq!(account.balance, 42);
+        assert_eq!(account.owner, "alice");
+        assert_eq!(account.note, "");
+
+        let op = AccountOp::balance(Update::write(account2.secure(), 7));
+        account2.append(op);
+        assert_eq!(account1.read(|a| a.balance), 7);
+    }
+
+    #[test]
+    fn replicated_ops() {
+        let q = SharedQueue::new();
+        let me = Some(MetaEncryptor::new());
+        let runtime1 = Arc::new(Mutex::new(Runtime::new(q.clone(), me.clone())));
+        let runtime2 = Arc::new(Mutex::new(Runtime::new(q, me)));
+        let mut account1 = Replica::new(&runtime1, 1 as ObjId, Account::default());
+        let mut account2 = Replica::new(&runtime2, 1 as ObjId, Account::default());
+        account1.start();
+        account2.start();
+
+        assert_eq!(account1.deposit(10), Ok(()));
+        assert_eq!(account2.deposit(32), Ok(()));
+        assert_eq!(account1.withdraw(50, String::from("rent")), Ok(false));
+        assert_eq!(account2.withdraw(40, String::from("rent")), Ok(true));
+
+        let account = account1.read(|a| a.clone());
+        assert_eq!(account.balance, 2);
+        assert_eq!(account.note, "rent");
+    }
+
+    #[test]
+    fn replicated_diff() {
+        let me = Some(MetaEncryptor::new());
+        let before = Account::default();
+        let mut after = before.clone();
+        after.balance = 5;
+        after.owner = String::from("alice");
+
+        // changed fields only, the balance as an increment
+        let ops = after.diff(&me, &before);
+        assert_eq!(ops.len(), 2);
+        let mut account = Account {
+            balance: 10,
+            ..Account::default()
+        };
+        for op in ops {
+            account.apply(&me, op);
+        }
+        assert_eq!(account.balance, 15);
+        assert_eq!(account.owner, "alice");
+        assert!(before.diff(&me, &before).is_empty());
+    }
+
+    #[test]
+    fn replicated_rejects_inc() {
+        let me = Some(MetaEncryptor::new());
+        let write = json::encode(&Update::<eq, String>::write(&me, String::from("bob"))).unwrap();
+        assert!(json::decode::<Update<eq, String>>(&write).is_ok());
+
+        // an increment forged on a field without additive encryption does not decode
+        assert!(write.starts_with("[false,"));
+        let inc = write.replacen("[false,", "[true,", 1);
+        assert!(json::decode::<Update<eq, String>>(&inc).is_err());
+
+        let inc = json::encode(&Update::<add, i32>::inc(&me, 1)).unwrap();
+        assert!(json::decode::<Update<add, i32>>(&inc).is_ok());
+    }
+
+    #[test]
+    fn replicated_vm_twin() {
+        let q = SharedQueue::new();
+        let mut vm: VM<SharedQueue, MapSkiplist, AsyncSnapshotter> =
+            VM::new(q.clone(),
+                    MapSkiplist::new(),
+                    AsyncSnapshotter::new(),
+                    SnapshotPolicy::new());
+        let twin = vm.register_replica(1 as ObjId, EncAccount::default());
+        vm.start();
+
+        let me = Some(MetaEncryptor::new());
+        let runtime = Arc::new(Mutex::new(Runtime::new(q, me.clone())));
+        let mut account = Replica::new(&runtime, 1 as ObjId, Account::default());
+        account.start();
+        let op = AccountOp::balance(Update::inc(account.secure(), 3));
+        account.append(op);
+        let op = AccountOp::balance(Update::inc(account.secure(), 4));
+        account.append(op);
+        let op = AccountOp::note(Update::write(account.secure(), String::from("savings")));
+        account.append(op);
+        assert_eq!(account.read(|a| a.balance), 7);
+
+        // VM sums balance homomorphically, a client restoring from its twin sees the same state
+        thread::sleep(Duration::from_millis(200));
+        let twin = twin.obj.lock().unwrap().clone();
+        assert!(twin.owner.is_none());
+        let mut restored = Account::default();
+        restored.restore(&me, twin);
+        assert_eq!(restored.balance, 7);
+        assert_eq!(restored.note, "savings");
+    }
+}
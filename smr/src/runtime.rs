@@ -1,11 +1,68 @@
 extern crate rustc_serialize;
+use self::rustc_serialize::json;
 
 use std::collections::{HashMap, HashSet};
-use indexed_queue::{IndexedQueue, Entry, ObjId, State, Operation, TxType, TxState, LogIndex, LogOp};
-use encryptors::MetaEncryptor;
+use std::sync::{Arc, Mutex};
+use std::fmt;
+use std::io::{self, Write};
+use rand;
+use indexed_queue::{IndexedQueue, Entry, ObjId, State, Operation, TxType, TxState, LogIndex, LogOp,
+                    Link, Integrity, ChainHead, Signature, AllowList, Snapshot};
+use encryptors::{MetaEncryptor, SearchToken, Signer, WriterId, KeySet, KeyWrapper, WrappedKeys};
+use directory::{Directory, DirectoryOp, DirectoryEntry, DirectoryObject, DIRECTORY};
 
 pub type Callback = FnMut(LogIndex, Operation) + Send;
 pub type EntryCallback = FnMut(Entry) + Send;
+pub type UnknownCallback = FnMut(LogIndex, &Operation) + Send;
+pub type GossipCallback = FnMut(&Vec<ChainHead>) + Send;
+
+// Enum: LogError
+// Misbehaviour of the SharedLog detected by the runtime
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogError {
+    IntegrityViolation(LogIndex, String), // index of offending entry, what was wrong with it
+    ForkDetected(String), // log showed this runtime a history diverging from another client's
+    Unauthorized(LogIndex, String), // entry not signed by a writer allowed on its objects
+    Unavailable(String), // log could not be reached, or failed to serve the request
+    Unsupported(String), // request the log does not serve, e.g. search on a plain queue
+    TypeMismatch(String), // object opened by name already exists with another type
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LogError::IntegrityViolation(idx, ref reason) => {
+                write!(f, "integrity violation at log index {}: {}", idx, reason)
+            }
+            LogError::ForkDetected(ref reason) => write!(f, "fork detected: {}", reason),
+            LogError::Unauthorized(idx, ref reason) => {
+                write!(f, "unauthorized entry at log index {}: {}", idx, reason)
+            }
+            LogError::Unavailable(ref reason) => write!(f, "log unavailable: {}", reason),
+            LogError::Unsupported(ref request) => write!(f, "not supported by log: {}", request),
+            LogError::TypeMismatch(ref reason) => write!(f, "type mismatch: {}", reason),
+        }
+    }
+}
+
+fn violation(e: &Entry, reason: &str) -> LogError {
+    LogError::IntegrityViolation(e.idx.unwrap_or(-1), String::from(reason))
+}
+
+// Bytes covered by an entry's mac: everything its writer decided, not the index the log assigned
+// Sets are sorted so every runtime encodes them the same way
+fn sealed_bytes(e: &Entry,
+                writer: u64,
+                links: &Vec<Link>,
+                observed: &Vec<ChainHead>)
+                -> Vec<u8> {
+    let mut reads: Vec<(ObjId, LogIndex)> = e.reads.iter().map(|(&o, &v)| (o, v)).collect();
+    reads.sort();
+    let mut writes: Vec<ObjId> = e.writes.iter().cloned().collect();
+    writes.sort();
+    let sealed = (writer, links, observed, reads, writes, &e.operations, &e.tx_type);
+    json::encode(&sealed).unwrap().into_bytes()
+}
 
 // Class: Runtime
 // Paramatrized By:
@@ -16,17 +73,34 @@ pub struct Runtime<Q> {
     callbacks: HashMap<ObjId, Vec<Box<Callback>>>, // main callbacks
     pre_callbacks: Vec<Box<EntryCallback>>, // pre 'main callbacks' callbacks, used by VM
     post_callbacks: Vec<Box<EntryCallback>>, // post 'main callbacks' callbacks, used by VM
+    unknown_callback: Option<Box<UnknownCallback>>, // operations on objects not tracked, used by VM
     pub global_idx: LogIndex, // index of last SharedLog entry synced
     obj_ids: HashSet<ObjId>, // ids of objectes registered with runtime
 
     // Transaction semantics
     reads: HashMap<ObjId, LogIndex>, // map of <object id, version read during transaction>
     writes: HashSet<ObjId>, // set of obj_ids written in current transaction
-    version: HashMap<ObjId, LogIndex>, // map of last version read for each object id
+    version: Arc<Mutex<HashMap<ObjId, LogIndex>>>, // last version read for each object id
     operations: Vec<Operation>, // operations to be included in current open transaction, if any
     pub tx_mode: bool, // true during transaction
 
     pub secure: Option<MetaEncryptor>, // structure to allow use of exising Encryptors/ Decryptors
+    directory: Option<Directory>, // names of objects, registered on first open
+    type_tags: HashMap<ObjId, String>, // type tags stamped on operations of objects
+
+    // Integrity of the SharedLog, only checked by runtimes with encryptors
+    writer: u64, // random id stamped on entries appended by runtime
+    chains: HashMap<ObjId, (u64, Vec<u8>)>, // seq and mac of last entry appended, per object
+    heads: HashMap<(u64, ObjId), (u64, Vec<u8>)>, // seq and mac of last entry synced, per writer
+    violation: Option<LogError>, // tampering detected, runtime stops syncing
+    gossip_callback: Option<Box<GossipCallback>>, // told chain heads after each sync
+
+    // Writer authentication
+    signer: Option<Signer>, // key signing entries appended by runtime
+    allowed: AllowList, // writers allowed on objects, entries of others are rejected
+
+    // Access control, objects with own keys are only readable by users granted them
+    keys: HashMap<ObjId, MetaEncryptor>, // per object encryptors, default to secure
 }
 
 impl<Q> Drop for Runtime<Q> {
@@ -40,7 +114,8 @@ impl<Q> Runtime<Q> {
         self.callbacks.clear();
         self.pre_callbacks.clear();
         self.post_callbacks.clear();
-        self.version.clear();
+        self.unknown_callback.take();
+        self.version.lock().unwrap().clear();
         self.obj_ids.clear();
 
         self.reads.clear();
@@ -48,6 +123,13 @@ impl<Q> Runtime<Q> {
         self.operations.clear();
 
         self.secure.take();
+        self.keys.clear();
+        self.directory.take();
+        self.type_tags.clear();
+
+        self.chains.clear();
+        self.heads.clear();
+        self.gossip_callback.take();
     }
 }
 
@@ -61,7 +143,8 @@ impl<Q> Runtime<Q> where Q: IndexedQueue + Send
             callbacks: HashMap::new(),
             pre_callbacks: Vec::new(),
             post_callbacks: Vec::new(),
-            version: HashMap::new(),
+            unknown_callback: None,
+            version: Arc::new(Mutex::new(HashMap::new())),
             global_idx: -1 as LogIndex,
 
             reads: HashMap::new(),
@@ -70,42 +153,470 @@ impl<Q> Runtime<Q> where Q: IndexedQueue + Send
             tx_mode: false,
 
             secure: me,
+            directory: None,
+            type_tags: HashMap::new(),
+
+            writer: rand::random::<u64>(),
+            chains: HashMap::new(),
+            heads: HashMap::new(),
+            violation: None,
+            gossip_callback: None,
+
+            signer: None,
+            allowed: AllowList::new(),
+
+            keys: HashMap::new(),
         };
     }
 
+    // Sign appended entries with signer, readers tell the runtime's entries apart by its writer id
+    pub fn with_signer(mut self, signer: Signer) -> Runtime<Q> {
+        self.signer = Some(signer);
+        self
+    }
+
+    // Writer id of runtime, if it signs its entries
+    pub fn writer_id(&self) -> Option<WriterId> {
+        self.signer.as_ref().map(|signer| signer.writer_id())
+    }
+
+    // Accept entries on obj_id only if signed by an allowed writer, may be called for several
+    pub fn allow_writer(&mut self, obj_id: ObjId, writer: WriterId) {
+        self.allowed.allow(obj_id, writer);
+    }
+
+    // Encryptor of obj_id, its own keys if it has any
+    // Data structures capture it on creation, so keys must be set before
+    pub fn secure_for(&self, obj_id: ObjId) -> Option<MetaEncryptor> {
+        match self.keys.get(&obj_id) {
+            Some(me) => Some(me.clone()),
+            None => self.secure.clone(),
+        }
+    }
+
+    // Encrypt obj_id under fresh keys, readable only by runtimes granted them
+    // Only its symmetric keys are fresh, its Addable values use the runtime's shared Paillier key
+    pub fn new_object_keys(&mut self, obj_id: ObjId) {
+        let me = self.secure
+                     .as_ref()
+                     .expect("object keys need runtime encryptors")
+                     .fresh_keys();
+        self.keys.insert(obj_id, me);
+    }
+
+    pub fn set_object_keys(&mut self, obj_id: ObjId, me: MetaEncryptor) {
+        self.keys.insert(obj_id, me);
+    }
+
+    // Method: grant
+    // Wraps keys of objects for the user owning wrapper
+    // Arguments:
+    // * obj_ids: objects granted, each must have its own keys
+    // * wrapper: key of user granted access
+    // Returns: keys to be handed to the user, see accept_grant,
+    //          Err(obj_id) if obj_id has no keys of its own
+    pub fn grant(&self, obj_ids: &[ObjId], wrapper: &KeyWrapper) -> Result<WrappedKeys, ObjId> {
+        let mut key_sets: Vec<(ObjId, KeySet)> = Vec::new();
+        for obj_id in obj_ids {
+            match self.keys.get(obj_id) {
+                Some(me) => key_sets.push((*obj_id, me.key_set())),
+                None => return Err(*obj_id),
+            }
+        }
+        Ok(wrapper.wrap(json::encode(&key_sets).unwrap().as_bytes()))
+    }
+
+    // Method: accept_grant
+    // Unwraps keys granted by grant, objects opened afterwards use them
+    // Returns: objects granted, None if keys were not wrapped for wrapper
+    pub fn accept_grant(&mut self,
+                        wrapped: &WrappedKeys,
+                        wrapper: &KeyWrapper)
+                        -> Option<Vec<ObjId>> {
+        let data = match wrapper.unwrap(wrapped) {
+            Some(data) => data,
+            None => return None,
+        };
+        let key_sets: Vec<(ObjId, KeySet)> = match String::from_utf8(data)
+                                                       .ok()
+                                                       .and_then(|s| json::decode(&s).ok()) {
+            Some(key_sets) => key_sets,
+            None => return None,
+        };
+        let base = self.secure.clone().expect("object keys need runtime encryptors");
+        let mut granted = Vec::new();
+        for (obj_id, key_set) in key_sets {
+            self.keys.insert(obj_id, base.with_key_set(key_set));
+            granted.push(obj_id);
+        }
+        Some(granted)
+    }
+
+    // Same as try_append, panics if the log refuses the entry
     pub fn append(&mut self, obj_id: ObjId, data: State) {
+        if let Err(err) = self.try_append(obj_id, data) {
+            panic!("{}", err);
+        }
+    }
+
+    // Method: try_append
+    // Appends operation data on obj_id, or adds it to the transaction in progress
+    // Returns: error of the log if it refused or could not take the entry, e.g. when the
+    //          runtime's writer is not allowed on obj_id
+    pub fn try_append(&mut self, obj_id: ObjId, data: State) -> Result<(), LogError> {
+        let op = Operation::with_type(obj_id, data, self.type_tags.get(&obj_id).cloned());
         if self.tx_mode {
             // accumulate transaction writes
             self.writes.insert(obj_id);
-            self.operations.push(Operation::new(obj_id, data));
+            self.operations.push(op);
+            return Ok(());
+        }
+        // a refused entry is not in the log, the next one links to the same predecessor
+        let chain = self.chains.get(&obj_id).cloned();
+        // append (send) entry to SharedLog
+        let e = self.seal(Entry::new(HashMap::new(),
+                                     vec![obj_id].into_iter().collect(),
+                                     vec![op],
+                                     TxType::None,
+                                     TxState::None));
+        if let Err(err) = self.iq.try_append(e) {
+            match chain {
+                Some(chain) => self.chains.insert(obj_id, chain),
+                None => self.chains.remove(&obj_id),
+            };
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    // Method: seal
+    // Links entry to the runtime's previous entries on the objects it writes, macs it,
+    // and signs it if runtime has a signer
+    // Entries are left unsealed if runtime has no encryptors
+    fn seal(&mut self, e: Entry) -> Entry {
+        let mut e = if self.secure.is_some() {
+            self.link(e)
         } else {
-            // append (send) entry to SharedLog
-            self.iq.append(Entry::new(HashMap::new(),
-                                      vec![obj_id].into_iter().collect(),
-                                      vec![Operation::new(obj_id, data)],
-                                      TxType::None,
-                                      TxState::None));
+            e
+        };
+        if let Some(ref signer) = self.signer {
+            e.signature = Some(Signature {
+                writer: signer.writer_id(),
+                sig: signer.sign(&e.signed_bytes()),
+            });
+        }
+        e
+    }
+
+    // Stamp entry with links and mac
+    fn link(&mut self, mut e: Entry) -> Entry {
+        let mut writes: Vec<ObjId> = e.writes.iter().cloned().collect();
+        writes.sort();
+        let links: Vec<Link> = writes.iter()
+                                     .map(|&obj_id| {
+                                         match self.chains.get(&obj_id) {
+                                             Some(&(seq, ref mac)) => {
+                                                 Link {
+                                                     obj_id: obj_id,
+                                                     seq: seq + 1,
+                                                     prev: mac.clone(),
+                                                 }
+                                             }
+                                             None => {
+                                                 Link {
+                                                     obj_id: obj_id,
+                                                     seq: 0,
+                                                     prev: Vec::new(),
+                                                 }
+                                             }
+                                         }
+                                     })
+                                     .collect();
+        // entries of objects written that this runtime synced, and so precede entry in the log
+        let observed: Vec<ChainHead> = self.chain_heads()
+                                           .into_iter()
+                                           .filter(|head| e.writes.contains(&head.obj_id))
+                                           .collect();
+        let mac = self.secure
+                      .as_ref()
+                      .unwrap()
+                      .mac(&sealed_bytes(&e, self.writer, &links, &observed));
+        for link in &links {
+            self.chains.insert(link.obj_id, (link.seq, mac.clone()));
+        }
+        e.integrity = Some(Integrity {
+            writer: self.writer,
+            links: links,
+            observed: observed,
+            mac: mac,
+        });
+        e
+    }
+
+    // Method: authenticate
+    // Checks the signature of entry against allowed writers, and its mac,
+    // i.e. that it was appended by a runtime holding the mac key and not modified since;
+    // runtimes without encryptors (VM) cannot check macs
+    fn authenticate(&self, e: &Entry) -> Result<(), LogError> {
+        if let Err(reason) = self.allowed.check(e) {
+            return Err(LogError::Unauthorized(e.idx.unwrap_or(-1), reason));
+        }
+        let secure = match self.secure {
+            Some(ref secure) => secure,
+            None => return Ok(()),
+        };
+        let integrity = match e.integrity {
+            Some(ref integrity) => integrity,
+            None => return Err(violation(e, "entry is not authenticated")),
+        };
+        let mut writes: Vec<ObjId> = e.writes.iter().cloned().collect();
+        writes.sort();
+        let linked: Vec<ObjId> = integrity.links.iter().map(|link| link.obj_id).collect();
+        if linked != writes {
+            return Err(violation(e, "entry links do not match its writes"));
+        }
+        let sealed = sealed_bytes(e, integrity.writer, &integrity.links, &integrity.observed);
+        if secure.mac(&sealed) != integrity.mac {
+            return Err(violation(e,
+                                 "entry mac does not match, entry was modified or macked with \
+                                  another key than this runtime's"));
+        }
+        Ok(())
+    }
+
+    // Method: verify
+    // Authenticates entry and checks it extends its writer's chains on tracked objects
+    // Entries dropped, replayed or reordered by the log break a chain
+    fn verify(&mut self, e: &Entry) -> Result<(), LogError> {
+        try!(self.authenticate(e));
+        let integrity = match e.integrity {
+            Some(ref integrity) => integrity,
+            None => return Ok(()),
+        };
+        for link in &integrity.links {
+            if !self.obj_ids.contains(&link.obj_id) {
+                // chain not followed, runtime does not see every entry on the object
+                continue;
+            }
+            match self.heads.get(&(integrity.writer, link.obj_id)) {
+                Some(&(seq, ref mac)) => {
+                    if link.seq <= seq {
+                        return Err(violation(e, "entry was replayed or reordered"));
+                    }
+                    if link.seq > seq + 1 {
+                        return Err(violation(e, "entries preceding entry were dropped"));
+                    }
+                    if link.prev != *mac {
+                        return Err(violation(e, "entry does not extend its hash chain"));
+                    }
+                }
+                None => {
+                    // first entry of writer seen, snapshots carry the last one they cover
+                    if link.seq > 0 {
+                        return Err(violation(e, "entries preceding entry were dropped"));
+                    }
+                }
+            }
+        }
+        // writer synced the entries it observed before appending, so they precede entry
+        for head in &integrity.observed {
+            if let Err(reason) = self.check_head(head) {
+                let reason = format!("entry {} of writer {:x}: {}",
+                                     e.idx.unwrap_or(-1),
+                                     integrity.writer,
+                                     reason);
+                return Err(LogError::ForkDetected(reason));
+            }
+        }
+        self.advance_heads(integrity, None);
+        Ok(())
+    }
+
+    // Method: check_head
+    // Checks a chain head observed by another client is part of the history synced by runtime
+    // Returns:
+    // * reason the histories diverge, if they do
+    fn check_head(&self, head: &ChainHead) -> Result<(), String> {
+        if !self.obj_ids.contains(&head.obj_id) {
+            // chain not followed
+            return Ok(());
+        }
+        match self.heads.get(&(head.writer, head.obj_id)) {
+            Some(&(seq, ref mac)) => {
+                if seq < head.seq {
+                    return Err(format!("entry {} of writer {:x} on object {} was hidden",
+                                       head.seq,
+                                       head.writer,
+                                       head.obj_id));
+                }
+                if seq == head.seq && *mac != head.mac {
+                    return Err(format!("entry {} of writer {:x} on object {} differs",
+                                       head.seq,
+                                       head.writer,
+                                       head.obj_id));
+                }
+                // runtime is ahead, entry was synced earlier
+                Ok(())
+            }
+            None => {
+                Err(format!("entries of writer {:x} on object {} were hidden",
+                            head.writer,
+                            head.obj_id))
+            }
         }
     }
 
+    // Method: chain_heads
+    // Version vector of runtime: last entry synced of every writer on every tracked object
+    // Clients exchange them out of band (see register_gossip_callback) and check them with
+    // check_heads, a log showing them diverging histories cannot keep it hidden
+    pub fn chain_heads(&self) -> Vec<ChainHead> {
+        let mut heads: Vec<ChainHead> = self.heads
+                                            .iter()
+                                            .map(|(&(writer, obj_id), &(seq, ref mac))| {
+                                                ChainHead {
+                                                    writer: writer,
+                                                    obj_id: obj_id,
+                                                    seq: seq,
+                                                    mac: mac.clone(),
+                                                }
+                                            })
+                                            .collect();
+        heads.sort_by(|a, b| (a.obj_id, a.writer).cmp(&(b.obj_id, b.writer)));
+        heads
+    }
+
+    // Method: check_heads, Blocking
+    // Syncs, then checks chain heads received from another client sharing the log
+    // Heads of objects not tracked by runtime are ignored
+    // Returns:
+    // * ForkDetected if the log hid entries the other client synced, or showed it other ones
+    pub fn check_heads(&mut self, heads: &[ChainHead]) -> Result<(), LogError> {
+        try!(self.try_sync(None));
+        for head in heads {
+            if let Err(reason) = self.check_head(head) {
+                let err = LogError::ForkDetected(format!("gossiped heads: {}", reason));
+                self.violation = Some(err.clone());
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    // record entry as last synced entry of its writer on tracked objects, or only on obj_id
+    fn advance_heads(&mut self, integrity: &Integrity, only: Option<ObjId>) {
+        for link in &integrity.links {
+            if only.map_or(self.obj_ids.contains(&link.obj_id), |obj_id| obj_id == link.obj_id) {
+                self.heads.insert((integrity.writer, link.obj_id),
+                                  (link.seq, integrity.mac.clone()));
+            }
+        }
+    }
+
+    // Method: resume_chains
+    // Entries of the snapshotted object up to the snapshot are covered by it, its chains resume
+    // from the last entry of each writer the snapshot carries
+    // Those entries are authenticated, so the log can neither forge them nor roll back a chain
+    // or drop a writer this runtime synced without it being detected
+    fn resume_chains(&mut self, s: &Snapshot) -> Result<(), LogError> {
+        let invalid = |reason: &str| {
+            LogError::IntegrityViolation(s.idx,
+                                         format!("snapshot of object {}: {}", s.obj_id, reason))
+        };
+        let mut resumed: HashMap<u64, (u64, Vec<u8>)> = HashMap::new();
+        for e in &s.heads {
+            try!(self.authenticate(e));
+            let integrity = match e.integrity {
+                Some(ref integrity) => integrity,
+                None => return Err(invalid("carries an unauthenticated entry")),
+            };
+            if e.idx.map_or(true, |idx| idx > s.idx) {
+                return Err(invalid("carries an entry it does not cover"));
+            }
+            match integrity.links.iter().find(|link| link.obj_id == s.obj_id) {
+                Some(link) => resumed.insert(integrity.writer, (link.seq, integrity.mac.clone())),
+                None => return Err(invalid("carries an entry of another object")),
+            };
+        }
+        for (&(writer, obj_id), &(seq, ref mac)) in &self.heads {
+            if obj_id != s.obj_id {
+                continue;
+            }
+            let kept = match resumed.get(&writer) {
+                Some(&(resumed_seq, ref resumed_mac)) => {
+                    resumed_seq > seq || (resumed_seq == seq && resumed_mac == mac)
+                }
+                None => false,
+            };
+            if !kept {
+                return Err(LogError::ForkDetected(format!("snapshot {} of object {} rolls back \
+                                                           entries of writer {:x}",
+                                                          s.idx,
+                                                          obj_id,
+                                                          writer)));
+            }
+        }
+        for (writer, head) in resumed {
+            self.heads.insert((writer, s.obj_id), head);
+        }
+        Ok(())
+    }
+
+    // Tampering detected so far, if any
+    pub fn integrity_violation(&self) -> Option<LogError> {
+        self.violation.clone()
+    }
+
     pub fn begin_tx(&mut self) {
-        self.tx_mode = true;
+        if let Err(err) = self.try_begin_tx() {
+            panic!("{}", err);
+        }
+    }
+
+    // Same as begin_tx, returns the integrity violation detected while syncing
+    // No transaction is started then
+    pub fn try_begin_tx(&mut self) -> Result<(), LogError> {
         // Sync all objects
-        self.sync(None);
+        try!(self.try_sync(None));
+        self.tx_mode = true;
+        Ok(())
     }
 
+    // Method: end_tx, Blocking
+    // Panics if the SharedLog was tampered with, see try_end_tx
     pub fn end_tx(&mut self) -> TxState {
+        match self.try_end_tx() {
+            Ok(tx_state) => tx_state,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Method: try_end_tx, Blocking
+    // Appends the transaction and syncs up to it
+    // Returns:
+    // * state of the transaction, or the integrity violation detected while syncing
+    // * error of the log if it refused the transaction, which is dropped
+    pub fn try_end_tx(&mut self) -> Result<TxState, LogError> {
+        // a refused entry is not in the log, the next ones link to the same predecessors
+        let chains = self.chains.clone();
         // signal end of transaction by sending TxEnd logentry to SharedLog
-        let tx_idx = self.iq.append(Entry::new(self.reads.drain().collect(),
-                                               self.writes.drain().collect(),
-                                               self.operations.drain(..).collect(),
-                                               TxType::End,
-                                               TxState::None));
+        let e = self.seal(Entry::new(self.reads.drain().collect(),
+                                     self.writes.drain().collect(),
+                                     self.operations.drain(..).collect(),
+                                     TxType::End,
+                                     TxState::None));
         // clean up transaction state
         self.tx_mode = false;
         self.reads.clear();
         self.writes.clear();
         self.operations.clear();
+        let tx_idx = match self.iq.try_append(e) {
+            Ok(tx_idx) => tx_idx,
+            Err(err) => {
+                self.chains = chains;
+                return Err(err);
+            }
+        };
         // sync up to transaction before returning to client
         return self.internal_sync(None, Some(tx_idx));
     }
@@ -113,8 +624,9 @@ impl<Q> Runtime<Q> where Q: IndexedQueue + Send
     pub fn validate_tx(&mut self, e: &mut Entry) {
         if e.tx_type == TxType::End && e.tx_state == TxState::None {
             // validate based on versions
+            let mut versions = self.version.lock().unwrap();
             for (obj_id, version) in &e.reads {
-                if *version < self.version[obj_id] {
+                if *version < *versions.get(obj_id).unwrap_or(&-1) {
                     // there exist more recent changes to obj_id in tx reads set
                     // so transaction must be aborted
                     e.tx_state = TxState::Aborted;
@@ -125,7 +637,7 @@ impl<Q> Runtime<Q> where Q: IndexedQueue + Send
             e.tx_state = TxState::Accepted;
             // update versions of objects in writes set
             for obj_id in &e.writes {
-                let idx: &mut i64 = self.version.get_mut(obj_id).unwrap();
+                let idx: &mut i64 = versions.entry(*obj_id).or_insert(-1);
                 *idx = e.idx.unwrap();
             }
         }
@@ -133,10 +645,22 @@ impl<Q> Runtime<Q> where Q: IndexedQueue + Send
 
     // Method: sync, Blocking
     // Syncs all objects registered with runtime
+    // Panics if the SharedLog was tampered with, see try_sync
     // Arguments:
     //  * obj_id : obj_id of object that led to need of sync, or None
     pub fn sync(&mut self, obj_id: Option<ObjId>) {
-        self.internal_sync(obj_id, None);
+        if let Err(err) = self.try_sync(obj_id) {
+            panic!("{}", err);
+        }
+    }
+
+    // Method: try_sync, Blocking
+    // Syncs all objects registered with runtime
+    // Once a violation is detected no further entries are applied, and every sync returns it
+    // Arguments:
+    //  * obj_id : obj_id of object that led to need of sync, or None
+    pub fn try_sync(&mut self, obj_id: Option<ObjId>) -> Result<(), LogError> {
+        self.internal_sync(obj_id, None).map(|_| ())
     }
 
     // Method: internal_sync, Blocking
@@ -146,24 +670,41 @@ impl<Q> Runtime<Q> where Q: IndexedQueue + Send
     // * tx_idx: sync up to transaction idx if some
     // Returns:
     // * returns TxState::None if tx_idx is None, or the state of transaction tx_idx if tx_idx is some
-    pub fn internal_sync(&mut self, obj_id: Option<ObjId>, tx_idx: Option<LogIndex>) -> TxState {
+    // * integrity violation if an entry was tampered with, the entry is not applied
+    pub fn internal_sync(&mut self,
+                         obj_id: Option<ObjId>,
+                         tx_idx: Option<LogIndex>)
+                         -> Result<TxState, LogError> {
         use indexed_queue::LogData::{LogEntry, LogSnapshot};
+        if let Some(ref err) = self.violation {
+            return Err(err.clone());
+        }
         // during transaction, record read, return
         if obj_id.is_some() {
             if self.tx_mode {
                 let obj_id = obj_id.unwrap();
-                self.reads.insert(obj_id, self.version[&obj_id]);
-                return TxState::None;
+                let version = self.version.lock().unwrap()[&obj_id];
+                self.reads.insert(obj_id, version);
+                return Ok(TxState::None);
             }
 
         }
 
-        // sync all objects runtime tracks
-        let rx = self.iq.stream(&self.obj_ids, self.global_idx + 1, None);
+        // sync all objects runtime tracks, or every object if someone listens for unknown ones
+        let rx = if self.unknown_callback.is_some() {
+            try!(self.iq.stream_all(self.global_idx + 1, None))
+        } else {
+            self.iq.stream(&self.obj_ids, self.global_idx + 1, None)
+        };
         // process and send updates to relevant callbacks
         loop {
             match rx.recv() {
                 Ok(LogEntry(mut e)) => {
+                    if let Err(err) = self.verify(&e) {
+                        self.violation = Some(err.clone());
+                        return Err(err);
+                    }
+
                     // update global index to entry index
                     let e_idx = e.idx.clone().expect("index does not exist");
                     self.global_idx = e_idx.clone() as LogIndex;
@@ -179,7 +720,7 @@ impl<Q> Runtime<Q> where Q: IndexedQueue + Send
                     // no callback updates needed if tx was aborted
                     if e.tx_state == TxState::Aborted {
                         if same_idx {
-                            return TxState::Aborted;
+                            return Ok(TxState::Aborted);
                         }
                         continue;
                     }
@@ -193,12 +734,12 @@ impl<Q> Runtime<Q> where Q: IndexedQueue + Send
                     // report updates to callbacks
                     for op in &e.operations {
                         // every operation is a write, so we update object version
-                        *(self.version
-                              .get_mut(&op.obj_id)
-                              .expect("version for object must exist")) += 1;
+                        // objects not registered yet have no version, as seen by the VM
+                        *self.version.lock().unwrap().entry(op.obj_id).or_insert(-1) += 1;
 
                         if !self.obj_ids.contains(&op.obj_id) {
                             // entry also has operation on object not tracked
+                            self.unknown_callback.as_mut().map(|cb| cb(e_idx, op));
                             continue;
                         }
 
@@ -215,8 +756,12 @@ impl<Q> Runtime<Q> where Q: IndexedQueue + Send
                                 }
 
                             }
+                            // snapshots are streamed apart from entries, never inside one
                             _ => {
-                                unimplemented!();
+                                let _ = writeln!(io::stderr(),
+                                                 "runtime: skipping snapshot op of object {} at {}",
+                                                 obj_id,
+                                                 e_idx);
                             }
                         }
                     }
@@ -229,7 +774,7 @@ impl<Q> Runtime<Q> where Q: IndexedQueue + Send
 
                     // return to client waiting on current log entry
                     if same_idx {
-                        return e.tx_state.clone();
+                        return Ok(e.tx_state.clone());
                     }
                 }
                 Ok(LogSnapshot(s)) => {
@@ -240,6 +785,10 @@ impl<Q> Runtime<Q> where Q: IndexedQueue + Send
                         continue;
                     }
 
+                    if let Err(err) = self.resume_chains(&s) {
+                        self.violation = Some(err.clone());
+                        return Err(err);
+                    }
                     let obj_id = s.obj_id;
                     let idx = s.idx;
                     let callbacks = self.callbacks
@@ -253,20 +802,35 @@ impl<Q> Runtime<Q> where Q: IndexedQueue + Send
                 Err(_) => break,
             };
         }
-        return TxState::None;
+        if self.gossip_callback.is_some() {
+            let heads = self.chain_heads();
+            self.gossip_callback.as_mut().map(|cb| cb(&heads));
+        }
+        return Ok(TxState::None);
     }
 
     // Method: catch_up, Blocking
     // Syncs state of obj_id and reports updates via callback c
+    // Entries are authenticated and start the chains of obj_id, ordering is verified by later syncs
     pub fn catch_up(&mut self, obj_id: ObjId, mut c: &mut Box<Callback>) {
         use indexed_queue::LogData::{LogEntry, LogSnapshot};
+        // stream is exclusive of its upper bound, entry at global_idx was already synced
         let rx = self.iq.stream(&vec![obj_id].into_iter().collect(),
                                 0,
-                                Some(self.global_idx));
+                                Some(self.global_idx + 1));
 
         loop {
             match rx.recv() {
                 Ok(LogEntry(e)) => {
+                    if let Err(err) = self.authenticate(&e) {
+                        self.violation = Some(err);
+                        break;
+                    }
+                    if let Some(ref integrity) = e.integrity {
+                        // other objects of entry were synced already, their heads are newer
+                        self.advance_heads(integrity, Some(obj_id));
+                    }
+
                     for op in &e.operations {
                         if obj_id != op.obj_id {
                             // entry also has operation on different object
@@ -279,12 +843,19 @@ impl<Q> Runtime<Q> where Q: IndexedQueue + Send
                                 (*c)(e.idx.unwrap(), Operation::new(obj_id, dec_operator));
                             }
                             _ => {
-                                unimplemented!();
+                                let _ = writeln!(io::stderr(),
+                                                 "runtime: skipping snapshot op of object {} at {}",
+                                                 obj_id,
+                                                 e.idx.unwrap());
                             }
                         }
                     }
                 }
                 Ok(LogSnapshot(s)) => {
+                    if let Err(err) = self.resume_chains(&s) {
+                        self.violation = Some(err);
+                        break;
+                    }
                     let snapshot = s.payload;
                     (*c)(s.idx, Operation::from_snapshot(obj_id, snapshot));
                 }
@@ -293,13 +864,16 @@ impl<Q> Runtime<Q> where Q: IndexedQueue + Send
         }
     }
 
+    // Keys of object obj_id whose values contain the keyword behind token, as answered by the log
+    pub fn search(&mut self, obj_id: ObjId, token: &SearchToken) -> Result<Vec<String>, LogError> {
+        self.iq.search(obj_id, token)
+    }
+
     // Method: register_object
     // Registers obj_id in runtime and sync sobject to most recent state
     pub fn register_object(&mut self, obj_id: ObjId, mut c: Box<Callback>) {
         self.obj_ids.insert(obj_id);
-        if !self.version.contains_key(&obj_id) {
-            self.version.insert(obj_id, -1);
-        }
+        self.version.lock().unwrap().entry(obj_id).or_insert(-1);
 
         self.catch_up(obj_id, &mut c);
 
@@ -308,20 +882,239 @@ impl<Q> Runtime<Q> where Q: IndexedQueue + Send
         }
         self.callbacks.get_mut(&obj_id).unwrap().push(c);
     }
+
+    // directory of named objects, registered with runtime on first use
+    fn directory(&mut self) -> Directory {
+        if self.directory.is_none() {
+            let directory = Directory::new();
+            let mut copy = directory.clone();
+            self.register_object(DIRECTORY,
+                                 Box::new(move |_, op: Operation| copy.callback(op)));
+            self.directory = Some(directory);
+        }
+        self.directory.clone().unwrap()
+    }
+
+    // Method: lookup_or_create, Blocking
+    // Looks up object name in the directory, creating it if no object has that name
+    // Arguments:
+    //  * name : plain name of the object, only its encryption is appended to the log
+    //  * type_tag : data structure of the object
+    //  * scheme : encryption scheme of the object's elements
+    // Returns:
+    //  * directory entry of the object, the same on every runtime sharing the log
+    pub fn lookup_or_create(&mut self, name: &str, type_tag: &str, scheme: &str) -> DirectoryEntry {
+        match self.try_lookup_or_create(name, type_tag, scheme) {
+            Ok(entry) => entry,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as lookup_or_create, returns the integrity violation detected while syncing
+    pub fn try_lookup_or_create(&mut self,
+                                name: &str,
+                                type_tag: &str,
+                                scheme: &str)
+                                -> Result<DirectoryEntry, LogError> {
+        assert!(!self.tx_mode, "objects cannot be opened during a transaction");
+        let name = self.secure
+                       .as_ref()
+                       .map(|secure| secure.encrypt_eqable(name.as_bytes()))
+                       .expect("objects can only be opened by name with encryptors");
+        let directory = self.directory();
+        try!(self.try_sync(None));
+        if let Some(entry) = directory.get(&name) {
+            return Ok(entry);
+        }
+
+        let op = DirectoryOp::Create {
+            name: name.clone(),
+            type_tag: String::from(type_tag),
+            scheme: String::from(scheme),
+        };
+        let op = json::encode(&op).unwrap();
+        try!(self.try_append(DIRECTORY, State::Encrypted(op.into_bytes())));
+        // some other runtime may have created name first, its entry wins
+        try!(self.try_sync(None));
+        Ok(directory.get(&name).expect("directory entry must exist once created"))
+    }
+
+    // Objects named so far, as known after last sync
+    pub fn directory_entries(&mut self) -> Vec<DirectoryEntry> {
+        match self.try_directory_entries() {
+            Ok(entries) => entries,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as directory_entries, returns the integrity violation detected while syncing
+    pub fn try_directory_entries(&mut self) -> Result<Vec<DirectoryEntry>, LogError> {
+        let directory = self.directory();
+        try!(self.try_sync(None));
+        Ok(directory.entries())
+    }
+
+    // Method: open, Blocking
+    // Opens object name, creating it if needed, and registers it with runtime
+    // Returns TypeMismatch if name already exists with a different type
+    pub fn open<T>(aruntime: &Arc<Mutex<Runtime<Q>>>, name: &str) -> Result<T, LogError>
+        where T: DirectoryObject<Q>
+    {
+        let entry = try!(aruntime.lock()
+                                 .unwrap()
+                                 .try_lookup_or_create(name, T::type_tag(), T::scheme()));
+        if entry.type_tag != T::type_tag() {
+            return Err(LogError::TypeMismatch(format!("object {} is a {}, not a {}",
+                                                      name,
+                                                      entry.type_tag,
+                                                      T::type_tag())));
+        }
+        aruntime.lock().unwrap().set_type_tag(entry.obj_id, T::type_tag());
+        Ok(T::open(aruntime, entry.obj_id))
+    }
+
+    // Stamp operations appended to obj_id with type_tag, so VM can replicate it on first sight
+    // Objects opened by name are tagged already
+    pub fn set_type_tag(&mut self, obj_id: ObjId, type_tag: &str) {
+        self.type_tags.insert(obj_id, String::from(type_tag));
+    }
+
+    // Versions of objects, shared so VM can checkpoint them while entries are applied
+    pub fn versions(&self) -> Arc<Mutex<HashMap<ObjId, LogIndex>>> {
+        self.version.clone()
+    }
+
     pub fn register_pre_callback(&mut self, c: Box<EntryCallback>) {
         self.pre_callbacks.push(c);
     }
     pub fn register_post_callback(&mut self, c: Box<EntryCallback>) {
         self.post_callbacks.push(c);
     }
+    // c is called with operations on objects not registered with runtime
+    // runtime then syncs every entry of the log, queue must support stream_all
+    pub fn register_unknown_callback(&mut self, c: Box<UnknownCallback>) {
+        self.unknown_callback = Some(c);
+    }
+    // c is called with the chain heads of runtime after each sync, to be gossiped to other clients
+    pub fn register_gossip_callback(&mut self, c: Box<GossipCallback>) {
+        self.gossip_callback = Some(c);
+    }
 }
 
 
 #[cfg(test)]
 mod test {
-    use super::Runtime;
-    use indexed_queue::{InMemoryQueue, State};
-    use encryptors::MetaEncryptor;
+    use super::{Runtime, LogError};
+    use indexed_queue::{InMemoryQueue, IndexedQueue, State, Entry, ObjId, LogIndex, LogData,
+                        Operation, ChainHead, Signature, Snapshot};
+    use indexed_queue::LogData::{LogEntry, LogSnapshot};
+    use encryptors::{MetaEncryptor, Signer, KeyWrapper};
+    use maps::StringHMap;
+    use ds::IntRegister;
+    use rustc_serialize::json;
+
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc;
+
+    // SharedLog under the test's control, stored entries can be rewritten at will
+    // and hidden from some clients
+    #[derive(Clone)]
+    struct TamperedQueue {
+        log: Arc<Mutex<Vec<Entry>>>,
+        hidden: Arc<Mutex<HashSet<LogIndex>>>, // indices not shown to users of this queue
+        snapshot: Arc<Mutex<Option<Snapshot>>>, // streamed instead of the entries it covers
+        refused: Arc<Mutex<bool>>, // appends are rejected while set
+    }
+
+    impl TamperedQueue {
+        fn new() -> TamperedQueue {
+            TamperedQueue {
+                log: Arc::new(Mutex::new(Vec::new())),
+                hidden: Arc::new(Mutex::new(HashSet::new())),
+                snapshot: Arc::new(Mutex::new(None)),
+                refused: Arc::new(Mutex::new(false)),
+            }
+        }
+
+        // same log, entries hidden separately
+        fn view(&self) -> TamperedQueue {
+            TamperedQueue {
+                log: self.log.clone(),
+                hidden: Arc::new(Mutex::new(HashSet::new())),
+                snapshot: self.snapshot.clone(),
+                refused: self.refused.clone(),
+            }
+        }
+    }
+
+    impl IndexedQueue for TamperedQueue {
+        fn append(&mut self, mut e: Entry) -> LogIndex {
+            let mut log = self.log.lock().unwrap();
+            e.idx = Some(log.len() as LogIndex);
+            log.push(e);
+            (log.len() - 1) as LogIndex
+        }
+
+        fn try_append(&mut self, e: Entry) -> Result<LogIndex, LogError> {
+            if *self.refused.lock().unwrap() {
+                return Err(LogError::Unauthorized(-1, String::from("refused")));
+            }
+            Ok(self.append(e))
+        }
+
+        fn stream(&mut self,
+                  obj_ids: &HashSet<ObjId>,
+                  from: LogIndex,
+                  to: Option<LogIndex>)
+                  -> mpsc::Receiver<LogData> {
+            let (tx, rx) = mpsc::channel();
+            let mut covered = -1;
+            if let Some(ref s) = *self.snapshot.lock().unwrap() {
+                if from <= s.idx && s.idx < to.unwrap_or(s.idx + 1) && obj_ids.contains(&s.obj_id) {
+                    tx.send(LogSnapshot(s.clone())).unwrap();
+                    covered = s.idx;
+                }
+            }
+            let hidden = self.hidden.lock().unwrap();
+            for e in self.log.lock().unwrap().iter() {
+                let idx = e.idx.unwrap();
+                if hidden.contains(&idx) || idx <= covered {
+                    continue;
+                }
+                if from <= idx && idx < to.unwrap_or(idx + 1) && !e.writes.is_disjoint(obj_ids) {
+                    tx.send(LogEntry(e.clone())).unwrap();
+                }
+            }
+            rx
+        }
+    }
+
+    // writer appends 4 entries to object 0, log rewrites them with f, reader syncs
+    fn sync_tampered<F>(f: F) -> Result<(), LogError>
+        where F: FnOnce(&mut Vec<Entry>)
+    {
+        let q = TamperedQueue::new();
+        let me = Some(MetaEncryptor::new());
+        let mut writer = Runtime::new(q.clone(), me.clone());
+        let mut reader = Runtime::new(q.clone(), me);
+        for i in 0..4 {
+            writer.append(0, State::Encoded(format!("{}", i)));
+        }
+        reader.register_object(0, Box::new(|_, _| {}));
+        f(&mut q.log.lock().unwrap());
+        let res = reader.try_sync(None);
+        // violations stick, nothing is applied past them
+        assert_eq!(reader.try_sync(None), res);
+        res
+    }
+
+    fn violated_at(res: Result<(), LogError>, at: LogIndex) -> bool {
+        match res {
+            Err(LogError::IntegrityViolation(idx, _)) => idx == at,
+            _ => false,
+        }
+    }
 
     #[test]
     fn create_runtime() {
@@ -329,4 +1122,306 @@ mod test {
         let mut r: Runtime<InMemoryQueue> = Runtime::new(q, Some(MetaEncryptor::new()));
         r.append(0, State::Encoded(String::from("Hello")));
     }
+
+    #[test]
+    fn refused_append() {
+        let q = TamperedQueue::new();
+        let me = Some(MetaEncryptor::new());
+        let mut writer = Runtime::new(q.clone(), me.clone());
+        let mut reader = Runtime::new(q.clone(), me);
+        reader.register_object(0, Box::new(|_, _| {}));
+        writer.append(0, State::Encoded(String::from("1")));
+        *q.refused.lock().unwrap() = true;
+        let res = writer.try_append(0, State::Encoded(String::from("2")));
+        assert_eq!(res, Err(LogError::Unauthorized(-1, String::from("refused"))));
+        // refused entry is not part of the chain, the next one links to the first
+        *q.refused.lock().unwrap() = false;
+        writer.append(0, State::Encoded(String::from("3")));
+        assert_eq!(q.log.lock().unwrap().len(), 2);
+        assert_eq!(reader.try_sync(None), Ok(()));
+    }
+
+    #[test]
+    fn refused_tx() {
+        let q = TamperedQueue::new();
+        let me = Some(MetaEncryptor::new());
+        let mut writer = Runtime::new(q.clone(), me.clone());
+        let mut reader = Runtime::new(q.clone(), me);
+        reader.register_object(0, Box::new(|_, _| {}));
+        writer.append(0, State::Encoded(String::from("1")));
+        *q.refused.lock().unwrap() = true;
+        writer.begin_tx();
+        writer.append(0, State::Encoded(String::from("2")));
+        assert_eq!(writer.try_end_tx(),
+                   Err(LogError::Unauthorized(-1, String::from("refused"))));
+        // refused transaction is dropped, the next entry links to the first
+        *q.refused.lock().unwrap() = false;
+        writer.append(0, State::Encoded(String::from("3")));
+        assert_eq!(q.log.lock().unwrap().len(), 2);
+        assert_eq!(reader.try_sync(None), Ok(()));
+    }
+
+    #[test]
+    fn tampered_reads() {
+        let q = TamperedQueue::new();
+        let me = Some(MetaEncryptor::new());
+        let writer = Arc::new(Mutex::new(Runtime::new(q.clone(), me.clone())));
+        let reader = Arc::new(Mutex::new(Runtime::new(q.clone(), me)));
+        let mut written = IntRegister::new(&writer, 0, 0);
+        let mut read = IntRegister::new(&reader, 0, 0);
+        written.start();
+        read.start();
+        for i in 1..4 {
+            written.write(i);
+        }
+        q.log.lock().unwrap()[2].integrity = None;
+        // reads return the violation instead of panicking, and keep returning it
+        assert!(violated_at(read.try_read().map(|_| ()), 2));
+        assert!(violated_at(read.try_read().map(|_| ()), 2));
+    }
+
+    #[test]
+    fn integrity_untampered() {
+        assert_eq!(sync_tampered(|_| {}), Ok(()));
+    }
+
+    #[test]
+    fn integrity_modified() {
+        let res = sync_tampered(|log| {
+            log[2].operations[0] = Operation::new(0, State::Encoded(String::from("100")));
+        });
+        assert!(violated_at(res, 2));
+        let res = sync_tampered(|log| log[3].integrity = None);
+        assert!(violated_at(res, 3));
+    }
+
+    #[test]
+    fn integrity_dropped() {
+        let res = sync_tampered(|log| {
+            log.remove(1);
+        });
+        assert!(violated_at(res, 2));
+    }
+
+    #[test]
+    fn integrity_replayed() {
+        let res = sync_tampered(|log| {
+            let mut e = log[1].clone();
+            e.idx = Some(4);
+            log.push(e);
+        });
+        assert!(violated_at(res, 4));
+    }
+
+    #[test]
+    fn integrity_reordered() {
+        let res = sync_tampered(|log| {
+            log.swap(1, 2);
+            log[1].idx = Some(1);
+            log[2].idx = Some(2);
+        });
+        assert!(violated_at(res, 1));
+    }
+
+    // reader syncs 4 entries of writer, who appends 2 more, the log snapshots the first 5 as
+    // snapshot(log) and streams it with the last entry
+    fn sync_snapshotted<F>(snapshot: F) -> Result<(), LogError>
+        where F: FnOnce(&Vec<Entry>) -> Snapshot
+    {
+        let q = TamperedQueue::new();
+        let me = Some(MetaEncryptor::new());
+        let mut writer = Runtime::new(q.clone(), me.clone());
+        let mut reader = Runtime::new(q.clone(), me);
+        for i in 0..4 {
+            writer.append(0, State::Encoded(format!("{}", i)));
+        }
+        reader.register_object(0, Box::new(|_, _| {}));
+        assert_eq!(reader.try_sync(None), Ok(()));
+        for i in 4..6 {
+            writer.append(0, State::Encoded(format!("{}", i)));
+        }
+        let s = snapshot(&q.log.lock().unwrap());
+        *q.snapshot.lock().unwrap() = Some(s);
+        reader.try_sync(None)
+    }
+
+    #[test]
+    fn snapshot_resumes_chains() {
+        let payload = State::Encoded(String::from("{}"));
+        let honest = sync_snapshotted(|log| {
+            Snapshot::new(0, 4, payload.clone()).with_heads(vec![log[4].clone()])
+        });
+        assert_eq!(honest, Ok(()));
+
+        // snapshot without the writer's entries resets its chain
+        assert!(forked(sync_snapshotted(|_| Snapshot::new(0, 4, payload.clone()))));
+        // snapshot resuming from an older entry hides entry 4
+        let stale = sync_snapshotted(|log| {
+            Snapshot::new(0, 4, payload.clone()).with_heads(vec![log[2].clone()])
+        });
+        assert!(forked(stale));
+        // snapshot resuming chain past entries it does not cover
+        let ahead = sync_snapshotted(|log| {
+            Snapshot::new(0, 4, payload.clone()).with_heads(vec![log[5].clone()])
+        });
+        assert!(violated_at(ahead, 4));
+        // snapshot carrying a forged entry
+        let forged = sync_snapshotted(|log| {
+            let mut e = log[4].clone();
+            e.operations[0] = Operation::new(0, State::Encoded(String::from("100")));
+            Snapshot::new(0, 4, payload.clone()).with_heads(vec![e])
+        });
+        assert!(violated_at(forged, 4));
+    }
+
+    fn forked(res: Result<(), LogError>) -> bool {
+        match res {
+            Err(LogError::ForkDetected(_)) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn fork_detected_on_append() {
+        let q = TamperedQueue::new();
+        let me = Some(MetaEncryptor::new());
+        let mut writer = Runtime::new(q.view(), me.clone());
+        let mut honest = Runtime::new(q.view(), me.clone());
+        let victim_q = q.view();
+        let mut victim = Runtime::new(victim_q.clone(), me);
+        honest.register_object(0, Box::new(|_, _| {}));
+        victim.register_object(0, Box::new(|_, _| {}));
+
+        writer.append(0, State::Encoded(String::from("1")));
+        writer.append(0, State::Encoded(String::from("2")));
+        // log forks victim off, writer's second entry is never shown to it
+        victim_q.hidden.lock().unwrap().insert(1);
+        assert_eq!(victim.try_sync(None), Ok(()));
+
+        // honest client saw the hidden entry, its next append tells the victim
+        assert_eq!(honest.try_sync(None), Ok(()));
+        honest.append(0, State::Encoded(String::from("3")));
+        assert_eq!(honest.try_sync(None), Ok(()));
+        assert!(forked(victim.try_sync(None)));
+    }
+
+    #[test]
+    fn fork_detected_by_gossip() {
+        let q = TamperedQueue::new();
+        let me = Some(MetaEncryptor::new());
+        let mut writer = Runtime::new(q.view(), me.clone());
+        let mut honest = Runtime::new(q.view(), me.clone());
+        let mut other = Runtime::new(q.view(), me.clone());
+        let victim_q = q.view();
+        let mut victim = Runtime::new(victim_q.clone(), me);
+
+        let gossiped = Arc::new(Mutex::new(Vec::new()));
+        let gossip = gossiped.clone();
+        honest.register_gossip_callback(Box::new(move |heads: &Vec<ChainHead>| {
+            *gossip.lock().unwrap() = heads.clone();
+        }));
+        for r in vec![&mut honest, &mut other, &mut victim] {
+            r.register_object(0, Box::new(|_, _| {}));
+        }
+
+        writer.append(0, State::Encoded(String::from("1")));
+        writer.append(0, State::Encoded(String::from("2")));
+        victim_q.hidden.lock().unwrap().insert(1);
+
+        assert_eq!(honest.try_sync(None), Ok(()));
+        let heads = gossiped.lock().unwrap().clone();
+        assert_eq!(heads, honest.chain_heads());
+        assert_eq!(heads.len(), 1);
+        assert_eq!(heads[0].seq, 1);
+
+        assert_eq!(other.check_heads(&heads), Ok(()));
+        assert!(forked(victim.check_heads(&heads)));
+        // alarm sticks
+        assert!(forked(victim.try_sync(None)));
+    }
+
+    fn unauthorized_at(res: Result<(), LogError>, at: LogIndex) -> bool {
+        match res {
+            Err(LogError::Unauthorized(idx, _)) => idx == at,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn writers_allowed() {
+        let q = TamperedQueue::new();
+        let me = Some(MetaEncryptor::new());
+        let mut alice = Runtime::new(q.view(), me.clone()).with_signer(Signer::new());
+        let mut mallory = Runtime::new(q.view(), me.clone()).with_signer(Signer::new());
+        let mut reader = Runtime::new(q.view(), me);
+        reader.allow_writer(0, alice.writer_id().unwrap());
+        reader.register_object(0, Box::new(|_, _| {}));
+        reader.register_object(1, Box::new(|_, _| {}));
+
+        alice.append(0, State::Encoded(String::from("1")));
+        // objects not listed can be written by anyone
+        mallory.append(1, State::Encoded(String::from("2")));
+        assert_eq!(reader.try_sync(None), Ok(()));
+
+        mallory.append(0, State::Encoded(String::from("3")));
+        assert!(unauthorized_at(reader.try_sync(None), 2));
+    }
+
+    #[test]
+    fn forged_signature() {
+        let q = TamperedQueue::new();
+        let me = Some(MetaEncryptor::new());
+        let alice = Signer::new();
+        let mallory = Signer::new();
+        let mut writer = Runtime::new(q.view(), me.clone()).with_signer(mallory.clone());
+        let mut reader = Runtime::new(q.view(), me);
+        reader.allow_writer(0, alice.writer_id());
+        reader.register_object(0, Box::new(|_, _| {}));
+
+        writer.append(0, State::Encoded(String::from("1")));
+        {
+            // mallory claims its entry was signed by alice
+            let mut log = q.log.lock().unwrap();
+            let sig = mallory.sign(&log[0].signed_bytes());
+            log[0].signature = Some(Signature {
+                writer: alice.writer_id(),
+                sig: sig,
+            });
+        }
+        assert!(unauthorized_at(reader.try_sync(None), 0));
+    }
+
+    #[test]
+    fn object_keys_granted() {
+        let q = TamperedQueue::new();
+        let me = MetaEncryptor::new();
+        let mut alice = Runtime::new(q.view(), Some(me.clone()));
+        alice.new_object_keys(1);
+        alice.new_object_keys(2);
+        // bob shares the mac key of the log, but none of alice's object keys
+        let mut bob = Runtime::new(q.view(), Some(MetaEncryptor::new().with_auth(me.auth.clone())));
+
+        let encrypt = |runtime: &Runtime<TamperedQueue>, obj_id: ObjId| {
+            json::encode(&runtime.secure_for(obj_id).unwrap().encrypt(b"secret")).unwrap()
+        };
+        assert!(encrypt(&alice, 1) != encrypt(&alice, 2));
+        assert!(encrypt(&alice, 1) != json::encode(&me.encrypt(b"secret")).unwrap());
+
+        let wrapper = KeyWrapper::new();
+        assert_eq!(alice.grant(&[1, 3], &wrapper).err(), Some(3));
+        let grant = alice.grant(&[1], &wrapper).unwrap();
+        assert_eq!(bob.accept_grant(&grant, &KeyWrapper::new()), None);
+        assert_eq!(bob.accept_grant(&grant, &wrapper), Some(vec![1]));
+        assert_eq!(encrypt(&alice, 1), encrypt(&bob, 1));
+        assert!(encrypt(&alice, 2) != encrypt(&bob, 2));
+
+        let alice = Arc::new(Mutex::new(alice));
+        let bob = Arc::new(Mutex::new(bob));
+        let mut written = StringHMap::new(&alice, 1, HashMap::new());
+        written.start();
+        written.insert(String::from("k"), String::from("v"));
+        let mut read = StringHMap::new(&bob, 1, HashMap::new());
+        read.start();
+        assert_eq!(read.get(&String::from("k")), Some(String::from("v")));
+    }
 }
@@ -0,0 +1,594 @@
+extern crate rustc_serialize;
+use self::rustc_serialize::json;
+use self::rustc_serialize::{Encodable, Decodable, Encoder, Decoder};
+
+use runtime::{Runtime, LogError};
+use indexed_queue::{Operation, IndexedQueue, State};
+use replica::{self, ReplicatedObject, with_runtime};
+use encryptors::{MetaEncryptor, Eqable, Ordable};
+use converters::{SimpleConverter, EqableConverter, ConvertersLib};
+
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::collections::{HashSet, BTreeSet};
+use std::hash::Hash;
+use std::cmp::Eq;
+use std::vec;
+
+#[derive(RustcEncodable, RustcDecodable, Debug)]
+pub enum SetOp<T> {
+    Insert {
+        elem: T,
+    },
+    Remove {
+        elem: T,
+    },
+}
+
+// Trait: ReplicatedSet
+// Operations shared by the hash (HSet) and ordered (BTSet) replicated sets
+// Reads sync through runtime, writes are appended to the SharedLog
+pub trait ReplicatedSet<T> {
+    // Appends insertion of elem to log
+    fn insert(&mut self, elem: T) {
+        if let Err(err) = self.try_insert(elem) {
+            panic!("{}", err);
+        }
+    }
+    // Appends removal of elem to log
+    fn remove(&mut self, elem: T) {
+        if let Err(err) = self.try_remove(elem) {
+            panic!("{}", err);
+        }
+    }
+    // Same as insert, returns the error of the log if it refused the op
+    fn try_insert(&mut self, elem: T) -> Result<(), LogError>;
+    // Same as remove, returns the error of the log if it refused the op
+    fn try_remove(&mut self, elem: T) -> Result<(), LogError>;
+    // Syncs, returns true if elem is in set
+    fn contains(&self, elem: &T) -> bool {
+        match self.try_contains(elem) {
+            Ok(contains) => contains,
+            Err(err) => panic!("{}", err),
+        }
+    }
+    // Syncs, returns number of elements in set
+    fn len(&self) -> usize {
+        match self.try_len() {
+            Ok(len) => len,
+            Err(err) => panic!("{}", err),
+        }
+    }
+    // Syncs, returns an iterator over a copy of the elements in set
+    fn iter(&self) -> vec::IntoIter<T> {
+        match self.try_iter() {
+            Ok(iter) => iter,
+            Err(err) => panic!("{}", err),
+        }
+    }
+    // Same as contains, returns the integrity violation detected while syncing
+    fn try_contains(&self, elem: &T) -> Result<bool, LogError>;
+    // Same as len, returns the integrity violation detected while syncing
+    fn try_len(&self) -> Result<usize, LogError>;
+    // Same as iter, returns the integrity violation detected while syncing
+    fn try_iter(&self) -> Result<vec::IntoIter<T>, LogError>;
+}
+
+// Unencrypted StringHSet, to be used by client
+// Supports Eqable encryption for elements
+pub type StringHSet<Q> = HSet<String, Q>;
+impl<Q> StringHSet<Q> {
+    pub fn new(aruntime: &Arc<Mutex<Runtime<Q>>>,
+               obj_id: i32,
+               data: HashSet<String>)
+               -> StringHSet<Q> {
+        HSet::from(aruntime,
+                   obj_id,
+                   data,
+                   EqableConverter::new(ConvertersLib::encodable_from_eqable(),
+                                        ConvertersLib::eqable_from_encodable()))
+    }
+}
+
+// Encrypted StringHSet, to be used by VM
+// Supports Eqable encryption for elements
+pub type EncHSet<Q> = HSet<Eqable, Q>;
+impl<Q> EncHSet<Q> {
+    pub fn new(aruntime: &Arc<Mutex<Runtime<Q>>>,
+               obj_id: i32,
+               data: HashSet<Eqable>)
+               -> EncHSet<Q> {
+        HSet::from(aruntime,
+                   obj_id,
+                   data,
+                   EqableConverter::new(ConvertersLib::eqable_from_eqable(),
+                                        ConvertersLib::eqable_from_eqable()))
+    }
+}
+
+// Class: HSet
+// Parametrized by:
+// * T : element type
+// * Q : structure allowing seamless communicating with Shared Log
+#[derive(Clone)]
+pub struct HSet<T, Q> {
+    runtime: Option<Arc<Mutex<Runtime<Q>>>>, // runtime object is registered with
+    obj_id: i32, // unique id
+    pub data: Arc<Mutex<HashSet<T>>>, // local data structure
+
+    convert_eq: Option<EqableConverter<T>>, // converter between data states
+    secure: Option<MetaEncryptor>, // structure to allow use of existing Encryptors/ Decryptors
+}
+
+impl<T, Q> Decodable for HSet<T, Q>
+    where T: Encodable + Decodable + Hash + Eq
+{
+    fn decode<D: Decoder>(d: &mut D) -> Result<Self, D::Error> {
+        let mut vec: Vec<T> = try!(Decodable::decode(d));
+        let data: HashSet<T> = vec.drain(..).collect();
+        let hset: HSet<T, Q> = HSet::default(data);
+        let res: Result<Self, D::Error> = Ok(hset);
+        return res;
+    }
+}
+
+impl<T, Q> Encodable for HSet<T, Q>
+    where T: Encodable + Decodable + Hash + Eq + Clone
+{
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let data = self.data.lock().unwrap();
+        let vec: Vec<T> = data.iter().cloned().collect();
+        vec.encode(s)
+    }
+}
+
+impl<T, Q> HSet<T, Q> {
+    pub fn from(aruntime: &Arc<Mutex<Runtime<Q>>>,
+                obj_id: i32,
+                data: HashSet<T>,
+                convert_eq: EqableConverter<T>)
+                -> HSet<T, Q> {
+        let hset = HSet {
+            obj_id: obj_id,
+            runtime: Some(aruntime.clone()),
+            data: Arc::new(Mutex::new(data)),
+            convert_eq: Some(convert_eq),
+            secure: aruntime.lock().unwrap().secure_for(obj_id),
+        };
+        return hset;
+    }
+
+    fn default(data: HashSet<T>) -> HSet<T, Q> {
+        HSet {
+            runtime: None,
+            obj_id: 0,
+            data: Arc::new(Mutex::new(data)),
+            convert_eq: None,
+            secure: None,
+        }
+    }
+}
+
+impl<T, Q> HSet<T, Q>
+    where T: 'static + Send + Clone + Encodable + Decodable + Hash + Eq,
+          Q: 'static + IndexedQueue + Send + Clone
+{
+    pub fn start(&mut self) {
+        replica::register(&self.runtime, self.obj_id, None, self.clone());
+    }
+
+    fn append(&self, op: SetOp<T>) -> Result<(), LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            // convert element to shared log state
+            let encrypted_op = match op {
+                SetOp::Insert{elem} => SetOp::Insert { elem: self.to_elem(elem) },
+                SetOp::Remove{elem} => SetOp::Remove { elem: self.to_elem(elem) },
+            };
+            let op = json::encode(&encrypted_op).unwrap();
+            runtime.try_append(self.obj_id, State::Encrypted(op.into_bytes()))
+        })
+    }
+
+    fn to_elem(&self, elem: T) -> Eqable {
+        self.convert_eq
+            .as_ref()
+            .map(|convert_eq| {
+                let to = &convert_eq.to;
+                to(&self.secure, elem)
+            })
+            .unwrap()
+    }
+
+    pub fn get_elem(&self, elem: Eqable) -> T {
+        // convert element from shared log state to local state
+        self.convert_eq
+            .as_ref()
+            .map(|convert_eq| {
+                let from = &convert_eq.from;
+                from(&self.secure, elem)
+            })
+            .unwrap()
+    }
+
+    pub fn callback(&mut self, op: Operation) {
+        replica::callback(self, &None, op);
+    }
+}
+
+impl<T, Q> ReplicatedObject for HSet<T, Q>
+    where T: 'static + Send + Clone + Encodable + Decodable + Hash + Eq,
+          Q: 'static + IndexedQueue + Send + Clone
+{
+    type Op = SetOp<Eqable>;
+    type Twin = Vec<Eqable>;
+
+    fn apply(&mut self, _: &Option<MetaEncryptor>, op: SetOp<Eqable>) {
+        match op {
+            SetOp::Insert{elem} => {
+                let elem = self.get_elem(elem);
+                self.data.lock().unwrap().insert(elem);
+            }
+            SetOp::Remove{elem} => {
+                let elem = self.get_elem(elem);
+                self.data.lock().unwrap().remove(&elem);
+            }
+        }
+    }
+
+    fn restore(&mut self, _: &Option<MetaEncryptor>, mut twin: Vec<Eqable>) {
+        let converted: HashSet<T> = twin.drain(..).map(|elem| self.get_elem(elem)).collect();
+        *self.data.lock().unwrap() = converted;
+    }
+}
+
+impl<T, Q> ReplicatedSet<T> for HSet<T, Q>
+    where T: 'static + Send + Clone + Encodable + Decodable + Hash + Eq,
+          Q: 'static + IndexedQueue + Send + Clone
+{
+    fn try_insert(&mut self, elem: T) -> Result<(), LogError> {
+        self.append(SetOp::Insert { elem: elem })
+    }
+
+    fn try_remove(&mut self, elem: T) -> Result<(), LogError> {
+        self.append(SetOp::Remove { elem: elem })
+    }
+
+    fn try_contains(&self, elem: &T) -> Result<bool, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok(self.data.lock().unwrap().contains(elem))
+        })
+    }
+
+    fn try_len(&self) -> Result<usize, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok(self.data.lock().unwrap().len())
+        })
+    }
+
+    fn try_iter(&self) -> Result<vec::IntoIter<T>, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            let data = self.data.lock().unwrap();
+            let elems: Vec<T> = data.iter().cloned().collect();
+            Ok(elems.into_iter())
+        })
+    }
+}
+
+// Unencrypted StringBTSet, to be used by client
+// Supports Ordable encryption for elements
+pub type StringBTSet<Q> = BTSet<String, Q, Ordable>;
+impl<Q> StringBTSet<Q> {
+    pub fn new(aruntime: &Arc<Mutex<Runtime<Q>>>,
+               obj_id: i32,
+               data: BTreeSet<String>)
+               -> StringBTSet<Q> {
+        BTSet::from(aruntime,
+                    obj_id,
+                    data,
+                    SimpleConverter::new(ConvertersLib::encodable_from_ordable(),
+                                         ConvertersLib::ordable_from_encodable()))
+    }
+}
+
+// Encrypted StringBTSet, to be used by VM
+// Supports Ordable encryption for elements
+pub type EncBTSet<Q> = BTSet<Ordable, Q, Ordable>;
+impl<Q> EncBTSet<Q> {
+    pub fn new(aruntime: &Arc<Mutex<Runtime<Q>>>,
+               obj_id: i32,
+               data: BTreeSet<Ordable>)
+               -> EncBTSet<Q> {
+        BTSet::from(aruntime,
+                    obj_id,
+                    data,
+                    SimpleConverter::new(ConvertersLib::ordable_from_ordable(),
+                                         ConvertersLib::ordable_from_ordable()))
+    }
+}
+
+// Unencrypted StringBTSet, to be used by benchmark
+// Encryption is replaced by identity function
+pub type UnencBTSet<Q> = BTSet<String, Q, String>;
+impl<Q> UnencBTSet<Q> {
+    pub fn new(aruntime: &Arc<Mutex<Runtime<Q>>>,
+               obj_id: i32,
+               data: BTreeSet<String>)
+               -> UnencBTSet<Q> {
+        BTSet::from(aruntime,
+                    obj_id,
+                    data,
+                    SimpleConverter::new(ConvertersLib::encodable_from_encodable(),
+                                         ConvertersLib::encodable_from_encodable()))
+    }
+}
+
+// Class: BTSet
+// Parametrized by:
+// * T : element type
+// * Q : structure allowing seamless communicating with Shared Log
+// * TE : encrypted element type
+#[derive(Clone)]
+pub struct BTSet<T, Q, TE> {
+    runtime: Option<Arc<Mutex<Runtime<Q>>>>, // runtime object is registered with
+    obj_id: i32, // unique id
+
+    convert_ord: Option<SimpleConverter<T, TE>>, // converter between data states
+
+    secure: Option<MetaEncryptor>, // structure to allow use of existing Encryptors/ Decryptors
+    pub data: Arc<Mutex<BTreeSet<T>>>, // local data structure
+}
+
+impl<T, Q, TE> Decodable for BTSet<T, Q, TE>
+    where T: Encodable + Decodable + Ord,
+          TE: Encodable + Decodable + Ord
+{
+    fn decode<D: Decoder>(d: &mut D) -> Result<Self, D::Error> {
+        let data = try!(Decodable::decode(d));
+        let btset: BTSet<T, Q, TE> = BTSet::default(data);
+        let res: Result<Self, D::Error> = Ok(btset);
+        return res;
+    }
+}
+
+impl<T, Q, TE> Encodable for BTSet<T, Q, TE>
+    where T: Encodable + Decodable + Ord,
+          TE: Encodable + Decodable + Ord
+{
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let data = self.data.lock().unwrap();
+        data.encode(s)
+    }
+}
+
+impl<T, Q, TE> BTSet<T, Q, TE> {
+    pub fn from(aruntime: &Arc<Mutex<Runtime<Q>>>,
+                obj_id: i32,
+                data: BTreeSet<T>,
+                convert_ord: SimpleConverter<T, TE>)
+                -> BTSet<T, Q, TE> {
+        let btset = BTSet {
+            obj_id: obj_id,
+            runtime: Some(aruntime.clone()),
+            secure: aruntime.lock().unwrap().secure_for(obj_id),
+            data: Arc::new(Mutex::new(data)),
+            convert_ord: Some(convert_ord),
+        };
+        return btset;
+    }
+
+    fn default(data: BTreeSet<T>) -> BTSet<T, Q, TE> {
+        BTSet {
+            obj_id: 0,
+            runtime: None,
+            secure: None,
+            data: Arc::new(Mutex::new(data)),
+            convert_ord: None,
+        }
+    }
+}
+
+impl<T, Q, TE> BTSet<T, Q, TE>
+    where T: 'static + Ord + Send + Clone + Encodable + Decodable + Debug,
+          Q: 'static + IndexedQueue + Send + Clone,
+          TE: 'static + Ord + Send + Clone + Encodable + Decodable + Debug
+{
+    pub fn start(&mut self) {
+        replica::register(&self.runtime, self.obj_id, None, self.clone());
+    }
+
+    fn append(&self, op: SetOp<T>) -> Result<(), LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            // convert element to shared log state
+            let encrypted_op = match op {
+                SetOp::Insert{elem} => SetOp::Insert { elem: self.to_elem(elem) },
+                SetOp::Remove{elem} => SetOp::Remove { elem: self.to_elem(elem) },
+            };
+            let op = json::encode(&encrypted_op).unwrap();
+            runtime.try_append(self.obj_id, State::Encrypted(op.into_bytes()))
+        })
+    }
+
+    fn to_elem(&self, elem: T) -> TE {
+        self.convert_ord
+            .as_ref()
+            .map(|convert_ord| {
+                let to = &convert_ord.to;
+                to(&self.secure, elem)
+            })
+            .unwrap()
+    }
+
+    pub fn get_elem(&self, elem: TE) -> T {
+        // convert element from shared log state to local state
+        self.convert_ord
+            .as_ref()
+            .map(|convert_ord| {
+                let from = &convert_ord.from;
+                from(&self.secure, elem)
+            })
+            .unwrap()
+    }
+
+    // Syncs, returns smallest element in set
+    pub fn first(&self) -> Option<T> {
+        match self.try_first() {
+            Ok(v) => v,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Same as first, returns the integrity violation detected while syncing
+    pub fn try_first(&self) -> Result<Option<T>, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            let data = self.data.lock().unwrap();
+            Ok(data.iter().next().cloned())
+        })
+    }
+
+    pub fn callback(&mut self, op: Operation) {
+        replica::callback(self, &None, op);
+    }
+}
+
+impl<T, Q, TE> ReplicatedObject for BTSet<T, Q, TE>
+    where T: 'static + Ord + Send + Clone + Encodable + Decodable + Debug,
+          Q: 'static + IndexedQueue + Send + Clone,
+          TE: 'static + Ord + Send + Clone + Encodable + Decodable + Debug
+{
+    type Op = SetOp<TE>;
+    type Twin = BTreeSet<TE>;
+
+    fn apply(&mut self, _: &Option<MetaEncryptor>, op: SetOp<TE>) {
+        match op {
+            SetOp::Insert{elem} => {
+                let elem = self.get_elem(elem);
+                self.data.lock().unwrap().insert(elem);
+            }
+            SetOp::Remove{elem} => {
+                let elem = self.get_elem(elem);
+                self.data.lock().unwrap().remove(&elem);
+            }
+        }
+    }
+
+    fn restore(&mut self, _: &Option<MetaEncryptor>, twin: BTreeSet<TE>) {
+        let converted: BTreeSet<T> = twin.into_iter().map(|elem| self.get_elem(elem)).collect();
+        *self.data.lock().unwrap() = converted;
+    }
+}
+
+impl<T, Q, TE> ReplicatedSet<T> for BTSet<T, Q, TE>
+    where T: 'static + Ord + Send + Clone + Encodable + Decodable + Debug,
+          Q: 'static + IndexedQueue + Send + Clone,
+          TE: 'static + Ord + Send + Clone + Encodable + Decodable + Debug
+{
+    fn try_insert(&mut self, elem: T) -> Result<(), LogError> {
+        self.append(SetOp::Insert { elem: elem })
+    }
+
+    fn try_remove(&mut self, elem: T) -> Result<(), LogError> {
+        self.append(SetOp::Remove { elem: elem })
+    }
+
+    fn try_contains(&self, elem: &T) -> Result<bool, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok(self.data.lock().unwrap().contains(elem))
+        })
+    }
+
+    fn try_len(&self) -> Result<usize, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            Ok(self.data.lock().unwrap().len())
+        })
+    }
+
+    // elements are returned in order
+    fn try_iter(&self) -> Result<vec::IntoIter<T>, LogError> {
+        with_runtime(&self.runtime, |mut runtime| {
+            try!(runtime.try_sync(Some(self.obj_id)));
+            let data = self.data.lock().unwrap();
+            let elems: Vec<T> = data.iter().cloned().collect();
+            Ok(elems.into_iter())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ReplicatedSet, StringHSet, StringBTSet, UnencBTSet};
+    use std::collections::{HashSet, BTreeSet};
+    use std::sync::{Arc, Mutex};
+    use runtime::Runtime;
+    use indexed_queue::InMemoryQueue;
+    use encryptors::MetaEncryptor;
+
+    #[test]
+    fn hset_insert_remove() {
+        let q = InMemoryQueue::new();
+        let runtime: Runtime<InMemoryQueue> = Runtime::new(q, Some(MetaEncryptor::new()));
+        let aruntime = Arc::new(Mutex::new(runtime));
+        let obj_id = 1;
+        let mut hset = StringHSet::new(&aruntime, obj_id, HashSet::new());
+        hset.start();
+
+        let elems = vec!["h0", "h1", "h2", "alphabet", "h0rry"];
+        for elem in &elems {
+            hset.insert(String::from(*elem));
+            assert!(hset.contains(&String::from(*elem)));
+        }
+        // inserting an existing element does not change the set
+        hset.insert(String::from("h0"));
+        assert_eq!(hset.len(), elems.len());
+
+        hset.remove(String::from("h1"));
+        assert!(!hset.contains(&String::from("h1")));
+        assert_eq!(hset.len(), elems.len() - 1);
+        assert_eq!(hset.iter().count(), elems.len() - 1);
+    }
+
+    #[test]
+    fn btset_in_order() {
+        let q = InMemoryQueue::new();
+        let runtime: Runtime<InMemoryQueue> = Runtime::new(q, Some(MetaEncryptor::new()));
+        let aruntime = Arc::new(Mutex::new(runtime));
+        let obj_id = 1;
+        let mut btset = StringBTSet::new(&aruntime, obj_id, BTreeSet::new());
+        btset.start();
+
+        let elems = vec!["h0", "h1", "h2", "alphabet", "h0rry"];
+        let should_be_at = vec![3, 0, 4, 1, 2];
+        for elem in &elems {
+            btset.insert(String::from(*elem));
+        }
+        let sorted: Vec<String> = btset.iter().collect();
+        for i in 0..elems.len() {
+            assert_eq!(sorted[i], elems[should_be_at[i]]);
+        }
+
+        btset.remove(String::from("alphabet"));
+        assert_eq!(btset.first().unwrap(), "h0");
+        assert_eq!(btset.len(), elems.len() - 1);
+    }
+
+    #[test]
+    fn btset_unenc() {
+        let q = InMemoryQueue::new();
+        let runtime: Runtime<InMemoryQueue> = Runtime::new(q, Some(MetaEncryptor::new()));
+        let aruntime = Arc::new(Mutex::new(runtime));
+        let mut btset = UnencBTSet::new(&aruntime, 1, BTreeSet::new());
+        btset.start();
+
+        btset.insert(String::from("b"));
+        btset.insert(String::from("a"));
+        assert!(btset.contains(&String::from("a")));
+        btset.remove(String::from("a"));
+        assert!(!btset.contains(&String::from("a")));
+        assert_eq!(btset.first().unwrap(), "b");
+    }
+}
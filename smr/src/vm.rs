@@ -1,29 +1,55 @@
 extern crate rustc_serialize;
 extern crate chan;
 
+// Default snapshot policy: snapshot every NENTRIES_PER_SNAP entries,
+// keep GC_LAG entries behind the snapshot in the skiplist
 const NENTRIES_PER_SNAP: usize = 100;
+const GC_LAG: LogIndex = 50;
+const POLL_MS: u64 = 10;
 
-use std::collections::{HashMap, HashSet};
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicBool, AtomicUsize};
-use std::sync::atomic::Ordering::{Acquire, Release, SeqCst};
+use std::collections::{HashMap, HashSet, BTreeMap, BTreeSet, VecDeque, BinaryHeap};
+use std::cmp;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::{Acquire, Release};
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::sync::mpsc;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 
-use indexed_queue::{IndexedQueue, ObjId, LogIndex, Operation, Entry, LogData, Snapshot};
-use runtime::{Runtime, Callback};
+use indexed_queue::{IndexedQueue, SharedLog, ObjId, LogIndex, Operation, Entry, LogData, Snapshot,
+                    AllowList};
+use runtime::{Runtime, Callback, LogError};
+use replica::{Replica, ReplicatedObject};
+use directory;
+use directory::{Directory, DirectoryEntry, DIRECTORY};
+use maps::{Searchable, EncHMap, EncBTMap, UnencBTMap};
+use sets::{EncHSet, EncBTSet};
+use ds::{EncList, EncQueue, AddableRegister, EncPriorityQueue, EncLock, AddableGCounter,
+         AddablePNCounter};
+use encryptors::{SearchToken, WriterId, Addable};
 use indexed_queue::State::Encoded;
 
 use self::chan::{Sender, Receiver, WaitGroup};
 
 use self::SnapshotOp::*;
 use self::rustc_serialize::{json, Encodable};
+use self::rustc_serialize::hex::ToHex;
+use openssl::crypto::hash;
+
+// Last entry of each writer (by writer id of its integrity) on each object
+// Snapshots carry those of their object, so readers can resume its hash chains
+pub type ChainEnds = HashMap<ObjId, HashMap<u64, Entry>>;
 
 #[derive(Debug)]
 enum SnapshotOp {
-    SnapshotRequest(WaitGroup, LogIndex),
+    // index, number of objects snapshotted, last entries of the object's writers
+    SnapshotRequest(WaitGroup, LogIndex, usize, Vec<Entry>),
     LogOp(LogIndex, Operation),
     Stop,
 }
@@ -50,22 +76,22 @@ pub trait Skiplist {
 // A skiplist is a list of log indicies relevant to a particular object
 #[derive(Clone)]
 pub struct MapSkiplist {
-    skiplist: Arc<Mutex<HashMap<ObjId, HashSet<LogIndex>>>>, // Map from object_id to skiplist
+    skiplist: Arc<RwLock<HashMap<ObjId, HashSet<LogIndex>>>>, // Map from object_id to skiplist
 }
 
 impl MapSkiplist {
     pub fn new() -> MapSkiplist {
-        MapSkiplist { skiplist: Arc::new(Mutex::new(HashMap::new())) }
+        MapSkiplist { skiplist: Arc::new(RwLock::new(HashMap::new())) }
     }
 }
 
 impl Skiplist for MapSkiplist {
     fn insert(&mut self, obj_id: ObjId) {
-        let mut skiplist = self.skiplist.lock().unwrap();
+        let mut skiplist = self.skiplist.write().unwrap();
         skiplist.insert(obj_id, HashSet::new());
     }
     fn append(&mut self, obj_id: ObjId, idx: LogIndex) {
-        let mut skiplist = self.skiplist.lock().unwrap();
+        let mut skiplist = self.skiplist.write().unwrap();
         skiplist.get_mut(&obj_id).unwrap().insert(idx);
     }
 
@@ -83,7 +109,7 @@ impl Skiplist for MapSkiplist {
               to: Option<LogIndex>)
               -> Vec<LogIndex> {
         let mut set: HashSet<LogIndex> = HashSet::new();
-        let skiplist = self.skiplist.lock().unwrap();
+        let skiplist = self.skiplist.read().unwrap();
         for obj in skiplist.keys() {
             if obj_ids.contains(obj) {
                 for &idx in &skiplist[obj] {
@@ -100,7 +126,7 @@ impl Skiplist for MapSkiplist {
 
     // GC entries in skiplist periodically (as snapshotting makes them redundant)
     fn gc(&mut self, idx: LogIndex) {
-        let mut skiplist = self.skiplist.lock().unwrap();
+        let mut skiplist = self.skiplist.write().unwrap();
         let mut new_skiplist = HashMap::new();
         for (obj_id, set) in skiplist.drain() {
             let mut keep = HashSet::new();
@@ -115,6 +141,103 @@ impl Skiplist for MapSkiplist {
     }
 }
 
+// Class: SortedSkiplist
+// Per object skiplist kept as a sorted vector of log indicies
+// Entries are appended in log order, so appends are pushes and
+// range bounds are found by binary search
+#[derive(Clone)]
+pub struct SortedSkiplist {
+    skiplist: Arc<RwLock<HashMap<ObjId, Vec<LogIndex>>>>, // Map from object_id to sorted skiplist
+}
+
+impl SortedSkiplist {
+    pub fn new() -> SortedSkiplist {
+        SortedSkiplist { skiplist: Arc::new(RwLock::new(HashMap::new())) }
+    }
+}
+
+// position of the first index >= idx in sorted list
+fn lower_bound(list: &[LogIndex], idx: LogIndex) -> usize {
+    match list.binary_search(&idx) {
+        Ok(pos) => pos,
+        Err(pos) => pos,
+    }
+}
+
+impl Skiplist for SortedSkiplist {
+    fn insert(&mut self, obj_id: ObjId) {
+        let mut skiplist = self.skiplist.write().unwrap();
+        skiplist.insert(obj_id, Vec::new());
+    }
+    fn append(&mut self, obj_id: ObjId, idx: LogIndex) {
+        let mut skiplist = self.skiplist.write().unwrap();
+        let list = skiplist.get_mut(&obj_id).unwrap();
+        if list.last().map_or(true, |&last| last < idx) {
+            list.push(idx);
+        } else if let Err(pos) = list.binary_search(&idx) {
+            // out of order append, e.g. preloaded entries of a late registered object
+            list.insert(pos, idx);
+        }
+    }
+
+    // Method: Stream
+    // Arguments:
+    // * obj_ids: ids of objects we want to stream entries for
+    // * from: initial log index to start streaming
+    // * to: final log index for streaming, or None if we want to stream till the end
+    // Merges the (from, to) range of each object's skiplist,
+    // entries concerning several objects are returned once
+    // Returns:
+    // * sorted vector of indices
+    fn stream(&self,
+              obj_ids: &HashSet<ObjId>,
+              from: LogIndex,
+              to: Option<LogIndex>)
+              -> Vec<LogIndex> {
+        let skiplist = self.skiplist.read().unwrap();
+        let ranges: Vec<&[LogIndex]> = obj_ids.iter()
+                                              .filter_map(|obj| skiplist.get(obj))
+                                              .map(|list| {
+                                                  let lo = lower_bound(list, from);
+                                                  let hi = to.map_or(list.len(), |to| {
+                                                      lower_bound(list, to)
+                                                  });
+                                                  &list[lo..cmp::max(lo, hi)]
+                                              })
+                                              .filter(|range| !range.is_empty())
+                                              .collect();
+        if ranges.len() == 1 {
+            return ranges[0].to_vec();
+        }
+
+        // k-way merge, heap is a max heap so indicies are negated
+        let mut heap = BinaryHeap::new();
+        for (k, range) in ranges.iter().enumerate() {
+            heap.push((-range[0], k, 0));
+        }
+        let mut v: Vec<LogIndex> = Vec::new();
+        while let Some((neg_idx, k, pos)) = heap.pop() {
+            let idx = -neg_idx;
+            if v.last() != Some(&idx) {
+                v.push(idx);
+            }
+            if pos + 1 < ranges[k].len() {
+                heap.push((-ranges[k][pos + 1], k, pos + 1));
+            }
+        }
+        v
+    }
+
+    // GC entries in skiplist periodically (as snapshotting makes them redundant)
+    fn gc(&mut self, idx: LogIndex) {
+        let mut skiplist = self.skiplist.write().unwrap();
+        for list in skiplist.values_mut() {
+            let pos = lower_bound(list, idx);
+            list.drain(..pos);
+        }
+    }
+}
+
 pub trait Snapshotter {
     // Register object obj_id with Snapshotter, to be kept track of
     // Provide an empty object T, to construct a snapshotted object in
@@ -124,13 +247,49 @@ pub trait Snapshotter {
                                                       mut callback: Box<Callback>,
                                                       obj: T);
     // Snapshot all objects, as of and including the idx
-    fn snapshot(&mut self, idx: LogIndex);
+    // ends are the last entries of each writer up to idx, each snapshot carries its object's
+    // versions are the runtime's object versions as of idx, persistent snapshotters keep them
+    // Takes &self so streams can read snapshots while one is taken
+    fn snapshot(&self, idx: LogIndex, ends: &ChainEnds, versions: &HashMap<ObjId, LogIndex>);
     // Get most recent snapshots for objects in obj_ids
     fn get_snapshots(&self, obj_ids: &HashSet<ObjId>) -> HashMap<ObjId, Snapshot>;
     // Sends log operation and index to obj_id object to be applied
     fn exec(&mut self, obj_id: ObjId, idx: LogIndex, op: Operation);
     // Starts main thread that listens for snapshotting requests
+    // Objects may still be registered once started
     fn start(&mut self);
+    // Record type tag of obj_id, persistent snapshotters keep it to re-create the object
+    fn set_type_tag(&mut self, _: ObjId, _: &str) {}
+    // Most recent persisted checkpoint, to resume from on startup
+    fn recover(&mut self) -> Option<Checkpoint> {
+        None
+    }
+    // Stops and joins threads, snapshots taken so far remain readable
+    fn shutdown(&mut self) {}
+    // Index of the oldest snapshot still kept, entries below it are no longer needed
+    // None if only the most recent snapshot is kept
+    fn retained(&self) -> Option<LogIndex> {
+        None
+    }
+}
+
+// Class: Checkpoint
+// Snapshots of all objects as of idx, as persisted by a Snapshotter
+// Skiplist entries up to idx are redundant once snapshotted,
+// so the skiplist is checkpointed as the objects it tracks
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug)]
+pub struct Checkpoint {
+    pub idx: LogIndex, // log index snapshots are taken at
+    pub snapshots: Vec<Snapshot>, // snapshot of every object
+    pub objects: Vec<(ObjId, Option<String>)>, // objects tracked, with their type tag if known
+    pub versions: Vec<(ObjId, LogIndex)>, // runtime versions of objects, transactions check them
+}
+
+// Checkpoint as written to disk, body is the json encoded Checkpoint
+#[derive(RustcEncodable, RustcDecodable)]
+struct CheckpointFile {
+    checksum: String, // hex encoded SHA256 of body
+    body: String,
 }
 
 // Class: AsyncSnapshotter
@@ -142,8 +301,8 @@ pub trait Snapshotter {
 pub struct AsyncSnapshotter {
     snapshots: Arc<Mutex<HashMap<ObjId, Snapshot>>>, // per object most recent snapshot
     obj_chan: HashMap<ObjId, Sender<SnapshotOp>>, // per object send channel
-    snapshots_tx: Sender<Option<(WaitGroup, Snapshot)>>, // for objects send their snapshot to main thread
-    snapshots_rx: Receiver<Option<(WaitGroup, Snapshot)>>, /* for main thread to receive and aggregate snapshots */
+    snapshots_tx: Sender<Option<(WaitGroup, usize, Snapshot)>>, // for objects send their snapshot to main thread
+    snapshots_rx: Receiver<Option<(WaitGroup, usize, Snapshot)>>, /* for main thread to receive and aggregate snapshots */
     threads: Arc<Mutex<Vec<JoinHandle<()>>>>, // one thread per object and a main thread
 }
 
@@ -175,10 +334,11 @@ impl Snapshotter for AsyncSnapshotter {
             // messages for the object
             while let Some(msg) = obj_chan_rx.recv() {
                 match msg {
-                    SnapshotRequest(wg, idx) => {
+                    SnapshotRequest(wg, idx, n, heads) => {
                         let snap = json::encode(&obj).unwrap();
+                        let snap = Snapshot::new(obj_id, idx, Encoded(snap)).with_heads(heads);
                         // send the snapshot to the snapshot aggregator/sender (main thread)
-                        snapshots_tx.send(Some((wg, Snapshot::new(obj_id, idx, Encoded(snap)))));
+                        snapshots_tx.send(Some((wg, n, snap)));
                     }
                     LogOp(idx, op) => {
                         callback(idx, op);
@@ -193,14 +353,13 @@ impl Snapshotter for AsyncSnapshotter {
 
     fn start(&mut self) {
         let snapshots_rx = self.snapshots_rx.clone();
-        let n_objects = self.obj_chan.len();
         let snapshots = self.snapshots.clone();
 
         // main Snapshotter thread
         self.threads.lock().unwrap().push(thread::spawn(move || {
             let mut idx_snapshots: HashMap<LogIndex, Vec<Snapshot>> = HashMap::new();
             // Listen to the channel of snapshots.
-            while let Some(Some((wg, s))) = snapshots_rx.recv() {
+            while let Some(Some((wg, n_objects, s))) = snapshots_rx.recv() {
                 // Aggregate a vector of snapshots on a per index basis
                 let idx = s.idx;
                 if !idx_snapshots.contains_key(&idx) {
@@ -229,13 +388,19 @@ impl Snapshotter for AsyncSnapshotter {
         self.obj_chan[&obj_id].send(LogOp(idx, op));
     }
 
-    fn snapshot(&mut self, idx: LogIndex) {
+    fn snapshot(&self, idx: LogIndex, ends: &ChainEnds, _: &HashMap<ObjId, LogIndex>) {
         let wg = chan::WaitGroup::new();
-        for chan in self.obj_chan.values() {
+        let n_objects = self.obj_chan.len();
+        for (obj_id, chan) in self.obj_chan.iter() {
             // get snapshot for each object
             wg.add(1);
             let wg = wg.clone();
-            chan.send(SnapshotRequest(wg, idx));
+            let mut heads: Vec<(u64, Entry)> = ends.get(obj_id).map_or(Vec::new(), |ends| {
+                ends.iter().map(|(&writer, e)| (writer, e.clone())).collect()
+            });
+            heads.sort_by(|a, b| a.0.cmp(&b.0));
+            let heads = heads.into_iter().map(|(_, e)| e).collect();
+            chan.send(SnapshotRequest(wg, idx, n_objects, heads));
         }
         // wait for all the snapshots to complete
         wg.wait();
@@ -251,10 +416,13 @@ impl Snapshotter for AsyncSnapshotter {
         }
         return target;
     }
-}
 
-impl Drop for AsyncSnapshotter {
-    fn drop(&mut self) {
+    fn shutdown(&mut self) {
+        let mut threads = self.threads.lock().unwrap();
+        if threads.is_empty() {
+            // stopped already
+            return;
+        }
         // Tell all per object threads threads to stop (register_object) -> snapshots_tx will close
         for chan in self.obj_chan.values() {
             chan.send(Stop);
@@ -262,277 +430,1508 @@ impl Drop for AsyncSnapshotter {
         // close tx, to allow main snapshotting thread to return
         self.snapshots_tx.send(None);
         // wait for threads
-        let mut threads = self.threads.lock().unwrap();
         for thread in threads.drain(..) {
             thread.join().unwrap();
         }
     }
 }
 
-// Class: VM
-// Responsible for snapshotting, streaming, and creating the skip list
-// Starts one thread that is constantly polling the queue and using new entries to construct skip list.
-// Starts the Snapshotter
-// Accepts and handles stream requests
+impl Drop for AsyncSnapshotter {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// Class: FileSnapshotter
+// AsyncSnapshotter persisting a checkpoint to a local directory after every snapshot
+// Checkpoints are handed to a writer thread, so snapshotting does not wait on the disk
+// Checkpoints are written to a temporary file then renamed, so a crash never leaves a partial one
+// Only the keep most recent checkpoints are retained
 #[derive(Clone)]
-pub struct VM<Q: IndexedQueue + Send,
-              Skip: Skiplist + Clone + Send,
-              Snap: Snapshotter + Clone + Send>
-{
-    pub runtime: Arc<Mutex<Runtime<Q>>>, // VM runtime, same as Client VM, but works with encrypted data
-    obj_id: Vec<ObjId>, // ids of objects registered with VM
-    local_queue: Arc<Mutex<HashMap<LogIndex, Entry>>>, // cached SharedLog
-    skiplist: Arc<Mutex<Skip>>, // skiplist
-    snapshotter: Arc<Mutex<Snap>>, // snapshotter
-    threads: Arc<Mutex<Vec<JoinHandle<()>>>>, // threads spawned by VM
-    stop: Arc<AtomicBool>, // used to stop polling thread
-    queue: Q, // queue interface that allows communication with client
+pub struct FileSnapshotter {
+    inner: AsyncSnapshotter, // takes the snapshots
+    dir: CheckpointDir, // directory checkpoints are written to
+    types: Arc<Mutex<HashMap<ObjId, String>>>, // type tags of objects
+    checkpoints_tx: Sender<Option<Checkpoint>>, // to writer thread, None stops it
+    checkpoints_rx: Receiver<Option<Checkpoint>>, // for writer thread
+    writer: Arc<Mutex<Option<JoinHandle<()>>>>, // writer thread, once started
 }
 
-impl<Q, Skip, Snap> VM<Q, Skip, Snap>
-    where Q: 'static + IndexedQueue + Clone + Send,
-          Skip: 'static + Skiplist + Clone + Send,
-          Snap: 'static + Snapshotter + Clone + Send
-{
-    pub fn new(q: Q, skiplist: Skip, snapshotter: Snap) -> VM<Q, Skip, Snap> {
-        let queue = q.clone();
-        let vm = VM {
-            runtime: Arc::new(Mutex::new(Runtime::new(q, None))),
-            obj_id: Vec::new(),
-            local_queue: Arc::new(Mutex::new(HashMap::new())),
-            skiplist: Arc::new(Mutex::new(skiplist)),
-            snapshotter: Arc::new(Mutex::new(snapshotter)),
-            threads: Arc::new(Mutex::new(Vec::new())),
-            stop: Arc::new(AtomicBool::new(false)),
-            queue: queue,
-        };
-        return vm;
+// Directory checkpoints are kept in
+#[derive(Clone)]
+struct CheckpointDir {
+    dir: PathBuf, // directory checkpoints are written to
+    keep: usize, // number of checkpoints retained
+}
+
+impl FileSnapshotter {
+    pub fn new<P: AsRef<Path>>(dir: P, keep: usize) -> FileSnapshotter {
+        assert!(keep > 0, "at least one checkpoint must be kept");
+        fs::create_dir_all(dir.as_ref()).expect("error creating checkpoint directory");
+        let (checkpoints_tx, checkpoints_rx) = chan::async();
+        FileSnapshotter {
+            inner: AsyncSnapshotter::new(),
+            dir: CheckpointDir {
+                dir: dir.as_ref().to_path_buf(),
+                keep: keep,
+            },
+            types: Arc::new(Mutex::new(HashMap::new())),
+            checkpoints_tx: checkpoints_tx,
+            checkpoints_rx: checkpoints_rx,
+            writer: Arc::new(Mutex::new(None)),
+        }
     }
+}
 
-    pub fn start(&mut self) {
-        // number of log entries seen
-        let seen = Box::new(Arc::new(AtomicUsize::new(0)));
-        {
-            let snapshotter = self.snapshotter.clone();
-            let skiplist = self.skiplist.clone();
-            let seen = seen.clone();
-            let local_queue = self.local_queue.clone();
+impl CheckpointDir {
+    fn path(&self, idx: LogIndex) -> PathBuf {
+        self.dir.join(format!("checkpoint-{:020}.json", idx))
+    }
 
-            // Pre_hook to be called before the main object callbacks
-            // Makes sure entry exists in local_queue
-            let pre_hook = Box::new(move |entry: Entry| {
-                // Ensure log entry exists in local queue
-                let idx = entry.idx.unwrap();
-                let mut local_queue = local_queue.lock().unwrap();
-                local_queue.insert(idx, entry);
-            });
+    // indices of checkpoints in directory, most recent first, none if it cannot be read
+    fn checkpoints(&self) -> Vec<LogIndex> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        let mut idxs: Vec<LogIndex> = entries.filter_map(|e| e.ok())
+                                      .filter_map(|e| {
+                                          let name = e.file_name();
+                                          let name = name.to_string_lossy();
+                                          if name.starts_with("checkpoint-") &&
+                                             name.ends_with(".json") {
+                                              name[11..name.len() - 5].parse().ok()
+                                          } else {
+                                              None
+                                          }
+                                      })
+                                      .collect();
+        idxs.sort_by(|a, b| b.cmp(a));
+        idxs
+    }
 
-            // Post_hook to be called after main object callbacks
-            // Sees if we have enough entries to require snapshot
-            let post_hook = Box::new(move |entry: Entry| {
-                let idx = entry.idx.unwrap();
-                let seen = seen.fetch_add(1, SeqCst);
-                if (seen + 1) % NENTRIES_PER_SNAP == 0 {
-                    // Time for a snapshot
-                    snapshotter.lock().unwrap().snapshot(idx);
-                    // Remove now redundant entries from skiplist
-                    skiplist.lock().unwrap().gc(idx - 50);
-                    // Gc local queue if it grows too large (not yet needed)
-                    // let mut new_queue = HashMap::new();
-                    // for (i, entry) in local_queue.drain() {
-                    // if i >= idx {
-                    // new_queue.insert(i, entry);
-                    // }
-                    // }
-                    // local_queue = new_queue;
+    // Method: write
+    // Writes checkpoint, then removes all but the keep most recent ones
+    // On error, the previous checkpoints are left as they were
+    fn write(&self, checkpoint: &Checkpoint) -> io::Result<()> {
+        let body = json::encode(checkpoint).unwrap();
+        let file = CheckpointFile {
+            checksum: hash::hash(hash::Type::SHA256, body.as_bytes()).to_hex(),
+            body: body,
+        };
+        let path = self.path(checkpoint.idx);
+        let tmp = path.with_extension("tmp");
+        let written = File::create(&tmp).and_then(|mut f| {
+            try!(f.write_all(json::encode(&file).unwrap().as_bytes()));
+            f.sync_all()
+        });
+        if let Err(err) = written.and_then(|_| fs::rename(&tmp, &path)) {
+            let _ = fs::remove_file(&tmp);
+            return Err(err);
+        }
 
-                }
-            });
+        // retain the keep most recent checkpoints
+        for idx in self.checkpoints().into_iter().skip(self.keep) {
+            let _ = fs::remove_file(self.path(idx));
+        }
+        Ok(())
+    }
 
-            self.runtime.lock().unwrap().register_pre_callback(pre_hook);
-            self.runtime.lock().unwrap().register_post_callback(post_hook);
+    // Method: read
+    // Reads checkpoint idx
+    // Returns:
+    // * checkpoint, or None if it is unreadable or its checksum does not match
+    fn read(&self, idx: LogIndex) -> Option<Checkpoint> {
+        let mut data = String::new();
+        if File::open(self.path(idx)).and_then(|mut f| f.read_to_string(&mut data)).is_err() {
+            return None;
+        }
+        let file: CheckpointFile = match json::decode(&data) {
+            Ok(file) => file,
+            Err(_) => return None,
+        };
+        if hash::hash(hash::Type::SHA256, file.body.as_bytes()).to_hex() != file.checksum {
+            return None;
         }
+        json::decode(&file.body).ok()
+    }
+}
 
-        self.snapshotter.lock().unwrap().start();
-        self.poll_runtime();
+impl Snapshotter for FileSnapshotter {
+    fn register_object<T: 'static + Encodable + Send>(&mut self,
+                                                      obj_id: ObjId,
+                                                      callback: Box<Callback>,
+                                                      obj: T) {
+        self.inner.register_object(obj_id, callback, obj);
     }
 
-    // Poll runtime for updates every x milliseconds
-    fn poll_runtime(&mut self) {
-        let runtime = self.runtime.clone();
-        let stop = self.stop.clone();
-        self.threads.lock().unwrap().push(thread::spawn(move || {
-            loop {
-                if stop.load(Acquire) {
-                    // notice to stop
-                    return;
-                }
-                // sync all of the objects
-                runtime.lock().unwrap().sync(None);
-                Duration::from_millis(1000);
-            }
-        }));
+    fn snapshot(&self, idx: LogIndex, ends: &ChainEnds, versions: &HashMap<ObjId, LogIndex>) {
+        self.inner.snapshot(idx, ends, versions);
+        let snapshots: Vec<Snapshot> = {
+            let snapshots = self.inner.snapshots.lock().unwrap();
+            snapshots.values().filter(|s| s.idx == idx).cloned().collect()
+        };
+        let types = self.types.lock().unwrap();
+        let objects = self.inner
+                          .obj_chan
+                          .keys()
+                          .map(|obj_id| (*obj_id, types.get(obj_id).cloned()))
+                          .collect();
+        let checkpoint = Checkpoint {
+            idx: idx,
+            snapshots: snapshots,
+            objects: objects,
+            versions: versions.iter().map(|(obj_id, version)| (*obj_id, *version)).collect(),
+        };
+        self.checkpoints_tx.send(Some(checkpoint));
     }
 
-    // Register object obj_id with VM, to be kept track of
-    // Provide an empty object Snapshottable, to construct a snapshotted object in
-    // and a callback that handles applying log entry operations to the object
-    pub fn register_object<Snapshottable: 'static + Encodable + Send>(&mut self,
-                                                                      obj_id: ObjId,
-                                                                      callback: Box<Callback>,
-                                                                      obj: Snapshottable) {
-        // insert/ register object
-        self.obj_id.push(obj_id);
-        self.skiplist.lock().unwrap().insert(obj_id);
-        self.snapshotter.lock().unwrap().register_object(obj_id, callback, obj);
+    fn get_snapshots(&self, obj_ids: &HashSet<ObjId>) -> HashMap<ObjId, Snapshot> {
+        self.inner.get_snapshots(obj_ids)
+    }
 
-        // cloned arc references callback is closed over
-        let skiplist = self.skiplist.clone();
-        let snapshotter = self.snapshotter.clone();
-        // VM version of object callback
-        let cb = Box::new(move |idx, op: Operation| {
-            // Add this index to the skiplist
-            skiplist.lock().unwrap().append(obj_id, idx);
-            // Execute this entry on the snapshotter for this object
-            snapshotter.lock().unwrap().exec(obj_id, idx, op.clone());
-        });
-        // Register object with VM's Runtime
-        self.runtime.lock().unwrap().register_object(obj_id, cb);
+    fn exec(&mut self, obj_id: ObjId, idx: LogIndex, op: Operation) {
+        self.inner.exec(obj_id, idx, op);
     }
-}
 
-impl<Q, Skip, Snap> IndexedQueue for VM<Q, Skip, Snap>
-    where Q: IndexedQueue + Send + Clone,
-          Skip: Skiplist + Clone + Send,
-          Snap: Snapshotter + Clone + Send
-{
-    fn append(&mut self, e: Entry) -> LogIndex {
-        self.queue.append(e)
+    fn start(&mut self) {
+        self.inner.start();
+
+        // writer thread, writes checkpoints in the order they were taken
+        let checkpoints_rx = self.checkpoints_rx.clone();
+        let dir = self.dir.clone();
+        *self.writer.lock().unwrap() = Some(thread::spawn(move || {
+            while let Some(Some(checkpoint)) = checkpoints_rx.recv() {
+                // snapshots are still served from memory, a restart resumes from an older one
+                if let Err(err) = dir.write(&checkpoint) {
+                    let _ = writeln!(io::stderr(),
+                                     "error writing checkpoint {}: {}",
+                                     checkpoint.idx,
+                                     err);
+                }
+            }
+        }));
     }
 
-    fn stream(&mut self,
-              obj_ids: &HashSet<ObjId>,
-              mut from: LogIndex,
-              to: Option<LogIndex>)
-              -> mpsc::Receiver<LogData> {
-        use indexed_queue::LogData::{LogEntry, LogSnapshot};
-        // channel to communicate with client
-        let (tx, rx) = mpsc::channel();
+    fn set_type_tag(&mut self, obj_id: ObjId, type_tag: &str) {
+        self.types.lock().unwrap().insert(obj_id, String::from(type_tag));
+    }
 
-        // acquire and send most recent object snaps
-        let snaps = self.snapshotter.lock().unwrap().get_snapshots(obj_ids);
-        let mut new_from = from;
-        for (_, snapshot) in snaps {
-            if from <= snapshot.idx && (to.is_none() || snapshot.idx < to.unwrap()) {
-                new_from = snapshot.idx + 1; // all snapshots are guaranteed to have the same index
-                tx.send(LogSnapshot(snapshot)).unwrap();
+    // most recent checkpoint passing its checksum, its snapshots are served until the next one
+    fn recover(&mut self) -> Option<Checkpoint> {
+        for idx in self.dir.checkpoints() {
+            match self.dir.read(idx) {
+                Some(checkpoint) => {
+                    let mut snapshots = self.inner.snapshots.lock().unwrap();
+                    for s in &checkpoint.snapshots {
+                        snapshots.insert(s.obj_id, s.clone());
+                    }
+                    let mut types = self.types.lock().unwrap();
+                    for &(obj_id, ref type_tag) in &checkpoint.objects {
+                        type_tag.as_ref().map(|t| types.insert(obj_id, t.clone()));
+                    }
+                    return Some(checkpoint);
+                }
+                None => {
+                    let _ = writeln!(io::stderr(), "skipping corrupt checkpoint: {}", idx);
+                }
             }
         }
+        None
+    }
 
-        // send log entries appended after most recent snap
-        from = new_from;
-        let idxs = self.skiplist.lock().unwrap().stream(obj_ids, from, to);
-        for idx in idxs {
-            if idx < from {
-                continue;
-            }
-            let local_queue = self.local_queue.lock().unwrap();
-            let entry = local_queue.get(&idx)
-                                   .expect(&format!("skiplist entries should be in local log: {}",
-                                                    idx))
-                                   .clone();
-            tx.send(LogEntry(entry)).unwrap();
+    // checkpoints taken so far are written before the writer thread is joined
+    fn shutdown(&mut self) {
+        self.inner.shutdown();
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            self.checkpoints_tx.send(None);
+            writer.join().unwrap();
         }
-        return rx;
+    }
+
+    // oldest checkpoint on disk, a restart may resume from it
+    fn retained(&self) -> Option<LogIndex> {
+        self.dir.checkpoints().last().cloned()
     }
 }
 
-impl<Q, Skip, Snap> Drop for VM<Q, Skip, Snap>
-    where Q: IndexedQueue + Send,
-          Skip: Skiplist + Clone + Send,
-          Snap: Snapshotter + Clone + Send
-{
+impl Drop for FileSnapshotter {
     fn drop(&mut self) {
-        self.stop.store(true, Release); // stop polling thread
-        {
-            let mut threads = self.threads.lock().unwrap();
-            for t in threads.drain(..) {
-                t.join().unwrap();
-            }
-            self.runtime.lock().unwrap().stop();
-        }
-        // Snapshotter stops on drop snapshotter
+        self.shutdown();
     }
 }
 
-#[cfg(test)]
-mod test {
-    extern crate rustc_serialize;
-    use self::rustc_serialize::json;
-    use super::{VM, Skiplist, MapSkiplist, Snapshotter, AsyncSnapshotter};
+// Class: SnapshotPolicy
+// When VM snapshots its objects, a snapshot is taken as soon as any threshold set is reached
+// Thresholds are checked as entries are applied, the interval also by the polling thread
+// An idle log is never snapshotted
+#[derive(Clone, Debug)]
+pub struct SnapshotPolicy {
+    pub entries: Option<usize>, // snapshot every N entries
+    pub interval: Option<Duration>, // snapshot entries applied T after last snapshot
+    pub replay_bytes: Option<usize>, // snapshot once payloads since last snapshot weigh B bytes
+    pub per_object: HashMap<ObjId, usize>, // snapshot once an object has N entries since last one
+    pub gc_lag: LogIndex, // number of entries kept in skiplist and local queue behind a snapshot
+    // entries trimmed from local queue are spilled there, or dropped
+    // spilled entries are kept down to the oldest snapshot retained by the snapshotter
+    pub spill: Option<PathBuf>,
+}
 
-    use indexed_queue::{SharedQueue, IndexedQueue, ObjId, Operation, LogOp, State};
-    use indexed_queue::LogData::LogEntry;
-    use indexed_queue::State::Encoded;
-    use runtime::Runtime;
-    use ds::{RegisterOp, IntRegister, AddableRegister};
-    use encryptors::{MetaEncryptor, Addable, AddEncryptor, EqEncryptor, Encryptor, OrdEncryptor};
+impl SnapshotPolicy {
+    // snapshot every NENTRIES_PER_SNAP entries
+    pub fn new() -> SnapshotPolicy {
+        SnapshotPolicy {
+            entries: Some(NENTRIES_PER_SNAP),
+            interval: None,
+            replay_bytes: None,
+            per_object: HashMap::new(),
+            gc_lag: GC_LAG,
+            spill: None,
+        }
+    }
 
-    use std::sync::{Arc, Mutex};
-    use std::thread::sleep;
-    use std::time::Duration;
+    // None disables the entry count threshold
+    pub fn with_entries(mut self, n: Option<usize>) -> SnapshotPolicy {
+        self.entries = n;
+        self
+    }
 
-    #[test]
-    fn skiplist_test() {
-        let mut skiplist = MapSkiplist::new();
-        let obj0 = vec![0, 2, 4, 5, 9];
-        let obj1 = vec![0, 1, 5, 7, 9, 10];
-        let objs = vec![obj0, obj1];
-        skiplist.insert(0);
-        skiplist.insert(1);
-        for obj in 0..objs.len() {
-            for &idx in &objs[obj] {
-                skiplist.append(obj as ObjId, idx);
-            }
+    // Checked on every poll of the log, entries applied since last snapshot are snapshotted
+    // within a poll of T passing, even if the log went idle
+    pub fn with_interval(mut self, t: Duration) -> SnapshotPolicy {
+        self.interval = Some(t);
+        self
+    }
+
+    // Counts the encoded/ encrypted operations of entries, not their integrity or signature
+    pub fn with_replay_bytes(mut self, b: usize) -> SnapshotPolicy {
+        self.replay_bytes = Some(b);
+        self
+    }
+
+    pub fn with_object(mut self, obj_id: ObjId, n: usize) -> SnapshotPolicy {
+        self.per_object.insert(obj_id, n);
+        self
+    }
+
+    pub fn with_gc_lag(mut self, lag: LogIndex) -> SnapshotPolicy {
+        self.gc_lag = lag;
+        self
+    }
+
+    pub fn with_spill<P: AsRef<Path>>(mut self, dir: P) -> SnapshotPolicy {
+        self.spill = Some(dir.as_ref().to_path_buf());
+        self
+    }
+}
+
+// Class: SnapshotStats
+// Counters of snapshots taken by VM, to tune recovery time against VM load
+#[derive(Clone, Debug)]
+pub struct SnapshotStats {
+    pub snapshots: u64, // number of snapshots taken
+    pub total_time: Duration, // time spent snapshotting
+    pub max_time: Duration, // longest snapshot
+    pub last_idx: Option<LogIndex>, // log index of most recent snapshot
+}
+
+impl SnapshotStats {
+    pub fn new() -> SnapshotStats {
+        SnapshotStats {
+            snapshots: 0,
+            total_time: Duration::new(0, 0),
+            max_time: Duration::new(0, 0),
+            last_idx: None,
         }
-        let stream = skiplist.stream(&[0, 1].iter().cloned().collect(), 0, Some(4));
-        assert_eq!(stream, [0, 1, 2]);
-        let stream = skiplist.stream(&[0, 1].iter().cloned().collect(), 1, Some(5));
-        assert_eq!(stream, [1, 2, 4]);
-        let stream = skiplist.stream(&[0].iter().cloned().collect(), 0, None);
-        assert_eq!(stream, [0, 2, 4, 5, 9]);
-        // gc test
-        skiplist.gc(4);
-        let stream = skiplist.stream(&[0, 1].iter().cloned().collect(), 0, None);
-        assert_eq!(stream, [4, 5, 7, 9, 10]);
     }
-    #[test]
-    fn snapshot_test() {
-        let q = SharedQueue::new();
-        let mut snapshotter = AsyncSnapshotter::new();
-        let runtime: Arc<Mutex<Runtime<SharedQueue>>> = Arc::new(Mutex::new(Runtime::new(q, None)));
-        let reg = IntRegister::new(&runtime, 0, 0);
-        let mut reg2 = reg.clone();
-        let n = 250;
 
-        snapshotter.register_object(0, Box::new(move |_, e| reg2.callback(e)), reg);
-        snapshotter.start();
-        for _ in 0..n {
-            let reg_op = RegisterOp::Inc { add: 2 };
-            snapshotter.exec(0,
-                             0,
-                             Operation {
-                                 obj_id: 0,
-                                 operator: LogOp::Op(State::Encoded(json::encode(&reg_op).unwrap())),
-                             });
+    pub fn mean_time(&self) -> Duration {
+        if self.snapshots == 0 {
+            return Duration::new(0, 0);
         }
-        snapshotter.snapshot(n);
-        let snaps = snapshotter.get_snapshots(&[0].iter().cloned().collect());
-        for (_, s) in snaps {
-            assert_eq!(s.idx, n);
-            match s.payload {
-                Encoded(s) => {
-                    let reg: IntRegister<SharedQueue> = json::decode(&s).unwrap();
+        self.total_time / self.snapshots as u32
+    }
+
+    fn record(&mut self, idx: LogIndex, time: Duration) {
+        self.snapshots += 1;
+        self.total_time = self.total_time + time;
+        if time > self.max_time {
+            self.max_time = time;
+        }
+        self.last_idx = Some(idx);
+    }
+}
+
+// Progress towards the thresholds of a SnapshotPolicy since last snapshot
+struct PolicyState {
+    entries: usize, // entries applied
+    bytes: usize, // size of the operation payloads of entries applied
+    per_object: HashMap<ObjId, usize>, // entries applied per object
+    since: Instant, // time of last snapshot
+}
+
+impl PolicyState {
+    fn new() -> PolicyState {
+        PolicyState {
+            entries: 0,
+            bytes: 0,
+            per_object: HashMap::new(),
+            since: Instant::now(),
+        }
+    }
+
+    // record entry e, returns true if policy calls for a snapshot
+    fn record(&mut self, policy: &SnapshotPolicy, e: &Entry) -> bool {
+        self.entries += 1;
+        if policy.replay_bytes.is_some() {
+            self.bytes += e.payload_size();
+        }
+        for obj_id in &e.writes {
+            *self.per_object.entry(*obj_id).or_insert(0) += 1;
+        }
+
+        policy.entries.map_or(false, |n| self.entries >= n) ||
+        policy.interval.map_or(false, |t| self.since.elapsed() >= t) ||
+        policy.replay_bytes.map_or(false, |b| self.bytes >= b) ||
+        policy.per_object
+              .iter()
+              .any(|(obj_id, n)| self.per_object.get(obj_id).map_or(false, |count| count >= n))
+    }
+
+    // returns true if entries were applied and interval passed since last snapshot
+    fn interval_passed(&self, policy: &SnapshotPolicy) -> bool {
+        self.entries > 0 && policy.interval.map_or(false, |t| self.since.elapsed() >= t)
+    }
+
+    fn reset(&mut self) {
+        *self = PolicyState::new();
+    }
+}
+
+// Class: SnapshotScheduler
+// Takes the snapshots a SnapshotPolicy calls for, as entries are applied and as the log is polled
+// Called with the runtime locked, so no entry is applied while objects are snapshotted
+struct SnapshotScheduler<Skip, Snap> {
+    policy: SnapshotPolicy, // when to snapshot
+    state: Arc<Mutex<PolicyState>>, // progress since last snapshot
+    snapshotter: Arc<RwLock<Snap>>,
+    skiplist: Arc<RwLock<Skip>>,
+    local_queue: Arc<Mutex<LocalQueue>>,
+    ends: Arc<Mutex<ChainEnds>>,
+    versions: Arc<Mutex<HashMap<ObjId, LogIndex>>>, // runtime versions
+    stats: Arc<Mutex<SnapshotStats>>,
+}
+
+impl<Skip, Snap> Clone for SnapshotScheduler<Skip, Snap> {
+    fn clone(&self) -> SnapshotScheduler<Skip, Snap> {
+        SnapshotScheduler {
+            policy: self.policy.clone(),
+            state: self.state.clone(),
+            snapshotter: self.snapshotter.clone(),
+            skiplist: self.skiplist.clone(),
+            local_queue: self.local_queue.clone(),
+            ends: self.ends.clone(),
+            versions: self.versions.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+impl<Skip: Skiplist, Snap: Snapshotter> SnapshotScheduler<Skip, Snap> {
+    // records applied entry, snapshots up to it if a threshold is reached
+    fn applied(&self, entry: &Entry) {
+        let due = self.state.lock().unwrap().record(&self.policy, entry);
+        if due {
+            self.snapshot(entry.idx.unwrap());
+        }
+    }
+
+    // snapshots up to idx, the last entry applied, if the interval passed since last snapshot
+    fn poll(&self, idx: LogIndex) {
+        let due = self.state.lock().unwrap().interval_passed(&self.policy);
+        if due {
+            self.snapshot(idx);
+        }
+    }
+
+    fn snapshot(&self, idx: LogIndex) {
+        let start = Instant::now();
+        {
+            let ends = self.ends.lock().unwrap();
+            let versions = self.versions.lock().unwrap();
+            self.snapshotter.read().unwrap().snapshot(idx, &ends, &versions);
+        }
+        self.stats.lock().unwrap().record(idx, start.elapsed());
+        self.state.lock().unwrap().reset();
+        // Remove now redundant entries from skiplist and local queue
+        self.skiplist.write().unwrap().gc(idx - self.policy.gc_lag);
+        let retained = self.snapshotter.read().unwrap().retained().unwrap_or(idx);
+        let gc = self.local_queue.lock().unwrap().gc(idx - self.policy.gc_lag, retained);
+        if let Err(err) = gc {
+            let _ = writeln!(io::stderr(), "VM: stopped spilling local queue: {}", err);
+        }
+    }
+}
+
+// Entries trimmed from LocalQueue, appended to a file
+struct Spill {
+    path: PathBuf, // spill file
+    file: File, // one json encoded entry after the other
+    offsets: HashMap<LogIndex, (u64, usize)>, // offset and length of each spilled entry
+    end: u64, // length of file
+    garbage: u64, // bytes of entries no longer kept
+}
+
+// errors decoding a spilled entry
+fn invalid_data<E: ::std::fmt::Display>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("corrupt spilled entry: {}", err))
+}
+
+impl Spill {
+    fn create(path: PathBuf) -> io::Result<Spill> {
+        let file = try!(fs::OpenOptions::new()
+                            .read(true)
+                            .write(true)
+                            .create(true)
+                            .truncate(true)
+                            .open(&path));
+        Ok(Spill {
+            path: path,
+            file: file,
+            offsets: HashMap::new(),
+            end: 0,
+            garbage: 0,
+        })
+    }
+
+    fn append(&mut self, idx: LogIndex, entry: &Entry) -> io::Result<()> {
+        let data = json::encode(entry).unwrap().into_bytes();
+        try!(self.file.seek(SeekFrom::Start(self.end)));
+        try!(self.file.write_all(&data));
+        self.offsets.insert(idx, (self.end, data.len()));
+        self.end += data.len() as u64;
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        try!(self.file.seek(SeekFrom::Start(offset)));
+        try!(self.file.read_exact(&mut buf));
+        Ok(buf)
+    }
+
+    // Entry idx, None if it was not spilled
+    fn read(&mut self, idx: LogIndex) -> io::Result<Option<Entry>> {
+        let (offset, len) = match self.offsets.get(&idx) {
+            Some(&offset) => offset,
+            None => return Ok(None),
+        };
+        let buf = try!(self.read_bytes(offset, len));
+        let data = try!(String::from_utf8(buf).map_err(invalid_data));
+        json::decode(&data).map(Some).map_err(invalid_data)
+    }
+
+    // Method: trim
+    // Forgets entries below idx, the file is rewritten without them once they fill half of it
+    fn trim(&mut self, idx: LogIndex) -> io::Result<()> {
+        let trimmed: Vec<LogIndex> = self.offsets.keys().cloned().filter(|&i| i < idx).collect();
+        for i in trimmed {
+            let (_, len) = self.offsets.remove(&i).unwrap();
+            self.garbage += len as u64;
+        }
+        if self.garbage == 0 || self.garbage * 2 < self.end {
+            return Ok(());
+        }
+
+        // copy kept entries to a new file, which then replaces the spill file
+        let mut spill = try!(Spill::create(self.path.with_extension("tmp")));
+        let mut idxs: Vec<LogIndex> = self.offsets.keys().cloned().collect();
+        idxs.sort();
+        for i in idxs {
+            let (offset, len) = self.offsets[&i];
+            let data = try!(self.read_bytes(offset, len));
+            try!(spill.file.write_all(&data));
+            spill.offsets.insert(i, (spill.end, len));
+            spill.end += len as u64;
+        }
+        try!(fs::rename(&spill.path, &self.path));
+        spill.path = self.path.clone();
+        *self = spill;
+        Ok(())
+    }
+}
+
+// Class: LocalQueue
+// Cache of SharedLog entries streamed to clients by VM
+// Entries below the gc index are covered by snapshots: they are dropped,
+// or spilled to disk if a spill directory is set
+// Spilled entries are kept down to the oldest snapshot retained, spilling stops on the first error
+pub struct LocalQueue {
+    entries: HashMap<LogIndex, Entry>, // entries retained in memory
+    low: LogIndex, // index of last gc, entries below it were trimmed
+    spill: Option<Spill>, // trimmed entries, if kept
+}
+
+impl LocalQueue {
+    pub fn new(spill_dir: Option<&PathBuf>) -> LocalQueue {
+        let spill = spill_dir.map(|dir| {
+            fs::create_dir_all(dir).expect("error creating spill directory");
+            Spill::create(dir.join("local-queue.log")).expect("error opening spill file")
+        });
+        LocalQueue {
+            entries: HashMap::new(),
+            low: -1,
+            spill: spill,
+        }
+    }
+
+    pub fn insert(&mut self, idx: LogIndex, entry: Entry) {
+        self.entries.insert(idx, entry);
+    }
+
+    // Entry idx, or None if it was trimmed and not spilled
+    // Returns the error reading it back if it was spilled
+    pub fn get(&mut self, idx: LogIndex) -> io::Result<Option<Entry>> {
+        if let Some(entry) = self.entries.get(&idx) {
+            return Ok(Some(entry.clone()));
+        }
+        match self.spill {
+            Some(ref mut spill) => spill.read(idx),
+            None => Ok(None),
+        }
+    }
+
+    // Number of entries held in memory
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // Index below which entries were trimmed
+    pub fn low(&self) -> LogIndex {
+        self.low
+    }
+
+    // Method: gc
+    // Trim entries below idx, consistently with Skiplist::gc
+    // Arguments:
+    //  * idx : entries below idx are trimmed
+    //  * retained : index of the oldest snapshot retained, entries below it are not spilled
+    //               and those spilled before are dropped
+    // Returns: error of the spill file, entries are dropped instead of spilled from then on
+    pub fn gc(&mut self, idx: LogIndex, retained: LogIndex) -> io::Result<()> {
+        let mut trimmed: Vec<LogIndex> = self.entries
+                                             .keys()
+                                             .cloned()
+                                             .filter(|&i| i < idx)
+                                             .collect();
+        trimmed.sort();
+        if idx > self.low {
+            self.low = idx;
+        }
+        let spilled = match self.spill {
+            Some(ref mut spill) => {
+                let mut spilled = Ok(());
+                for i in trimmed.iter().filter(|&&i| i >= retained) {
+                    spilled = spill.append(*i, &self.entries[i]);
+                    if spilled.is_err() {
+                        break;
+                    }
+                }
+                spilled.and_then(|_| spill.trim(retained))
+            }
+            None => Ok(()),
+        };
+        for i in trimmed {
+            self.entries.remove(&i);
+        }
+        if spilled.is_err() {
+            self.spill = None;
+        }
+        spilled
+    }
+}
+
+// Registers a VM replica of a newly named object, given its id
+pub type ObjectFactory<Q, Skip, Snap> = Box<Fn(&mut ObjectRegistrar<Q, Skip, Snap>, ObjId) + Send>;
+
+// Class: ObjectRegistrar
+// Registers objects with the VM's runtime, skiplist and snapshotter
+// Shared by VM and its polling thread, which registers objects as they are named in the directory
+#[derive(Clone)]
+pub struct ObjectRegistrar<Q, Skip, Snap> {
+    pub runtime: Arc<Mutex<Runtime<Q>>>, // VM runtime
+    queue: Q, // SharedLog, to fetch entries preceding registration
+    local_queue: Arc<Mutex<LocalQueue>>, // cached SharedLog
+    skiplist: Arc<RwLock<Skip>>, // skiplist, read locked by streams
+    snapshotter: Arc<RwLock<Snap>>, // snapshotter, read locked by streams
+    registered: Arc<Mutex<HashSet<ObjId>>>, // ids of objects registered with VM
+    searchable: Arc<Mutex<HashMap<ObjId, Box<Searchable + Send>>>>, // objects answering searches
+}
+
+impl<Q, Skip, Snap> ObjectRegistrar<Q, Skip, Snap>
+    where Q: 'static + IndexedQueue + Clone + Send,
+          Skip: 'static + Skiplist + Clone + Send + Sync,
+          Snap: 'static + Snapshotter + Clone + Send + Sync
+{
+    pub fn is_registered(&self, obj_id: ObjId) -> bool {
+        self.registered.lock().unwrap().contains(&obj_id)
+    }
+
+    // Method: register_object, Blocking
+    // Registers obj_id with VM, catching up on entries appended before registration
+    // Arguments:
+    //  * obj_id : id of the object
+    //  * callback : applies log entry operations to obj
+    //  * obj : empty Snapshottable, to construct a snapshotted object in
+    pub fn register_object<Snapshottable: 'static + Encodable + Send>(&mut self,
+                                                                      obj_id: ObjId,
+                                                                      callback: Box<Callback>,
+                                                                      obj: Snapshottable) {
+        // insert/ register object
+        self.registered.lock().unwrap().insert(obj_id);
+        self.skiplist.write().unwrap().insert(obj_id);
+        self.snapshotter.write().unwrap().register_object(obj_id, callback, obj);
+
+        // cloned arc references callback is closed over
+        let skiplist = self.skiplist.clone();
+        let snapshotter = self.snapshotter.clone();
+        // VM version of object callback
+        let cb = Box::new(move |idx, op: Operation| {
+            // Add this index to the skiplist
+            skiplist.write().unwrap().append(obj_id, idx);
+            // Execute this entry on the snapshotter for this object
+            snapshotter.write().unwrap().exec(obj_id, idx, op.clone());
+        });
+
+        let mut runtime = self.runtime.lock().unwrap();
+        if runtime.global_idx >= 0 {
+            // entries already synced by runtime are only reported to the object by catch up,
+            // cache them so they can be streamed to clients
+            let rx = self.queue.stream(&vec![obj_id].into_iter().collect(),
+                                       0,
+                                       Some(runtime.global_idx + 1));
+            let mut local_queue = self.local_queue.lock().unwrap();
+            for data in rx {
+                if let LogData::LogEntry(e) = data {
+                    local_queue.insert(e.idx.unwrap(), e);
+                }
+            }
+        }
+        // Register object with VM's Runtime
+        runtime.register_object(obj_id, cb);
+    }
+
+    // Let registered object obj_id answer keyword searches
+    // obj should be (a clone of) the object registered with register_object
+    pub fn register_searchable<S: Searchable>(&mut self, obj_id: ObjId, obj: S) {
+        self.searchable.lock().unwrap().insert(obj_id, Box::new(obj));
+    }
+
+    // Registers obj_id with the factory of type_tag, if there is one
+    // Returns true if obj_id got registered
+    fn register_typed(&mut self,
+                      types: &Mutex<HashMap<String, ObjectFactory<Q, Skip, Snap>>>,
+                      obj_id: ObjId,
+                      type_tag: &str)
+                      -> bool {
+        match types.lock().unwrap().get(type_tag) {
+            Some(factory) => factory(self, obj_id),
+            None => return false,
+        }
+        self.snapshotter.write().unwrap().set_type_tag(obj_id, type_tag);
+        true
+    }
+}
+
+// Class: VM
+// Responsible for snapshotting, streaming, and creating the skip list
+// Starts one thread that is constantly polling the queue and using new entries to construct skip list.
+// Starts the Snapshotter
+// Accepts and handles stream requests
+#[derive(Clone)]
+pub struct VM<Q: IndexedQueue + Send,
+              Skip: Skiplist + Clone + Send + Sync,
+              Snap: Snapshotter + Clone + Send + Sync>
+{
+    // shared by clones only, stops the threads once all are dropped, before any other field
+    running: Arc<Running<Q>>,
+    pub runtime: Arc<Mutex<Runtime<Q>>>, // VM runtime, same as Client VM, but works with encrypted data
+    objects: ObjectRegistrar<Q, Skip, Snap>, // registers objects with runtime, skiplist, snapshots
+    types: Arc<Mutex<HashMap<String, ObjectFactory<Q, Skip, Snap>>>>, // replica type per type tag
+    local_queue: Arc<Mutex<LocalQueue>>, // cached SharedLog
+    skiplist: Arc<RwLock<Skip>>, // skiplist, read locked by streams
+    snapshotter: Arc<RwLock<Snap>>, // snapshotter, read locked by streams
+    threads: Arc<Mutex<Vec<JoinHandle<()>>>>, // threads spawned by VM
+    stop: Arc<AtomicBool>, // used to stop polling thread
+    queue: Q, // queue interface that allows communication with client
+    searchable: Arc<Mutex<HashMap<ObjId, Box<Searchable + Send>>>>, // objects answering searches
+    policy: SnapshotPolicy, // when to snapshot
+    stats: Arc<Mutex<SnapshotStats>>, // snapshots taken so far
+    allowed: Arc<RwLock<AllowList>>, // writers allowed to append to objects
+    ends: Arc<Mutex<ChainEnds>>, // last entry of each writer on each object, for snapshots
+}
+
+// Threads of a VM and its clones, stopped when the last of them drops its handle
+struct Running<Q: IndexedQueue + Send> {
+    threads: Arc<Mutex<Vec<JoinHandle<()>>>>, // threads spawned by VM
+    stop: Arc<AtomicBool>, // used to stop polling thread
+    runtime: Arc<Mutex<Runtime<Q>>>, // stopped with the threads
+}
+
+impl<Q: IndexedQueue + Send> Running<Q> {
+    // Stops and joins polling thread, returns false if it was not running
+    fn stop(&self) -> bool {
+        self.stop.store(true, Release); // stop polling thread
+        let threads: Vec<JoinHandle<()>> = self.threads.lock().unwrap().drain(..).collect();
+        let started = !threads.is_empty();
+        for t in threads {
+            t.join().unwrap();
+        }
+        self.runtime.lock().unwrap().stop();
+        started
+    }
+}
+
+impl<Q: IndexedQueue + Send> Drop for Running<Q> {
+    fn drop(&mut self) {
+        self.stop();
+        // Snapshotter stops on drop snapshotter
+    }
+}
+
+impl<Q, Skip, Snap> VM<Q, Skip, Snap>
+    where Q: 'static + IndexedQueue + Clone + Send,
+          Skip: 'static + Skiplist + Clone + Send + Sync,
+          Snap: 'static + Snapshotter + Clone + Send + Sync
+{
+    pub fn new(q: Q,
+               skiplist: Skip,
+               snapshotter: Snap,
+               policy: SnapshotPolicy)
+               -> VM<Q, Skip, Snap> {
+        let queue = q.clone();
+        let runtime = Arc::new(Mutex::new(Runtime::new(q, None)));
+        let local_queue = Arc::new(Mutex::new(LocalQueue::new(policy.spill.as_ref())));
+        let skiplist = Arc::new(RwLock::new(skiplist));
+        let snapshotter = Arc::new(RwLock::new(snapshotter));
+        let searchable = Arc::new(Mutex::new(HashMap::new()));
+        let threads = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let running = Arc::new(Running {
+            threads: threads.clone(),
+            stop: stop.clone(),
+            runtime: runtime.clone(),
+        });
+        let mut vm = VM {
+            running: running,
+            runtime: runtime.clone(),
+            objects: ObjectRegistrar {
+                runtime: runtime,
+                queue: queue.clone(),
+                local_queue: local_queue.clone(),
+                skiplist: skiplist.clone(),
+                snapshotter: snapshotter.clone(),
+                registered: Arc::new(Mutex::new(HashSet::new())),
+                searchable: searchable.clone(),
+            },
+            types: Arc::new(Mutex::new(HashMap::new())),
+            local_queue: local_queue,
+            skiplist: skiplist,
+            snapshotter: snapshotter,
+            threads: threads,
+            stop: stop,
+            queue: queue,
+            searchable: searchable,
+            policy: policy,
+            stats: Arc::new(Mutex::new(SnapshotStats::new())),
+            allowed: Arc::new(RwLock::new(AllowList::new())),
+            ends: Arc::new(Mutex::new(HashMap::new())),
+        };
+        vm.register_default_types();
+        return vm;
+    }
+
+    pub fn start(&mut self) {
+        // track the directory, objects it names get registered by the polling thread
+        let mut directory = Directory::new();
+        let created = directory.watch();
+        let mut copy = directory.clone();
+        self.register_object(DIRECTORY,
+                             Box::new(move |_, op: Operation| copy.callback(op)),
+                             directory);
+
+        // objects first seen through a tagged operation, registered by the polling thread too
+        let discovered = Arc::new(Mutex::new(VecDeque::new()));
+        {
+            let discovered = discovered.clone();
+            let unknown_hook = Box::new(move |_, op: &Operation| {
+                // untagged operations belong to objects only their clients know the type of
+                if let Some(ref type_tag) = op.type_tag {
+                    discovered.lock().unwrap().push_back((op.obj_id, type_tag.clone()));
+                }
+            });
+            self.runtime.lock().unwrap().register_unknown_callback(unknown_hook);
+        }
+
+        // resume from latest checkpoint, if snapshotter persists them
+        let checkpoint = self.snapshotter.write().unwrap().recover();
+        if let Some(checkpoint) = checkpoint {
+            self.resume(checkpoint);
+        }
+
+        let scheduler = SnapshotScheduler {
+            policy: self.policy.clone(),
+            state: Arc::new(Mutex::new(PolicyState::new())),
+            snapshotter: self.snapshotter.clone(),
+            skiplist: self.skiplist.clone(),
+            local_queue: self.local_queue.clone(),
+            ends: self.ends.clone(),
+            versions: self.runtime.lock().unwrap().versions(),
+            stats: self.stats.clone(),
+        };
+        {
+            let local_queue = self.local_queue.clone();
+            let ends = self.ends.clone();
+            let scheduler = scheduler.clone();
+
+            // Pre_hook to be called before the main object callbacks
+            // Makes sure entry exists in local_queue, and records it as its writer's last one
+            let pre_hook = Box::new(move |entry: Entry| {
+                if let Some(ref integrity) = entry.integrity {
+                    let mut ends = ends.lock().unwrap();
+                    for link in &integrity.links {
+                        ends.entry(link.obj_id)
+                            .or_insert(HashMap::new())
+                            .insert(integrity.writer, entry.clone());
+                    }
+                }
+                // Ensure log entry exists in local queue
+                let idx = entry.idx.unwrap();
+                let mut local_queue = local_queue.lock().unwrap();
+                local_queue.insert(idx, entry);
+            });
+
+            // Post_hook to be called after main object callbacks
+            // Sees if we have enough entries to require snapshot
+            let post_hook = Box::new(move |entry: Entry| scheduler.applied(&entry));
+
+            self.runtime.lock().unwrap().register_pre_callback(pre_hook);
+            self.runtime.lock().unwrap().register_post_callback(post_hook);
+        }
+
+        self.snapshotter.write().unwrap().start();
+        self.poll_runtime(created, discovered, scheduler);
+    }
+
+    // Method: resume
+    // Restores objects from checkpoint, syncing resumes after the checkpoint
+    // Objects not registered yet are re-created from their type tag
+    fn resume(&mut self, checkpoint: Checkpoint) {
+        for (obj_id, type_tag) in checkpoint.objects {
+            if self.objects.is_registered(obj_id) {
+                continue;
+            }
+            // runtime has not synced yet, objects registered now do not catch up on the log
+            let registered = type_tag.map_or(false, |type_tag| {
+                self.objects.register_typed(&self.types, obj_id, &type_tag)
+            });
+            if !registered {
+                let _ = writeln!(io::stderr(),
+                                 "VM: object {} in checkpoint but not registered",
+                                 obj_id);
+            }
+        }
+        for s in checkpoint.snapshots {
+            {
+                let mut ends = self.ends.lock().unwrap();
+                let ends = ends.entry(s.obj_id).or_insert(HashMap::new());
+                for e in &s.heads {
+                    if let Some(ref integrity) = e.integrity {
+                        ends.insert(integrity.writer, e.clone());
+                    }
+                }
+            }
+            if self.objects.is_registered(s.obj_id) {
+                let op = Operation::from_snapshot(s.obj_id, s.payload);
+                self.snapshotter.write().unwrap().exec(s.obj_id, s.idx, op);
+            }
+        }
+        let mut runtime = self.runtime.lock().unwrap();
+        runtime.global_idx = checkpoint.idx;
+        // transactions reading objects before the checkpoint still abort
+        runtime.versions().lock().unwrap().extend(checkpoint.versions);
+    }
+
+    // Poll runtime for updates every POLL_MS milliseconds
+    // Registers objects named in the directory or first operated on since last poll,
+    // and snapshots once the policy's interval passed
+    fn poll_runtime(&mut self,
+                    created: Arc<Mutex<VecDeque<DirectoryEntry>>>,
+                    discovered: Arc<Mutex<VecDeque<(ObjId, String)>>>,
+                    scheduler: SnapshotScheduler<Skip, Snap>) {
+        let runtime = self.runtime.clone();
+        let mut objects = self.objects.clone();
+        let types = self.types.clone();
+        let stop = self.stop.clone();
+        self.threads.lock().unwrap().push(thread::spawn(move || {
+            loop {
+                if stop.load(Acquire) {
+                    // notice to stop
+                    return;
+                }
+                // sync all of the objects, a tampered log is not replicated any further
+                if let Err(err) = runtime.lock().unwrap().try_sync(None) {
+                    let _ = writeln!(io::stderr(), "VM: stopped replicating: {}", err);
+                    return;
+                }
+                let mut found: Vec<(ObjId, String)> = created.lock()
+                                                             .unwrap()
+                                                             .drain(..)
+                                                             .map(|e| (e.obj_id, e.type_tag))
+                                                             .collect();
+                found.extend(discovered.lock().unwrap().drain(..));
+                for (obj_id, type_tag) in found {
+                    if objects.is_registered(obj_id) {
+                        continue;
+                    }
+                    if !objects.register_typed(&types, obj_id, &type_tag) {
+                        let _ = writeln!(io::stderr(),
+                                         "VM: no replica type for {}, object {} not tracked",
+                                         type_tag,
+                                         obj_id);
+                    }
+                }
+                {
+                    // no entry is applied while the objects are snapshotted
+                    let runtime = runtime.lock().unwrap();
+                    scheduler.poll(runtime.global_idx);
+                }
+                thread::sleep(Duration::from_millis(POLL_MS));
+            }
+        }));
+    }
+
+    // Register object obj_id with VM, to be kept track of
+    // Provide an empty object Snapshottable, to construct a snapshotted object in
+    // and a callback that handles applying log entry operations to the object
+    pub fn register_object<Snapshottable: 'static + Encodable + Send>(&mut self,
+                                                                      obj_id: ObjId,
+                                                                      callback: Box<Callback>,
+                                                                      obj: Snapshottable) {
+        self.objects.register_object(obj_id, callback, obj);
+    }
+
+    // Snapshots taken so far, and how long they took
+    pub fn snapshot_stats(&self) -> SnapshotStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    // Append entries on obj_id only if signed by an allowed writer, may be called for several
+    // Signatures are checked without decryption keys, forged appends never reach the queue
+    pub fn allow_writer(&mut self, obj_id: ObjId, writer: WriterId) {
+        self.allowed.write().unwrap().allow(obj_id, writer);
+    }
+
+    // Let registered object obj_id answer keyword searches
+    // obj should be (a clone of) the object registered with register_object
+    pub fn register_searchable<S: Searchable>(&mut self, obj_id: ObjId, obj: S) {
+        self.objects.register_searchable(obj_id, obj);
+    }
+
+    // Registers obj_id as a replica of type_tag, for objects whose clients do not tag their
+    // operations (see Runtime::set_type_tag), which the VM would otherwise never discover
+    // Returns false if no factory is registered for type_tag
+    pub fn register_as(&mut self, obj_id: ObjId, type_tag: &str) -> bool {
+        self.objects.register_typed(&self.types, obj_id, type_tag)
+    }
+
+    // Register factory of the VM replica of objects named with type_tag in the directory
+    // Replaces the default factory of type_tag, if any
+    pub fn register_type<F>(&mut self, type_tag: &str, factory: F)
+        where F: 'static + Fn(&mut ObjectRegistrar<Q, Skip, Snap>, ObjId) + Send
+    {
+        self.types.lock().unwrap().insert(String::from(type_tag), Box::new(factory));
+    }
+
+    // VM replicas of the data structures clients can open by name
+    fn register_default_types(&mut self) {
+        self.register_type(directory::HMAP,
+                           |objects: &mut ObjectRegistrar<Q, Skip, Snap>, obj_id| {
+                               let map = EncHMap::new(&objects.runtime, obj_id, HashMap::new());
+                               let mut copy = map.clone();
+                               objects.register_object(obj_id,
+                                                       Box::new(move |_, e| copy.callback(e)),
+                                                       map.clone());
+                               objects.register_searchable(obj_id, map);
+                           });
+        self.register_type(directory::BTMAP,
+                           |objects: &mut ObjectRegistrar<Q, Skip, Snap>, obj_id| {
+                               let map = EncBTMap::new(&objects.runtime, obj_id, BTreeMap::new());
+                               let mut copy = map.clone();
+                               objects.register_object(obj_id,
+                                                       Box::new(move |_, e| copy.callback(e)),
+                                                       map.clone());
+                               objects.register_searchable(obj_id, map);
+                           });
+        self.register_type(directory::UNENC_BTMAP,
+                           |objects: &mut ObjectRegistrar<Q, Skip, Snap>, obj_id| {
+                               let map = UnencBTMap::new(&objects.runtime, obj_id, BTreeMap::new());
+                               let mut copy = map.clone();
+                               objects.register_object(obj_id,
+                                                       Box::new(move |_, e| copy.callback(e)),
+                                                       map);
+                           });
+        self.register_type(directory::HSET,
+                           |objects: &mut ObjectRegistrar<Q, Skip, Snap>, obj_id| {
+                               let set = EncHSet::new(&objects.runtime, obj_id, HashSet::new());
+                               let mut copy = set.clone();
+                               objects.register_object(obj_id,
+                                                       Box::new(move |_, e| copy.callback(e)),
+                                                       set);
+                           });
+        self.register_type(directory::BTSET,
+                           |objects: &mut ObjectRegistrar<Q, Skip, Snap>, obj_id| {
+                               let set = EncBTSet::new(&objects.runtime, obj_id, BTreeSet::new());
+                               let mut copy = set.clone();
+                               objects.register_object(obj_id,
+                                                       Box::new(move |_, e| copy.callback(e)),
+                                                       set);
+                           });
+        self.register_type(directory::LIST,
+                           |objects: &mut ObjectRegistrar<Q, Skip, Snap>, obj_id| {
+                               let list = EncList::new(&objects.runtime, obj_id);
+                               let mut copy = list.clone();
+                               objects.register_object(obj_id,
+                                                       Box::new(move |_, e| copy.callback(e)),
+                                                       list);
+                           });
+        self.register_type(directory::QUEUE,
+                           |objects: &mut ObjectRegistrar<Q, Skip, Snap>, obj_id| {
+                               let queue = EncQueue::new(&objects.runtime, obj_id);
+                               let mut copy = queue.clone();
+                               objects.register_object(obj_id,
+                                                       Box::new(move |_, e| copy.callback(e)),
+                                                       queue);
+                           });
+        // the VM has no key to encrypt the initial value with, clients start from 0 too
+        self.register_type(directory::REGISTER,
+                           |objects: &mut ObjectRegistrar<Q, Skip, Snap>, obj_id| {
+                               let reg = AddableRegister::new(&objects.runtime,
+                                                              obj_id,
+                                                              Addable::zero());
+                               let mut copy = reg.clone();
+                               objects.register_object(obj_id,
+                                                       Box::new(move |_, e| copy.callback(e)),
+                                                       reg);
+                           });
+        self.register_type(directory::PRIORITY_QUEUE,
+                           |objects: &mut ObjectRegistrar<Q, Skip, Snap>, obj_id| {
+                               let pq = EncPriorityQueue::new(&objects.runtime, obj_id);
+                               let mut copy = pq.clone();
+                               objects.register_object(obj_id,
+                                                       Box::new(move |_, e| copy.callback(e)),
+                                                       pq);
+                           });
+        self.register_type(directory::LOCK,
+                           |objects: &mut ObjectRegistrar<Q, Skip, Snap>, obj_id| {
+                               let lock = EncLock::new(&objects.runtime, obj_id);
+                               let mut copy = lock.clone();
+                               objects.register_object(obj_id,
+                                                       Box::new(move |_, e| copy.callback(e)),
+                                                       lock);
+                           });
+        self.register_type(directory::GCOUNTER,
+                           |objects: &mut ObjectRegistrar<Q, Skip, Snap>, obj_id| {
+                               let counter = AddableGCounter::new(&objects.runtime, obj_id);
+                               let mut copy = counter.clone();
+                               objects.register_object(obj_id,
+                                                       Box::new(move |_, e| copy.callback(e)),
+                                                       counter);
+                           });
+        self.register_type(directory::PNCOUNTER,
+                           |objects: &mut ObjectRegistrar<Q, Skip, Snap>, obj_id| {
+                               let counter = AddablePNCounter::new(&objects.runtime, obj_id);
+                               let mut copy = counter.clone();
+                               objects.register_object(obj_id,
+                                                       Box::new(move |_, e| copy.callback(e)),
+                                                       counter);
+                           });
+    }
+
+    // Register encrypted twin obj of a ReplicatedObject with VM
+    // Returns handle to the VM's replica of the object
+    pub fn register_replica<T>(&mut self, obj_id: ObjId, obj: T) -> Replica<T, Q>
+        where T: ReplicatedObject + Encodable
+    {
+        let replica = Replica::new(&self.runtime, obj_id, obj);
+        let mut copy = replica.clone();
+        self.register_object(obj_id,
+                             Box::new(move |_, op: Operation| copy.callback(op)),
+                             replica.clone());
+        replica
+    }
+}
+
+impl<Q, Skip, Snap> IndexedQueue for VM<Q, Skip, Snap>
+    where Q: IndexedQueue + Send + Clone,
+          Skip: Skiplist + Clone + Send + Sync,
+          Snap: Snapshotter + Clone + Send + Sync
+{
+    fn append(&mut self, e: Entry) -> LogIndex {
+        match self.try_append(e) {
+            Ok(idx) => idx,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    fn try_append(&mut self, e: Entry) -> Result<LogIndex, LogError> {
+        if let Err(reason) = self.allowed.read().unwrap().check(&e) {
+            return Err(LogError::Unauthorized(-1, reason));
+        }
+        // retries of e (same request id) are deduplicated by the queue, shared by all VMs
+        self.queue.try_append(e)
+    }
+
+    fn stream(&mut self,
+              obj_ids: &HashSet<ObjId>,
+              from: LogIndex,
+              to: Option<LogIndex>)
+              -> mpsc::Receiver<LogData> {
+        self.stream_limited(obj_ids, from, to, None)
+    }
+
+    fn stream_page(&mut self,
+                   obj_ids: &HashSet<ObjId>,
+                   from: LogIndex,
+                   to: Option<LogIndex>,
+                   page: usize)
+                   -> mpsc::Receiver<LogData> {
+        self.stream_limited(obj_ids, from, to, Some(page))
+    }
+
+    fn stream_all(&mut self,
+                  from: LogIndex,
+                  to: Option<LogIndex>)
+                  -> Result<mpsc::Receiver<LogData>, LogError> {
+        self.stream_all_limited(from, to, None)
+    }
+
+    fn stream_all_page(&mut self,
+                       from: LogIndex,
+                       to: Option<LogIndex>,
+                       page: usize)
+                       -> Result<mpsc::Receiver<LogData>, LogError> {
+        self.stream_all_limited(from, to, Some(page))
+    }
+
+    // answered by the VM's replica of obj_id, as of the entries it has applied
+    fn search(&mut self, obj_id: ObjId, token: &SearchToken) -> Result<Vec<String>, LogError> {
+        let searchable = self.searchable.lock().unwrap();
+        searchable.get(&obj_id)
+                  .map(|obj| obj.search(token))
+                  .ok_or(LogError::Unsupported(format!("search on object {}", obj_id)))
+    }
+}
+
+// VM clones share their replicas and local queue, and append to q's log
+impl<Q, Skip, Snap> SharedLog for VM<Q, Skip, Snap>
+    where Q: SharedLog + Send,
+          Skip: Skiplist + Clone + Send + Sync,
+          Snap: Snapshotter + Clone + Send + Sync
+{
+}
+
+impl<Q, Skip, Snap> VM<Q, Skip, Snap>
+    where Q: IndexedQueue + Send + Clone,
+          Skip: Skiplist + Clone + Send + Sync,
+          Snap: Snapshotter + Clone + Send + Sync
+{
+    // Streams latest snapshots of obj_ids, then the entries after them
+    // Stops once limit entries were sent, if given
+    fn stream_limited(&mut self,
+                      obj_ids: &HashSet<ObjId>,
+                      mut from: LogIndex,
+                      to: Option<LogIndex>,
+                      limit: Option<usize>)
+                      -> mpsc::Receiver<LogData> {
+        use indexed_queue::LogData::{LogEntry, LogSnapshot};
+        // channel to communicate with client
+        let (tx, rx) = mpsc::channel();
+        // objects registered since last snapshot have none yet
+        let n_registered = obj_ids.iter()
+                                  .filter(|&&obj_id| self.objects.is_registered(obj_id))
+                                  .count();
+
+        let mut retried = false;
+        let mut sent = 0;
+        loop {
+            // acquire and send most recent object snaps
+            let mut snaps = self.snapshotter.read().unwrap().get_snapshots(obj_ids);
+            if snaps.len() < n_registered && self.snapshot_now() {
+                snaps = self.snapshotter.read().unwrap().get_snapshots(obj_ids);
+            }
+            if snaps.len() < n_registered {
+                // snapshots of the others cannot be mixed with entries before them,
+                // stream entries of all objects, as far as they are still cached
+                snaps.clear();
+            }
+            let mut new_from = from;
+            for (_, snapshot) in snaps {
+                // once entries before to were trimmed, only snapshots past to still cover them
+                let before_to = to.is_none() || snapshot.idx < to.unwrap();
+                if from <= snapshot.idx && (before_to || retried) {
+                    new_from = snapshot.idx + 1; // all snapshots share the same index
+                    tx.send(LogSnapshot(snapshot)).unwrap();
+                }
+            }
+            if new_from > from {
+                retried = false;
+            }
+
+            // send log entries appended after most recent snap
+            from = new_from;
+            let idxs = self.skiplist.read().unwrap().stream(obj_ids, from, to);
+            let mut trimmed = None;
+            for idx in idxs {
+                if idx < from {
+                    continue;
+                }
+                if limit.map_or(false, |limit| sent >= limit) {
+                    return rx;
+                }
+                match self.local_queue.lock().unwrap().get(idx) {
+                    Ok(Some(entry)) => {
+                        tx.send(LogEntry(entry)).unwrap();
+                        sent += 1;
+                    }
+                    Ok(None) => {
+                        trimmed = Some(idx);
+                        break;
+                    }
+                    Err(err) => {
+                        let _ = writeln!(io::stderr(), "VM: error reading local queue: {}", err);
+                        trimmed = Some(idx);
+                        break;
+                    }
+                }
+            }
+
+            match trimmed {
+                None => return rx,
+                // no snapshot covers the trimmed entries, stream ends before them
+                Some(_) if retried => return rx,
+                Some(idx) => {
+                    // a snapshot was taken and entries trimmed since snapshots were acquired,
+                    // answer from the newer snapshots, even if they are past to
+                    retried = true;
+                    from = idx;
+                }
+            }
+        }
+    }
+
+    // Streams entries of all objects between (from, to), stops once limit entries were sent
+    // Entries are read from the local queue while it holds every index in a row, the log
+    // serves the rest (entries trimmed, aborted, or not synced yet) with a single stream
+    fn stream_all_limited(&mut self,
+                          mut from: LogIndex,
+                          to: Option<LogIndex>,
+                          limit: Option<usize>)
+                          -> Result<mpsc::Receiver<LogData>, LogError> {
+        use indexed_queue::LogData::LogEntry;
+        let (tx, rx) = mpsc::channel();
+        let mut sent = 0;
+        {
+            let mut local_queue = self.local_queue.lock().unwrap();
+            while to.map_or(true, |to| from < to) && limit.map_or(true, |limit| sent < limit) {
+                match local_queue.get(from) {
+                    Ok(Some(entry)) => {
+                        tx.send(LogEntry(entry)).unwrap();
+                        sent += 1;
+                        from += 1;
+                    }
+                    // the log serves entries the local queue cannot
+                    Ok(None) | Err(_) => break,
+                }
+            }
+        }
+        if limit.map_or(false, |limit| sent >= limit) || to.map_or(false, |to| from >= to) {
+            return Ok(rx);
+        }
+        let rest = match limit {
+            Some(limit) => try!(self.queue.stream_all_page(from, to, limit - sent)),
+            None => try!(self.queue.stream_all(from, to)),
+        };
+        for data in rest {
+            tx.send(data).unwrap();
+        }
+        Ok(rx)
+    }
+
+    // Snapshots all objects as of the last entry synced, the same round as the policy takes
+    // Returns false if the VM is not polling the log or has not synced any entry yet
+    fn snapshot_now(&self) -> bool {
+        if self.threads.lock().unwrap().is_empty() {
+            // snapshotter is not started, the round would never complete
+            return false;
+        }
+        // runtime stays locked, no entry is applied to the objects while they are snapshotted
+        let runtime = self.runtime.lock().unwrap();
+        let idx = runtime.global_idx;
+        if idx < 0 {
+            return false;
+        }
+        let start = Instant::now();
+        let versions = runtime.versions();
+        self.snapshotter
+            .read()
+            .unwrap()
+            .snapshot(idx, &self.ends.lock().unwrap(), &versions.lock().unwrap());
+        self.stats.lock().unwrap().record(idx, start.elapsed());
+        true
+    }
+}
+
+impl<Q, Skip, Snap> VM<Q, Skip, Snap>
+    where Q: IndexedQueue + Send,
+          Skip: Skiplist + Clone + Send + Sync,
+          Snap: Snapshotter + Clone + Send + Sync
+{
+    // Method: shutdown, Blocking
+    // Stops polling the log, snapshots objects as of the last entry synced,
+    // then joins the polling and snapshotter threads
+    // Persistent snapshotters checkpoint the final snapshot, a restarted VM resumes from it
+    // Streams may still be served from the snapshots and cached log
+    pub fn shutdown(&mut self) {
+        // stopping the runtime clears its versions
+        let versions = self.runtime.lock().unwrap().versions().lock().unwrap().clone();
+        let started = self.stop_threads();
+        if started {
+            let idx = self.runtime.lock().unwrap().global_idx;
+            let last_idx = self.stats.lock().unwrap().last_idx;
+            if idx >= 0 && last_idx.map_or(true, |last_idx| last_idx < idx) {
+                let start = Instant::now();
+                self.snapshotter
+                    .read()
+                    .unwrap()
+                    .snapshot(idx, &self.ends.lock().unwrap(), &versions);
+                self.stats.lock().unwrap().record(idx, start.elapsed());
+            }
+        }
+        self.snapshotter.write().unwrap().shutdown();
+    }
+
+    // Stops and joins polling thread, returns false if it was not running
+    fn stop_threads(&mut self) -> bool {
+        self.running.stop()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate rustc_serialize;
+    use self::rustc_serialize::json;
+    use super::{VM, Skiplist, MapSkiplist, SortedSkiplist, Snapshotter, AsyncSnapshotter,
+                SnapshotPolicy, FileSnapshotter, LocalQueue};
+    use rand;
+
+    use indexed_queue::{SharedQueue, IndexedQueue, ObjId, Operation, LogOp, State, Entry, TxType,
+                        TxState, Signature, LogData};
+    use indexed_queue::LogData::{LogEntry, LogSnapshot};
+    use indexed_queue::State::Encoded;
+    use runtime::{Runtime, LogError};
+    use ds::{RegisterOp, IntRegister, AddableRegister};
+    use encryptors::{MetaEncryptor, Addable, AddEncryptor, EqEncryptor, Encryptor, OrdEncryptor,
+                     SearchEncryptor, Signer, Authenticator};
+
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::thread::{self, sleep};
+    use std::time::Duration;
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Write;
+
+    fn check_skiplist<S: Skiplist>(mut skiplist: S) {
+        let obj0 = vec![0, 2, 4, 5, 9];
+        let obj1 = vec![0, 1, 5, 7, 9, 10];
+        let objs = vec![obj0, obj1];
+        skiplist.insert(0);
+        skiplist.insert(1);
+        for obj in 0..objs.len() {
+            for &idx in &objs[obj] {
+                skiplist.append(obj as ObjId, idx);
+            }
+        }
+        let stream = skiplist.stream(&[0, 1].iter().cloned().collect(), 0, Some(4));
+        assert_eq!(stream, [0, 1, 2]);
+        let stream = skiplist.stream(&[0, 1].iter().cloned().collect(), 1, Some(5));
+        assert_eq!(stream, [1, 2, 4]);
+        let stream = skiplist.stream(&[0].iter().cloned().collect(), 0, None);
+        assert_eq!(stream, [0, 2, 4, 5, 9]);
+        // gc test
+        skiplist.gc(4);
+        let stream = skiplist.stream(&[0, 1].iter().cloned().collect(), 0, None);
+        assert_eq!(stream, [4, 5, 7, 9, 10]);
+    }
+
+    #[test]
+    fn skiplist_test() {
+        check_skiplist(MapSkiplist::new());
+    }
+
+    #[test]
+    fn sorted_skiplist_test() {
+        check_skiplist(SortedSkiplist::new());
+
+        let mut skiplist = SortedSkiplist::new();
+        skiplist.insert(0);
+        skiplist.insert(1);
+        skiplist.insert(2);
+        for &idx in &[3, 8, 1, 5, 8] {
+            skiplist.append(0, idx);
+        }
+        for idx in 0..10 {
+            skiplist.append(1, 2 * idx);
+        }
+        assert_eq!(skiplist.stream(&[0].iter().cloned().collect(), 0, None),
+                   [1, 3, 5, 8]);
+        assert_eq!(skiplist.stream(&[0, 1, 2, 3].iter().cloned().collect(), 3, Some(9)),
+                   [3, 4, 5, 6, 8]);
+        assert!(skiplist.stream(&[2].iter().cloned().collect(), 0, None).is_empty());
+        skiplist.gc(12);
+        assert_eq!(skiplist.stream(&[0, 1].iter().cloned().collect(), 0, None),
+                   [12, 14, 16, 18]);
+    }
+    #[test]
+    fn snapshot_test() {
+        let q = SharedQueue::new();
+        let mut snapshotter = AsyncSnapshotter::new();
+        let runtime: Arc<Mutex<Runtime<SharedQueue>>> = Arc::new(Mutex::new(Runtime::new(q, None)));
+        let reg = IntRegister::new(&runtime, 0, 0);
+        let mut reg2 = reg.clone();
+        let n = 250;
+
+        snapshotter.register_object(0, Box::new(move |_, e| reg2.callback(e)), reg);
+        snapshotter.start();
+        for _ in 0..n {
+            let reg_op = RegisterOp::Inc { add: 2 };
+            snapshotter.exec(0,
+                             0,
+                             Operation {
+                                 obj_id: 0,
+                                 operator: LogOp::Op(State::Encoded(json::encode(&reg_op).unwrap())),
+                                 type_tag: None,
+                             });
+        }
+        snapshotter.snapshot(n, &HashMap::new(), &HashMap::new());
+        let snaps = snapshotter.get_snapshots(&[0].iter().cloned().collect());
+        for (_, s) in snaps {
+            assert_eq!(s.idx, n);
+            match s.payload {
+                Encoded(s) => {
+                    let reg: IntRegister<SharedQueue> = json::decode(&s).unwrap();
                     let data = reg.data.lock().unwrap();
                     assert_eq!(*data, (n * 2) as i32);
                 }
@@ -544,7 +1943,10 @@ mod test {
     fn vm_streaming() {
         let q = SharedQueue::new();
         let mut vm: VM<SharedQueue, MapSkiplist, AsyncSnapshotter> =
-            VM::new(q.clone(), MapSkiplist::new(), AsyncSnapshotter::new());
+            VM::new(q.clone(),
+                    MapSkiplist::new(),
+                    AsyncSnapshotter::new(),
+                    SnapshotPolicy::new());
         let add_encryptor = AddEncryptor::new();
         let obj_id: ObjId = 0;
         let reg = AddableRegister::new(&vm.runtime,
@@ -559,7 +1961,9 @@ mod test {
         let me = MetaEncryptor::from(EqEncryptor::new(Encryptor::new()),
                                      add_encryptor.clone(),
                                      Encryptor::new(),
-                                     OrdEncryptor::new(Encryptor::new()));
+                                     OrdEncryptor::new(Encryptor::new()),
+                                     SearchEncryptor::new(),
+                                     Authenticator::new());
         let client_runtime = Runtime::new(q, Some(me));
         let client_runtime = Arc::new(Mutex::new(client_runtime));
         let mut client_reg = IntRegister::new(&client_runtime, obj_id, 0);
@@ -582,10 +1986,42 @@ mod test {
         assert_eq!(i, 10);
     }
 
+    #[test]
+    fn vm_snapshot_policy() {
+        let q = SharedQueue::new();
+        let policy = SnapshotPolicy::new().with_entries(None).with_object(0, 30).with_gc_lag(0);
+        let mut vm = VM::new(q.clone(), MapSkiplist::new(), AsyncSnapshotter::new(), policy);
+        let add_encryptor = AddEncryptor::new();
+        let mut reg = AddableRegister::new(&vm.runtime,
+                                           0,
+                                           Addable::default(add_encryptor.public_key()));
+        let reg1 = reg.clone();
+        vm.register_object(0, Box::new(move |_, e| reg.callback(e)), reg1);
+        vm.start();
+
+        let reg_run = Arc::new(Mutex::new(Runtime::new(q, Some(MetaEncryptor::new()))));
+        let mut reg = IntRegister::new(&reg_run, 0, 0);
+        reg.start();
+        for i in 0..100 {
+            reg.write(i);
+        }
+        assert_eq!(reg.read(), 99);
+
+        // entries 29, 59 and 89 hit the threshold on object 0
+        vm.runtime.lock().unwrap().sync(Some(0));
+        let stats = vm.snapshot_stats();
+        assert_eq!(stats.snapshots, 3);
+        assert_eq!(stats.last_idx, Some(89));
+        assert!(stats.max_time >= stats.mean_time());
+    }
+
     #[test]
     fn vm_full() {
         let q = SharedQueue::new();
-        let mut vm = VM::new(q.clone(), MapSkiplist::new(), AsyncSnapshotter::new());
+        let mut vm = VM::new(q.clone(),
+                             MapSkiplist::new(),
+                             AsyncSnapshotter::new(),
+                             SnapshotPolicy::new());
 
         let add_encryptor = AddEncryptor::new();
         // VM does snapshotting in reg, decrypting not needed
@@ -632,4 +2068,422 @@ mod test {
         assert_eq!(reg.read(), 149);
         // Now try to recover new register from VM: needs snapshots
     }
+
+    #[test]
+    fn vm_resume_from_checkpoint() {
+        let dir = env::temp_dir().join(format!("smr-checkpoints-{}", rand::random::<u32>()));
+        let q = SharedQueue::new();
+        let add_encryptor = AddEncryptor::new();
+        let me = MetaEncryptor::from(EqEncryptor::new(Encryptor::new()),
+                                     add_encryptor.clone(),
+                                     Encryptor::new(),
+                                     OrdEncryptor::new(Encryptor::new()),
+                                     SearchEncryptor::new(),
+                                     Authenticator::new());
+        let start_vm = || {
+            let policy = SnapshotPolicy::new().with_entries(Some(10));
+            let mut vm = VM::new(q.clone(),
+                                 MapSkiplist::new(),
+                                 FileSnapshotter::new(&dir, 2),
+                                 policy);
+            let mut reg = AddableRegister::new(&vm.runtime,
+                                               0,
+                                               Addable::default(add_encryptor.public_key()));
+            let reg1 = reg.clone();
+            vm.register_object(0, Box::new(move |_, e| reg.callback(e)), reg1);
+            vm.start();
+            vm
+        };
+
+        {
+            let vm = start_vm();
+            let reg_run = Arc::new(Mutex::new(Runtime::new(q.clone(), Some(me.clone()))));
+            let mut reg = IntRegister::new(&reg_run, 0, 0);
+            reg.start();
+            for i in 0..35 {
+                reg.write(i);
+            }
+            vm.runtime.lock().unwrap().sync(Some(0));
+            assert_eq!(vm.runtime.lock().unwrap().versions().lock().unwrap()[&0], 34);
+        }
+        // dropping the VM waits for checkpoints 9, 19 and 29 to be written, the last two are kept
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        // restarted VM serves the checkpointed snapshot, then entries appended after it
+        let mut vm = start_vm();
+        vm.runtime.lock().unwrap().sync(Some(0));
+        // versions resume from the checkpoint, not from the entries synced since
+        assert_eq!(vm.runtime.lock().unwrap().versions().lock().unwrap()[&0], 34);
+        let entries = vm.stream(&[0].iter().cloned().collect(), 0, None);
+        match entries.recv().unwrap() {
+            LogEntry(_) => panic!("first response should be snapshot"),
+            LogSnapshot(s) => {
+                assert_eq!(s.idx, 29);
+                // writer's last entry it covers, its readers resume its chain from it
+                let heads: Vec<_> = s.heads.iter().map(|e| e.idx.unwrap()).collect();
+                assert_eq!(heads, vec![29]);
+            }
+        }
+        let idxs: Vec<_> = entries.iter()
+                                  .map(|e| {
+                                      match e {
+                                          LogEntry(e) => e.idx.unwrap(),
+                                          _ => panic!("should only be one snapshot"),
+                                      }
+                                  })
+                                  .collect();
+        assert_eq!(idxs, vec![30, 31, 32, 33, 34]);
+        drop(entries);
+        drop(vm);
+
+        // corrupt checkpoints are skipped
+        let mut f = File::create(dir.join(format!("checkpoint-{:020}.json", 29))).unwrap();
+        f.write_all(b"{\"checksum\":\"00\",\"body\":\"{}\"}").unwrap();
+        let checkpoint = FileSnapshotter::new(&dir, 2).recover().unwrap();
+        assert_eq!(checkpoint.idx, 19);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checkpoint_write_error() {
+        let dir = env::temp_dir().join(format!("smr-checkpoints-{}", rand::random::<u32>()));
+        let q = SharedQueue::new();
+        let runtime: Arc<Mutex<Runtime<SharedQueue>>> = Arc::new(Mutex::new(Runtime::new(q, None)));
+        let reg = IntRegister::new(&runtime, 0, 0);
+        let mut reg2 = reg.clone();
+        let mut snapshotter = FileSnapshotter::new(&dir, 2);
+        snapshotter.register_object(0, Box::new(move |_, e| reg2.callback(e)), reg);
+        snapshotter.start();
+        snapshotter.snapshot(1, &HashMap::new(), &HashMap::new());
+
+        // checkpoint 2 cannot be written, snapshotting goes on from memory
+        fs::remove_dir_all(&dir).unwrap();
+        snapshotter.snapshot(2, &HashMap::new(), &HashMap::new());
+        let snaps = snapshotter.get_snapshots(&[0].iter().cloned().collect());
+        assert_eq!(snaps[&0].idx, 2);
+        assert!(snapshotter.recover().is_none());
+        snapshotter.shutdown();
+    }
+
+    #[test]
+    fn vm_shutdown_checkpoints() {
+        let dir = env::temp_dir().join(format!("smr-checkpoints-{}", rand::random::<u32>()));
+        let q = SharedQueue::new();
+        let add_encryptor = AddEncryptor::new();
+        let me = MetaEncryptor::from(EqEncryptor::new(Encryptor::new()),
+                                     add_encryptor.clone(),
+                                     Encryptor::new(),
+                                     OrdEncryptor::new(Encryptor::new()),
+                                     SearchEncryptor::new(),
+                                     Authenticator::new());
+        let policy = SnapshotPolicy::new().with_entries(Some(10));
+        let mut vm = VM::new(q.clone(), MapSkiplist::new(), FileSnapshotter::new(&dir, 2), policy);
+        let mut reg = AddableRegister::new(&vm.runtime,
+                                           0,
+                                           Addable::default(add_encryptor.public_key()));
+        let reg1 = reg.clone();
+        vm.register_object(0, Box::new(move |_, e| reg.callback(e)), reg1);
+        vm.start();
+
+        let reg_run = Arc::new(Mutex::new(Runtime::new(q.clone(), Some(me.clone()))));
+        let mut reg = IntRegister::new(&reg_run, 0, 0);
+        reg.start();
+        for i in 0..15 {
+            reg.write(i);
+        }
+        vm.runtime.lock().unwrap().sync(Some(0));
+        assert_eq!(vm.snapshot_stats().last_idx, Some(9));
+
+        // entries since the last snapshot are snapshotted and checkpointed on shutdown
+        vm.shutdown();
+        assert_eq!(vm.snapshot_stats().last_idx, Some(14));
+        assert_eq!(FileSnapshotter::new(&dir, 2).recover().unwrap().idx, 14);
+        // later shutdowns do nothing
+        vm.shutdown();
+        assert_eq!(vm.snapshot_stats().snapshots, 2);
+        drop(vm);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn vm_stream_all_pages() {
+        let mut q = SharedQueue::new();
+        let mut vm: VM<SharedQueue, MapSkiplist, AsyncSnapshotter> =
+            VM::new(q.clone(),
+                    MapSkiplist::new(),
+                    AsyncSnapshotter::new(),
+                    SnapshotPolicy::new());
+        let entry = |op: &str| {
+            Entry::new(HashMap::new(),
+                       vec![0].into_iter().collect(),
+                       vec![Operation::new(0, Encoded(String::from(op)))],
+                       TxType::None,
+                       TxState::None)
+        };
+        for _ in 0..10 {
+            q.append(entry("log"));
+        }
+        // entries cached by the VM are not read from the log again
+        for idx in 0..3 {
+            let mut e = entry("local");
+            e.idx = Some(idx);
+            vm.local_queue.lock().unwrap().insert(idx, e);
+        }
+        let ops = |data: Vec<LogData>| {
+            data.into_iter()
+                .map(|d| {
+                    match d {
+                        LogEntry(e) => (e.idx.unwrap(), e.operations[0].operator.clone()),
+                        _ => panic!("unexpected snapshot"),
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+        let page = ops(vm.stream_all_page(1, None, 4).unwrap().iter().collect());
+        assert_eq!(page,
+                   vec![(1, LogOp::Op(Encoded(String::from("local")))),
+                        (2, LogOp::Op(Encoded(String::from("local")))),
+                        (3, LogOp::Op(Encoded(String::from("log")))),
+                        (4, LogOp::Op(Encoded(String::from("log"))))]);
+        assert_eq!(vm.stream_all_page(8, None, 4).unwrap().iter().count(), 2);
+        assert_eq!(vm.stream_all_page(0, Some(2), 4).unwrap().iter().count(), 2);
+        assert_eq!(vm.stream_all(0, None).unwrap().iter().count(), 10);
+    }
+
+    #[test]
+    fn vm_clones_dropped_together() {
+        for _ in 0..20 {
+            let mut vm: VM<SharedQueue, MapSkiplist, AsyncSnapshotter> =
+                VM::new(SharedQueue::new(),
+                        MapSkiplist::new(),
+                        AsyncSnapshotter::new(),
+                        SnapshotPolicy::new());
+            vm.start();
+            let threads = vm.threads.clone();
+            let clones: Vec<_> = (0..4)
+                                     .map(|_| {
+                                         let vm = vm.clone();
+                                         thread::spawn(move || drop(vm))
+                                     })
+                                     .collect();
+            drop(vm);
+            for t in clones {
+                t.join().unwrap();
+            }
+            // whichever handle went last stopped the polling thread
+            assert!(threads.lock().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn local_queue_gc() {
+        let dir = env::temp_dir().join(format!("smr-spill-{}", rand::random::<u32>()));
+        let entry = |i| {
+            let op = Operation::new(0, State::Encoded(format!("{}", i)));
+            let mut e = Entry::new(HashMap::new(),
+                                   vec![0].into_iter().collect(),
+                                   vec![op],
+                                   TxType::None,
+                                   TxState::None);
+            e.idx = Some(i);
+            e
+        };
+        let mut dropped = LocalQueue::new(None);
+        let mut spilled = LocalQueue::new(Some(&dir));
+        for i in 0..10 {
+            dropped.insert(i, entry(i));
+            spilled.insert(i, entry(i));
+        }
+        dropped.gc(6, 0).unwrap();
+        spilled.gc(6, 0).unwrap();
+        assert_eq!((dropped.len(), dropped.low()), (4, 6));
+        assert_eq!(spilled.len(), 4);
+        assert!(dropped.get(2).unwrap().is_none());
+        assert_eq!(spilled.get(2).unwrap().unwrap().operations, entry(2).operations);
+        assert_eq!(spilled.get(5).unwrap().unwrap().operations, entry(5).operations);
+        assert_eq!(spilled.get(7).unwrap().unwrap().operations, entry(7).operations);
+
+        // spilled entries below the oldest snapshot retained are dropped from the file
+        let log = dir.join("local-queue.log");
+        let len = fs::metadata(&log).unwrap().len();
+        spilled.gc(10, 8).unwrap();
+        assert!(fs::metadata(&log).unwrap().len() < len);
+        assert!(spilled.get(5).unwrap().is_none());
+        assert_eq!(spilled.get(8).unwrap().unwrap().operations, entry(8).operations);
+        assert_eq!(spilled.get(9).unwrap().unwrap().operations, entry(9).operations);
+
+        // a spill file that can no longer be read returns errors
+        File::create(&log).unwrap();
+        assert!(spilled.get(9).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn vm_snapshots_on_interval() {
+        let q = SharedQueue::new();
+        let policy = SnapshotPolicy::new()
+                         .with_entries(None)
+                         .with_interval(Duration::from_millis(100));
+        let mut vm = VM::new(q.clone(), MapSkiplist::new(), AsyncSnapshotter::new(), policy);
+        let add_encryptor = AddEncryptor::new();
+        let mut reg = AddableRegister::new(&vm.runtime,
+                                           0,
+                                           Addable::default(add_encryptor.public_key()));
+        let reg1 = reg.clone();
+        vm.register_object(0, Box::new(move |_, e| reg.callback(e)), reg1);
+        vm.start();
+
+        let reg_run = Arc::new(Mutex::new(Runtime::new(q, Some(MetaEncryptor::new()))));
+        let mut reg = IntRegister::new(&reg_run, 0, 0);
+        reg.start();
+        for i in 0..5 {
+            reg.write(i);
+        }
+        // the log goes idle, the polling thread snapshots once the interval passed
+        sleep(Duration::from_millis(400));
+        let stats = vm.snapshot_stats();
+        assert_eq!((stats.snapshots, stats.last_idx), (1, Some(4)));
+        // nothing was applied since, no further snapshot
+        sleep(Duration::from_millis(200));
+        assert_eq!(vm.snapshot_stats().snapshots, 1);
+    }
+
+    #[test]
+    fn vm_trims_local_queue() {
+        let q = SharedQueue::new();
+        let policy = SnapshotPolicy::new().with_entries(Some(10)).with_gc_lag(0);
+        let mut vm = VM::new(q.clone(), MapSkiplist::new(), AsyncSnapshotter::new(), policy);
+        let add_encryptor = AddEncryptor::new();
+        let mut reg = AddableRegister::new(&vm.runtime,
+                                           0,
+                                           Addable::default(add_encryptor.public_key()));
+        let reg1 = reg.clone();
+        vm.register_object(0, Box::new(move |_, e| reg.callback(e)), reg1);
+        vm.start();
+
+        let reg_run = Arc::new(Mutex::new(Runtime::new(q, Some(MetaEncryptor::new()))));
+        let mut reg = IntRegister::new(&reg_run, 0, 0);
+        reg.start();
+        for i in 0..95 {
+            reg.write(i);
+        }
+        vm.runtime.lock().unwrap().sync(Some(0));
+
+        // last snapshot at 89, only entries from there on are kept
+        assert_eq!(vm.local_queue.lock().unwrap().len(), 6);
+        let entries = vm.stream(&[0].iter().cloned().collect(), 0, None);
+        match entries.recv().unwrap() {
+            LogSnapshot(s) => assert_eq!(s.idx, 89),
+            _ => panic!("first response should be snapshot"),
+        }
+        let idxs: Vec<_> = entries.iter()
+                                  .map(|e| {
+                                      match e {
+                                          LogEntry(e) => e.idx.unwrap(),
+                                          _ => panic!("should only be one snapshot"),
+                                      }
+                                  })
+                                  .collect();
+        assert_eq!(idxs, vec![90, 91, 92, 93, 94]);
+
+        // entries before 50 are trimmed, only the snapshot past 50 still covers them
+        let entries: Vec<_> = vm.stream(&[0].iter().cloned().collect(), 0, Some(50))
+                                .iter()
+                                .collect();
+        assert_eq!(entries.len(), 1);
+        match entries[0] {
+            LogSnapshot(ref s) => assert_eq!(s.idx, 89),
+            _ => panic!("trimmed range should be answered by snapshot"),
+        }
+    }
+
+    #[test]
+    fn vm_snapshots_late_object() {
+        let q = SharedQueue::new();
+        let policy = SnapshotPolicy::new().with_entries(Some(10)).with_gc_lag(0);
+        let mut vm = VM::new(q.clone(), MapSkiplist::new(), AsyncSnapshotter::new(), policy);
+        let add_encryptor = AddEncryptor::new();
+        let mut reg = AddableRegister::new(&vm.runtime,
+                                           0,
+                                           Addable::default(add_encryptor.public_key()));
+        let reg1 = reg.clone();
+        vm.register_object(0, Box::new(move |_, e| reg.callback(e)), reg1);
+        vm.start();
+
+        let reg_run = Arc::new(Mutex::new(Runtime::new(q, Some(MetaEncryptor::new()))));
+        let mut reg = IntRegister::new(&reg_run, 0, 0);
+        reg.start();
+        for i in 0..95 {
+            reg.write(i);
+        }
+        let mut late = IntRegister::new(&reg_run, 1, 0);
+        late.start();
+        late.write(1);
+        late.write(2);
+        vm.runtime.lock().unwrap().sync(Some(0));
+
+        // object 1 is registered after the snapshot at 89, entries before it are trimmed
+        let mut reg = AddableRegister::new(&vm.runtime,
+                                           1,
+                                           Addable::default(add_encryptor.public_key()));
+        let reg1 = reg.clone();
+        vm.register_object(1, Box::new(move |_, e| reg.callback(e)), reg1);
+
+        // both objects are answered from a new snapshot round
+        let entries: Vec<_> = vm.stream(&[0, 1].iter().cloned().collect(), 0, None)
+                                .iter()
+                                .collect();
+        assert_eq!(entries.len(), 2);
+        for data in entries {
+            match data {
+                LogSnapshot(s) => assert_eq!(s.idx, 96),
+                _ => panic!("objects should be answered by snapshots"),
+            }
+        }
+    }
+
+    #[test]
+    fn vm_rejects_forged_append() {
+        let q = SharedQueue::new();
+        let mut vm = VM::new(q.clone(),
+                             MapSkiplist::new(),
+                             AsyncSnapshotter::new(),
+                             SnapshotPolicy::new());
+        let alice = Signer::new();
+        let mallory = Signer::new();
+        vm.allow_writer(0, alice.writer_id());
+
+        let entry = |obj_id: ObjId, signer: Option<&Signer>| {
+            let mut e = Entry::new(HashMap::new(),
+                                   vec![obj_id].into_iter().collect(),
+                                   vec![Operation::new(obj_id, Encoded(String::from("1")))],
+                                   TxType::None,
+                                   TxState::None);
+            e.signature = signer.map(|signer| {
+                Signature {
+                    writer: signer.writer_id(),
+                    sig: signer.sign(&e.signed_bytes()),
+                }
+            });
+            e
+        };
+        assert_eq!(vm.try_append(entry(0, Some(&alice))), Ok(0));
+        // objects not listed can be written by anyone
+        assert_eq!(vm.try_append(entry(1, None)), Ok(1));
+
+        let rejected = |res| {
+            match res {
+                Err(LogError::Unauthorized(_, _)) => true,
+                _ => false,
+            }
+        };
+        assert!(rejected(vm.try_append(entry(0, Some(&mallory)))));
+        assert!(rejected(vm.try_append(entry(0, None))));
+        let mut forged = entry(0, Some(&mallory));
+        forged.signature.as_mut().unwrap().writer = alice.writer_id();
+        assert!(rejected(vm.try_append(forged)));
+
+        let mut q = q;
+        assert_eq!(q.stream_all(0, None).unwrap().iter().count(), 2);
+    }
 }
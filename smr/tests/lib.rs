@@ -3,16 +3,19 @@ extern crate rustc_serialize;
 
 use self::rustc_serialize::json;
 
-use smr::ds::{IntRegister, AddableRegister};
-use smr::maps::{UnencBTMap, EncBTMap, StringHMap, EncHMap};
+use smr::ds::{IntRegister, AddableRegister, DeadlineQueue, EncPriorityQueue, IntGCounter};
+use smr::maps::{UnencBTMap, EncBTMap, StringHMap, EncHMap, IndexKind};
+use smr::sets::{ReplicatedSet, StringHSet, EncHSet};
 use smr::runtime::Runtime;
+use smr::directory;
 use smr::indexed_queue::{SharedQueue, ObjId, LogData};
 use std::sync::{Arc, Mutex};
-use smr::vm::{VM, MapSkiplist, Snapshotter, AsyncSnapshotter};
+use smr::vm::{VM, MapSkiplist, Snapshotter, AsyncSnapshotter, SnapshotPolicy};
+use smr::converters::ConvertersLib;
 use smr::encryptors::{MetaEncryptor, Encryptor, AddEncryptor, EqEncryptor, OrdEncryptor, Addable,
-                      Ordable, Encrypted};
+                      Ordable, Encrypted, SearchEncryptor, Authenticator};
 use smr::indexed_queue::IndexedQueue;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::time::Duration;
 use std::thread;
 
@@ -23,7 +26,8 @@ fn vm_streaming_test() {
     let q = SharedQueue::new();
     let mut vm: VM<SharedQueue, MapSkiplist, AsyncSnapshotter> = VM::new(q.clone(),
                                                                          MapSkiplist::new(),
-                                                                         AsyncSnapshotter::new());
+                                                                         AsyncSnapshotter::new(),
+                                                                         SnapshotPolicy::new());
     let add_encryptor = AddEncryptor::new();
     let obj_id: ObjId = 0;
     let reg = AddableRegister::new(&vm.runtime,
@@ -38,7 +42,9 @@ fn vm_streaming_test() {
     let me = MetaEncryptor::from(EqEncryptor::new(Encryptor::new()),
                                  add_encryptor.clone(),
                                  Encryptor::new(),
-                                 OrdEncryptor::new(Encryptor::new()));
+                                 OrdEncryptor::new(Encryptor::new()),
+                                 SearchEncryptor::new(),
+                                 Authenticator::new());
     let client_runtime = Runtime::new(q, Some(me));
     let client_runtime = Arc::new(Mutex::new(client_runtime));
     let mut client_reg = IntRegister::new(&client_runtime, obj_id, 0);
@@ -65,7 +71,10 @@ fn vm_streaming_test() {
 #[test]
 fn vm_full() {
     let q = SharedQueue::new();
-    let mut vm = VM::new(q.clone(), MapSkiplist::new(), AsyncSnapshotter::new());
+    let mut vm = VM::new(q.clone(),
+                         MapSkiplist::new(),
+                         AsyncSnapshotter::new(),
+                         SnapshotPolicy::new());
 
     let add_encryptor = AddEncryptor::new();
     // VM does snapshotting in reg, decrypting not needed
@@ -120,7 +129,10 @@ fn register_integration_tests() {
     let encryptor = MetaEncryptor::new();
 
     // SETUP VM: Register two registers
-    let mut vm = VM::new(q.clone(), MapSkiplist::new(), AsyncSnapshotter::new());
+    let mut vm = VM::new(q.clone(),
+                         MapSkiplist::new(),
+                         AsyncSnapshotter::new(),
+                         SnapshotPolicy::new());
     let vm_reg1 = AddableRegister::new(&vm.runtime,
                                        1 as ObjId,
                                        Addable::default(encryptor.add.public_key()));
@@ -208,7 +220,10 @@ fn btmap_integration_tests() {
     let q = SharedQueue::new();
     let encryptor = MetaEncryptor::new();
     // SETUP VM
-    let mut vm = VM::new(q.clone(), MapSkiplist::new(), AsyncSnapshotter::new());
+    let mut vm = VM::new(q.clone(),
+                         MapSkiplist::new(),
+                         AsyncSnapshotter::new(),
+                         SnapshotPolicy::new());
     let vm_map1 = UnencBTMap::new(&vm.runtime, 1 as ObjId, BTreeMap::new());
     let mut vm_map1_copy = vm_map1.clone();
     vm.register_object(1 as ObjId,
@@ -288,7 +303,10 @@ fn hmap_integration_tests() {
     let q = SharedQueue::new();
     let encryptor = MetaEncryptor::new();
     // SETUP VM
-    let mut vm = VM::new(q.clone(), MapSkiplist::new(), AsyncSnapshotter::new());
+    let mut vm = VM::new(q.clone(),
+                         MapSkiplist::new(),
+                         AsyncSnapshotter::new(),
+                         SnapshotPolicy::new());
     let vm_map1 = EncHMap::new(&vm.runtime, 1 as ObjId, HashMap::new());
     let mut vm_map1_copy = vm_map1.clone();
     vm.register_object(1 as ObjId,
@@ -353,3 +371,262 @@ fn hmap_integration_tests() {
                    vals2[i]);
     }
 }
+
+#[test]
+fn hset_integration_tests() {
+    let q = SharedQueue::new();
+    let encryptor = MetaEncryptor::new();
+    // SETUP VM
+    let mut vm = VM::new(q.clone(),
+                         MapSkiplist::new(),
+                         AsyncSnapshotter::new(),
+                         SnapshotPolicy::new());
+    let vm_set = EncHSet::new(&vm.runtime, 1 as ObjId, HashSet::new());
+    let mut vm_set_copy = vm_set.clone();
+    vm.register_object(1 as ObjId,
+                       Box::new(move |_, e| vm_set_copy.callback(e)),
+                       vm_set.clone());
+    vm.start();
+
+    // SETUP CLIENT SET
+    let runtime: Runtime<SharedQueue> = Runtime::new(q.clone(), Some(encryptor.clone()));
+    let aruntime = Arc::new(Mutex::new(runtime));
+    let mut hset = StringHSet::new(&aruntime, 1 as ObjId, HashSet::new());
+    hset.start();
+
+    // Execute enough writes to lead to snapshotting
+    let elems = vec!["h0", "h1", "h2", "alphabet", "h0rry"];
+    let rounds = 120;
+    for i in 0..rounds {
+        hset.insert(String::from(elems[i % elems.len()]));
+    }
+    hset.remove(String::from("h2"));
+    assert_eq!(hset.len(), elems.len() - 1);
+
+    // check if set can recover from the vm
+    thread::sleep(Duration::from_millis(200));
+    let meta_runtime = Runtime::new(vm, Some(encryptor));
+    let a_meta_runtime = Arc::new(Mutex::new(meta_runtime));
+    let mut meta_hset = StringHSet::new(&a_meta_runtime, 1 as ObjId, HashSet::new());
+    meta_hset.start();
+
+    assert_eq!(meta_hset.len(), elems.len() - 1);
+    assert!(!meta_hset.contains(&String::from("h2")));
+    for elem in meta_hset.iter() {
+        assert!(hset.contains(&elem));
+    }
+}
+
+// VM answers peek-min over its encrypted replica
+#[test]
+fn priority_queue_vm_peek() {
+    let q = SharedQueue::new();
+    let encryptor = MetaEncryptor::new();
+    // SETUP VM
+    let mut vm = VM::new(q.clone(),
+                         MapSkiplist::new(),
+                         AsyncSnapshotter::new(),
+                         SnapshotPolicy::new());
+    let vm_pq = EncPriorityQueue::new(&vm.runtime, 1 as ObjId);
+    let mut vm_pq_copy = vm_pq.clone();
+    vm.register_object(1 as ObjId,
+                       Box::new(move |_, e| vm_pq_copy.callback(e)),
+                       vm_pq.clone());
+    vm.start();
+
+    // SETUP CLIENT QUEUE
+    let runtime: Runtime<SharedQueue> = Runtime::new(q.clone(), Some(encryptor.clone()));
+    let aruntime = Arc::new(Mutex::new(runtime));
+    let mut pq = DeadlineQueue::new(&aruntime, 1 as ObjId);
+    pq.start();
+    for deadline in vec![300, 20, 1000, 4000] {
+        pq.push(deadline, deadline.to_string());
+    }
+
+    // VM objects apply operations on their own snapshotter thread
+    thread::sleep(Duration::from_millis(200));
+    let (_, payload) = vm_pq.peek_min().expect("vm should see pushed jobs");
+    let payload: String = json::decode(&String::from_utf8(encryptor.decrypt(payload)).unwrap())
+                              .unwrap();
+    assert_eq!(payload, "20");
+
+    // a pop by the client is reflected on the VM replica
+    assert_eq!(pq.pop_min().unwrap().0, 20);
+    thread::sleep(Duration::from_millis(200));
+    let (_, payload) = vm_pq.peek_min().unwrap();
+    let payload: String = json::decode(&String::from_utf8(encryptor.decrypt(payload)).unwrap())
+                              .unwrap();
+    assert_eq!(payload, "300");
+}
+
+// VM replicas maintain secondary indexes from the same log ops as clients
+#[test]
+fn hmap_vm_index_lookup() {
+    let q = SharedQueue::new();
+    let encryptor = Some(MetaEncryptor::new());
+    // SETUP VM
+    let mut vm = VM::new(q.clone(),
+                         MapSkiplist::new(),
+                         AsyncSnapshotter::new(),
+                         SnapshotPolicy::new());
+    let vm_map = EncHMap::new(&vm.runtime, 1 as ObjId, HashMap::new());
+    let mut vm_map_copy = vm_map.clone();
+    vm.register_object(1 as ObjId,
+                       Box::new(move |_, e| vm_map_copy.callback(e)),
+                       vm_map.clone());
+    vm.start();
+
+    // SETUP CLIENT MAP
+    let runtime: Runtime<SharedQueue> = Runtime::new(q.clone(), encryptor.clone());
+    let aruntime = Arc::new(Mutex::new(runtime));
+    let mut hmap = StringHMap::new(&aruntime, 1 as ObjId, HashMap::new())
+                       .with_index("email", IndexKind::Eq, |record: &String| {
+                           String::from(record.split('|').next().unwrap())
+                       });
+    hmap.start();
+    hmap.insert(String::from("u1"), String::from("a@x.org|alice"));
+    hmap.insert(String::from("u2"), String::from("b@x.org|bob"));
+    hmap.insert(String::from("u3"), String::from("a@x.org|carol"));
+    assert_eq!(hmap.find("email", String::from("b@x.org")), vec!["u2"]);
+
+    // VM objects apply operations on their own snapshotter thread
+    thread::sleep(Duration::from_millis(200));
+    let attr = (ConvertersLib::eqable_from_encodable())(&encryptor, String::from("a@x.org"));
+    let keys = vm_map.index.lock().unwrap().get_eq("email", &attr);
+    let key_from_eqable = ConvertersLib::encodable_from_eqable();
+    let mut keys: Vec<String> = keys.into_iter()
+                                    .map(|k| key_from_eqable(&encryptor, k))
+                                    .collect();
+    keys.sort();
+    assert_eq!(keys, vec!["u1", "u3"]);
+}
+
+// VM answers keyword searches over its encrypted replica
+#[test]
+fn hmap_vm_keyword_search() {
+    let q = SharedQueue::new();
+    let encryptor = MetaEncryptor::new();
+    // SETUP VM
+    let mut vm = VM::new(q.clone(),
+                         MapSkiplist::new(),
+                         AsyncSnapshotter::new(),
+                         SnapshotPolicy::new());
+    let vm_map = EncHMap::new(&vm.runtime, 1 as ObjId, HashMap::new());
+    let mut vm_map_copy = vm_map.clone();
+    vm.register_object(1 as ObjId,
+                       Box::new(move |_, e| vm_map_copy.callback(e)),
+                       vm_map.clone());
+    vm.register_searchable(1 as ObjId, vm_map);
+    vm.start();
+
+    // SETUP CLIENT MAP
+    let runtime: Runtime<SharedQueue> = Runtime::new(q.clone(), Some(encryptor.clone()));
+    let aruntime = Arc::new(Mutex::new(runtime));
+    let words = |val: &String| val.split_whitespace().map(|w| String::from(w)).collect();
+    let mut hmap = StringHMap::new(&aruntime, 1 as ObjId, HashMap::new()).with_search(words);
+    hmap.start();
+    hmap.insert(String::from("d1"), String::from("encrypted shared log"));
+    hmap.insert(String::from("d2"), String::from("shared memory"));
+    hmap.insert(String::from("d3"), String::from("log structured merge tree"));
+    assert_eq!(hmap.search("memory"), vec!["d2"]);
+
+    // VM objects apply operations on their own snapshotter thread
+    thread::sleep(Duration::from_millis(200));
+    let meta_runtime = Runtime::new(vm, Some(encryptor));
+    let a_meta_runtime = Arc::new(Mutex::new(meta_runtime));
+    let remote = StringHMap::new(&a_meta_runtime, 1 as ObjId, HashMap::new());
+    let mut found = remote.remote_search("log").unwrap();
+    found.sort();
+    assert_eq!(found, vec!["d1", "d3"]);
+    assert!(remote.remote_search("merge sort").unwrap().is_empty());
+}
+
+// VM registers objects clients open by name, other clients open them through the VM
+#[test]
+fn vm_directory_discovery() {
+    let q = SharedQueue::new();
+    let encryptor = MetaEncryptor::new();
+    // SETUP VM, no objects registered up front
+    let mut vm = VM::new(q.clone(),
+                         MapSkiplist::new(),
+                         AsyncSnapshotter::new(),
+                         SnapshotPolicy::new());
+    vm.start();
+
+    // SETUP CLIENT MAP
+    let runtime: Runtime<SharedQueue> = Runtime::new(q.clone(), Some(encryptor.clone()));
+    let aruntime = Arc::new(Mutex::new(runtime));
+    let mut hmap: StringHMap<SharedQueue> = Runtime::open(&aruntime, "users").unwrap();
+    for i in 0..150 {
+        hmap.insert(format!("u{}", i), format!("user {}", i));
+    }
+
+    // VM discovers the object on its next poll and catches up on it
+    thread::sleep(Duration::from_millis(200));
+    let meta_runtime = Runtime::new(vm, Some(encryptor));
+    let a_meta_runtime = Arc::new(Mutex::new(meta_runtime));
+    let remote: StringHMap<VM<SharedQueue, MapSkiplist, AsyncSnapshotter>> =
+        Runtime::open(&a_meta_runtime, "users").unwrap();
+    assert_eq!(remote.get(&String::from("u7")).unwrap(), "user 7");
+    assert_eq!(remote.get(&String::from("u149")).unwrap(), "user 149");
+}
+
+// VM replicates registers and counters clients open by name
+#[test]
+fn vm_directory_register_and_counter() {
+    let q = SharedQueue::new();
+    let encryptor = MetaEncryptor::new();
+    let mut vm = VM::new(q.clone(),
+                         MapSkiplist::new(),
+                         AsyncSnapshotter::new(),
+                         SnapshotPolicy::new());
+    vm.start();
+
+    let runtime: Runtime<SharedQueue> = Runtime::new(q.clone(), Some(encryptor.clone()));
+    let aruntime = Arc::new(Mutex::new(runtime));
+    let mut reg: IntRegister<SharedQueue> = Runtime::open(&aruntime, "level").unwrap();
+    let mut visits: IntGCounter<SharedQueue> = Runtime::open(&aruntime, "visits").unwrap();
+    reg.inc(4);
+    reg.inc(3);
+    visits.inc(2);
+    visits.inc(5);
+    visits.flush();
+
+    thread::sleep(Duration::from_millis(200));
+    let meta_runtime = Runtime::new(vm, Some(encryptor));
+    let a_meta_runtime = Arc::new(Mutex::new(meta_runtime));
+    let mut remote_reg: IntRegister<VM<SharedQueue, MapSkiplist, AsyncSnapshotter>> =
+        Runtime::open(&a_meta_runtime, "level").unwrap();
+    let mut remote_visits: IntGCounter<VM<SharedQueue, MapSkiplist, AsyncSnapshotter>> =
+        Runtime::open(&a_meta_runtime, "visits").unwrap();
+    assert_eq!(remote_reg.read(), 7);
+    assert_eq!(remote_visits.read(), 7);
+}
+
+// VM registers objects on their first tagged operation, no directory involved
+#[test]
+fn vm_discovers_tagged_object() {
+    let q = SharedQueue::new();
+    let encryptor = MetaEncryptor::new();
+    let mut vm = VM::new(q.clone(),
+                         MapSkiplist::new(),
+                         AsyncSnapshotter::new(),
+                         SnapshotPolicy::new());
+    vm.start();
+
+    let mut runtime: Runtime<SharedQueue> = Runtime::new(q.clone(), Some(encryptor.clone()));
+    runtime.set_type_tag(5 as ObjId, directory::HSET);
+    let aruntime = Arc::new(Mutex::new(runtime));
+    let mut set = StringHSet::new(&aruntime, 5 as ObjId, HashSet::new());
+    set.start();
+    set.insert(String::from("a"));
+    set.insert(String::from("b"));
+
+    thread::sleep(Duration::from_millis(200));
+    let meta_runtime = Runtime::new(vm, Some(encryptor));
+    let a_meta_runtime = Arc::new(Mutex::new(meta_runtime));
+    let mut remote = StringHSet::new(&a_meta_runtime, 5 as ObjId, HashSet::new());
+    remote.start();
+    assert_eq!(remote.len(), 2);
+    assert!(remote.contains(&String::from("b")));
+}